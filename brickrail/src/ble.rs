@@ -1,28 +1,67 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
     bevy_tokio_tasks::TokioTasksRuntime,
     ble_train::{BLETrain, TrainData},
     editor::{
         DespawnMessage, EditorState, GenericID, Selection, SelectionState, SpawnHubMessage,
-        delete_selection_shortcut,
+        ViewSettings, delete_selection_shortcut,
     },
     inspector::{Inspectable, InspectorPlugin},
     layout::EntityMap,
-    layout_devices::LayoutDevice,
-    layout_primitives::{HubID, HubPort, HubType},
+    layout_devices::{LayoutData, LayoutDevice},
+    layout_primitives::{HubHardware, HubID, HubPort, HubType},
     persistent_hub_state::PersistentHubState,
     selectable::{Selectable, SelectablePlugin, SelectableType},
+    signal::Signal,
     switch::Switch,
     switch_motor::PulseMotor,
 };
 use bevy::prelude::*;
-use bevy::{ecs::system::SystemState, platform::collections::HashMap};
+use bevy::{
+    ecs::system::SystemState,
+    platform::collections::{HashMap, HashSet},
+};
 use bevy_inspector_egui::bevy_egui::egui::{self, Grid, Ui, widgets::Button};
-use pybricks_ble::io_hub::{IOEvent, IOHub, IOMessage, Input as IOInput, SysCode, mod_checksum};
-use pybricks_ble::pybricks_hub::HubStatusFlags;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, EguiPrimaryContextPass};
+use pybricks_ble::hub_backend::BackendKind;
+use pybricks_ble::io_hub::{
+    IOEvent, IOHub, IOMessage, Input as IOInput, InputQueueSender, SysCode, mod_checksum,
+};
+use pybricks_ble::pybricks_hub::{CancelToken, HubStatusFlags};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, mpsc::UnboundedSender};
+use tokio::sync::Mutex;
+
+// `--simulate-hubs` runs every hub in-process, for working without hardware
+fn hub_backend(transport: DeviceTransport) -> BackendKind {
+    if std::env::args().any(|arg| arg == "--simulate-hubs") {
+        return BackendKind::Simulated;
+    }
+    match transport {
+        DeviceTransport::Ble => BackendKind::Ble,
+        DeviceTransport::Tcp => BackendKind::Tcp,
+    }
+}
+
+/// How the commands of a hub reach the device. For `Tcp`, the hub name is its `host:port`.
+///
+/// Only the saved choice, the transports themselves implement `HubBackend` in `pybricks_ble`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DeviceTransport {
+    #[default]
+    Ble,
+    Tcp,
+}
+
+impl DeviceTransport {
+    pub fn iter() -> impl Iterator<Item = DeviceTransport> {
+        [DeviceTransport::Ble, DeviceTransport::Tcp].iter().copied()
+    }
+}
 
 #[derive(Component, Debug, Clone, Default)]
 pub struct HubState {
@@ -195,6 +234,25 @@ pub struct ObserverHub {
     keep_connected: bool,
 }
 
+impl ObserverHub {
+    pub fn new(defaults: &HubDefaults) -> Self {
+        Self {
+            keep_connected: defaults.observer_keep_connected,
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HubDefaults {
+    pub observer_keep_connected: bool,
+    // bytes per BLE write while downloading, None uses the most the hub accepts
+    #[serde(default)]
+    pub download_chunk_size: Option<usize>,
+    // only safe while hubs stay powered between attempts
+    #[serde(default)]
+    pub resume_downloads: bool,
+}
+
 #[derive(Component, Debug, Clone, PartialEq)]
 pub enum HubBusy {
     Connecting,
@@ -206,12 +264,273 @@ pub enum HubBusy {
     SettingReady,
 }
 
+const RECONNECT_INITIAL_DELAY: f32 = 1.0;
+const RECONNECT_MAX_DELAY: f32 = 16.0;
+const RECONNECT_MAX_ATTEMPTS: u32 = 8;
+
+// the hub dropped its connection while running, kept until it is prepared again
+#[derive(Component, Debug, Clone, Default)]
+pub struct HubReconnecting {
+    pub attempts: u32,
+}
+
 #[derive(Component, Debug, Clone, PartialEq)]
 pub enum HubError {
     ConnectError,
     ProgramError,
 }
 
+#[derive(Resource, Debug, Clone)]
+pub struct HubFailurePolicy {
+    // 0 disables the policy
+    pub max_failures: usize,
+    pub window: f32,
+}
+
+impl Default for HubFailurePolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: 120.0,
+        }
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct HubFailureLog {
+    failures: Vec<(f32, HubID, HubError)>,
+    pub summary: Option<String>,
+}
+
+impl HubFailureLog {
+    pub fn num_failures(&self) -> usize {
+        self.failures.len()
+    }
+}
+
+fn record_hub_failure(
+    trigger: On<Insert, HubError>,
+    q_hubs: Query<(&BLEHub, &HubError)>,
+    editor_state: Res<State<EditorState>>,
+    time: Res<Time>,
+    mut failure_log: ResMut<HubFailureLog>,
+) {
+    if editor_state.get() != &EditorState::PreparingDeviceControl {
+        return;
+    }
+    if let Ok((hub, error)) = q_hubs.get(trigger.entity) {
+        failure_log
+            .failures
+            .push((time.elapsed_secs(), hub.id, error.clone()));
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HubConnectionStats {
+    pub connected_time: f32,
+    pub disconnects: u32,
+    #[serde(skip)]
+    connected: bool,
+}
+
+// kept by hub name in the persistent state, so the tally spans restarts
+fn track_hub_uptime(
+    time: Res<Time>,
+    q_hubs: Query<(&BLEHub, &HubState)>,
+    mut persistent_hub_state: ResMut<PersistentHubState>,
+) {
+    let mut disconnected = false;
+    for (hub, state) in q_hubs.iter() {
+        let Some(name) = &hub.name else {
+            continue;
+        };
+        let stats = persistent_hub_state
+            .hub_stats
+            .entry(name.clone())
+            .or_default();
+        if state.connected {
+            stats.connected_time += time.delta_secs();
+        } else if stats.connected {
+            stats.disconnects += 1;
+            disconnected = true;
+        }
+        stats.connected = state.connected;
+    }
+    // connected time is written on exit, a dropped hub right away
+    if disconnected {
+        persistent_hub_state.save_to_disk();
+    }
+}
+
+fn reset_hub_failures(mut failure_log: ResMut<HubFailureLog>) {
+    failure_log.failures.clear();
+    failure_log.summary = None;
+}
+
+fn check_hub_failures(
+    time: Res<Time>,
+    policy: Res<HubFailurePolicy>,
+    mut failure_log: ResMut<HubFailureLog>,
+    mut next_editor_state: ResMut<NextState<EditorState>>,
+    hubs: Query<&BLEHub>,
+) {
+    let now = time.elapsed_secs();
+    failure_log
+        .failures
+        .retain(|(failure_time, _, _)| now - failure_time <= policy.window);
+    if policy.max_failures == 0 || failure_log.failures.len() < policy.max_failures {
+        return;
+    }
+    let mut summary = format!(
+        "BLE preparation failed after {} hub errors within {:1.0}s:",
+        failure_log.failures.len(),
+        policy.window
+    );
+    for (_, hub_id, error) in failure_log.failures.iter() {
+        summary.push_str(&format!("\n{}: {:?}", get_hub_label(&hubs, hub_id), error));
+    }
+    error!("{}", summary);
+    failure_log.failures.clear();
+    failure_log.summary = Some(summary);
+    next_editor_state.set(EditorState::Edit);
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct HubBatteryPolicy {
+    pub low_voltage: f32,
+    // a low hub only counts as recovered once it is this far above low_voltage
+    pub hysteresis: f32,
+    pub stop_trains: bool,
+}
+
+impl Default for HubBatteryPolicy {
+    fn default() -> Self {
+        Self {
+            low_voltage: 6.5,
+            hysteresis: 0.2,
+            stop_trains: true,
+        }
+    }
+}
+
+impl HubBatteryPolicy {
+    pub fn is_low(&self, voltage: f32, was_low: bool) -> bool {
+        if was_low {
+            voltage < self.low_voltage + self.hysteresis
+        } else {
+            voltage < self.low_voltage
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Low below");
+            ui.add(
+                egui::DragValue::new(&mut self.low_voltage)
+                    .range(0.0..=12.0)
+                    .speed(0.05)
+                    .suffix("V"),
+            );
+        });
+        ui.horizontal(|ui| {
+            ui.label("Recover above +");
+            ui.add(
+                egui::DragValue::new(&mut self.hysteresis)
+                    .range(0.0..=2.0)
+                    .speed(0.01)
+                    .suffix("V"),
+            );
+        });
+        ui.checkbox(&mut self.stop_trains, "Stop trains on low battery");
+    }
+}
+
+// latest reading from the hub's alive messages
+#[derive(Component, Debug, Clone)]
+pub struct HubBattery {
+    pub voltage: f32,
+    pub current: f32,
+    pub low: bool,
+}
+
+impl HubBattery {
+    pub fn ui(&self, ui: &mut Ui) {
+        let text = egui::RichText::new(format!(
+            "Battery: {:.2}V, {:.2}A",
+            self.voltage, self.current
+        ));
+        if self.low {
+            ui.label(text.color(egui::Color32::RED));
+        } else {
+            ui.label(text);
+        }
+    }
+}
+
+const RSSI_POLL_INTERVAL: f32 = 2.0;
+// below this the link tends to drop
+const WEAK_RSSI: i16 = -85;
+
+#[derive(Component, Debug, Clone)]
+pub struct HubRssi {
+    pub rssi: i16,
+}
+
+impl HubRssi {
+    pub fn is_weak(&self) -> bool {
+        self.rssi < WEAK_RSSI
+    }
+
+    pub fn ui(&self, ui: &mut Ui) {
+        // map roughly -100dBm..-40dBm onto the bar
+        let strength = ((self.rssi as f32 + 100.0) / 60.0).clamp(0.0, 1.0);
+        ui.horizontal(|ui| {
+            ui.label("Signal:");
+            ui.add(
+                egui::ProgressBar::new(strength)
+                    .desired_width(80.0)
+                    .text(format!("{} dBm", self.rssi)),
+            );
+        });
+        if self.is_weak() {
+            ui.label(
+                egui::RichText::new("Weak signal, the hub may disconnect")
+                    .color(egui::Color32::YELLOW),
+            );
+        }
+    }
+}
+
+fn poll_hub_rssi(
+    time: Res<Time>,
+    mut elapsed: Local<f32>,
+    q_hubs: Query<&BLEHub, (With<HubConnected>, Without<HubBusy>)>,
+    runtime: Res<TokioTasksRuntime>,
+) {
+    *elapsed += time.delta_secs();
+    if *elapsed < RSSI_POLL_INTERVAL {
+        return;
+    }
+    *elapsed = 0.0;
+    for hub in q_hubs.iter() {
+        let io_hub = hub.hub.clone();
+        runtime.spawn_background_task(move |_| async move {
+            // don't queue up behind a download or connect holding the hub
+            if let Ok(io_hub) = io_hub.try_lock() {
+                if let Err(err) = io_hub.poll_rssi().await {
+                    debug!("Could not read rssi: {:?}", err);
+                }
+            }
+        });
+    }
+}
+
+fn clear_hub_batteries(q_hubs: Query<Entity, With<HubBattery>>, mut commands: Commands) {
+    for entity in q_hubs.iter() {
+        commands.entity(entity).remove::<HubBattery>();
+    }
+}
+
 #[derive(Message, Debug)]
 pub struct HubDeviceStateMessage {
     pub hub_id: HubID,
@@ -241,6 +560,37 @@ fn handle_device_state_msgs(
     }
 }
 
+// Pybricks fixes the broadcast channel of a hub when the program starts, so the broadcaster
+// and all observers share BLE channel 0. Within that channel every state is sent as
+// [name_id, state_id, state], and the name_id of an observer acts as its own sub-channel:
+// each observer only applies the triples prefixed with the checksum of its own name.
+// The broadcaster advertises the most recent MAX_BROADCAST_STATES triples at once.
+const MAX_BROADCAST_STATES: usize = 8;
+// after the device settings of all six ports, see io_hub_unfrozen.py
+const COMM_TYPE_ADDRESS: u8 = 56;
+
+// dedups per (name_id, state_id) so only the latest state is sent, in order of first change
+fn batch_broadcast_states(states: &[(u8, u8, u8)]) -> Vec<Vec<u8>> {
+    let mut latest: Vec<(u8, u8, u8)> = Vec::new();
+    for (name_id, state_id, state) in states.iter().copied() {
+        match latest.iter_mut().find(|(other_name, other_state_id, _)| {
+            *other_name == name_id && *other_state_id == state_id
+        }) {
+            Some(entry) => entry.2 = state,
+            None => latest.push((name_id, state_id, state)),
+        }
+    }
+    latest
+        .chunks(MAX_BROADCAST_STATES)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .flat_map(|(name_id, state_id, state)| [*name_id, *state_id, *state])
+                .collect()
+        })
+        .collect()
+}
+
 fn handle_observer_device_state_msgs(
     mut device_state_reader: MessageReader<HubDeviceStateMessage>,
     mut hub_command_writer: MessageWriter<HubCommandMessage>,
@@ -248,41 +598,97 @@ fn handle_observer_device_state_msgs(
     broadcaster: Option<Single<&BLEHub, With<BroadcasterHub>>>,
     entity_map: Res<EntityMap>,
 ) {
+    let mut states = Vec::new();
     for state_msg in device_state_reader.read() {
         let hub_entity = entity_map.hubs.get(&state_msg.hub_id).unwrap();
-        if !observer_hubs.contains(*hub_entity) {
+        let Ok(observer_hub) = observer_hubs.get(*hub_entity) else {
             continue;
-        }
-        let observer_hub = observer_hubs.get(*hub_entity).unwrap();
-        let hub_msg = HubCommandMessage {
-            hub_id: broadcaster.as_ref().unwrap().id.clone(),
-            command: HubCommand::QueueInput(IOInput::broadcast_cmd(&[
-                observer_hub.name_id().unwrap(),
-                state_msg.state_id,
-                state_msg.state,
-            ])),
         };
-        hub_command_writer.write(hub_msg);
+        let Some(name_id) = observer_hub.name_id() else {
+            warn!(
+                "Observer hub {:?} has no name, can't broadcast to it",
+                observer_hub.id
+            );
+            continue;
+        };
+        if let Some(other) = observer_hubs
+            .iter()
+            .find(|other| other.id != observer_hub.id && other.name_id() == Some(name_id))
+        {
+            warn!(
+                "Observer hubs {:?} and {:?} share name id {}, rename one of them",
+                observer_hub.id, other.id, name_id
+            );
+        }
+        states.push((name_id, state_msg.state_id, state_msg.state));
+    }
+    if states.is_empty() {
+        return;
+    }
+    let Some(broadcaster) = broadcaster else {
+        warn!("No broadcaster hub to send observer device states");
+        return;
+    };
+    for batch in batch_broadcast_states(&states) {
+        hub_command_writer.write(HubCommandMessage {
+            hub_id: broadcaster.id.clone(),
+            command: HubCommand::QueueInput(IOInput::broadcast_cmd(&batch)),
+        });
     }
 }
 
 #[derive(Component, Serialize, Deserialize, Clone)]
+#[serde(from = "SerializedBLEHub")]
 pub struct BLEHub {
     pub id: HubID,
     #[serde(skip)]
     hub: Arc<Mutex<IOHub>>,
     #[serde(skip)]
-    input_sender: Option<UnboundedSender<IOInput>>,
+    input_sender: Option<InputQueueSender>,
+    #[serde(skip)]
+    download_cancel: CancelToken,
     pub name: Option<String>,
+    #[serde(default)]
+    pub hardware: HubHardware,
+    #[serde(default)]
+    pub transport: DeviceTransport,
+}
+
+// the skipped fields have to be created together, so deserialize through new()
+#[derive(Deserialize)]
+struct SerializedBLEHub {
+    id: HubID,
+    name: Option<String>,
+    #[serde(default)]
+    hardware: HubHardware,
+    #[serde(default)]
+    transport: DeviceTransport,
+}
+
+impl From<SerializedBLEHub> for BLEHub {
+    fn from(serialized: SerializedBLEHub) -> Self {
+        let mut hub = BLEHub::with_transport(serialized.id, serialized.transport);
+        hub.name = serialized.name;
+        hub.hardware = serialized.hardware;
+        hub
+    }
 }
 
 impl BLEHub {
     pub fn new(id: HubID) -> Self {
+        Self::with_transport(id, DeviceTransport::default())
+    }
+
+    pub fn with_transport(id: HubID, transport: DeviceTransport) -> Self {
+        let io_hub = IOHub::new(hub_backend(transport));
         Self {
             id,
-            hub: Arc::new(Mutex::new(IOHub::new())),
+            download_cancel: io_hub.download_cancel_token(),
+            hub: Arc::new(Mutex::new(io_hub)),
             input_sender: None,
             name: None,
+            hardware: HubHardware::default(),
+            transport,
         }
     }
 
@@ -291,18 +697,27 @@ impl BLEHub {
         Some(mod_checksum(self.name.as_ref()?.as_bytes()))
     }
 
-    pub fn get_program_path(&self) -> &'static Path {
-        // print cwd:
-        println!("{:?}", std::env::current_dir().unwrap());
-        match self.id.kind {
+    pub fn queue_depth(&self) -> usize {
+        self.input_sender
+            .as_ref()
+            .map_or(0, |sender| sender.depth())
+    }
+
+    pub fn get_program_path(&self) -> PathBuf {
+        let path = match self.id.kind {
             HubType::Layout => Path::new("pybricks/programs/mpy/layout_controller.mpy"),
             HubType::Train => Path::new("pybricks/programs/mpy/smart_train.mpy"),
+        };
+        if path.exists() {
+            return path.to_path_buf();
         }
+        // started from the crate directory, like cargo test does
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(path)
     }
 
     pub fn get_program_hash(&self) -> String {
         let path = self.get_program_path();
-        crate::utils::get_file_hash(path)
+        crate::utils::get_file_hash(&path)
     }
 
     pub fn is_marked_downloaded_in_persistent_cache(
@@ -369,15 +784,21 @@ impl BLEHub {
     pub fn inspector(ui: &mut Ui, world: &mut World) {
         let mut state = SystemState::<(
             Query<(
-                &BLEHub,
+                &mut BLEHub,
                 &HubState,
                 Option<&HubBusy>,
                 Option<&mut ObserverHub>,
                 Option<&BroadcasterHub>,
+                Option<&HubReconnecting>,
+                Option<&HubError>,
+                Option<&HubBattery>,
+                Option<&HubRssi>,
             )>,
             Res<EntityMap>,
             Res<SelectionState>,
             Res<AppTypeRegistry>,
+            Res<HubDefaults>,
+            Res<PersistentHubState>,
             MessageWriter<HubCommandMessage>,
             Commands,
         )>::new(world);
@@ -386,11 +807,23 @@ impl BLEHub {
             entity_map,
             selection_state,
             _type_registry,
+            hub_defaults,
+            persistent_hub_state,
             mut command_messages,
             mut commands,
         ) = state.get_mut(world);
         if let Some(entity) = selection_state.get_entity(&entity_map) {
-            if let Ok((hub, state, busy, maybe_observer, maybe_broadcaster)) = hubs.get_mut(entity)
+            if let Ok((
+                mut hub,
+                state,
+                busy,
+                maybe_observer,
+                maybe_broadcaster,
+                reconnecting,
+                maybe_error,
+                maybe_battery,
+                maybe_rssi,
+            )) = hubs.get_mut(entity)
             {
                 ui.label(format!("BLE Hub {:?}", hub.id));
                 ui.label(format!(
@@ -398,11 +831,91 @@ impl BLEHub {
                     hub.name.as_deref().unwrap_or("Unknown")
                 ));
                 ui.label(format!("name id: {:?}", hub.name_id()));
+                let mut hardware = hub.hardware;
+                egui::ComboBox::from_label("Hardware")
+                    .selected_text(hardware.to_string())
+                    .show_ui(ui, |ui| {
+                        for option in HubHardware::iter() {
+                            ui.selectable_value(&mut hardware, option, option.to_string());
+                        }
+                    });
+                if hardware != hub.hardware {
+                    hub.hardware = hardware;
+                }
+                ui.label(format!("Queued inputs: {}", hub.queue_depth()));
+                if let Some(stats) = hub
+                    .name
+                    .as_ref()
+                    .and_then(|name| persistent_hub_state.hub_stats.get(name))
+                {
+                    ui.label(format!(
+                        "Connected: {:1.0}s, disconnects: {}",
+                        stats.connected_time, stats.disconnects
+                    ));
+                }
+                if let Some(battery) = maybe_battery {
+                    battery.ui(ui);
+                }
+                if let Some(rssi) = maybe_rssi {
+                    rssi.ui(ui);
+                }
                 // ui.label(state.pretty_print());
                 state.ui(ui, busy);
+                if let Some(reconnecting) = reconnecting {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!(
+                            "Reconnecting, attempt {}/{}",
+                            reconnecting.attempts, RECONNECT_MAX_ATTEMPTS
+                        ));
+                    });
+                }
+                if let Some(err) = maybe_error {
+                    ui.colored_label(egui::Color32::LIGHT_RED, format!("Error: {:?}", err));
+                }
+
+                ui.add_enabled_ui(!state.connected && busy.is_none(), |ui| {
+                    let mut transport = hub.transport;
+                    egui::ComboBox::from_label("Transport")
+                        .selected_text(format!("{:?}", transport))
+                        .show_ui(ui, |ui| {
+                            for option in DeviceTransport::iter() {
+                                ui.selectable_value(
+                                    &mut transport,
+                                    option,
+                                    format!("{:?}", option),
+                                );
+                            }
+                        });
+                    if transport != hub.transport {
+                        hub.transport = transport;
+                        command_messages.write(HubCommandMessage {
+                            hub_id: hub.id.clone(),
+                            command: HubCommand::SetTransport(transport),
+                        });
+                    }
+                    if hub.transport == DeviceTransport::Tcp {
+                        let mut address = hub.name.clone().unwrap_or_default();
+                        ui.horizontal(|ui| {
+                            ui.label("Address");
+                            if ui
+                                .add(
+                                    egui::TextEdit::singleline(&mut address)
+                                        .hint_text("192.168.0.10:4242"),
+                                )
+                                .changed()
+                            {
+                                hub.name = (!address.is_empty()).then_some(address);
+                            }
+                        });
+                    }
+                });
 
                 if ui
-                    .button("Discover Name")
+                    .add_enabled(
+                        hub.transport == DeviceTransport::Ble,
+                        Button::new("Discover Name"),
+                    )
                     .on_hover_text("Discover the name of the hub")
                     .clicked()
                 {
@@ -449,6 +962,14 @@ impl BLEHub {
                         command: HubCommand::DownloadProgram,
                     });
                 }
+                if matches!(busy, Some(HubBusy::Downloading(_)))
+                    && ui.button("Cancel Download").clicked()
+                {
+                    command_messages.write(HubCommandMessage {
+                        hub_id: hub.id,
+                        command: HubCommand::CancelDownload,
+                    });
+                }
                 if ui
                     .add_enabled(
                         state.downloaded
@@ -483,9 +1004,9 @@ impl BLEHub {
                 if ui.checkbox(&mut is_observer, "Observer Hub").changed() {
                     let entity = entity_map.hubs[&hub.id];
                     if is_observer {
-                        commands.entity(entity).insert(ObserverHub {
-                            keep_connected: false,
-                        });
+                        commands
+                            .entity(entity)
+                            .insert(ObserverHub::new(&hub_defaults));
                     } else {
                         commands.entity(entity).remove::<ObserverHub>();
                     }
@@ -537,6 +1058,9 @@ impl BLEHub {
             }
             ui.end_row();
             ui.label("Port");
+            let hardware = selected_hub
+                .and_then(|id| hubs.iter().find(|hub| hub.id == id))
+                .map(|hub| hub.hardware);
             ui.add_enabled_ui(selected_hub.is_some(), |ui| {
                 ui.push_id("port", |ui| {
                     ui.horizontal(|ui| {
@@ -549,7 +1073,9 @@ impl BLEHub {
                             ))
                             .show_ui(ui, |ui| {
                                 ui.selectable_value(selected_port, None, "None");
-                                for option in HubPort::iter() {
+                                for option in HubPort::iter().filter(|port| {
+                                    hardware.is_none_or(|hardware| hardware.has_port(port))
+                                }) {
                                     ui.selectable_value(
                                         selected_port,
                                         Some(option),
@@ -557,6 +1083,14 @@ impl BLEHub {
                                     );
                                 }
                             });
+                        if let (Some(hardware), Some(port)) = (hardware, *selected_port)
+                            && !hardware.has_port(&port)
+                        {
+                            ui.colored_label(
+                                egui::Color32::YELLOW,
+                                format!("{} has no port {}", hardware, port),
+                            );
+                        }
                     });
                 });
             });
@@ -622,6 +1156,95 @@ fn on_inserted_broadcaster(
         }
     }
 }
+fn hub_roles_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    editor_state: Res<State<EditorState>>,
+    q_hubs: Query<(
+        Entity,
+        &BLEHub,
+        Option<&ObserverHub>,
+        Option<&BroadcasterHub>,
+    )>,
+    hub_defaults: Res<HubDefaults>,
+    mut commands: Commands,
+) {
+    let Ok(ctx) = &egui_contexts.ctx_mut().cloned() else {
+        return;
+    };
+    let mut hubs = q_hubs.iter().collect::<Vec<_>>();
+    hubs.sort_by_key(|(_, hub, _, _)| hub.id);
+    egui::Window::new("Hub roles")
+        .open(&mut view_settings.hub_roles)
+        .show(ctx, |ui| {
+            let editable = editor_state.get() == &EditorState::Edit;
+            let mut num_observers = 0;
+            let mut num_broadcasters = 0;
+            ui.add_enabled_ui(editable, |ui| {
+                Grid::new("hub_roles").striped(true).show(ui, |ui| {
+                    ui.label("Hub");
+                    ui.label("Regular");
+                    ui.label("Observer");
+                    ui.label("Broadcaster");
+                    ui.end_row();
+                    for (entity, hub, maybe_observer, maybe_broadcaster) in hubs.iter() {
+                        let role = HubCommType::from_query(*maybe_observer, *maybe_broadcaster);
+                        match role {
+                            HubCommType::Observer => num_observers += 1,
+                            HubCommType::Broadcaster => num_broadcasters += 1,
+                            HubCommType::Regular => {}
+                        }
+                        ui.label(format!(
+                            "{} ({})",
+                            hub.id,
+                            hub.name.as_deref().unwrap_or("Unknown")
+                        ));
+                        let mut selected = role;
+                        for option in [
+                            HubCommType::Regular,
+                            HubCommType::Observer,
+                            HubCommType::Broadcaster,
+                        ] {
+                            ui.radio_value(&mut selected, option, "");
+                        }
+                        ui.end_row();
+                        if selected == role {
+                            continue;
+                        }
+                        if selected == HubCommType::Broadcaster {
+                            // demote the previous broadcaster
+                            for (other, _, _, other_broadcaster) in hubs.iter() {
+                                if other_broadcaster.is_some() {
+                                    commands.entity(*other).remove::<BroadcasterHub>();
+                                }
+                            }
+                        }
+                        let mut entity_commands = commands.entity(*entity);
+                        entity_commands.remove::<(ObserverHub, BroadcasterHub)>();
+                        match selected {
+                            HubCommType::Observer => {
+                                entity_commands.insert(ObserverHub::new(&hub_defaults));
+                            }
+                            HubCommType::Broadcaster => {
+                                entity_commands.insert(BroadcasterHub);
+                            }
+                            HubCommType::Regular => {}
+                        }
+                    }
+                });
+            });
+            if !editable {
+                ui.label("Roles can only be changed in edit mode");
+            }
+            if num_observers > 0 && num_broadcasters != 1 {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    "Observer hubs need exactly one broadcaster hub",
+                );
+            }
+        });
+}
+
 fn get_hub_label(hubs: &Query<&BLEHub>, id: &HubID) -> String {
     for hub in hubs.iter() {
         if &hub.id == id {
@@ -634,6 +1257,42 @@ fn get_hub_label(hubs: &Query<&BLEHub>, id: &HubID) -> String {
     return format!("Unkown {:}", id);
 }
 
+// ids for the hubs of a layout loaded into a session that already knows the `existing` hubs.
+// hubs with a known name get that hub's id, the others keep their id unless it's taken.
+pub fn merge_hub_ids(
+    existing: &[(HubID, Option<String>)],
+    incoming: &[(HubID, Option<String>)],
+) -> HashMap<HubID, HubID> {
+    let mut mapping = HashMap::new();
+    let mut taken = existing.iter().map(|(id, _)| *id).collect::<HashSet<_>>();
+    for (id, name) in incoming.iter() {
+        let known = existing.iter().find(|(existing_id, existing_name)| {
+            existing_id.kind == id.kind && name.is_some() && existing_name == name
+        });
+        if let Some((existing_id, _)) = known {
+            mapping.insert(*id, *existing_id);
+        }
+    }
+    for (id, _) in incoming.iter() {
+        if !mapping.contains_key(id) && !taken.contains(id) {
+            mapping.insert(*id, *id);
+            taken.insert(*id);
+        }
+    }
+    for (id, _) in incoming.iter() {
+        if mapping.contains_key(id) {
+            continue;
+        }
+        let mut new_id = *id;
+        while taken.contains(&new_id) {
+            new_id.id += 1;
+        }
+        taken.insert(new_id);
+        mapping.insert(*id, new_id);
+    }
+    mapping
+}
+
 fn spawn_hub(
     runtime: Res<TokioTasksRuntime>,
     mut spawn_event_reader: MessageReader<SpawnHubMessage>,
@@ -727,6 +1386,10 @@ impl HubConfiguration {
         self.data.insert(address, value);
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&u8, &u32)> {
+        self.data.iter()
+    }
+
     pub fn merge(&mut self, other: &Self) {
         for (address, value) in other.data.iter() {
             assert!(
@@ -739,7 +1402,7 @@ impl HubConfiguration {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HubCommType {
     Observer,
     Broadcaster,
@@ -773,8 +1436,11 @@ impl HubCommType {
 pub enum HubCommand {
     DiscoverName,
     Connect,
+    Reconnect,
     Disconnect,
     DownloadProgram,
+    CancelDownload,
+    SetTransport(DeviceTransport),
     StartProgram,
     StopProgram,
     QueueInput(IOInput),
@@ -804,6 +1470,7 @@ fn execute_hub_commands(
     runtime: Res<TokioTasksRuntime>,
     mut commands: Commands,
     mut persistent_hub_state: ResMut<PersistentHubState>,
+    hub_defaults: Res<HubDefaults>,
 ) {
     for event in hub_command_reader.read() {
         let entity = entity_map.hubs[&event.hub_id];
@@ -835,6 +1502,48 @@ fn execute_hub_commands(
                     }
                 });
             }
+            HubCommand::Reconnect => {
+                let io_hub = hub.hub.clone();
+                let name = hub.name.as_ref().unwrap().clone();
+                runtime.spawn_background_task(move |mut ctx| async move {
+                    let mut delay = RECONNECT_INITIAL_DELAY;
+                    for attempt in 1..=RECONNECT_MAX_ATTEMPTS {
+                        tokio::time::sleep(Duration::from_secs_f32(delay)).await;
+                        info!("Reconnecting to hub {:?}, attempt {}", name, attempt);
+                        let connected = io_hub.lock().await.connect(&name).await.is_ok();
+                        ctx.run_on_main_thread(move |ctx_main| {
+                            let mut system_state: SystemState<Commands> =
+                                SystemState::new(ctx_main.world);
+                            let mut commands = system_state.get_mut(ctx_main.world);
+                            if connected {
+                                commands.entity(entity).insert(HubConnected);
+                            } else {
+                                commands
+                                    .entity(entity)
+                                    .insert(HubReconnecting { attempts: attempt });
+                            }
+                            system_state.apply(ctx_main.world);
+                        })
+                        .await;
+                        if connected {
+                            return;
+                        }
+                        delay = (delay * 2.0).min(RECONNECT_MAX_DELAY);
+                    }
+                    error!("Giving up reconnecting to hub {:?}", name);
+                    ctx.run_on_main_thread(move |ctx_main| {
+                        let mut system_state: SystemState<Commands> =
+                            SystemState::new(ctx_main.world);
+                        let mut commands = system_state.get_mut(ctx_main.world);
+                        commands
+                            .entity(entity)
+                            .remove::<HubReconnecting>()
+                            .insert(HubError::ConnectError);
+                        system_state.apply(ctx_main.world);
+                    })
+                    .await;
+                });
+            }
             HubCommand::Disconnect => {
                 commands
                     .entity(entity)
@@ -858,8 +1567,37 @@ fn execute_hub_commands(
                 commands.entity(entity).insert(HubBusy::Downloading(0.0));
                 let io_hub = hub.hub.clone();
                 let program = hub.get_program_path();
+                let cancel = hub.download_cancel.clone();
+                cancel.reset();
+                let chunk_size = hub_defaults.download_chunk_size;
+                let resume = hub_defaults.resume_downloads;
                 runtime.spawn_background_task(move |mut ctx| async move {
-                    io_hub.lock().await.download_program(program).await.unwrap();
+                    let io_hub = io_hub.lock().await;
+                    io_hub.set_download_chunk_size(chunk_size).await;
+                    io_hub.set_resume_downloads(resume).await;
+                    let result = io_hub
+                        .download_program(&program)
+                        .await
+                        .map_err(|err| err.to_string());
+                    if let Err(err) = result {
+                        let canceled = cancel.is_canceled();
+                        if !canceled {
+                            error!("Download failed: {:?}", err);
+                        }
+                        ctx.run_on_main_thread(move |ctx_main| {
+                            let mut system_state: SystemState<Commands> =
+                                SystemState::new(ctx_main.world);
+                            let mut commands = system_state.get_mut(ctx_main.world);
+                            // the hub stays connected, but without a program
+                            commands.entity(entity).remove::<HubBusy>();
+                            if !canceled {
+                                commands.entity(entity).insert(HubError::ProgramError);
+                            }
+                            system_state.apply(ctx_main.world);
+                        })
+                        .await;
+                        return;
+                    }
                     ctx.run_on_main_thread(move |ctx_main| {
                         let mut system_state: SystemState<(
                             Query<&mut BLEHub>,
@@ -880,6 +1618,21 @@ fn execute_hub_commands(
                     .await;
                 });
             }
+            HubCommand::CancelDownload => {
+                info!("Canceling download to hub {:?}", hub.name);
+                hub.download_cancel.cancel();
+            }
+            HubCommand::SetTransport(transport) => {
+                info!("Switching hub {:?} to {:?}", hub.id, transport);
+                let io_hub = hub.hub.clone();
+                runtime.spawn_background_task(move |_ctx| async move {
+                    io_hub
+                        .lock()
+                        .await
+                        .set_backend(hub_backend(transport))
+                        .await;
+                });
+            }
             HubCommand::StartProgram => {
                 commands.entity(entity).insert(HubBusy::Starting);
                 let io_hub = hub.hub.clone();
@@ -973,20 +1726,34 @@ pub struct HubMessageMessage<T: FromIOMessage> {
 fn handle_hub_messages(
     mut hub_message_reader: MessageReader<HubMessage>,
     mut train_sender: MessageWriter<HubMessageMessage<TrainData>>,
+    mut layout_sender: MessageWriter<HubMessageMessage<LayoutData>>,
     mut q_hubs: Query<(
         &mut BLEHub,
         &mut Name,
         Option<&HubBusy>,
         Option<&HubRunningProgram>,
         Option<&HubConnected>,
+        Has<HubActive>,
+        Option<&HubBattery>,
+        Option<&HubRssi>,
     )>,
     entity_map: Res<EntityMap>,
+    battery_policy: Res<HubBatteryPolicy>,
+    mut command_messages: MessageWriter<HubCommandMessage>,
     mut commands: Commands,
 ) {
     for event in hub_message_reader.read() {
         let entity = entity_map.hubs[&event.hub_id];
-        let (mut hub, mut name_component, maybe_hub_busy, maybe_hub_running, maybe_connected) =
-            q_hubs.get_mut(entity).unwrap();
+        let (
+            mut hub,
+            mut name_component,
+            maybe_hub_busy,
+            maybe_hub_running,
+            maybe_connected,
+            active,
+            maybe_battery,
+            maybe_rssi,
+        ) = q_hubs.get_mut(entity).unwrap();
         match &event.event {
             IOEvent::NameDiscovered(name) => {
                 hub.name = Some(name.clone());
@@ -1018,6 +1785,20 @@ fn handle_hub_messages(
                                     warn!("Hub reported ready, but was not setting ready");
                                 }
                             }
+                            SysData::Alive { voltage, current } => {
+                                let was_low = maybe_battery.is_some_and(|battery| battery.low);
+                                let low = battery_policy.is_low(voltage, was_low);
+                                if low && !was_low {
+                                    warn!("Hub {:?} battery low: {:.2}V", hub.name, voltage);
+                                } else if was_low && !low {
+                                    info!("Hub {:?} battery recovered: {:.2}V", hub.name, voltage);
+                                }
+                                commands.entity(entity).insert(HubBattery {
+                                    voltage,
+                                    current,
+                                    low,
+                                });
+                            }
                             _ => {}
                         }
                     }
@@ -1028,17 +1809,49 @@ fn handle_hub_messages(
                                 train_sender.write(HubMessageMessage { id: hub.id, data });
                             }
                         }
-                        _ => {
-                            info!(
-                                "Unhandled message for hub kind: {:?} {:?}",
-                                hub.id.kind, msg
-                            );
+                        HubType::Layout => {
+                            if let Some(data) = LayoutData::from_io_message(msg) {
+                                debug!("sending LayoutData: {:?}", data);
+                                layout_sender.write(HubMessageMessage { id: hub.id, data });
+                            } else {
+                                info!(
+                                    "Unhandled message for hub kind: {:?} {:?}",
+                                    hub.id.kind, msg
+                                );
+                            }
                         }
                     },
                 }
             }
             IOEvent::Status(status) => {
                 debug!("Status: {:?}", status);
+                let host_connected = status.flags.clone() & HubStatusFlags::BLE_HOST_CONNECTED
+                    == HubStatusFlags::BLE_HOST_CONNECTED;
+                if !host_connected
+                    && active
+                    && maybe_connected.is_some()
+                    && maybe_hub_running.is_some()
+                    && !matches!(
+                        maybe_hub_busy,
+                        Some(HubBusy::Stopping) | Some(HubBusy::Disconnecting)
+                    )
+                {
+                    warn!("Lost connection to hub {:?}, reconnecting", hub.name);
+                    commands
+                        .entity(entity)
+                        .remove::<HubBusy>()
+                        .remove::<HubConnected>()
+                        .remove::<HubRunningProgram>()
+                        .remove::<HubConfigured>()
+                        .remove::<HubReady>()
+                        .remove::<HubPrepared>()
+                        .insert(HubReconnecting::default());
+                    command_messages.write(HubCommandMessage {
+                        hub_id: hub.id,
+                        command: HubCommand::Reconnect,
+                    });
+                    continue;
+                }
                 if maybe_hub_busy == Some(&HubBusy::Connecting) {
                     if maybe_connected.is_some() {
                         error!("Was in connecting state but already connected");
@@ -1077,11 +1890,24 @@ fn handle_hub_messages(
                     }
                 }
             }
+            IOEvent::Rssi(rssi) => {
+                let rssi = HubRssi { rssi: *rssi };
+                if rssi.is_weak() && !maybe_rssi.is_some_and(|old| old.is_weak()) {
+                    warn!(
+                        "Weak signal from hub {:?} ({} dBm), it may disconnect",
+                        hub.name, rssi.rssi
+                    );
+                }
+                commands.entity(entity).insert(rssi);
+            }
             IOEvent::DownloadProgress(progress) => {
                 // info!("Download progress: {:?}", progress);
-                commands
-                    .entity(entity)
-                    .insert(HubBusy::Downloading(*progress));
+                // late progress events shouldn't mark a finished or canceled download busy again
+                if matches!(maybe_hub_busy, Some(HubBusy::Downloading(_))) {
+                    commands
+                        .entity(entity)
+                        .insert(HubBusy::Downloading(*progress));
+                }
             }
         }
     }
@@ -1095,7 +1921,13 @@ struct HubMessage {
 
 pub fn prepare_hubs(
     q_hubs_not_busy: Query<
-        (Entity, &BLEHub, &HubState, Option<&ObserverHub>),
+        (
+            Entity,
+            &BLEHub,
+            &HubState,
+            Option<&ObserverHub>,
+            Has<HubReconnecting>,
+        ),
         (
             Without<HubError>,
             With<HubActive>,
@@ -1105,18 +1937,19 @@ pub fn prepare_hubs(
         ),
     >,
     q_hubs_busy: Query<&HubBusy>,
-
+    persistent_hub_state: Res<PersistentHubState>,
     mut command_messages: MessageWriter<HubCommandMessage>,
+    mut commands: Commands,
 ) {
     if !q_hubs_busy.is_empty() {
         return;
     }
     let mut entities = q_hubs_not_busy
         .iter()
-        .map(|(entity, _, _, _)| entity)
+        .map(|(entity, _, _, _, _)| entity)
         .collect::<Vec<_>>();
     entities.sort();
-    for (_entity, hub, state, maybe_observer) in entities
+    for (entity, hub, state, maybe_observer, reconnecting) in entities
         .iter()
         .filter_map(|entity| q_hubs_not_busy.get(*entity).ok())
     {
@@ -1124,6 +1957,10 @@ pub fn prepare_hubs(
             error!("Hub {:?} has no name, cannot prepare", hub.id);
             continue;
         }
+        if reconnecting && !state.connected {
+            // the reconnect task is still retrying
+            continue;
+        }
 
         if !state.connected && !state.ready {
             command_messages.write(HubCommandMessage {
@@ -1132,6 +1969,26 @@ pub fn prepare_hubs(
             });
             return;
         }
+        if state.connected && !state.running_program && !state.ready {
+            // the program file may have changed since the hub was marked
+            let cached = hub.is_marked_downloaded_in_persistent_cache(&persistent_hub_state);
+            if cached != state.downloaded {
+                if cached {
+                    info!(
+                        "Hub {:?} already has the current program, skipping download",
+                        hub.name.as_ref().unwrap()
+                    );
+                    commands.entity(entity).insert(HubDownloaded);
+                } else {
+                    info!(
+                        "Hub {:?} program is outdated, downloading again",
+                        hub.name.as_ref().unwrap()
+                    );
+                    commands.entity(entity).remove::<HubDownloaded>();
+                }
+                return;
+            }
+        }
         if !state.downloaded && !state.ready {
             command_messages.write(HubCommandMessage {
                 hub_id: hub.id,
@@ -1173,6 +2030,22 @@ pub fn prepare_hubs(
     }
 }
 
+// written right away so the list survives a crash
+fn track_connected_hubs(
+    q_hubs: Query<(&BLEHub, &HubState), Changed<HubState>>,
+    mut persistent_hub_state: ResMut<PersistentHubState>,
+) {
+    let mut changed = false;
+    for (hub, state) in q_hubs.iter() {
+        if let Some(name) = &hub.name {
+            changed |= persistent_hub_state.set_connected(name, state.connected);
+        }
+    }
+    if changed {
+        persistent_hub_state.save_to_disk();
+    }
+}
+
 fn finalize_hub_preparation(
     q_hubs: Query<&BLEHub, (With<HubActive>, Without<HubPrepared>)>,
     mut editor_state: ResMut<NextState<EditorState>>,
@@ -1190,6 +2063,7 @@ fn update_active_hubs(
     q_ble_trains: Query<&BLETrain>,
     q_switch_motors: Query<(&PulseMotor, &LayoutDevice)>,
     q_switches: Query<&Switch>,
+    q_signals: Query<&LayoutDevice, With<Signal>>,
     entity_map: Res<EntityMap>,
     mut commands: Commands,
 ) {
@@ -1203,7 +2077,10 @@ fn update_active_hubs(
         }
     }
 
-    for switch in q_switches.iter() {
+    for switch in q_switches
+        .iter()
+        .filter(|switch| switch.shared_with.is_none())
+    {
         for motor_id_option in switch.motors.iter() {
             if let Some(motor_id) = motor_id_option {
                 let entity = entity_map.layout_devices.get(motor_id).unwrap();
@@ -1215,6 +2092,11 @@ fn update_active_hubs(
             }
         }
     }
+    for device in q_signals.iter() {
+        if let Some(hub_id) = device.hub_id {
+            active_hub_ids.push(hub_id);
+        }
+    }
     for (entity, hub) in hubs.iter() {
         if active_hub_ids.contains(&hub.id) {
             commands.entity(entity).insert(HubActive);
@@ -1226,6 +2108,8 @@ fn update_active_hubs(
 
 fn get_hub_configs(
     q_switch_motors: Query<(&PulseMotor, &LayoutDevice)>,
+    q_signals: Query<(&Signal, &LayoutDevice)>,
+    q_switches: Query<&Switch>,
     q_ble_trains: Query<&BLETrain>,
     q_hubs: Query<(
         Entity,
@@ -1239,16 +2123,37 @@ fn get_hub_configs(
     for (_entity, hub, maybe_observer, maybe_broadcaster) in q_hubs.iter() {
         let mut config = HubConfiguration::default();
         config.add_value(
-            30,
+            COMM_TYPE_ADDRESS,
             HubCommType::from_query(maybe_observer, maybe_broadcaster).to_u8() as u32,
         );
         configs.insert(hub.id, config);
     }
+    // motors left on switches that share another switch's motors aren't driven
+    let (slaved, driving): (Vec<_>, Vec<_>) = q_switches
+        .iter()
+        .partition(|switch| switch.shared_with.is_some());
+    let unused = slaved
+        .iter()
+        .flat_map(|switch| switch.motors.iter().flatten())
+        .filter(|id| {
+            !driving
+                .iter()
+                .any(|switch| switch.motors.contains(&Some(**id)))
+        })
+        .collect::<HashSet<_>>();
     for (motor, device) in q_switch_motors.iter() {
+        if unused.contains(&device.id) {
+            continue;
+        }
         for (id, config) in motor.hub_configuration(device) {
             configs.get_mut(&id).unwrap().merge(&config);
         }
     }
+    for (signal, device) in q_signals.iter() {
+        for (id, config) in signal.hub_configuration(device) {
+            configs.get_mut(&id).unwrap().merge(&config);
+        }
+    }
     for ble_train in q_ble_trains.iter() {
         for (id, config) in ble_train.hubs_configuration() {
             configs.get_mut(&id).unwrap().merge(&config);
@@ -1387,7 +2292,10 @@ fn check_hub_prepared(
                     && maybe_ready.is_some()
                 {
                     info!("Hub {:?} is prepared", hub.name.as_ref().unwrap());
-                    commands.entity(entity).insert(HubPrepared);
+                    commands
+                        .entity(entity)
+                        .insert(HubPrepared)
+                        .remove::<HubReconnecting>();
                 }
             }
         }
@@ -1395,7 +2303,14 @@ fn check_hub_prepared(
 }
 
 fn monitor_non_prepared_hubs(
-    q_hubs: Query<&BLEHub, (With<HubActive>, Without<HubPrepared>)>,
+    q_hubs: Query<
+        &BLEHub,
+        (
+            With<HubActive>,
+            Without<HubPrepared>,
+            Without<HubReconnecting>,
+        ),
+    >,
     mut editor_state: ResMut<NextState<EditorState>>,
 ) {
     for hub in q_hubs.iter() {
@@ -1497,7 +2412,6 @@ pub struct BLEPlugin;
 impl Plugin for BLEPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SelectablePlugin::<BLEHub>::new());
-        app.add_plugins(InspectorPlugin::<BLEHub>::new());
         app.add_plugins(HubStateComponentPlugin::<HubConnected>::new());
         app.add_plugins(HubStateComponentPlugin::<HubDownloaded>::new());
         app.add_plugins(HubStateComponentPlugin::<HubRunningProgram>::new());
@@ -1508,12 +2422,16 @@ impl Plugin for BLEPlugin {
         app.add_message::<HubCommandMessage>();
         app.add_message::<HubDeviceStateMessage>();
         app.add_observer(on_inserted_broadcaster);
+        app.add_observer(record_hub_failure);
+        app.insert_resource(HubFailurePolicy::default());
+        app.insert_resource(HubFailureLog::default());
+        app.insert_resource(HubDefaults::default());
+        app.insert_resource(HubBatteryPolicy::default());
         app.add_systems(
             Update,
             (
                 spawn_hub.run_if(on_message::<SpawnHubMessage>),
                 despawn_hub.run_if(on_message::<DespawnMessage<BLEHub>>),
-                delete_selection_shortcut::<BLEHub>,
                 (
                     handle_device_state_msgs.run_if(on_message::<HubDeviceStateMessage>),
                     handle_observer_device_state_msgs.run_if(on_message::<HubDeviceStateMessage>),
@@ -1523,7 +2441,14 @@ impl Plugin for BLEPlugin {
                     disconnect_hubs.run_if(in_state(EditorState::Disconnecting)),
                     finalize_disconnection.run_if(in_state(EditorState::Disconnecting)),
                     check_hub_prepared,
-                    prepare_hubs.run_if(in_state(EditorState::PreparingDeviceControl)),
+                    track_connected_hubs,
+                    track_hub_uptime,
+                    poll_hub_rssi,
+                    check_hub_failures.run_if(in_state(EditorState::PreparingDeviceControl)),
+                    prepare_hubs.run_if(
+                        in_state(EditorState::PreparingDeviceControl)
+                            .or(in_state(EditorState::DeviceControl)),
+                    ),
                     execute_hub_commands.run_if(on_message::<HubCommandMessage>),
                 )
                     .chain(),
@@ -1532,6 +2457,7 @@ impl Plugin for BLEPlugin {
         app.add_systems(
             OnEnter(EditorState::PreparingDeviceControl),
             ((
+                reset_hub_failures,
                 ensure_broadcaster_hub,
                 get_hub_configs,
                 check_already_configured_hubs,
@@ -1540,13 +2466,31 @@ impl Plugin for BLEPlugin {
             )
                 .chain(),),
         );
-        app.add_systems(OnExit(EditorState::DeviceControl), stop_hub_programs);
+        app.add_systems(
+            OnExit(EditorState::DeviceControl),
+            (stop_hub_programs, clear_hub_batteries),
+        );
+    }
+}
+
+pub struct BLERenderPlugin;
+
+impl Plugin for BLERenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<BLEHub>::new());
+        app.add_systems(Update, delete_selection_shortcut::<BLEHub>);
+        app.add_systems(
+            EguiPrimaryContextPass,
+            hub_roles_window.run_if(|view: Res<ViewSettings>| view.hub_roles),
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bevy_tokio_tasks::TokioTasksPlugin;
+    use crate::layout_primitives::{LayoutDeviceID, LayoutDeviceType};
 
     #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
     struct EmptyStruct;
@@ -1558,4 +2502,209 @@ mod tests {
         let deserialized: Option<EmptyStruct> = serde_json::from_str(&serialized).unwrap();
         assert_eq!(maybe_broadcaster, deserialized);
     }
+
+    #[test]
+    fn test_program_hash_stability() {
+        // unique per run, tests in parallel processes must not share the file
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let path = std::env::temp_dir().join(format!(
+            "brickrail_test_program_hash_{}_{}.mpy",
+            std::process::id(),
+            nanos
+        ));
+        std::fs::write(&path, b"program").unwrap();
+        let hash = crate::utils::get_file_hash(&path);
+        assert_eq!(hash, crate::utils::get_file_hash(&path));
+        assert_eq!(hash.len(), 64);
+        std::fs::write(&path, b"program 2").unwrap();
+        assert_ne!(hash, crate::utils::get_file_hash(&path));
+        std::fs::write(&path, b"program").unwrap();
+        assert_eq!(hash, crate::utils::get_file_hash(&path));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_batch_broadcast_states() {
+        let mut states = vec![(1, 0, 1), (2, 0, 1), (1, 0, 2)];
+        assert_eq!(
+            batch_broadcast_states(&states),
+            vec![vec![1, 0, 2, 2, 0, 1]]
+        );
+        states.extend((0..10).map(|state_id| (3, state_id, 0)));
+        let batches = batch_broadcast_states(&states);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), MAX_BROADCAST_STATES * 3);
+        assert_eq!(batches[1].len(), 4 * 3);
+    }
+
+    #[test]
+    fn test_battery_hysteresis() {
+        let policy = HubBatteryPolicy::default();
+        assert!(!policy.is_low(7.0, false));
+        assert!(policy.is_low(6.4, false));
+        // noise just above the threshold keeps the hub low
+        assert!(policy.is_low(6.6, true));
+        assert!(!policy.is_low(6.6, false));
+        assert!(!policy.is_low(6.8, true));
+    }
+
+    #[test]
+    fn test_merge_port_f_config() {
+        let hub_id = HubID::new(0, HubType::Layout);
+        let mut config = HubConfiguration::default();
+        config.add_value(COMM_TYPE_ADDRESS, HubCommType::Broadcaster.to_u8() as u32);
+        let mut motor_device =
+            LayoutDevice::from_id(LayoutDeviceID::new(0, LayoutDeviceType::PulseMotor));
+        motor_device.hub_id = Some(hub_id);
+        motor_device.port = Some(HubPort::F);
+        let mut signal_device =
+            LayoutDevice::from_id(LayoutDeviceID::new(1, LayoutDeviceType::Signal));
+        signal_device.hub_id = Some(hub_id);
+        signal_device.port = Some(HubPort::E);
+
+        config.merge(&PulseMotor::default().hub_configuration(&motor_device)[&hub_id]);
+        config.merge(&Signal::default().hub_configuration(&signal_device)[&hub_id]);
+        assert_eq!(
+            config.data.get(&COMM_TYPE_ADDRESS),
+            Some(&(HubCommType::Broadcaster.to_u8() as u32))
+        );
+    }
+
+    #[test]
+    fn test_merge_colliding_hub_ids() {
+        let hub = |id| HubID::new(id, HubType::Layout);
+        let named = |id, name: &str| (hub(id), Some(name.to_string()));
+        let existing = vec![named(0, "alpha"), named(1, "gamma")];
+        let incoming = vec![
+            named(0, "beta"),
+            named(1, "alpha"),
+            named(2, "delta"),
+            (hub(3), None),
+            (HubID::new(0, HubType::Train), Some("alpha".to_string())),
+        ];
+        let mapping = merge_hub_ids(&existing, &incoming);
+        // known by name
+        assert_eq!(mapping[&hub(1)], hub(0));
+        // collides with a different hub
+        assert_eq!(mapping[&hub(0)], hub(4));
+        // no collision
+        assert_eq!(mapping[&hub(2)], hub(2));
+        assert_eq!(mapping[&hub(3)], hub(3));
+        // names only match hubs of the same kind
+        assert_eq!(
+            mapping[&HubID::new(0, HubType::Train)],
+            HubID::new(0, HubType::Train)
+        );
+    }
+
+    #[derive(Resource, Default)]
+    struct ExecutedCommands(Vec<String>);
+
+    fn record_hub_commands(
+        mut hub_command_reader: MessageReader<HubCommandMessage>,
+        mut executed: ResMut<ExecutedCommands>,
+    ) {
+        for message in hub_command_reader.read() {
+            executed.0.push(format!("{:?}", message.command));
+        }
+    }
+
+    fn hub_test_app(persistent_hub_state: PersistentHubState) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TokioTasksPlugin::default()));
+        app.add_plugins(HubStateComponentPlugin::<HubConnected>::new());
+        app.add_plugins(HubStateComponentPlugin::<HubDownloaded>::new());
+        app.add_plugins(HubStateComponentPlugin::<HubRunningProgram>::new());
+        app.add_plugins(HubStateComponentPlugin::<HubConfigured>::new());
+        app.add_plugins(HubStateComponentPlugin::<HubReady>::new());
+        app.add_plugins(HubStateComponentPlugin::<HubPrepared>::new());
+        app.add_message::<SpawnHubMessage>();
+        app.add_message::<HubMessage>();
+        app.add_message::<HubCommandMessage>();
+        app.add_message::<HubMessageMessage<TrainData>>();
+        app.add_message::<HubMessageMessage<LayoutData>>();
+        app.insert_resource(persistent_hub_state);
+        app.insert_resource(EntityMap::default());
+        app.insert_resource(HubDefaults::default());
+        app.insert_resource(HubBatteryPolicy::default());
+        app.insert_resource(ExecutedCommands::default());
+        app.add_systems(
+            Update,
+            (
+                spawn_hub.run_if(on_message::<SpawnHubMessage>),
+                handle_hub_messages.run_if(on_message::<HubMessage>),
+                prepare_hubs,
+                execute_hub_commands.run_if(on_message::<HubCommandMessage>),
+                record_hub_commands,
+            )
+                .chain(),
+        );
+        app
+    }
+
+    // spawns a simulated hub and updates until prepare_hubs got it ready
+    fn prepare_simulated_hub(app: &mut App, name: &str) -> Vec<String> {
+        let mut hub = BLEHub::new(HubID::new(0, HubType::Layout));
+        hub.name = Some(name.to_string());
+        let io_hub = hub.hub.clone();
+        let runtime = app.world().resource::<TokioTasksRuntime>();
+        runtime.runtime().block_on(async {
+            let io_hub = io_hub.lock().await;
+            io_hub.set_backend(BackendKind::Simulated).await;
+        });
+        app.world_mut().write_message(SpawnHubMessage {
+            hub,
+            observer: None,
+            broadcaster: false,
+        });
+        app.update();
+        let entity = app.world().resource::<EntityMap>().hubs[&HubID::new(0, HubType::Layout)];
+        app.world_mut()
+            .entity_mut(entity)
+            .insert((HubActive, HubConfiguration::default()));
+
+        for _ in 0..500 {
+            app.update();
+            if app.world().get::<HubState>(entity).unwrap().ready {
+                return app.world().resource::<ExecutedCommands>().0.clone();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Simulated hub never got ready");
+    }
+
+    #[test]
+    fn test_prepare_simulated_hub() {
+        let mut app = hub_test_app(PersistentHubState::default());
+        assert_eq!(
+            prepare_simulated_hub(&mut app, "simulated test"),
+            [
+                "Connect",
+                "DownloadProgram",
+                "StartProgram",
+                "Configure",
+                "SetReady"
+            ]
+        );
+        let hash = BLEHub::new(HubID::new(0, HubType::Layout)).get_program_hash();
+        let program_hashes = app
+            .world()
+            .resource::<PersistentHubState>()
+            .program_hashes
+            .clone();
+        assert_eq!(program_hashes.get("simulated test"), Some(&hash));
+
+        // the next session finds the hash and skips the download
+        let mut app = hub_test_app(PersistentHubState::default());
+        app.world_mut()
+            .resource_mut::<PersistentHubState>()
+            .program_hashes = program_hashes;
+        assert_eq!(
+            prepare_simulated_hub(&mut app, "simulated test"),
+            ["Connect", "StartProgram", "Configure", "SetReady"]
+        );
+    }
 }
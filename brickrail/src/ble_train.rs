@@ -7,13 +7,14 @@ use bevy_inspector_egui::bevy_egui::egui::{self, Grid, Ui};
 use bevy_inspector_egui::reflect_inspector::ui_for_value;
 use itertools::Itertools;
 use pybricks_ble::io_hub::{IOMessage, Input as IOInput};
+use rfd::FileDialog;
 use serde::{Deserialize, Serialize};
 
 use crate::route_modular::TrainSpeed;
 use crate::{
     ble::{BLEHub, FromIOMessage, HubCommandMessage, HubConfiguration, HubMessageMessage},
-    editor::{SelectionState, SpawnHubMessage},
-    layout::EntityMap,
+    editor::{EditorState, SelectionState, SpawnHubMessage},
+    layout::{EntityMap, LayoutUnits},
     layout_primitives::{Facing, HubID, HubPort, HubType, TrainID},
     marker::MarkerColor,
     route::{LegIntention, Route},
@@ -70,6 +71,9 @@ pub struct TrainHub {
     pub hub_id: Option<HubID>,
     #[serde(default)]
     inverted_ports: Vec<HubPort>,
+    // stable identity of the assigned hub, only kept in sync when saving
+    #[serde(default)]
+    pub hub_name: Option<String>,
 }
 
 impl TrainHub {
@@ -116,6 +120,139 @@ impl TrainHub {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CalibrationSample {
+    pub command: u16,
+    pub distance: f32,
+    pub time: f32,
+}
+
+impl CalibrationSample {
+    pub fn speed(&self) -> f32 {
+        self.distance / self.time
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SpeedCalibration {
+    pub samples: Vec<CalibrationSample>,
+    #[serde(skip)]
+    new_command: u16,
+    #[serde(skip)]
+    new_distance: f32,
+    #[serde(skip)]
+    started: Option<f32>,
+}
+
+impl SpeedCalibration {
+    // (speed, command) points sorted by measured speed
+    fn curve(&self) -> Vec<(f32, f32)> {
+        self.samples
+            .iter()
+            .filter(|sample| sample.time > 0.0)
+            .map(|sample| (sample.speed(), sample.command as f32))
+            .sorted_by(|a, b| a.0.total_cmp(&b.0))
+            .collect()
+    }
+
+    pub fn command_for_speed(&self, speed: f32) -> Option<u16> {
+        let curve = self.curve();
+        if curve.len() < 2 {
+            return None;
+        }
+        let (lower, upper) = curve
+            .iter()
+            .tuple_windows()
+            .find(|(_, upper)| upper.0 >= speed)
+            .unwrap_or((&curve[curve.len() - 2], &curve[curve.len() - 1]));
+        let t = if upper.0 - lower.0 > f32::EPSILON {
+            (speed - lower.0) / (upper.0 - lower.0)
+        } else {
+            0.0
+        };
+        let command = lower.1 + t * (upper.1 - lower.1);
+        Some(command.round().clamp(0.0, 100.0) as u16)
+    }
+
+    fn export_csv(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let mut csv = "command,distance,time,speed\n".to_string();
+        for sample in self.samples.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                sample.command,
+                sample.distance,
+                sample.time,
+                sample.speed()
+            ));
+        }
+        std::fs::write(path, csv)
+    }
+
+    fn inspector_ui(&mut self, ui: &mut Ui, time: f32, units: &LayoutUnits) {
+        let mut remove_index = None;
+        Grid::new("calibration").show(ui, |ui| {
+            ui.label("Command");
+            ui.label(format!("Distance ({})", units.unit_name));
+            ui.label("Time");
+            ui.label("Speed");
+            ui.end_row();
+            for (i, sample) in self.samples.iter().enumerate() {
+                ui.label(format!("{}", sample.command));
+                ui.label(format!("{:1.2}", units.to_units(sample.distance)));
+                ui.label(format!("{:1.2}s", sample.time));
+                ui.label(units.format_speed(sample.speed()));
+                if ui.button("Remove").clicked() {
+                    remove_index = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(i) = remove_index {
+            self.samples.remove(i);
+        }
+
+        let mut distance = units.to_units(self.new_distance);
+        ui.horizontal(|ui| {
+            ui.add(egui::Slider::new(&mut self.new_command, 0..=100));
+            ui.add(egui::DragValue::new(&mut distance).speed(0.1));
+        });
+        self.new_distance = distance / units.cell_length;
+        match self.started {
+            None => {
+                if ui.button("Start timing").clicked() {
+                    self.started = Some(time);
+                }
+            }
+            Some(start) => {
+                ui.label(format!("Timing: {:1.1}s", time - start));
+                if ui.button("Stop timing").clicked() {
+                    self.samples.push(CalibrationSample {
+                        command: self.new_command,
+                        distance: self.new_distance,
+                        time: time - start,
+                    });
+                    self.started = None;
+                }
+            }
+        }
+        if ui.button("Export curve").clicked() {
+            if let Some(path) = FileDialog::new().add_filter("csv", &["csv"]).save_file() {
+                if let Err(err) = self.export_csv(&path) {
+                    error!("Failed to export calibration curve: {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightsMode {
+    #[default]
+    Manual,
+    // on while the train is running, off when stopped
+    Auto,
+}
+
 #[derive(Component, Serialize, Deserialize, Clone)]
 pub struct BLETrain {
     pub master_hub: TrainHub,
@@ -133,6 +270,15 @@ pub struct BLETrain {
     deceleration: u16,
     #[serde(default)]
     chroma_threshold: u16,
+    #[serde(default)]
+    calibration: SpeedCalibration,
+    #[serde(default)]
+    lights: bool,
+    #[serde(default)]
+    lights_mode: LightsMode,
+    // last light state sent to the hubs
+    #[serde(skip)]
+    lights_synced: Option<bool>,
 }
 
 impl BLETrain {
@@ -147,6 +293,10 @@ impl BLETrain {
             acceleration: 40,
             deceleration: 90,
             chroma_threshold: 3500,
+            calibration: SpeedCalibration::default(),
+            lights: false,
+            lights_mode: LightsMode::Manual,
+            lights_synced: None,
         }
     }
 
@@ -154,10 +304,24 @@ impl BLETrain {
         self.puppets.iter().filter_map(|id| id.hub_id.as_ref())
     }
 
+    pub fn iter_train_hubs_mut(&mut self) -> impl Iterator<Item = &mut TrainHub> {
+        iter::once(&mut self.master_hub).chain(self.puppets.iter_mut())
+    }
+
     pub fn iter_all_hubs(&self) -> impl Iterator<Item = &HubID> {
         self.master_hub.hub_id.iter().chain(self.iter_puppets())
     }
 
+    pub fn speed_command(&self, speed: TrainSpeed) -> u16 {
+        self.calibration
+            .command_for_speed(speed.get_speed())
+            .unwrap_or(match speed {
+                TrainSpeed::Slow => self.slow_speed,
+                TrainSpeed::Cruise => self.cruise_speed,
+                TrainSpeed::Fast => self.fast_speed,
+            })
+    }
+
     pub fn run_command(&self, facing: Facing, speed: TrainSpeed) -> HubCommands {
         let arg: u8 = (facing.as_train_flag()) << 4 | speed.as_train_u8();
         let input = IOInput::rpc("run", &vec![arg]);
@@ -169,6 +333,24 @@ impl BLETrain {
         self.all_command(input)
     }
 
+    pub fn set_lights_command(&self, on: bool) -> HubCommands {
+        let input = IOInput::rpc("set_lights", &vec![on as u8]);
+        self.all_command(input)
+    }
+
+    // commands to bring the hubs' lights in line with the mode, if they changed
+    pub fn sync_lights(&mut self, running: bool) -> Option<HubCommands> {
+        let lights = match self.lights_mode {
+            LightsMode::Manual => self.lights,
+            LightsMode::Auto => running,
+        };
+        if self.lights_synced == Some(lights) {
+            return None;
+        }
+        self.lights_synced = Some(lights);
+        Some(self.set_lights_command(lights))
+    }
+
     fn master_command(&self, input: IOInput) -> HubCommands {
         let mut command = HubCommands::new();
         command.push(HubCommandMessage::input(
@@ -224,9 +406,9 @@ impl BLETrain {
         let mut configs = HashMap::default();
         for hub in iter::once(&self.master_hub).chain(self.puppets.iter()) {
             let mut config = HubConfiguration::default();
-            config.add_value(4, self.slow_speed as u32);
-            config.add_value(5, self.cruise_speed as u32);
-            config.add_value(3, self.fast_speed as u32);
+            config.add_value(4, self.speed_command(TrainSpeed::Slow) as u32);
+            config.add_value(5, self.speed_command(TrainSpeed::Cruise) as u32);
+            config.add_value(3, self.speed_command(TrainSpeed::Fast) as u32);
             config.add_value(1, self.acceleration as u32);
             config.add_value(2, self.deceleration as u32);
             config.add_value(0, self.chroma_threshold as u32);
@@ -249,6 +431,8 @@ impl BLETrain {
             Res<AppTypeRegistry>,
             Query<&BLEHub>,
             MessageWriter<SpawnHubMessage>,
+            Res<Time>,
+            Res<LayoutUnits>,
         )>::new(world);
         let (
             mut ble_trains,
@@ -257,6 +441,8 @@ impl BLETrain {
             type_registry,
             hubs,
             mut spawn_messages,
+            time,
+            units,
         ) = state.get_mut(world);
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut ble_train) = ble_trains.get_mut(entity) {
@@ -324,6 +510,29 @@ impl BLETrain {
                     ui.add(egui::DragValue::new(&mut ble_train.chroma_threshold));
                     ui.end_row();
                 });
+
+                ui.heading("Lights");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut ble_train.lights_mode, LightsMode::Manual, "Manual");
+                    ui.radio_value(&mut ble_train.lights_mode, LightsMode::Auto, "Auto");
+                });
+                ui.add_enabled(
+                    ble_train.lights_mode == LightsMode::Manual,
+                    egui::Checkbox::new(&mut ble_train.lights, "On"),
+                );
+
+                ui.heading("Speed calibration");
+                ble_train
+                    .calibration
+                    .inspector_ui(ui, time.elapsed_secs(), &units);
+                if ble_train.calibration.command_for_speed(0.0).is_some() {
+                    ui.label(format!(
+                        "Calibrated commands: slow {}, cruise {}, fast {}",
+                        ble_train.speed_command(TrainSpeed::Slow),
+                        ble_train.speed_command(TrainSpeed::Cruise),
+                        ble_train.speed_command(TrainSpeed::Fast),
+                    ));
+                }
             }
         }
     }
@@ -413,12 +622,20 @@ fn handle_messages(
     }
 }
 
+fn reset_lights_sync(mut ble_trains: Query<&mut BLETrain>) {
+    // the hubs may have restarted since the lights were last set
+    for mut ble_train in ble_trains.iter_mut() {
+        ble_train.lights_synced = None;
+    }
+}
+
 pub struct BLETrainPlugin;
 
 impl Plugin for BLETrainPlugin {
     fn build(&self, app: &mut App) {
         app.add_message::<HubMessageMessage<TrainData>>();
         app.add_message::<MarkerAdvanceMessage>();
+        app.add_systems(OnEnter(EditorState::DeviceControl), reset_lights_sync);
         app.add_systems(
             Update,
             handle_messages.run_if(on_message::<HubMessageMessage<TrainData>>),
@@ -1,20 +1,27 @@
 use crate::destination::{BlockDirectionFilter, Destination, SpawnDestinationMessage};
 use crate::editor::{
-    DespawnMessage, GenericID, HoverState, Selection, SelectionState, delete_selection_shortcut,
-    finish_hover,
+    ControlState, DespawnMessage, EditAction, GenericID, HoverState, Selection, SelectionState,
+    ViewSettings, delete_selection_shortcut, finish_hover,
 };
 use crate::inspector::{Inspectable, InspectorPlugin};
-use crate::layout::{Connections, EntityMap, MarkerMap};
+use crate::layout::{Connections, EntityMap, LayoutUnits, MarkerMap, SignalAspects, TrackLocks};
 use crate::marker::{Marker, MarkerColor, MarkerKey, MarkerSpawnMessage, spawn_marker};
 use crate::route_modular::TrainSpeed;
 use crate::section::LogicalSection;
 use crate::selectable::{Selectable, SelectablePlugin, SelectableType};
-use crate::train::{SpawnTrainMessage, Train};
-use crate::{layout_primitives::*, section::DirectedSection, track::LAYOUT_SCALE};
-use bevy::color::palettes::css::{BLUE, GREEN, RED};
+use crate::signal::Signal;
+use crate::train::{LocksChangedEvent, SpawnTrainMessage, Train, TrainDefaults};
+use crate::{
+    layout_primitives::*,
+    section::DirectedSection,
+    track::{LAYOUT_SCALE, PortalSettings},
+};
+use bevy::color::palettes::css::{BLUE, GREEN, ORANGE, RED};
 use bevy::ecs::system::{SystemParam, SystemState};
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
-use bevy_inspector_egui::bevy_egui::egui::Ui;
+use bevy_inspector_egui::bevy_egui::egui::{self, Ui};
+use bevy_inspector_egui::bevy_egui::{EguiContexts, EguiPrimaryContextPass};
 use bevy_inspector_egui::egui::Grid;
 use bevy_inspector_egui::reflect_inspector::ui_for_value;
 use bevy_prototype_lyon::prelude::*;
@@ -25,9 +32,21 @@ pub const BLOCK_WIDTH: f32 = 20.0;
 #[derive(
     Debug, Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Reflect, Serialize, Deserialize,
 )]
-struct LogicalID {
-    direction: BlockDirection,
-    facing: Facing,
+pub struct LogicalID {
+    pub direction: BlockDirection,
+    pub facing: Facing,
+}
+
+impl LogicalID {
+    pub fn iter_all() -> impl Iterator<Item = LogicalID> {
+        [BlockDirection::Aligned, BlockDirection::Opposite]
+            .into_iter()
+            .flat_map(|direction| {
+                [Facing::Forward, Facing::Backward]
+                    .into_iter()
+                    .map(move |facing| LogicalID { direction, facing })
+            })
+    }
 }
 
 #[derive(Debug, Clone, Message)]
@@ -36,6 +55,12 @@ struct UpdateReverseConnections {
     disallow_reversing: bool,
 }
 
+#[derive(Debug, Clone, Message)]
+struct UpdateBlockEntries {
+    block_id: BlockID,
+    disallowed_entries: Vec<LogicalID>,
+}
+
 #[derive(Debug, Reflect, Default, Serialize, Deserialize, Clone)]
 pub struct BlockSettings {
     #[serde(default)]
@@ -44,6 +69,29 @@ pub struct BlockSettings {
     pub disallow_reversing: bool,
     #[serde(default)]
     pub speed: TrainSpeed,
+    #[serde(default)]
+    pub disallowed_entries: Vec<LogicalID>,
+    // trains may change ends here when reversing is restricted to reversal points
+    #[serde(default)]
+    pub reversal_point: bool,
+    // signal device showing whether the block is clear
+    #[serde(default)]
+    pub signal: Option<LayoutDeviceID>,
+    // in cells per second, for trains in either direction
+    #[serde(default)]
+    pub speed_limit: Option<f32>,
+}
+
+impl BlockSettings {
+    pub fn allows_reversing(&self, reversal_points_only: bool) -> bool {
+        !self.disallow_reversing && (!reversal_points_only || self.reversal_point)
+    }
+
+    pub fn allows_entry(&self, direction: BlockDirection, facing: Facing) -> bool {
+        !self
+            .disallowed_entries
+            .contains(&LogicalID { direction, facing })
+    }
 }
 
 #[derive(Component, Debug, Reflect, Serialize, Deserialize, Clone)]
@@ -51,6 +99,9 @@ pub struct Block {
     pub id: BlockID,
     section: DirectedSection,
     pub settings: BlockSettings,
+    // train whose body is on the block, unlike locks this is only set once it's there
+    #[serde(skip)]
+    pub occupancy: Option<TrainID>,
 }
 
 #[derive(Component)]
@@ -129,10 +180,45 @@ impl Block {
             id: section.to_block_id(),
             section: section,
             settings: BlockSettings::default(),
+            occupancy: None,
         };
         block
     }
 
+    // keeps the settings, entries are flipped if the block id ends up reversed
+    pub fn transformed(&self, transform: &SectionTransform) -> Self {
+        let section = DirectedSection {
+            tracks: self
+                .section
+                .tracks
+                .iter()
+                .map(|track| transform.dirtrack(*track))
+                .collect(),
+        };
+        let first = section.tracks[0];
+        let mut block = Block::new(section);
+        block.settings = self.settings.clone();
+        if block.section.tracks[0] != first {
+            for entry in block.settings.disallowed_entries.iter_mut() {
+                entry.direction = entry.direction.opposite();
+            }
+        }
+        block
+    }
+
+    pub fn section(&self) -> &DirectedSection {
+        &self.section
+    }
+
+    // from the center of the first track to the center of the last
+    pub fn length(&self, ramp_length: f32) -> f32 {
+        self.section.length(ramp_length)
+    }
+
+    pub fn fits(&self, train_length: f32, ramp_length: f32) -> bool {
+        train_length <= self.length(ramp_length)
+    }
+
     pub fn distance_to(&self, pos: Vec2) -> f32 {
         self.section.distance_to(pos)
     }
@@ -163,6 +249,13 @@ impl Block {
             Query<(&mut Destination, &Name)>,
             MessageWriter<SpawnDestinationMessage>,
             MessageWriter<UpdateReverseConnections>,
+            MessageWriter<UpdateBlockEntries>,
+            Res<LayoutUnits>,
+            Res<TrainDefaults>,
+            ResMut<SignalAspects>,
+            Query<(&Train, &Name)>,
+            Commands,
+            Res<PortalSettings>,
         )>::new(world);
         let (
             mut blocks,
@@ -173,36 +266,156 @@ impl Block {
             mut destinations,
             mut destination_spawner,
             mut update_reverse_connections,
+            mut update_block_entries,
+            units,
+            train_defaults,
+            mut signals,
+            q_trains,
+            mut commands,
+            portal_settings,
         ) = state.get_mut(world);
+        let ramp_length = portal_settings.ramp_length;
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut block) = blocks.get_mut(entity) {
                 ui.label(format!("Block {:?}", block.id));
+                ui.label(format!(
+                    "Length: {}",
+                    units.format_length(block.length(ramp_length))
+                ));
+                match block.occupancy {
+                    Some(train_id) => {
+                        match entity_map.query_get(&q_trains, &GenericID::Train(train_id)) {
+                            Some((train, name)) => {
+                                ui.label(format!("Occupied by {}", name));
+                                if !block.fits(train.length(), ramp_length) {
+                                    ui.colored_label(
+                                        egui::Color32::YELLOW,
+                                        format!(
+                                            "{} is longer than the block ({})",
+                                            name,
+                                            units.format_length(train.length())
+                                        ),
+                                    );
+                                }
+                            }
+                            None => {
+                                ui.label(format!("Occupied by {:?}", train_id));
+                            }
+                        }
+                    }
+                    None => {
+                        ui.label("Unoccupied");
+                    }
+                }
                 Grid::new("settings").show(ui, |ui| {
                     ui.label("Passthrough");
                     ui_for_value(&mut block.settings.passthrough, ui, &type_registry.read());
                     ui.end_row();
                     ui.label("Disallow reversing");
-                    if ui_for_value(
+                    let mut reversing_changed = ui_for_value(
                         &mut block.settings.disallow_reversing,
                         ui,
                         &type_registry.read(),
-                    ) {
+                    );
+                    ui.end_row();
+                    ui.label("Reversal point");
+                    reversing_changed |= ui_for_value(
+                        &mut block.settings.reversal_point,
+                        ui,
+                        &type_registry.read(),
+                    );
+                    ui.end_row();
+                    if reversing_changed {
                         update_reverse_connections.write(UpdateReverseConnections {
                             block_id: block.id,
-                            disallow_reversing: block.settings.disallow_reversing,
+                            disallow_reversing: !block
+                                .settings
+                                .allows_reversing(train_defaults.reversal_points_only),
                         });
                     }
                     ui.label("Speed");
                     ui_for_value(&mut block.settings.speed, ui, &type_registry.read());
                     ui.end_row();
+                    ui.label("Speed limit");
+                    ui.horizontal(|ui| {
+                        let mut limited = block.settings.speed_limit.is_some();
+                        if ui.checkbox(&mut limited, "").changed() {
+                            block.settings.speed_limit =
+                                limited.then_some(TrainSpeed::Cruise.get_speed());
+                        }
+                        if let Some(limit) = block.settings.speed_limit.as_mut() {
+                            ui.add(
+                                egui::DragValue::new(limit)
+                                    .speed(0.05)
+                                    .range(0.1..=f32::MAX),
+                            );
+                            ui.label(units.format_speed(*limit));
+                        }
+                    });
+                    ui.end_row();
+                    ui.label("Allowed entries");
+                    ui.vertical(|ui| {
+                        let mut changed = false;
+                        for logical in LogicalID::iter_all() {
+                            let mut allowed = block
+                                .settings
+                                .allows_entry(logical.direction, logical.facing);
+                            let label = format!("{:?} {:?}", logical.direction, logical.facing);
+                            if ui.checkbox(&mut allowed, label).changed() {
+                                if allowed {
+                                    block.settings.disallowed_entries.retain(|l| *l != logical);
+                                } else {
+                                    block.settings.disallowed_entries.push(logical);
+                                }
+                                changed = true;
+                            }
+                        }
+                        if changed {
+                            update_block_entries.write(UpdateBlockEntries {
+                                block_id: block.id,
+                                disallowed_entries: block.settings.disallowed_entries.clone(),
+                            });
+                        }
+                    });
+                    ui.end_row();
+                    ui.label("Signal");
+                    let mut aspect = signals.aspects.get(&block.id).copied();
+                    egui::ComboBox::from_id_salt("signal")
+                        .selected_text(aspect.map_or("None".to_string(), |a| a.to_string()))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut aspect, None, "None");
+                            for option in
+                                [SignalAspect::Green, SignalAspect::Yellow, SignalAspect::Red]
+                            {
+                                ui.selectable_value(&mut aspect, Some(option), option.to_string());
+                            }
+                        });
+                    if aspect != signals.aspects.get(&block.id).copied() {
+                        match aspect {
+                            Some(aspect) => signals.aspects.insert(block.id, aspect),
+                            None => signals.aspects.remove(&block.id),
+                        };
+                        commands.trigger(LocksChangedEvent {});
+                    }
+                    ui.end_row();
                 });
 
-                if ui.button("Add train").clicked() {
-                    let train_id = entity_map.new_train_id();
-                    let logical_block_id = block
-                        .id
-                        .to_logical(BlockDirection::Aligned, Facing::Forward);
-                    let train = Train::at_block_id(train_id, logical_block_id);
+                let train_id = entity_map.new_train_id();
+                let logical_block_id = block
+                    .id
+                    .to_logical(BlockDirection::Aligned, Facing::Forward);
+                let train = Train::at_block_id(train_id, logical_block_id, &train_defaults);
+                let add_train = ui.button("Add train");
+                if !block.fits(train.length(), ramp_length) {
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        format!(
+                            "A new train ({}) won't fit in this block",
+                            units.format_length(train.length())
+                        ),
+                    );
+                }
+                if add_train.clicked() {
                     train_spawner.write(SpawnTrainMessage {
                         train: train,
                         ble_train: None,
@@ -253,10 +466,8 @@ impl Block {
                 }
                 if ui.button("Add to new Destination").clicked() {
                     let dest_id = entity_map.new_destination_id();
-                    let dest = Destination {
-                        id: dest_id,
-                        blocks: vec![(block.id, BlockDirectionFilter::Any, None)],
-                    };
+                    let mut dest = Destination::new(dest_id);
+                    dest.add_block(block.id, BlockDirectionFilter::Any, None);
                     destination_spawner.write(SpawnDestinationMessage {
                         dest: dest,
                         name: None,
@@ -264,6 +475,9 @@ impl Block {
                 }
             }
         }
+        state.apply(world);
+
+        Signal::inspector(ui, world);
     }
 }
 
@@ -289,6 +503,10 @@ impl Selectable for Block {
         0.0
     }
 
+    fn removal_action(&self, _connections: &Connections) -> Option<EditAction> {
+        Some(EditAction::DespawnBlock(self.clone()))
+    }
+
     fn generic_id(&self) -> GenericID {
         GenericID::Block(self.id)
     }
@@ -315,12 +533,12 @@ pub struct BlockBundle {
 }
 
 impl BlockBundle {
-    pub fn new(section: DirectedSection) -> Self {
-        Self::from_block(Block::new(section))
+    pub fn new(section: DirectedSection, ramp_length: f32) -> Self {
+        Self::from_block(Block::new(section), ramp_length)
     }
 
-    pub fn from_block(block: Block) -> Self {
-        let shape = generate_block_shape(&block.section);
+    pub fn from_block(block: Block, ramp_length: f32) -> Self {
+        let shape = generate_block_shape(&block.section, ramp_length);
 
         let shape = ShapeBuilder::with(&shape)
             .stroke(Stroke {
@@ -338,20 +556,60 @@ impl BlockBundle {
     }
 }
 
-fn generate_block_shape(section: &DirectedSection) -> ShapePath {
+fn generate_block_shape(section: &DirectedSection, ramp_length: f32) -> ShapePath {
     let mut path = ShapePath::new();
-    path = path.move_to(section.interpolate_pos(0.0) * LAYOUT_SCALE);
+    path = path.move_to(section.interpolate_pos(0.0, ramp_length) * LAYOUT_SCALE);
 
     let num_segments = 10 * section.len();
-    let length = section.length();
+    let length = section.length(ramp_length);
 
     for i in 1..(num_segments + 1) {
         let dist = i as f32 * length / num_segments as f32;
-        path = path.line_to(section.interpolate_pos(dist) * LAYOUT_SCALE);
+        path = path.line_to(section.interpolate_pos(dist, ramp_length) * LAYOUT_SCALE);
     }
     path
 }
 
+fn update_block_entries(
+    mut update_block_entries: MessageReader<UpdateBlockEntries>,
+    mut connections: ResMut<Connections>,
+) {
+    for UpdateBlockEntries {
+        block_id,
+        disallowed_entries,
+    } in update_block_entries.read()
+    {
+        for logical in LogicalID::iter_all() {
+            let in_track = block_id
+                .to_logical(logical.direction, logical.facing)
+                .default_in_marker_track();
+            if disallowed_entries.contains(&logical) {
+                connections.blocked_tracks.insert(in_track);
+            } else {
+                connections.blocked_tracks.remove(&in_track);
+            }
+        }
+    }
+}
+
+fn update_reversal_points(
+    train_defaults: Res<TrainDefaults>,
+    q_blocks: Query<&Block>,
+    mut update_reverse_connections: MessageWriter<UpdateReverseConnections>,
+    mut last: Local<Option<bool>>,
+) {
+    let reversal_points_only = train_defaults.reversal_points_only;
+    if last.replace(reversal_points_only) == Some(reversal_points_only) {
+        return;
+    }
+    for block in q_blocks.iter() {
+        update_reverse_connections.write(UpdateReverseConnections {
+            block_id: block.id,
+            disallow_reversing: !block.settings.allows_reversing(reversal_points_only),
+        });
+    }
+}
+
 fn update_reverse_connections(
     mut update_reverse_connections: MessageReader<UpdateReverseConnections>,
     mut connections: ResMut<Connections>,
@@ -411,6 +669,7 @@ fn create_block(
     mut block_message_writer: MessageWriter<BlockSpawnMessage>,
     mut marker_message_writer: MessageWriter<MarkerSpawnMessage>,
     mut marker_map: ResMut<MarkerMap>,
+    entity_map: Res<EntityMap>,
 ) {
     for BlockCreateMessage(block) in create_messages.read() {
         let block_id = block.id;
@@ -422,7 +681,10 @@ fn create_block(
             let in_track = logical_id.default_in_marker_track();
             if logical_id.facing == Facing::Forward {
                 let marker = Marker::new(in_track.track(), MarkerColor::Any);
-                marker_message_writer.write(MarkerSpawnMessage(marker));
+                // kept from before, e.g. when redoing a block creation
+                if !entity_map.markers.contains_key(&marker.marker_id()) {
+                    marker_message_writer.write(MarkerSpawnMessage(marker));
+                }
             }
             marker_map.register_marker(in_track, MarkerKey::In, logical_id);
         }
@@ -434,6 +696,8 @@ pub fn spawn_block(
     mut entity_map: ResMut<EntityMap>,
     mut block_event_reader: MessageReader<BlockSpawnMessage>,
     mut connections: ResMut<Connections>,
+    train_defaults: Res<TrainDefaults>,
+    portal_settings: Res<PortalSettings>,
 ) {
     for request in block_event_reader.read() {
         println!("Spawning block {:?}", request.block.id);
@@ -442,7 +706,10 @@ pub fn spawn_block(
         // println!("Spawning block {:?}", block_id);
         let name = Name::new(request.name.clone().unwrap_or(block_id.to_string()));
         let entity = commands
-            .spawn((BlockBundle::from_block(block.clone()), name))
+            .spawn((
+                BlockBundle::from_block(block.clone(), portal_settings.ramp_length),
+                name,
+            ))
             .id();
         entity_map.add_block(block_id, entity);
         for direction in [BlockDirection::Aligned, BlockDirection::Opposite] {
@@ -479,9 +746,15 @@ pub fn spawn_block(
                         section: block.get_logical_section(logical_id),
                     },
                 ));
-                if !block.settings.disallow_reversing {
+                if block
+                    .settings
+                    .allows_reversing(train_defaults.reversal_points_only)
+                {
                     connections.connect_tracks(&in_track, &in_track.reversed());
                 }
+                if !block.settings.allows_entry(direction, facing) {
+                    connections.blocked_tracks.insert(in_track);
+                }
             }
         }
     }
@@ -493,6 +766,7 @@ pub fn despawn_block(
     mut block_event_reader: MessageReader<DespawnMessage<Block>>,
     mut marker_map: ResMut<MarkerMap>,
     mut connections: ResMut<Connections>,
+    mut signals: ResMut<SignalAspects>,
 ) {
     for request in block_event_reader.read() {
         let block_id = request.0;
@@ -500,23 +774,67 @@ pub fn despawn_block(
         for logical_id in block_id.logical_block_ids() {
             let in_track = logical_id.default_in_marker_track();
             connections.disconnect_tracks(&in_track, &in_track.reversed());
+            connections.blocked_tracks.remove(&in_track);
         }
         let entity = entity_map.blocks.get(&block_id).unwrap().clone();
         commands.entity(entity).despawn();
         entity_map.remove_block(block_id);
         marker_map.remove_block(block_id);
+        signals.aspects.remove(&block_id);
+    }
+}
+
+// how far a train body can be from a block's center line and still occupy it
+const OCCUPANCY_DISTANCE: f32 = 0.1;
+const OCCUPANCY_STEP: f32 = 0.25;
+
+fn update_block_occupancy(q_trains: Query<&Train>, mut q_blocks: Query<&mut Block>) {
+    let mut occupancy = HashMap::new();
+    for train in q_trains.iter() {
+        if train.get_route().is_none() {
+            if let Some(logical_block) = train.get_logical_block_id() {
+                occupancy.insert(logical_block.block, train.id);
+            }
+            continue;
+        }
+        for (pos, layer) in train.body_positions(OCCUPANCY_STEP) {
+            let layer = layer.round() as i32;
+            for block in q_blocks.iter() {
+                if block.distance_to(pos) < OCCUPANCY_DISTANCE
+                    && block.section.tracks.iter().any(|t| t.cell().l == layer)
+                {
+                    occupancy.insert(block.id, train.id);
+                }
+            }
+        }
+    }
+    for mut block in q_blocks.iter_mut() {
+        let occupied = occupancy.get(&block.id).copied();
+        // only touch blocks that changed, their color refreshes on change
+        if block.occupancy != occupied {
+            block.occupancy = occupied;
+        }
+    }
+}
+
+fn clear_block_occupancy(mut q_blocks: Query<&mut Block>) {
+    for mut block in q_blocks.iter_mut() {
+        if block.occupancy.is_some() {
+            block.occupancy = None;
+        }
     }
 }
 
 fn update_block_color(
-    mut q_strokes: Query<(&Block, &mut Shape)>,
+    mut q_strokes: Query<(Ref<Block>, &mut Shape)>,
     selection_state: Res<SelectionState>,
     hover_state: Res<HoverState>,
 ) {
-    if !selection_state.is_changed() && !hover_state.is_changed() {
-        return;
-    }
+    let refresh_all = selection_state.is_changed() || hover_state.is_changed();
     for (block, mut shape) in q_strokes.iter_mut() {
+        if !refresh_all && !block.is_changed() {
+            continue;
+        }
         if let Some(GenericID::Block(block_id)) = &hover_state.hover {
             if block.id == *block_id {
                 shape.stroke.as_mut().unwrap().color = Color::from(RED);
@@ -529,28 +847,129 @@ fn update_block_color(
                 continue;
             }
         }
+        if block.occupancy.is_some() {
+            shape.stroke.as_mut().unwrap().color = Color::from(ORANGE);
+            continue;
+        }
         shape.stroke.as_mut().unwrap().color = Color::from(GREEN);
     }
 }
 
+fn draw_block_reservations(
+    mut gizmos: Gizmos,
+    q_blocks: Query<&Block>,
+    track_locks: Res<TrackLocks>,
+    portal_settings: Res<PortalSettings>,
+) {
+    let ramp_length = portal_settings.ramp_length;
+    for block in q_blocks.iter() {
+        let section = block.section();
+        let reserved = section
+            .tracks
+            .iter()
+            .any(|dirtrack| track_locks.locked_tracks.contains_key(&dirtrack.track));
+        let color = if reserved {
+            Color::from(RED)
+        } else {
+            Color::from(GREEN)
+        };
+        let length = section.length(ramp_length);
+        let num_points = (length / 0.1).ceil().max(1.0) as usize;
+        let points = (0..=num_points).map(|i| {
+            section.interpolate_pos(length * i as f32 / num_points as f32, ramp_length)
+                * LAYOUT_SCALE
+        });
+        gizmos.linestrip_2d(points, color);
+    }
+}
+
+fn draw_block_outlines(
+    mut gizmos: Gizmos,
+    q_blocks: Query<&Block>,
+    portal_settings: Res<PortalSettings>,
+) {
+    let ramp_length = portal_settings.ramp_length;
+    let color = Color::srgba(0.3, 0.7, 1.0, 0.5);
+    for block in q_blocks.iter() {
+        let section = block.section();
+        let length = section.length(ramp_length);
+        let num_points = (length / 0.1).ceil().max(1.0) as usize;
+        let points = (0..=num_points)
+            .map(|i| section.interpolate_pos(length * i as f32 / num_points as f32, ramp_length))
+            .collect::<Vec<_>>();
+        // offset both sides of the track to outline the block
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for (i, point) in points.iter().enumerate() {
+            let prev = points[i.saturating_sub(1)];
+            let next = points[(i + 1).min(points.len() - 1)];
+            let normal = (next - prev).normalize_or_zero().perp() * 0.2;
+            left.push((point + normal) * LAYOUT_SCALE);
+            right.push((point - normal) * LAYOUT_SCALE);
+        }
+        right.reverse();
+        let outline = left
+            .iter()
+            .chain(right.iter())
+            .chain(left.first())
+            .cloned()
+            .collect::<Vec<_>>();
+        gizmos.linestrip_2d(outline, color);
+    }
+}
+
+fn draw_block_labels(
+    mut egui_contexts: EguiContexts,
+    q_blocks: Query<(&Block, &Name)>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    portal_settings: Res<PortalSettings>,
+) {
+    let ramp_length = portal_settings.ramp_length;
+    let Ok((camera, camera_transform)) = q_camera.single() else {
+        return;
+    };
+    let Ok(ctx) = egui_contexts.ctx_mut() else {
+        return;
+    };
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    for (block, name) in q_blocks.iter() {
+        let section = block.section();
+        let center =
+            section.interpolate_pos(section.length(ramp_length) * 0.5, ramp_length) * LAYOUT_SCALE;
+        if let Ok(screen_pos) = camera.world_to_viewport(camera_transform, center.extend(0.0)) {
+            painter.text(
+                egui::pos2(screen_pos.x, screen_pos.y),
+                egui::Align2::CENTER_CENTER,
+                name.as_str(),
+                egui::FontId::proportional(12.0),
+                egui::Color32::LIGHT_BLUE,
+            );
+        }
+    }
+}
+
 pub struct BlockPlugin;
 
 impl Plugin for BlockPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SelectablePlugin::<Block>::new());
-        app.add_plugins(InspectorPlugin::<Block>::new());
         app.register_type::<Block>();
         app.add_message::<BlockSpawnMessage>();
         app.add_message::<DespawnMessage<Block>>();
         app.add_message::<BlockCreateMessage>();
         app.add_message::<UpdateReverseConnections>();
+        app.add_message::<UpdateBlockEntries>();
+        app.add_systems(OnExit(ControlState), clear_block_occupancy);
         app.add_systems(
             Update,
             (
                 create_block.run_if(on_message::<BlockCreateMessage>),
+                update_reversal_points
+                    .run_if(resource_changed::<TrainDefaults>)
+                    .before(update_reverse_connections),
                 update_reverse_connections.run_if(on_message::<UpdateReverseConnections>),
-                update_block_color.after(finish_hover),
-                delete_selection_shortcut::<Block>,
+                update_block_entries.run_if(on_message::<UpdateBlockEntries>),
+                update_block_occupancy.run_if(in_state(ControlState)),
             ),
         );
         app.add_systems(
@@ -564,3 +983,26 @@ impl Plugin for BlockPlugin {
         );
     }
 }
+
+pub struct BlockRenderPlugin;
+
+impl Plugin for BlockRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<Block>::new());
+        app.add_systems(
+            Update,
+            (
+                update_block_color
+                    .after(finish_hover)
+                    .after(update_block_occupancy),
+                draw_block_reservations.run_if(|view: Res<ViewSettings>| view.reservations),
+                draw_block_outlines.run_if(|view: Res<ViewSettings>| view.block_outlines),
+                delete_selection_shortcut::<Block>,
+            ),
+        );
+        app.add_systems(
+            EguiPrimaryContextPass,
+            draw_block_labels.run_if(|view: Res<ViewSettings>| view.block_outlines),
+        );
+    }
+}
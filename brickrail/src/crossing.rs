@@ -1,20 +1,26 @@
-use bevy::prelude::*;
-use bevy_inspector_egui::egui::Ui;
+use bevy::{ecs::system::SystemState, prelude::*};
+use bevy_inspector_egui::egui::{self, Ui};
 use bevy_prototype_lyon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     ble::HubCommandMessage,
-    editor::{EditorState, GenericID},
+    editor::{ControlState, EditorState, GenericID, SelectionState},
     inspector::{Inspectable, InspectorPlugin},
     layout::EntityMap,
     layout_devices::LayoutDevice,
-    layout_primitives::{LayoutDeviceID, TrackID},
+    layout_primitives::{LayoutDeviceID, TrackID, TrainID},
     selectable::{Selectable, SelectablePlugin, SelectableType},
     switch_motor::{MotorPosition, PulseMotor},
     track::{LAYOUT_SCALE, TRACK_WIDTH},
+    train::Train,
 };
 
+// spacing of the route samples checked against the crossing
+const APPROACH_RESOLUTION: f32 = 0.05;
+// how close a sample has to come to the crossing track to count as on it
+const CROSSING_CLEARANCE: f32 = 0.1;
+
 #[derive(Debug)]
 pub enum CrossingPosition {
     Open,
@@ -34,17 +40,104 @@ impl CrossingPosition {
 pub struct LevelCrossing {
     id: TrackID,
     pub motors: Vec<Option<LayoutDeviceID>>,
+    // seconds before the first train arrives that the barrier lowers
+    #[serde(default = "default_close_lead_time")]
+    pub close_lead_time: f32,
+    // seconds after the last wagon cleared that the barrier raises
+    #[serde(default = "default_open_delay")]
+    pub open_delay: f32,
+    #[serde(skip)]
+    #[reflect(ignore)]
+    closed_for: Vec<TrainID>,
+    #[serde(skip)]
+    #[reflect(ignore)]
+    clear_since: Option<f32>,
+}
+
+fn default_close_lead_time() -> f32 {
+    3.0
+}
+
+fn default_open_delay() -> f32 {
+    1.0
 }
 
 impl LevelCrossing {
     pub fn new(id: TrackID) -> Self {
-        Self { id, motors: vec![] }
+        Self {
+            id,
+            motors: vec![],
+            close_lead_time: default_close_lead_time(),
+            open_delay: default_open_delay(),
+            closed_for: vec![],
+            clear_since: None,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.closed_for.is_empty() || self.clear_since.is_some()
+    }
+
+    // the train is on the crossing, or will reach it within the lead time
+    fn needs_closing_for(&self, train: &Train) -> bool {
+        let Some(route) = train.get_route() else {
+            return false;
+        };
+        let lookahead = train.get_speed().abs() * self.close_lead_time;
+        route
+            .offset_near(-train.length(), lookahead, APPROACH_RESOLUTION, |pos| {
+                self.id.distance_to(pos) < CROSSING_CLEARANCE
+            })
+            .is_some()
+    }
+
+    // returns whether the barrier has to move
+    fn update_closed_for(&mut self, closed_for: Vec<TrainID>, now: f32) -> bool {
+        let was_closed = self.is_closed();
+        if !closed_for.is_empty() {
+            self.clear_since = None;
+        } else if was_closed {
+            let since = *self.clear_since.get_or_insert(now);
+            if now - since >= self.open_delay {
+                self.clear_since = None;
+            }
+        }
+        self.closed_for = closed_for;
+        self.is_closed() != was_closed
     }
 }
 
 impl Inspectable for LevelCrossing {
-    fn inspector(ui: &mut Ui, _world: &mut World) {
+    fn inspector(ui: &mut Ui, world: &mut World) {
+        let mut state = SystemState::<(
+            Query<&mut LevelCrossing>,
+            Res<EntityMap>,
+            Res<SelectionState>,
+        )>::new(world);
+        let (mut crossings, entity_map, selection_state) = state.get_mut(world);
         ui.label("Level Crossing");
+        if let Some(entity) = selection_state.get_entity(&entity_map) {
+            if let Ok(mut crossing) = crossings.get_mut(entity) {
+                ui.horizontal(|ui| {
+                    ui.label("Close lead time (s)");
+                    ui.add(egui::DragValue::new(&mut crossing.close_lead_time).speed(0.1));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Open delay (s)");
+                    ui.add(egui::DragValue::new(&mut crossing.open_delay).speed(0.1));
+                });
+                crossing.close_lead_time = crossing.close_lead_time.max(0.0);
+                crossing.open_delay = crossing.open_delay.max(0.0);
+                if !crossing.closed_for.is_empty() {
+                    let trains = crossing
+                        .closed_for
+                        .iter()
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>();
+                    ui.label(format!("Closed for {}", trains.join(", ")));
+                }
+            }
+        }
     }
 
     fn run_condition(selection_state: Res<crate::editor::SelectionState>) -> bool {
@@ -145,17 +238,52 @@ pub fn update_crossing_position(
     }
 }
 
+// lowers the barrier ahead of approaching trains and keeps it down
+// until every one of them has cleared, plus the open delay
+fn update_crossing_barriers(
+    mut crossings: Query<&mut LevelCrossing>,
+    trains: Query<Ref<Train>>,
+    time: Res<Time>,
+    mut set_crossing_position: MessageWriter<SetCrossingPositionMessage>,
+) {
+    let now = time.elapsed_secs();
+    for mut crossing in crossings.iter_mut() {
+        // routes are only sampled again for trains that changed
+        let closed_for = trains
+            .iter()
+            .filter(|train| match train.is_changed() || crossing.is_added() {
+                true => crossing.needs_closing_for(train),
+                false => crossing.closed_for.contains(&train.id),
+            })
+            .map(|train| train.id)
+            .collect::<Vec<_>>();
+        if crossing.update_closed_for(closed_for, now) {
+            set_crossing_position.write(SetCrossingPositionMessage {
+                id: crossing.id,
+                position: if crossing.is_closed() {
+                    CrossingPosition::Closed
+                } else {
+                    CrossingPosition::Open
+                },
+            });
+        }
+    }
+}
+
 pub struct CrossingPlugin;
 
 impl Plugin for CrossingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(InspectorPlugin::<LevelCrossing>::new());
         app.add_plugins(SelectablePlugin::<LevelCrossing>::new());
         app.add_message::<SpawnCrossingMessage>();
         app.add_message::<SetCrossingPositionMessage>();
         app.add_systems(
             Update,
-            update_crossing_position.run_if(on_message::<SetCrossingPositionMessage>),
+            (
+                update_crossing_barriers.run_if(in_state(ControlState)),
+                update_crossing_position.run_if(on_message::<SetCrossingPositionMessage>),
+            )
+                .chain(),
         );
         app.add_systems(
             PostUpdate,
@@ -163,3 +291,42 @@ impl Plugin for CrossingPlugin {
         );
     }
 }
+
+pub struct CrossingRenderPlugin;
+
+impl Plugin for CrossingRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<LevelCrossing>::new());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::layout_primitives::{CellID, Orientation};
+
+    #[test]
+    fn test_barrier_timing() {
+        let mut crossing = LevelCrossing::new(TrackID::new(CellID::new(0, 0, 0), Orientation::EW));
+        crossing.open_delay = 1.0;
+        let [a, b] = [0, 1].map(TrainID::new);
+
+        assert!(crossing.update_closed_for(vec![a], 0.0));
+        assert!(crossing.is_closed());
+        // a second train from the other side keeps it down after the first one cleared
+        assert!(!crossing.update_closed_for(vec![a, b], 1.0));
+        assert!(!crossing.update_closed_for(vec![b], 2.0));
+        assert!(!crossing.update_closed_for(vec![], 3.0));
+        assert!(!crossing.update_closed_for(vec![], 3.5));
+        assert!(crossing.update_closed_for(vec![], 4.0));
+        assert!(!crossing.is_closed());
+
+        // a train arriving during the open delay restarts it
+        crossing.update_closed_for(vec![a], 5.0);
+        crossing.update_closed_for(vec![], 6.0);
+        assert!(!crossing.update_closed_for(vec![b], 6.5));
+        assert!(!crossing.update_closed_for(vec![], 7.0));
+        assert!(crossing.is_closed());
+        assert!(crossing.update_closed_for(vec![], 8.0));
+    }
+}
@@ -1,10 +1,17 @@
 use crate::{
-    editor::GenericID,
+    editor::{GenericID, SelectionState},
+    inspector::{Inspectable, InspectorPlugin},
     layout::EntityMap,
-    layout_primitives::{BlockDirection, BlockID, DestinationID, Facing},
-    selectable::Selectable,
+    layout_primitives::{BlockDirection, BlockID, DestinationID, Facing, TrainID},
+    selectable::{Selectable, SelectableType},
+    train::Train,
 };
-use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy::{
+    ecs::system::{SystemParam, SystemState},
+    platform::collections::HashMap,
+    prelude::*,
+};
+use bevy_inspector_egui::egui::{self, Ui};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Message)]
@@ -49,15 +56,179 @@ impl BlockDirectionFilter {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ArrivalAction {
+    DispatchTrain {
+        train: Option<TrainID>,
+        dest: Option<DestinationID>,
+    },
+    AdvanceSchedule {
+        train: Option<TrainID>,
+    },
+}
+
+// how trains dispatched to the same destination are spread over its blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TargetDistribution {
+    #[default]
+    Independent,
+    RoundRobin,
+    LeastRecentlyUsed,
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct DestinationUsage {
+    counter: usize,
+    last_assigned: HashMap<(DestinationID, BlockID), usize>,
+}
+
+impl DestinationUsage {
+    pub fn rank(&self, destination: &Destination, block_id: BlockID) -> usize {
+        let stamp = |id: &BlockID| {
+            self.last_assigned
+                .get(&(destination.id, *id))
+                .copied()
+                .unwrap_or(0)
+        };
+        match destination.distribution {
+            TargetDistribution::Independent => 0,
+            TargetDistribution::LeastRecentlyUsed => stamp(&block_id),
+            TargetDistribution::RoundRobin => {
+                let num_blocks = destination.blocks.len();
+                let index = destination
+                    .blocks
+                    .iter()
+                    .position(|(id, _, _)| *id == block_id)
+                    .unwrap_or(0);
+                let last_index = destination
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (id, _, _))| stamp(id) > 0)
+                    .max_by_key(|(_, (id, _, _))| stamp(id))
+                    .map_or(num_blocks - 1, |(i, _)| i);
+                (index + num_blocks - last_index - 1) % num_blocks
+            }
+        }
+    }
+
+    pub fn record(&mut self, dest: DestinationID, block_id: BlockID) {
+        self.counter += 1;
+        self.last_assigned.insert((dest, block_id), self.counter);
+    }
+}
+
+#[derive(Debug, Clone, Message)]
+pub struct DestinationArrivalMessage {
+    pub train_id: TrainID,
+    pub dest: DestinationID,
+}
+
 #[derive(Debug, Clone, Component, Serialize, Deserialize)]
 pub struct Destination {
     pub id: DestinationID,
     pub blocks: Vec<(BlockID, BlockDirectionFilter, Option<Facing>)>,
+    #[serde(default)]
+    pub arrival_actions: Vec<ArrivalAction>,
+    #[serde(default)]
+    pub distribution: TargetDistribution,
 }
 
 impl Destination {
     pub fn new(id: DestinationID) -> Self {
-        Self { id, blocks: vec![] }
+        Self {
+            id,
+            blocks: vec![],
+            arrival_actions: vec![],
+            distribution: TargetDistribution::default(),
+        }
+    }
+
+    pub fn inspector(ui: &mut Ui, world: &mut World) {
+        let mut state = SystemState::<(
+            Query<(&Destination, Option<&Name>)>,
+            Query<(&Train, Option<&Name>)>,
+            Res<EntityMap>,
+            Res<SelectionState>,
+        )>::new(world);
+        let (destinations, trains, entity_map, selection_state) = state.get_mut(world);
+        let Some(entity) = selection_state.get_entity(&entity_map) else {
+            return;
+        };
+        let Ok((dest, _)) = destinations.get(entity) else {
+            return;
+        };
+        let mut edited = dest.clone();
+        ui.label(format!("{} blocks", dest.blocks.len()));
+        egui::ComboBox::from_label("Distribution")
+            .selected_text(format!("{:?}", edited.distribution))
+            .show_ui(ui, |ui| {
+                for distribution in [
+                    TargetDistribution::Independent,
+                    TargetDistribution::RoundRobin,
+                    TargetDistribution::LeastRecentlyUsed,
+                ] {
+                    ui.selectable_value(
+                        &mut edited.distribution,
+                        distribution,
+                        format!("{:?}", distribution),
+                    );
+                }
+            })
+            .response
+            .on_hover_text("How trains sharing this destination are spread over its blocks");
+        ui.separator();
+        ui.heading("On arrival");
+        let mut remove_action = None;
+        for (i, action) in edited.arrival_actions.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                egui::Grid::new("action").show(ui, |ui| {
+                    match action {
+                        ArrivalAction::DispatchTrain { train, dest } => {
+                            ui.label("Dispatch train");
+                            ui.push_id("train", |ui| Train::selector_option(&trains, ui, train));
+                            ui.end_row();
+                            ui.label("to");
+                            ui.push_id("dest", |ui| {
+                                Destination::selector_option(&destinations, ui, dest)
+                            });
+                            ui.end_row();
+                        }
+                        ArrivalAction::AdvanceSchedule { train } => {
+                            ui.label("Advance schedule of");
+                            ui.push_id("train", |ui| Train::selector_option(&trains, ui, train));
+                            ui.end_row();
+                        }
+                    }
+                    if ui.button("Remove").clicked() {
+                        remove_action = Some(i);
+                    }
+                    ui.end_row();
+                });
+            });
+            ui.separator();
+        }
+        if let Some(i) = remove_action {
+            edited.arrival_actions.remove(i);
+        }
+        ui.horizontal(|ui| {
+            if ui.button("Add dispatch").clicked() {
+                edited.arrival_actions.push(ArrivalAction::DispatchTrain {
+                    train: None,
+                    dest: None,
+                });
+            }
+            if ui.button("Add schedule advance").clicked() {
+                edited
+                    .arrival_actions
+                    .push(ArrivalAction::AdvanceSchedule { train: None });
+            }
+        });
+        if edited.arrival_actions != dest.arrival_actions
+            || edited.distribution != dest.distribution
+        {
+            world.entity_mut(entity).insert(edited);
+        }
     }
 
     pub fn contains_block(&self, block_id: BlockID) -> bool {
@@ -91,6 +262,16 @@ impl Destination {
     }
 }
 
+impl Inspectable for Destination {
+    fn inspector(ui: &mut Ui, world: &mut World) {
+        Destination::inspector(ui, world);
+    }
+
+    fn run_condition(selection_state: Res<SelectionState>) -> bool {
+        selection_state.selected_type() == Some(SelectableType::Destination)
+    }
+}
+
 impl Selectable for Destination {
     type SpawnMessage = SpawnDestinationMessage;
     type ID = DestinationID;
@@ -137,6 +318,8 @@ pub struct DestinationPlugin;
 impl Plugin for DestinationPlugin {
     fn build(&self, app: &mut App) {
         app.add_message::<SpawnDestinationMessage>();
+        app.add_message::<DestinationArrivalMessage>();
+        app.insert_resource(DestinationUsage::default());
         app.register_type::<BlockDirectionFilter>();
         app.add_systems(
             Update,
@@ -144,3 +327,11 @@ impl Plugin for DestinationPlugin {
         );
     }
 }
+
+pub struct DestinationRenderPlugin;
+
+impl Plugin for DestinationRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<Destination>::new());
+    }
+}
@@ -3,28 +3,43 @@ use std::io::{Read, Write};
 use std::path::PathBuf;
 
 use crate::ble::{
-    BLEHub, BroadcasterHub, HubActive, HubBusy, HubError, HubReady, HubRunningProgram, HubState,
-    ManualReady, ObserverHub,
+    BLEHub, BroadcasterHub, HubActive, HubBattery, HubBatteryPolicy, HubBusy, HubCommand,
+    HubCommandMessage, HubDefaults, HubError, HubFailureLog, HubFailurePolicy, HubReady, HubRssi,
+    HubRunningProgram, HubState, ManualReady, ObserverHub, merge_hub_ids,
 };
-use crate::block::{Block, BlockSpawnMessage, BlockSpawnMessageQuery};
+use crate::block::{Block, BlockCreateMessage, BlockSpawnMessage, BlockSpawnMessageQuery};
 use crate::destination::{Destination, SpawnDestinationMessage, SpawnDestinationMessageQuery};
-use crate::layout::{Connections, EntityMap, MarkerMap, TrackLocks};
+use crate::headless::Headless;
+use crate::layout::{
+    Connections, EntityMap, LayoutUnits, MIN_CELL_LENGTH, MarkerMap, SignalAspects, TrackLocks,
+};
 use crate::layout_devices::LayoutDevice;
 use crate::layout_primitives::*;
 use crate::marker::{Marker, MarkerSpawnMessage};
+use crate::persistent_hub_state::{
+    AUTOSAVE_DIR, AutosaveOffer, PersistentHubState, ReconnectOffer, list_autosaves,
+};
 use crate::schedule::{
-    ControlInfo, SpawnScheduleMessage, SpawnScheduleMessageQuery, TrainSchedule,
+    ControlInfo, RandomSeed, SpawnScheduleMessage, SpawnScheduleMessageQuery, TrainSchedule,
 };
 use crate::section::DirectedSection;
 use crate::selectable::{Selectable, SelectableType};
-use crate::switch::{SpawnSwitchMessage, SpawnSwitchMessageQuery, Switch};
+use crate::signal::{Signal, SpawnSignalMessage};
+use crate::switch::{RestoredSwitches, SpawnSwitchMessage, SpawnSwitchMessageQuery, Switch};
 use crate::switch_motor::{PulseMotor, SpawnPulseMotorMessage};
-use crate::track::{LAYOUT_SCALE, SpawnConnectionMessage, SpawnTrackMessage, Track};
-use crate::train::{SpawnTrainMessage, SpawnTrainMessageQuery, Train};
+use crate::track::{
+    DespawnConnectionMessage, LAYOUT_SCALE, PortalSettings, SpawnConnectionMessage,
+    SpawnTrackMessage, TRACK_INNER_WIDTH, Track, make_track_filters_verbose,
+};
+use crate::train::{
+    EmergencyStop, EmergencyStopMessage, SpawnTrainMessage, SpawnTrainMessageQuery, Train,
+    TrainDefaults, TrainWagon,
+};
 
-use bevy::color::palettes::css::BLUE;
+use bevy::color::palettes::css::{BLUE, ORANGE};
 use bevy::ecs::component::Mutable;
-use bevy::ecs::system::{RunSystemOnce, SystemState};
+use bevy::ecs::system::{RunSystemOnce, SystemParam, SystemState};
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
 use bevy::window::{PrimaryWindow, WindowCloseRequested};
 use bevy_egui::egui::panel::TopBottomSide;
@@ -65,6 +80,25 @@ impl Default for EditorInfo {
     }
 }
 
+#[derive(Resource, Debug, Default)]
+pub struct ViewSettings {
+    pub direction_arrows: bool,
+    pub locks: bool,
+    pub reservations: bool,
+    pub block_outlines: bool,
+    pub portals: bool,
+    pub validation: bool,
+    pub failure_injection: bool,
+    pub motion_tuning: bool,
+    pub sensor_log: bool,
+    pub hub_roles: bool,
+    pub layout_properties: bool,
+    pub minimap: bool,
+    pub ruler: bool,
+    pub grid: bool,
+    pub grid_labels: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ControlState;
 
@@ -79,6 +113,48 @@ impl ComputedStates for ControlState {
     }
 }
 
+// freezes control time and train movement, hubs stay connected
+#[derive(Resource, Debug, Default)]
+pub struct ControlPaused(pub bool);
+
+pub fn control_running(paused: Res<ControlPaused>) -> bool {
+    !paused.0
+}
+
+fn toggle_pause_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    control_mode: Res<State<ControlStateMode>>,
+    editor_state: Res<State<EditorState>>,
+    selection_state: Res<SelectionState>,
+    mut paused: ResMut<ControlPaused>,
+    mut egui_contexts: EguiContexts,
+) {
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    if !keyboard_buttons.just_pressed(KeyCode::Space) {
+        return;
+    }
+    // space stops the selected train when driving it manually
+    if control_mode.get() == &ControlStateMode::Manual
+        && editor_state.get() == &EditorState::VirtualControl
+        && matches!(
+            selection_state.selection,
+            Selection::Single(GenericID::Train(_))
+        )
+    {
+        return;
+    }
+    paused.0 = !paused.0;
+}
+
+fn reset_pause(mut paused: ResMut<ControlPaused>) {
+    paused.0 = false;
+}
+
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, SubStates, Display)]
 #[source(ControlState = ControlState)]
 pub enum ControlStateMode {
@@ -107,7 +183,7 @@ impl EditorState {
     }
 }
 
-#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Reflect, Hash)]
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Reflect, Hash, Serialize, Deserialize)]
 pub enum GenericID {
     Cell(CellID),
     Track(TrackID),
@@ -117,7 +193,7 @@ pub enum GenericID {
     Switch(DirectedTrackID),
     Crossing(TrackID),
     TrackConnection(TrackConnectionID),
-    Marker(TrackID),
+    Marker(MarkerID),
     Hub(HubID),
     Destination(DestinationID),
     Schedule(ScheduleID),
@@ -177,6 +253,43 @@ pub enum Selection {
     Multi(Vec<GenericID>),
     Section(DirectedSection),
 }
+// selection stored in a loaded layout, applied once its entity has spawned
+#[derive(Resource, Debug, Default)]
+pub struct PendingSelection(Option<GenericID>);
+
+// whether the layout changed since it was last saved, so idle layouts aren't autosaved
+#[derive(Resource, Debug, Default)]
+pub struct LayoutDirty(pub bool);
+
+// the elevation layer that is drawn on and hovered, see CellID::l
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CurrentLayer(pub i32);
+
+#[derive(Resource, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SaveSettings {
+    pub verbose_track_filters: bool,
+}
+
+#[derive(Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LayoutMetadata {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl LayoutMetadata {
+    pub fn window_title(&self) -> String {
+        if self.name.is_empty() {
+            "Brickrail".to_string()
+        } else {
+            format!("Brickrail - {}", self.name)
+        }
+    }
+}
+
 #[derive(Resource, Debug, Default)]
 pub struct SelectionState {
     pub selection: Selection,
@@ -238,9 +351,11 @@ pub fn directory_panel(world: &mut World) {
             ui.heading("Directory");
             {
                 directory_ui::<Train>(ui, world, "Trains");
+                delete_all_ui::<Train>(ui, world, "trains");
                 directory_ui::<Block>(ui, world, "Blocks");
                 directory_ui::<Switch>(ui, world, "Switches");
                 directory_ui::<BLEHub>(ui, world, "Hubs");
+                delete_all_ui::<BLEHub>(ui, world, "hubs");
                 directory_ui::<Destination>(ui, world, "Destinations");
                 directory_ui::<TrainSchedule>(ui, world, "Schedules");
             };
@@ -313,6 +428,42 @@ pub fn directory_ui<T: Sized + Component + Selectable>(
     }
 }
 
+pub fn delete_all_ui<T: Selectable>(ui: &mut egui::Ui, world: &mut World, label: &str) {
+    let mut state = SystemState::<(
+        Query<&T>,
+        ResMut<SelectionState>,
+        Res<State<EditorState>>,
+        MessageWriter<DespawnMessage<T>>,
+    )>::new(world);
+    let (query, mut selection_state, editor_state, mut despawn_messages) = state.get_mut(world);
+    let count = query.iter().count();
+    if count == 0 {
+        return;
+    }
+    let confirm_id = ui.make_persistent_id(("delete_all", label));
+    let mut confirming = ui.data(|data| data.get_temp::<bool>(confirm_id).unwrap_or(false));
+    ui.add_enabled_ui(editor_state.get() == &EditorState::Edit, |ui| {
+        if confirming {
+            ui.horizontal(|ui| {
+                ui.label(format!("Delete {} {}?", count, label));
+                if ui.button("Yes").clicked() {
+                    for selectable in query.iter() {
+                        despawn_messages.write(DespawnMessage(selectable.id()));
+                    }
+                    selection_state.selection = Selection::None;
+                    confirming = false;
+                }
+                if ui.button("No").clicked() {
+                    confirming = false;
+                }
+            });
+        } else if ui.button(format!("Delete all {}", label)).clicked() {
+            confirming = true;
+        }
+    });
+    ui.data_mut(|data| data.insert_temp(confirm_id, confirming));
+}
+
 pub fn top_panel(
     mut egui_contexts: EguiContexts,
     mut input_data: ResMut<InputData>,
@@ -322,8 +473,26 @@ pub fn top_panel(
     control_mode: Option<Res<State<ControlStateMode>>>,
     mut next_mode: ResMut<NextState<ControlStateMode>>,
     mut editor_info: ResMut<EditorInfo>,
-    control_info: Res<ControlInfo>,
-    mut save_messages: MessageWriter<SaveLayoutMessage>,
+    (mut control_info, mut random_seed, mut paused, emergency_stop, mut emergency_stop_messages): (
+        ResMut<ControlInfo>,
+        ResMut<RandomSeed>,
+        ResMut<ControlPaused>,
+        Res<EmergencyStop>,
+        MessageWriter<EmergencyStopMessage>,
+    ),
+    (mut save_messages, mut export_messages): (
+        MessageWriter<SaveLayoutMessage>,
+        MessageWriter<ExportSvgMessage>,
+    ),
+    mut view_settings: ResMut<ViewSettings>,
+    mut units: ResMut<LayoutUnits>,
+    mut train_defaults: ResMut<TrainDefaults>,
+    (mut save_settings, mut hub_defaults, mut persistent_hub_state): (
+        ResMut<SaveSettings>,
+        ResMut<HubDefaults>,
+        ResMut<PersistentHubState>,
+    ),
+    mut current_layer: ResMut<CurrentLayer>,
 ) {
     if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
         egui::TopBottomPanel::new(TopBottomSide::Top, "Mode").show(ctx, |ui| {
@@ -347,9 +516,143 @@ pub fn top_panel(
                         .add_filter("brickrail layouts", &["json"])
                         .save_file()
                     {
-                        save_messages.write(SaveLayoutMessage { path: path });
+                        save_messages.write(SaveLayoutMessage {
+                            path: path,
+                            autosave: false,
+                        });
                     }
                 }
+                if ui.button("Export SVG").clicked() {
+                    if let Some(path) = FileDialog::new().add_filter("svg", &["svg"]).save_file() {
+                        export_messages.write(ExportSvgMessage { path: path });
+                    }
+                }
+                ui.menu_button("Autosave", |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Interval");
+                        ui.add(
+                            egui::DragValue::new(&mut persistent_hub_state.autosave_interval)
+                                .range(0.0..=3600.0)
+                                .suffix(" s"),
+                        )
+                        .on_hover_text("0 disables autosaving");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Keep");
+                        ui.add(
+                            egui::DragValue::new(&mut persistent_hub_state.autosave_keep)
+                                .range(1..=50)
+                                .suffix(" files"),
+                        );
+                    });
+                });
+                ui.menu_button("Layout", |ui| {
+                    ui.checkbox(&mut view_settings.layout_properties, "Properties");
+                    ui.horizontal(|ui| {
+                        ui.label("Cell length");
+                        ui.add(
+                            egui::DragValue::new(&mut units.cell_length)
+                                .speed(0.01)
+                                .range(MIN_CELL_LENGTH..=f32::MAX),
+                        );
+                        ui.text_edit_singleline(&mut units.unit_name);
+                    });
+                    egui::ComboBox::from_label("Default facing")
+                        .selected_text(format!("{:?}", train_defaults.prefer_facing))
+                        .show_ui(ui, |ui| {
+                            for preference in [
+                                FacingPreference::Any,
+                                FacingPreference::Prefer(Facing::Forward),
+                                FacingPreference::Prefer(Facing::Backward),
+                                FacingPreference::Only(Facing::Forward),
+                                FacingPreference::Only(Facing::Backward),
+                            ] {
+                                ui.selectable_value(
+                                    &mut train_defaults.prefer_facing,
+                                    preference,
+                                    format!("{:?}", preference),
+                                );
+                            }
+                        });
+                    ui.horizontal(|ui| {
+                        ui.label("Dead end clearance");
+                        ui.add(
+                            egui::DragValue::new(&mut train_defaults.dead_end_clearance)
+                                .speed(0.01)
+                                .range(0.0..=2.0),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Reservation margin");
+                        ui.add(
+                            egui::DragValue::new(&mut train_defaults.reservation_margin)
+                                .range(0..=10)
+                                .suffix(" cells"),
+                        )
+                        .on_hover_text("Extra tracks every train reserves ahead of its route");
+                    });
+                    ui.checkbox(
+                        &mut train_defaults.reversal_points_only,
+                        "Reverse only at reversal points",
+                    )
+                    .on_hover_text("Trains change ends only in blocks marked as reversal points");
+                    ui.checkbox(
+                        &mut train_defaults.fit_blocks_only,
+                        "Route only to blocks trains fit in",
+                    )
+                    .on_hover_text("Destinations skip blocks shorter than the train");
+                    ui.checkbox(
+                        &mut hub_defaults.observer_keep_connected,
+                        "Observers keep connected",
+                    )
+                    .on_hover_text("Default for newly assigned observer hubs");
+                    ui.horizontal(|ui| {
+                        let mut limit_chunks = hub_defaults.download_chunk_size.is_some();
+                        ui.checkbox(&mut limit_chunks, "Download chunk size")
+                            .on_hover_text(
+                                "Smaller chunks lose less progress on a dropped connection",
+                            );
+                        match (limit_chunks, hub_defaults.download_chunk_size) {
+                            (true, None) => hub_defaults.download_chunk_size = Some(64),
+                            (false, Some(_)) => hub_defaults.download_chunk_size = None,
+                            _ => {}
+                        }
+                        if let Some(chunk_size) = hub_defaults.download_chunk_size.as_mut() {
+                            ui.add(egui::DragValue::new(chunk_size).range(1..=512).suffix(" B"));
+                        }
+                    });
+                    ui.checkbox(&mut hub_defaults.resume_downloads, "Resume downloads")
+                        .on_hover_text(
+                            "Continue interrupted downloads, only if hubs stay on in between",
+                        );
+                    ui.checkbox(
+                        &mut save_settings.verbose_track_filters,
+                        "Verbose track filters",
+                    )
+                    .on_hover_text("Save track filters in a readable, diff-friendly form");
+                });
+                ui.menu_button("View", |ui| {
+                    ui.checkbox(&mut view_settings.direction_arrows, "Direction arrows");
+                    ui.checkbox(&mut view_settings.locks, "Locks");
+                    ui.checkbox(&mut view_settings.reservations, "Block reservations");
+                    ui.checkbox(&mut view_settings.block_outlines, "Block outlines");
+                    ui.checkbox(&mut view_settings.portals, "Portals");
+                    ui.checkbox(&mut view_settings.motion_tuning, "Motion tuning");
+                    ui.checkbox(&mut view_settings.sensor_log, "Sensor log");
+                    ui.checkbox(&mut view_settings.validation, "Validation");
+                    ui.checkbox(&mut view_settings.failure_injection, "Failure injection");
+                    ui.checkbox(&mut view_settings.hub_roles, "Hub roles");
+                    ui.checkbox(&mut view_settings.minimap, "Minimap");
+                    ui.checkbox(&mut view_settings.ruler, "Ruler");
+                    ui.checkbox(&mut view_settings.grid, "Grid");
+                    ui.add_enabled(
+                        view_settings.grid,
+                        egui::Checkbox::new(&mut view_settings.grid_labels, "Grid labels"),
+                    );
+                });
+                ui.label("Layer");
+                ui.add(egui::DragValue::new(&mut current_layer.0).range(-8..=8))
+                    .on_hover_text("Elevation layer to draw and select tracks on");
                 ui.separator();
                 ui.vertical(|ui| {
                     ui.label(format!("Layout mode: {:?}", editor_state.get()));
@@ -409,6 +712,56 @@ pub fn top_panel(
                             if editable_mode != mode {
                                 next_mode.set(editable_mode);
                             }
+                            if mode == ControlStateMode::Random {
+                                ui.menu_button("Settings", |ui| {
+                                    ui.horizontal(|ui| {
+                                        ui.label("Wait time");
+                                        ui.add(
+                                            egui::DragValue::new(&mut control_info.wait_time)
+                                                .speed(0.1)
+                                                .range(0.0..=f32::MAX),
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("Idle time without destination");
+                                        ui.add(
+                                            egui::DragValue::new(&mut control_info.idle_time)
+                                                .speed(0.1)
+                                                .range(0.0..=f32::MAX),
+                                        );
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("Seed");
+                                        ui.add(egui::DragValue::new(&mut random_seed.0));
+                                    })
+                                    .response
+                                    .on_hover_text("Used the next time control starts");
+                                });
+                            }
+                            let (stop_label, stop_color) = if emergency_stop.engaged {
+                                ("RELEASE STOP", egui::Color32::DARK_RED)
+                            } else {
+                                ("EMERGENCY STOP", egui::Color32::RED)
+                            };
+                            let stop_button = egui::Button::new(
+                                egui::RichText::new(stop_label)
+                                    .strong()
+                                    .color(egui::Color32::WHITE),
+                            )
+                            .fill(stop_color);
+                            if ui
+                                .add(stop_button)
+                                .on_hover_text("Escape stops all trains")
+                                .clicked()
+                            {
+                                emergency_stop_messages.write(EmergencyStopMessage {
+                                    engage: !emergency_stop.engaged,
+                                });
+                            }
+                            let pause_label = if paused.0 { "Resume" } else { "Pause" };
+                            if ui.button(pause_label).clicked() {
+                                paused.0 = !paused.0;
+                            }
                             ui.heading(format!("Time: {:1.1}", control_info.time))
                         });
                     });
@@ -431,9 +784,15 @@ pub fn hub_status_window(
         &HubState,
         Option<&HubError>,
         Option<&ManualReady>,
+        Option<&HubBattery>,
+        Option<&HubRssi>,
     )>,
     mut editor_state: ResMut<NextState<EditorState>>,
     mut commands: Commands,
+    mut failure_policy: ResMut<HubFailurePolicy>,
+    failure_log: Res<HubFailureLog>,
+    mut battery_policy: ResMut<HubBatteryPolicy>,
+    mut command_messages: MessageWriter<HubCommandMessage>,
 ) {
     if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
         egui::Window::new("Hub status")
@@ -447,8 +806,17 @@ pub fn hub_status_window(
                 ui.set_width(ui.available_width());
                 ui.heading("Preparing hubs...");
                 ui.separator();
-                for (entity, hub, busy, active, state, maybe_error, maybe_manual_ready) in
-                    q_hubs.iter()
+                for (
+                    entity,
+                    hub,
+                    busy,
+                    active,
+                    state,
+                    maybe_error,
+                    maybe_manual_ready,
+                    maybe_battery,
+                    maybe_rssi,
+                ) in q_hubs.iter()
                 {
                     ui.with_layout(Layout::left_to_right(Align::Min), |ui| {
                         ui.heading(hub.name.clone().unwrap_or("Unknown".to_string()));
@@ -462,6 +830,12 @@ pub fn hub_status_window(
                         }
                     });
                     state.ui(ui, busy);
+                    if let Some(battery) = maybe_battery {
+                        battery.ui(ui);
+                    }
+                    if let Some(rssi) = maybe_rssi {
+                        rssi.ui(ui);
+                    }
                     if let Some(err) = maybe_error {
                         ui.label(format!("Error: {:?}", err));
                         if ui.button("Retry").clicked() {
@@ -480,7 +854,34 @@ pub fn hub_status_window(
                     }
                     ui.separator();
                 }
+                ui.collapsing("Failure policy", |ui| {
+                    ui.label(format!("Recent failures: {}", failure_log.num_failures()));
+                    ui.horizontal(|ui| {
+                        ui.label("Give up after");
+                        ui.add(egui::DragValue::new(&mut failure_policy.max_failures));
+                        ui.label("failures");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("within");
+                        ui.add(
+                            egui::DragValue::new(&mut failure_policy.window)
+                                .range(1.0..=f32::MAX)
+                                .suffix("s"),
+                        );
+                    });
+                });
+                ui.collapsing("Battery", |ui| {
+                    battery_policy.ui(ui);
+                });
                 if ui.button("Cancel").clicked() {
+                    for (_, hub, busy, ..) in q_hubs.iter() {
+                        if matches!(busy, Some(HubBusy::Downloading(_))) {
+                            command_messages.write(HubCommandMessage {
+                                hub_id: hub.id,
+                                command: HubCommand::CancelDownload,
+                            });
+                        }
+                    }
                     editor_state.set(EditorState::Edit);
                 }
             });
@@ -489,6 +890,325 @@ pub fn hub_status_window(
     }
 }
 
+fn hub_failure_window(mut egui_contexts: EguiContexts, mut failure_log: ResMut<HubFailureLog>) {
+    let Some(summary) = failure_log.summary.clone() else {
+        return;
+    };
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("BLE preparation failed")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(summary);
+                if ui.button("Ok").clicked() {
+                    failure_log.summary = None;
+                }
+            });
+    }
+}
+
+fn layout_properties_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    mut metadata: ResMut<LayoutMetadata>,
+) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Layout properties")
+            .open(&mut view_settings.layout_properties)
+            .default_width(300.0)
+            .show(ctx, |ui| {
+                let mut edited = metadata.bypass_change_detection().clone();
+                egui::Grid::new("layout_properties").show(ui, |ui| {
+                    ui.label("Name");
+                    ui.text_edit_singleline(&mut edited.name);
+                    ui.end_row();
+                    ui.label("Author");
+                    ui.text_edit_singleline(&mut edited.author);
+                    ui.end_row();
+                });
+                ui.label("Notes");
+                ui.text_edit_multiline(&mut edited.notes);
+                metadata.set_if_neq(edited);
+            });
+    }
+}
+
+// connections sampled in world space, only rebuilt when the connections change
+#[derive(Resource, Debug, Default)]
+pub struct MinimapGeometry {
+    lines: Vec<Vec<Vec2>>,
+    bounds: Option<Rect>,
+}
+
+const MINIMAP_SAMPLES: usize = 8;
+const MINIMAP_SIZE: f32 = 220.0;
+
+fn update_minimap_geometry(
+    connections: Res<Connections>,
+    portal_settings: Res<PortalSettings>,
+    mut geometry: ResMut<MinimapGeometry>,
+) {
+    let ramp_length = portal_settings.ramp_length;
+    geometry.lines = connections
+        .connection_graph
+        .all_edges()
+        .map(|(_, _, connection)| {
+            let directed = connection.to_directed(ConnectionDirection::Aligned);
+            let length = directed.connection_length(ramp_length);
+            (0..=MINIMAP_SAMPLES)
+                .map(|i| {
+                    directed
+                        .interpolate_pos(length * i as f32 / MINIMAP_SAMPLES as f32, ramp_length)
+                        * LAYOUT_SCALE
+                })
+                .collect()
+        })
+        .collect();
+    geometry.bounds = cells_world_rect(
+        connections
+            .connection_graph
+            .nodes()
+            .map(|track| track.cell()),
+    );
+}
+
+fn minimap_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    geometry: Res<MinimapGeometry>,
+    q_wagons: Query<(&TrainWagon, &Transform)>,
+    q_camera: Query<(&Transform, &Projection), With<PanCam>>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    mut focus: ResMut<CameraFocus>,
+) {
+    let Ok(ctx) = &egui_contexts.ctx_mut().cloned() else {
+        return;
+    };
+    let Ok((camera_transform, projection)) = q_camera.single() else {
+        return;
+    };
+    let scale = match projection {
+        Projection::Orthographic(ortho) => ortho.scale,
+        _ => 1.0,
+    };
+    let camera_pos = camera_transform.translation.truncate();
+    let viewport = q_window.single().ok().map(|window| {
+        Rect::from_center_size(
+            camera_pos,
+            Vec2::new(window.width(), window.height()) * scale,
+        )
+    });
+    egui::Window::new("Minimap")
+        .open(&mut view_settings.minimap)
+        .resizable(false)
+        .show(ctx, |ui| {
+            let Some(bounds) = geometry.bounds else {
+                ui.label("No tracks");
+                return;
+            };
+            let bounds = Rect::from_center_size(bounds.center(), bounds.size() * FRAME_PADDING);
+            let ratio = MINIMAP_SIZE / bounds.width().max(bounds.height());
+            let (response, painter) = ui.allocate_painter(
+                egui::vec2(bounds.width() * ratio, bounds.height() * ratio),
+                egui::Sense::click(),
+            );
+            let origin = response.rect.left_top();
+            // egui's y axis points down
+            let to_minimap =
+                |pos: Vec2| origin + egui::vec2(pos.x - bounds.min.x, bounds.max.y - pos.y) * ratio;
+            let to_world = |pos: egui::Pos2| {
+                let offset = (pos - origin) / ratio;
+                Vec2::new(bounds.min.x + offset.x, bounds.max.y - offset.y)
+            };
+            painter.rect_filled(response.rect, 2.0, egui::Color32::from_gray(20));
+            for line in geometry.lines.iter() {
+                painter.add(egui::Shape::line(
+                    line.iter().map(|pos| to_minimap(*pos)).collect(),
+                    egui::Stroke::new(1.5, egui::Color32::GRAY),
+                ));
+            }
+            for (wagon, transform) in q_wagons.iter() {
+                if wagon.id.index != 0 {
+                    continue;
+                }
+                painter.circle_filled(
+                    to_minimap(transform.translation.truncate()),
+                    3.0,
+                    egui::Color32::YELLOW,
+                );
+            }
+            if let Some(viewport) = viewport {
+                painter.rect_stroke(
+                    egui::Rect::from_two_pos(to_minimap(viewport.min), to_minimap(viewport.max)),
+                    0.0,
+                    egui::Stroke::new(1.0, egui::Color32::WHITE),
+                    egui::StrokeKind::Middle,
+                );
+            }
+            if let Some(pos) = response.interact_pointer_pos() {
+                if response.clicked() {
+                    focus.target = Some((to_world(pos), scale));
+                }
+            }
+        });
+}
+
+// measured points in cell units, snapped to the hovered track or marker if any
+#[derive(Debug, Clone, Copy)]
+pub struct RulerPoint {
+    pos: Vec2,
+    track: Option<TrackID>,
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct Ruler {
+    start: Option<RulerPoint>,
+    end: Option<RulerPoint>,
+}
+
+fn place_ruler_point(
+    buttons: Res<ButtonInput<MouseButton>>,
+    hover_state: Res<HoverState>,
+    input_data: Res<InputData>,
+    mouse_world_pos: Res<MousePosWorld>,
+    mut ruler: ResMut<Ruler>,
+) {
+    if input_data.mouse_over_ui || !buttons.just_pressed(MouseButton::Left) {
+        return;
+    }
+    let point = match hover_state.hover {
+        Some(GenericID::Marker(marker)) => RulerPoint {
+            pos: marker.get_vec2(),
+            track: Some(marker.track),
+        },
+        Some(GenericID::Track(track)) => RulerPoint {
+            pos: track.get_directed(TrackDirection::First).get_center_vec2(),
+            track: Some(track),
+        },
+        _ => RulerPoint {
+            pos: mouse_world_pos.pos / LAYOUT_SCALE,
+            track: None,
+        },
+    };
+    if ruler.start.is_none() || ruler.end.is_some() {
+        ruler.start = Some(point);
+        ruler.end = None;
+    } else {
+        ruler.end = Some(point);
+    }
+}
+
+fn draw_ruler(mut gizmos: Gizmos, ruler: Res<Ruler>, mouse_world_pos: Res<MousePosWorld>) {
+    let Some(start) = ruler.start else {
+        return;
+    };
+    let end = ruler
+        .end
+        .map(|end| end.pos * LAYOUT_SCALE)
+        .unwrap_or(mouse_world_pos.pos);
+    let color = Color::from(ORANGE);
+    gizmos.line_2d(start.pos * LAYOUT_SCALE, end, color);
+    gizmos.circle_2d(start.pos * LAYOUT_SCALE, 0.1 * LAYOUT_SCALE, color);
+    gizmos.circle_2d(end, 0.1 * LAYOUT_SCALE, color);
+}
+
+fn ruler_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    mut ruler: ResMut<Ruler>,
+    connections: Res<Connections>,
+    units: Res<LayoutUnits>,
+    portal_settings: Res<PortalSettings>,
+) {
+    let Ok(ctx) = &egui_contexts.ctx_mut().cloned() else {
+        return;
+    };
+    egui::Window::new("Ruler")
+        .open(&mut view_settings.ruler)
+        .resizable(false)
+        .show(ctx, |ui| {
+            let (Some(start), Some(end)) = (ruler.start, ruler.end) else {
+                ui.label("Click two points to measure");
+                return;
+            };
+            ui.label(format!(
+                "Straight: {}",
+                units.format_length((end.pos - start.pos).length())
+            ));
+            if let (Some(from), Some(to)) = (start.track, end.track) {
+                match connections.track_distance(from, to, portal_settings.ramp_length) {
+                    Some(distance) => {
+                        ui.label(format!("Along track: {}", units.format_length(distance)))
+                    }
+                    None => ui.label("Along track: no path"),
+                }
+                .on_hover_text("Shortest drivable path between the track centers");
+            }
+            if ui.button("Clear").clicked() {
+                *ruler = Ruler::default();
+            }
+        });
+}
+
+fn update_window_title(
+    metadata: Res<LayoutMetadata>,
+    mut q_window: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    for mut window in q_window.iter_mut() {
+        window.title = metadata.window_title();
+    }
+}
+
+fn reconnect_window(
+    mut egui_contexts: EguiContexts,
+    mut offer: ResMut<ReconnectOffer>,
+    mut persistent_hub_state: ResMut<PersistentHubState>,
+    q_hubs: Query<&BLEHub>,
+    mut next_editor_state: ResMut<NextState<EditorState>>,
+) {
+    // wait until a layout with the remembered hubs is loaded
+    let hubs = q_hubs
+        .iter()
+        .filter_map(|hub| hub.name.clone())
+        .filter(|name| offer.hubs.contains(name))
+        .collect::<Vec<_>>();
+    if hubs.is_empty() {
+        return;
+    }
+    if persistent_hub_state.auto_reconnect {
+        info!("Reconnecting hubs {:?}", hubs);
+        offer.hubs.clear();
+        next_editor_state.set(EditorState::PreparingDeviceControl);
+        return;
+    }
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Reconnect hubs")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label("These hubs were still connected when Brickrail last closed:");
+                for name in &hubs {
+                    ui.label(name);
+                }
+                ui.checkbox(
+                    &mut persistent_hub_state.auto_reconnect,
+                    "Reconnect automatically next time",
+                );
+                ui.horizontal(|ui| {
+                    if ui.button("Reconnect").clicked() {
+                        offer.hubs.clear();
+                        next_editor_state.set(EditorState::PreparingDeviceControl);
+                    }
+                    if ui.button("Dismiss").clicked() {
+                        offer.hubs.clear();
+                    }
+                });
+            });
+    }
+}
+
 fn spawn_camera(mut commands: Commands) {
     let pancam = PanCam {
         grab_buttons: vec![MouseButton::Middle],
@@ -513,6 +1233,136 @@ pub fn finish_hover(mut hover_state: ResMut<HoverState>) {
     }
 }
 
+// world space rectangle covering the cells, a cell spans one unit around its center
+pub fn cells_world_rect(cells: impl IntoIterator<Item = CellID>) -> Option<Rect> {
+    cells
+        .into_iter()
+        .map(|cell| {
+            Rect::from_center_size(cell.get_vec2() * LAYOUT_SCALE, Vec2::splat(LAYOUT_SCALE))
+        })
+        .reduce(|a, b| a.union(b))
+}
+
+fn selection_cells(
+    selection: &Selection,
+    q_blocks: &Query<&Block>,
+    entity_map: &EntityMap,
+) -> Vec<CellID> {
+    let ids = match selection {
+        Selection::None => return vec![],
+        Selection::Section(section) => {
+            return section
+                .tracks
+                .iter()
+                .map(|track| track.track.cell())
+                .collect();
+        }
+        Selection::Single(id) => vec![*id],
+        Selection::Multi(ids) => ids.clone(),
+    };
+    let mut cells = vec![];
+    for id in ids {
+        match id {
+            GenericID::Cell(cell) => cells.push(cell),
+            GenericID::Track(track) | GenericID::Crossing(track) => cells.push(track.cell()),
+            GenericID::LogicalTrack(track) => cells.push(track.cell()),
+            GenericID::Switch(track) => cells.push(track.track.cell()),
+            GenericID::Marker(marker) => cells.push(marker.track.cell()),
+            GenericID::TrackConnection(connection) => {
+                cells.extend(connection.tracks().map(|track| track.track.cell()));
+            }
+            GenericID::Block(block_id) => {
+                if let Some(block) = entity_map.query_get(q_blocks, &id) {
+                    cells.extend(
+                        block
+                            .section()
+                            .tracks
+                            .iter()
+                            .map(|track| track.track.cell()),
+                    );
+                } else {
+                    cells.extend([block_id.track1.track.cell(), block_id.track2.track.cell()]);
+                }
+            }
+            _ => {}
+        }
+    }
+    cells
+}
+
+// where the camera is being moved to, as translation and scale
+#[derive(Resource, Debug, Default)]
+pub struct CameraFocus {
+    target: Option<(Vec2, f32)>,
+}
+
+const FRAME_PADDING: f32 = 1.2;
+const CAMERA_FOCUS_RATE: f32 = 8.0;
+
+fn frame_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    selection_state: Res<SelectionState>,
+    connections: Res<Connections>,
+    q_blocks: Query<&Block>,
+    entity_map: Res<EntityMap>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    mut focus: ResMut<CameraFocus>,
+    mut egui_contexts: EguiContexts,
+) {
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    if !keyboard_buttons.just_pressed(KeyCode::KeyF) {
+        return;
+    }
+    let cells = if keyboard_buttons.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        selection_cells(&selection_state.selection, &q_blocks, &entity_map)
+    } else {
+        connections
+            .connection_graph
+            .nodes()
+            .map(|track| track.cell())
+            .collect()
+    };
+    let Some(rect) = cells_world_rect(cells) else {
+        return;
+    };
+    let Ok(window) = q_window.single() else {
+        return;
+    };
+    let size = rect.size() * FRAME_PADDING;
+    let scale = (size.x / window.width()).max(size.y / window.height());
+    focus.target = Some((rect.center(), scale));
+}
+
+fn animate_camera_focus(
+    time: Res<Time>,
+    mut focus: ResMut<CameraFocus>,
+    mut q_camera: Query<(&mut Transform, &mut Projection), With<PanCam>>,
+) {
+    let Some((target_pos, target_scale)) = focus.target else {
+        return;
+    };
+    let Ok((mut transform, mut projection)) = q_camera.single_mut() else {
+        return;
+    };
+    let Projection::Orthographic(ortho) = projection.as_mut() else {
+        return;
+    };
+    let t = 1.0 - (-CAMERA_FOCUS_RATE * time.delta_secs()).exp();
+    let pos = transform.translation.truncate().lerp(target_pos, t);
+    ortho.scale += (target_scale - ortho.scale) * t;
+    transform.translation = pos.extend(transform.translation.z);
+    if pos.distance(target_pos) < 0.5 && (ortho.scale - target_scale).abs() < 1e-3 {
+        transform.translation = target_pos.extend(transform.translation.z);
+        ortho.scale = target_scale;
+        focus.target = None;
+    }
+}
+
 #[derive(Resource, Debug, Default)]
 pub struct MousePosWorld {
     pub pos: Vec2,
@@ -541,11 +1391,15 @@ pub fn update_hover<T: Selectable>(
     mouse_world_pos: Res<MousePosWorld>,
     q_selectable: Query<(&T, Option<&Transform>, Option<&Shape>)>,
     mut hover_state: ResMut<HoverState>,
+    current_layer: Res<CurrentLayer>,
 ) {
     for (selectable, transform, shape) in q_selectable.iter() {
         if !hover_state.filter.matches(&selectable.generic_id()) {
             continue;
         }
+        if selectable.layer().is_some_and(|l| l != current_layer.0) {
+            continue;
+        }
         if selectable.get_depth() < hover_state.hover_depth {
             continue;
         }
@@ -567,13 +1421,28 @@ pub fn update_hover<T: Selectable>(
 
 fn init_select(
     buttons: Res<ButtonInput<MouseButton>>,
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
     hover_state: Res<HoverState>,
     mut selection_state: ResMut<SelectionState>,
     input_data: Res<InputData>,
+    view_settings: Res<ViewSettings>,
 ) {
     if input_data.mouse_over_ui {
         return;
     }
+    // shift+click shunts a selected train or drags a selected section instead
+    if keyboard_buttons.pressed(KeyCode::ShiftLeft)
+        && matches!(
+            selection_state.selection,
+            Selection::Single(GenericID::Train(_)) | Selection::Section(_)
+        )
+    {
+        return;
+    }
+    // clicks place ruler points while the ruler is open
+    if view_settings.ruler {
+        return;
+    }
     if buttons.just_pressed(MouseButton::Left) {
         match hover_state.hover {
             Some(id) => {
@@ -593,12 +1462,17 @@ pub fn delete_selection_shortcut<T: Selectable + Component<Mutability = Mutable>
     mut q_selectable: Query<&mut T>,
     mut despawn_messages: MessageWriter<DespawnMessage<T>>,
     entity_map: Res<EntityMap>,
+    connections: Res<Connections>,
+    mut history: ResMut<EditHistory>,
 ) {
     if keyboard_buttons.just_pressed(KeyCode::Delete) {
         match &selection_state.selection {
             Selection::Single(id) => {
                 let entity = entity_map.get_entity(id).unwrap();
                 if let Ok(component) = q_selectable.get_mut(entity) {
+                    if let Some(action) = component.removal_action(&connections) {
+                        history.push(action);
+                    }
                     despawn_messages.write(DespawnMessage(component.id()));
                     selection_state.selection = Selection::None;
                 }
@@ -608,6 +1482,326 @@ pub fn delete_selection_shortcut<T: Selectable + Component<Mutability = Mutable>
     }
 }
 
+// a reversible edit, applied by writing the same messages the editor uses
+#[derive(Debug, Clone)]
+pub enum EditAction {
+    SpawnTrack(Track),
+    // the connections are respawned when the deletion is undone
+    DespawnTrack(Track, Vec<TrackConnectionID>),
+    SpawnConnection(TrackConnectionID),
+    DespawnConnection(TrackConnectionID),
+    CreateBlock(Block),
+    DespawnBlock(Block),
+    AddMarker(Marker),
+    RemoveMarker(Marker),
+    EditSwitch {
+        id: DirectedTrackID,
+        before: Vec<Option<LayoutDeviceID>>,
+        after: Vec<Option<LayoutDeviceID>>,
+    },
+    // the switch it shares motors with and whether it's inverted
+    EditSwitchSharing {
+        id: DirectedTrackID,
+        before: (Option<DirectedTrackID>, bool),
+        after: (Option<DirectedTrackID>, bool),
+    },
+    // switches come and go with their connections, these only carry their settings
+    RemoveSwitch(Switch),
+    RestoreSwitch(Switch),
+    Group(Vec<EditAction>),
+}
+
+impl EditAction {
+    pub fn inverse(&self) -> EditAction {
+        match self {
+            EditAction::SpawnTrack(track) => EditAction::DespawnTrack(track.clone(), vec![]),
+            EditAction::DespawnTrack(track, connections) => {
+                let mut actions = vec![EditAction::SpawnTrack(track.clone())];
+                actions.extend(connections.iter().cloned().map(EditAction::SpawnConnection));
+                EditAction::Group(actions)
+            }
+            EditAction::SpawnConnection(id) => EditAction::DespawnConnection(*id),
+            EditAction::DespawnConnection(id) => EditAction::SpawnConnection(*id),
+            EditAction::CreateBlock(block) => EditAction::DespawnBlock(block.clone()),
+            EditAction::DespawnBlock(block) => EditAction::CreateBlock(block.clone()),
+            EditAction::AddMarker(marker) => EditAction::RemoveMarker(marker.clone()),
+            EditAction::RemoveMarker(marker) => EditAction::AddMarker(marker.clone()),
+            EditAction::EditSwitch { id, before, after } => EditAction::EditSwitch {
+                id: *id,
+                before: after.clone(),
+                after: before.clone(),
+            },
+            EditAction::EditSwitchSharing { id, before, after } => EditAction::EditSwitchSharing {
+                id: *id,
+                before: *after,
+                after: *before,
+            },
+            EditAction::RemoveSwitch(switch) => EditAction::RestoreSwitch(switch.clone()),
+            EditAction::RestoreSwitch(switch) => EditAction::RemoveSwitch(switch.clone()),
+            EditAction::Group(actions) => {
+                EditAction::Group(actions.iter().rev().map(|a| a.inverse()).collect())
+            }
+        }
+    }
+
+    pub fn apply(&self, writers: &mut EditWriters) {
+        match self {
+            EditAction::SpawnTrack(track) => {
+                writers.spawn_tracks.write(SpawnTrackMessage(track.clone()));
+            }
+            EditAction::DespawnTrack(track, _) => {
+                writers.despawn_tracks.write(DespawnMessage(track.id));
+            }
+            EditAction::SpawnConnection(id) => {
+                writers.spawn_connections.write(SpawnConnectionMessage {
+                    id: *id,
+                    update_switches: true,
+                });
+            }
+            EditAction::DespawnConnection(id) => {
+                writers
+                    .despawn_connections
+                    .write(DespawnConnectionMessage { id: *id });
+            }
+            EditAction::CreateBlock(block) => {
+                writers
+                    .create_blocks
+                    .write(BlockCreateMessage(block.clone()));
+            }
+            EditAction::DespawnBlock(block) => {
+                writers.despawn_blocks.write(DespawnMessage(block.id));
+            }
+            EditAction::AddMarker(marker) => {
+                writers
+                    .spawn_markers
+                    .write(MarkerSpawnMessage(marker.clone()));
+            }
+            EditAction::RemoveMarker(marker) => {
+                writers
+                    .despawn_markers
+                    .write(DespawnMessage(marker.marker_id()));
+            }
+            EditAction::EditSwitch { id, after, .. } => {
+                if let Some(entity) = writers.entity_map.switches.get(id) {
+                    if let Ok(mut switch) = writers.switches.get_mut(*entity) {
+                        switch.motors = after.clone();
+                    }
+                }
+            }
+            EditAction::EditSwitchSharing { id, after, .. } => {
+                if let Some(entity) = writers.entity_map.switches.get(id) {
+                    if let Ok(mut switch) = writers.switches.get_mut(*entity) {
+                        (switch.shared_with, switch.invert) = *after;
+                    }
+                }
+            }
+            EditAction::RemoveSwitch(_) => {}
+            EditAction::RestoreSwitch(switch) => {
+                writers.restored_switches.insert(switch.clone());
+            }
+            EditAction::Group(actions) => {
+                for action in actions {
+                    action.apply(writers);
+                }
+            }
+        }
+    }
+}
+
+#[derive(SystemParam)]
+pub struct EditWriters<'w, 's> {
+    spawn_tracks: MessageWriter<'w, SpawnTrackMessage>,
+    despawn_tracks: MessageWriter<'w, DespawnMessage<Track>>,
+    spawn_connections: MessageWriter<'w, SpawnConnectionMessage>,
+    despawn_connections: MessageWriter<'w, DespawnConnectionMessage>,
+    create_blocks: MessageWriter<'w, BlockCreateMessage>,
+    despawn_blocks: MessageWriter<'w, DespawnMessage<Block>>,
+    spawn_markers: MessageWriter<'w, MarkerSpawnMessage>,
+    despawn_markers: MessageWriter<'w, DespawnMessage<Marker>>,
+    switches: Query<'w, 's, &'static mut Switch>,
+    entity_map: Res<'w, EntityMap>,
+    restored_switches: ResMut<'w, RestoredSwitches>,
+}
+
+impl EditWriters<'_, '_> {
+    pub fn switch(&self, id: &DirectedTrackID) -> Option<&Switch> {
+        self.switches.get(*self.entity_map.switches.get(id)?).ok()
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct EditHistory {
+    undo_stack: Vec<EditAction>,
+    redo_stack: Vec<EditAction>,
+}
+
+impl EditHistory {
+    pub fn push(&mut self, action: EditAction) {
+        self.undo_stack.push(action);
+        self.redo_stack.clear();
+    }
+
+    // returns the action to apply to revert the last edit
+    pub fn undo(&mut self) -> Option<EditAction> {
+        let action = self.undo_stack.pop()?;
+        let inverse = action.inverse();
+        self.redo_stack.push(action);
+        Some(inverse)
+    }
+
+    pub fn redo(&mut self) -> Option<EditAction> {
+        let action = self.redo_stack.pop()?;
+        self.undo_stack.push(action.clone());
+        Some(action)
+    }
+}
+
+pub fn undo_redo_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    mut history: ResMut<EditHistory>,
+    mut writers: EditWriters,
+    mut egui_contexts: EguiContexts,
+) {
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    let ctrl = keyboard_buttons.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let shift = keyboard_buttons.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if !ctrl || !keyboard_buttons.just_pressed(KeyCode::KeyZ) {
+        return;
+    }
+    let action = if shift {
+        history.redo()
+    } else {
+        history.undo()
+    };
+    if let Some(action) = action {
+        action.apply(&mut writers);
+    }
+}
+
+#[derive(Resource, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Clipboard {
+    // directed so a copied section can be selected again after pasting
+    tracks: Vec<DirectedTrackID>,
+    connections: Vec<TrackConnectionID>,
+    is_section: bool,
+}
+
+impl Clipboard {
+    fn from_selection(selection: &Selection, connections: &Connections) -> Option<Self> {
+        let (tracks, is_section) = match selection {
+            Selection::Section(section) => (section.tracks.clone(), true),
+            Selection::Multi(ids) => (
+                ids.iter()
+                    .filter_map(|id| match id {
+                        GenericID::Track(track) => Some(track.get_directed(TrackDirection::First)),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+                false,
+            ),
+            _ => return None,
+        };
+        if tracks.is_empty() {
+            return None;
+        }
+        let track_ids = tracks.iter().map(|t| t.track).collect::<Vec<_>>();
+        let mut track_connections = vec![];
+        for track in track_ids.iter() {
+            for (_, other, connection) in connections.connection_graph.edges(*track) {
+                if track_ids.contains(&other) && !track_connections.contains(connection) {
+                    track_connections.push(*connection);
+                }
+            }
+        }
+        Some(Self {
+            tracks,
+            connections: track_connections,
+            is_section,
+        })
+    }
+
+    fn origin(&self) -> CellID {
+        self.tracks[0].cell()
+    }
+}
+
+fn copy_paste_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    mut clipboard: ResMut<Clipboard>,
+    mut selection_state: ResMut<SelectionState>,
+    connections: Res<Connections>,
+    mouse_world_pos: Res<MousePosWorld>,
+    mut history: ResMut<EditHistory>,
+    mut error_toast: ResMut<ErrorToast>,
+    mut writers: EditWriters,
+    mut egui_contexts: EguiContexts,
+) {
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    if !keyboard_buttons.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    if keyboard_buttons.just_pressed(KeyCode::KeyC) {
+        match Clipboard::from_selection(&selection_state.selection, &connections) {
+            Some(copied) => *clipboard = copied,
+            None => error_toast.push("Select tracks or a section to copy".to_string()),
+        }
+    }
+    if keyboard_buttons.just_pressed(KeyCode::KeyV) && !clipboard.tracks.is_empty() {
+        let mouse_cell = CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE);
+        let origin = clipboard.origin();
+        let delta = CellID::new(mouse_cell.x - origin.x, mouse_cell.y - origin.y, 0);
+        let mut pasted = vec![];
+        let mut actions = vec![];
+        for dirtrack in clipboard.tracks.iter() {
+            let track = dirtrack.track.translated(delta);
+            if connections.has_track(track) || pasted.contains(&track) {
+                continue;
+            }
+            pasted.push(track);
+            actions.push(EditAction::SpawnTrack(Track::from_id(track)));
+        }
+        for connection in clipboard.connections.iter() {
+            let connection = connection.translated(delta);
+            if connection
+                .tracks()
+                .iter()
+                .all(|track| pasted.contains(&track.track))
+            {
+                actions.push(EditAction::SpawnConnection(connection));
+            }
+        }
+        if actions.is_empty() {
+            return;
+        }
+        let action = EditAction::Group(actions);
+        action.apply(&mut writers);
+        history.push(action);
+
+        let tracks = clipboard
+            .tracks
+            .iter()
+            .map(|track| track.translated(delta))
+            .filter(|track| pasted.contains(&track.track));
+        selection_state.selection = if clipboard.is_section {
+            Selection::Section(DirectedSection {
+                tracks: tracks.collect(),
+            })
+        } else {
+            Selection::Multi(tracks.map(|track| GenericID::Track(track.track)).collect())
+        };
+    }
+}
+
 fn draw_selection(mut gizmos: Gizmos, selection_state: Res<SelectionState>) {
     match &selection_state.selection {
         Selection::Section(section) => {
@@ -622,9 +1816,14 @@ fn draw_selection(mut gizmos: Gizmos, selection_state: Res<SelectionState>) {
 fn extend_selection(
     hover_state: Res<HoverState>,
     buttons: Res<ButtonInput<MouseButton>>,
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
     mut selection_state: ResMut<SelectionState>,
     connections: Res<Connections>,
 ) {
+    // shift+drag moves the selected section instead
+    if keyboard_buttons.pressed(KeyCode::ShiftLeft) {
+        return;
+    }
     match hover_state.hover {
         Some(GenericID::Track(_)) => {}
         _ => {
@@ -679,8 +1878,59 @@ pub struct SpawnHubMessage {
     pub broadcaster: bool,
 }
 
+// bumped whenever the layout json changes in a way that needs migrating
+const LAYOUT_VERSION: u32 = 1;
+
+fn rename_device_kinds(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(kind) = map.get_mut("kind") {
+                if kind == "Switch" || kind == "SwitchMotor" {
+                    *kind = "PulseMotor".into();
+                }
+            }
+            for child in map.values_mut() {
+                rename_device_kinds(child);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for child in values.iter_mut() {
+                rename_device_kinds(child);
+            }
+        }
+        _ => {}
+    }
+}
+
+// upgrades layout json written by older versions before deserializing
+fn migrate(mut value: serde_json::Value, from_version: u32) -> Result<serde_json::Value, String> {
+    if from_version > LAYOUT_VERSION {
+        return Err(format!(
+            "layout version {} is newer than the supported version {}",
+            from_version, LAYOUT_VERSION
+        ));
+    }
+    if !value.is_object() {
+        return Err("layout is not a json object".to_string());
+    }
+    if from_version < 1 {
+        // switch motors used to be stored with the Switch or SwitchMotor device kind
+        rename_device_kinds(&mut value);
+        let object = value.as_object_mut().unwrap();
+        for key in ["destinations", "schedules"] {
+            object
+                .entry(key)
+                .or_insert(serde_json::Value::Array(vec![]));
+        }
+    }
+    value["version"] = LAYOUT_VERSION.into();
+    Ok(value)
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct SerializableLayout {
+    #[serde(default)]
+    version: u32,
     marker_map: MarkerMap,
     tracks: Vec<SpawnTrackMessage>,
     connections: Vec<SpawnConnectionMessage>,
@@ -695,9 +1945,99 @@ struct SerializableLayout {
     #[serde(default)]
     switch_motors: Vec<SpawnPulseMotorMessage>,
     #[serde(default)]
+    signals: Vec<SpawnSignalMessage>,
+    #[serde(default)]
     destinations: Vec<SpawnDestinationMessage>,
     #[serde(default)]
     schedules: Vec<SpawnScheduleMessage>,
+    #[serde(default)]
+    units: LayoutUnits,
+    #[serde(default)]
+    train_defaults: TrainDefaults,
+    #[serde(default)]
+    selection: Option<GenericID>,
+    #[serde(default)]
+    save_settings: SaveSettings,
+    #[serde(default)]
+    hub_defaults: HubDefaults,
+    #[serde(default)]
+    metadata: LayoutMetadata,
+    #[serde(default)]
+    portal_settings: PortalSettings,
+    #[serde(default)]
+    signal_aspects: SignalAspects,
+}
+
+impl SerializableLayout {
+    // stores the hub name next to every hub assignment,
+    // so assignments survive hubs being renumbered
+    fn store_hub_names(&mut self) {
+        let names = self
+            .hubs
+            .iter()
+            .filter_map(|h| Some((h.hub.id, h.hub.name.clone()?)))
+            .collect::<HashMap<_, _>>();
+        let name_of = |hub_id: &Option<HubID>| hub_id.and_then(|id| names.get(&id).cloned());
+        for motor in self.switch_motors.iter_mut() {
+            motor.device.hub_name = name_of(&motor.device.hub_id);
+        }
+        for signal in self.signals.iter_mut() {
+            signal.device.hub_name = name_of(&signal.device.hub_id);
+        }
+        for train in self.trains.iter_mut() {
+            if let Some(ble_train) = train.ble_train.as_mut() {
+                for train_hub in ble_train.iter_train_hubs_mut() {
+                    train_hub.hub_name = name_of(&train_hub.hub_id);
+                }
+            }
+        }
+    }
+
+    // moves the hubs to the ids a session that knows the `existing` hubs uses for them
+    fn remap_hub_ids(&mut self, existing: &[(HubID, Option<String>)]) {
+        let incoming = self
+            .hubs
+            .iter()
+            .map(|h| (h.hub.id, h.hub.name.clone()))
+            .collect::<Vec<_>>();
+        let mapping = merge_hub_ids(existing, &incoming);
+        for hub in self.hubs.iter_mut() {
+            hub.hub.id = mapping[&hub.hub.id];
+        }
+        let names = self
+            .hubs
+            .iter()
+            .filter_map(|h| Some((h.hub.name.clone()?, h.hub.id)))
+            .collect::<HashMap<_, _>>();
+        let remap = |hub_id: &mut Option<HubID>, hub_name: &Option<String>| {
+            let remapped = match (hub_id.and_then(|id| mapping.get(&id)), hub_name) {
+                (Some(id), _) => Some(*id),
+                // the assigned hub isn't part of the layout, find it by name
+                (None, Some(name)) => names.get(name).copied().or(*hub_id),
+                (None, None) => *hub_id,
+            };
+            if remapped != *hub_id {
+                warn!(
+                    "Hub assignment {:?} of {:?} remapped to {:?}",
+                    hub_id, hub_name, remapped
+                );
+                *hub_id = remapped;
+            }
+        };
+        for motor in self.switch_motors.iter_mut() {
+            remap(&mut motor.device.hub_id, &motor.device.hub_name);
+        }
+        for signal in self.signals.iter_mut() {
+            remap(&mut signal.device.hub_id, &signal.device.hub_name);
+        }
+        for train in self.trains.iter_mut() {
+            if let Some(ble_train) = train.ble_train.as_mut() {
+                for train_hub in ble_train.iter_train_hubs_mut() {
+                    remap(&mut train_hub.hub_id, &train_hub.hub_name);
+                }
+            }
+        }
+    }
 }
 
 pub fn save_layout(
@@ -708,10 +2048,35 @@ pub fn save_layout(
     q_markers: Query<&Marker>,
     q_tracks: Query<&Track>,
     q_hubs: Query<(&BLEHub, Option<&BroadcasterHub>, Option<&ObserverHub>)>,
-    q_switch_motors: Query<(&PulseMotor, &LayoutDevice)>,
+    (q_switch_motors, q_signals): (
+        Query<(&PulseMotor, &LayoutDevice)>,
+        Query<(&Signal, &LayoutDevice)>,
+    ),
     q_destinations: SpawnDestinationMessageQuery,
     q_schedules: SpawnScheduleMessageQuery,
     connections: Res<Connections>,
+    units: Res<LayoutUnits>,
+    train_defaults: Res<TrainDefaults>,
+    selection_state: Res<SelectionState>,
+    (
+        save_settings,
+        hub_defaults,
+        metadata,
+        portal_settings,
+        mut persistent_hub_state,
+        mut error_toast,
+        mut dirty,
+        signal_aspects,
+    ): (
+        Res<SaveSettings>,
+        Res<HubDefaults>,
+        Res<LayoutMetadata>,
+        Res<PortalSettings>,
+        ResMut<PersistentHubState>,
+        ResMut<ErrorToast>,
+        ResMut<LayoutDirty>,
+        Res<SignalAspects>,
+    ),
     mut save_messages: MessageReader<SaveLayoutMessage>,
 ) {
     for event in save_messages.read() {
@@ -724,7 +2089,6 @@ pub fn save_layout(
             );
         }
         println!("Saving layout");
-        let mut file = std::fs::File::create(event.path.clone()).unwrap();
         let mut tracks = q_tracks
             .iter()
             .map(|t| SpawnTrackMessage(t.clone()))
@@ -747,6 +2111,14 @@ pub fn save_layout(
             })
             .collect::<Vec<_>>();
         switch_motors.sort_by_key(|m| m.device.id);
+        let mut signals = q_signals
+            .iter()
+            .map(|(signal, device)| SpawnSignalMessage {
+                signal: signal.clone(),
+                device: device.clone(),
+            })
+            .collect::<Vec<_>>();
+        signals.sort_by_key(|s| s.device.id);
         let mut connections = connections
             .connection_graph
             .all_edges()
@@ -757,9 +2129,10 @@ pub fn save_layout(
             .collect::<Vec<_>>();
         connections.sort_by_key(|c| c.id);
         let mut markers = q_markers.iter().map(|m| m.clone()).collect::<Vec<_>>();
-        markers.sort_by_key(|m| m.track);
+        markers.sort_by_key(|m| m.marker_id());
 
-        let layout_val = SerializableLayout {
+        let mut layout_val = SerializableLayout {
+            version: LAYOUT_VERSION,
             marker_map: marker_map.clone(),
             blocks: q_blocks.get(),
             markers: markers,
@@ -769,49 +2142,368 @@ pub fn save_layout(
             hubs,
             switches: q_switches.get(),
             switch_motors,
+            signals,
             destinations: q_destinations.get(),
             schedules: q_schedules.get(),
+            units: units.clone(),
+            train_defaults: train_defaults.clone(),
+            selection: match &selection_state.selection {
+                Selection::Single(id) => Some(*id),
+                _ => None,
+            },
+            save_settings: save_settings.clone(),
+            hub_defaults: hub_defaults.clone(),
+            metadata: metadata.clone(),
+            portal_settings: portal_settings.clone(),
+            signal_aspects: signal_aspects.clone(),
+        };
+        layout_val.store_hub_names();
+        let json = serde_json::to_value(&layout_val).and_then(|mut val| {
+            if save_settings.verbose_track_filters {
+                make_track_filters_verbose(&mut val["tracks"])?;
+            }
+            val.sort_all_objects();
+            serde_json::to_string_pretty(&val)
+        });
+        let json = match json {
+            Ok(json) => json,
+            Err(err) => {
+                error_toast.push(format!("Failed to serialize layout: {}", err));
+                continue;
+            }
         };
-        let mut val = serde_json::to_value(&layout_val).unwrap();
-        val.sort_all_objects();
-        let json = serde_json::to_string_pretty(&val).unwrap();
+        let mut file = std::fs::File::create(event.path.clone()).unwrap();
         file.write(json.as_bytes()).unwrap();
+        dirty.0 = false;
+        if !event.autosave {
+            persistent_hub_state.last_saved_layout = Some(event.path.clone());
+            // the autosave offer on the next start compares against this
+            persistent_hub_state.save_to_disk();
+        }
+    }
+}
+
+// spawning a loaded layout adds components, which doesn't count as an edit
+fn edited<T: Component>(query: &Query<Ref<T>, Changed<T>>) -> bool {
+    query.iter().any(|component| !component.is_added())
+}
+
+fn mark_layout_dirty(
+    mut dirty: ResMut<LayoutDirty>,
+    edit_history: Res<EditHistory>,
+    q_tracks: Query<Ref<Track>, Changed<Track>>,
+    q_blocks: Query<Ref<Block>, Changed<Block>>,
+    q_markers: Query<Ref<Marker>, Changed<Marker>>,
+    q_switches: Query<Ref<Switch>, Changed<Switch>>,
+    q_trains: Query<Ref<Train>, Changed<Train>>,
+    q_devices: Query<Ref<LayoutDevice>, Changed<LayoutDevice>>,
+) {
+    if edit_history.is_changed()
+        || edited(&q_tracks)
+        || edited(&q_blocks)
+        || edited(&q_markers)
+        || edited(&q_switches)
+        || edited(&q_trains)
+        || edited(&q_devices)
+    {
+        dirty.0 = true;
+    }
+}
+
+fn autosave(
+    time: Res<Time>,
+    mut elapsed: Local<f32>,
+    persistent_hub_state: Res<PersistentHubState>,
+    connections: Res<Connections>,
+    dirty: Res<LayoutDirty>,
+    mut save_messages: MessageWriter<SaveLayoutMessage>,
+) {
+    if persistent_hub_state.autosave_interval <= 0.0 {
+        return;
+    }
+    *elapsed += time.delta_secs();
+    if *elapsed < persistent_hub_state.autosave_interval {
+        return;
+    }
+    *elapsed = 0.0;
+    // nothing worth keeping, and it would rotate out useful autosaves
+    if !dirty.0 || connections.connection_graph.node_count() == 0 {
+        return;
+    }
+    let autosaves = list_autosaves();
+    let keep = persistent_hub_state.autosave_keep.max(1) - 1;
+    for path in autosaves.iter().take(autosaves.len().saturating_sub(keep)) {
+        std::fs::remove_file(path).ok();
+    }
+    if let Err(err) = std::fs::create_dir_all(AUTOSAVE_DIR) {
+        error!("Could not create autosave directory: {}", err);
+        return;
+    }
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    save_messages.write(SaveLayoutMessage {
+        path: PathBuf::from(AUTOSAVE_DIR).join(format!("layout_{}.json", timestamp)),
+        autosave: true,
+    });
+}
+
+fn autosave_restore_window(
+    mut egui_contexts: EguiContexts,
+    mut offer: ResMut<AutosaveOffer>,
+    mut load_messages: MessageWriter<LoadLayoutMessage>,
+) {
+    let Some(path) = offer.path.clone() else {
+        return;
+    };
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Restore autosave")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, (0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label("An autosave newer than the last saved layout exists:");
+                ui.label(path.display().to_string());
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        load_messages.write(LoadLayoutMessage { path: path.clone() });
+                        offer.path = None;
+                    }
+                    if ui.button("Discard").clicked() {
+                        offer.path = None;
+                    }
+                });
+            });
+    }
+}
+
+fn svg_color(color: Color) -> String {
+    color.to_srgba().to_hex()
+}
+
+// svg y points down, so the layout is mirrored vertically
+fn svg_point(pos: Vec2) -> String {
+    format!("{:.1},{:.1}", pos.x * LAYOUT_SCALE, -pos.y * LAYOUT_SCALE)
+}
+
+fn export_svg(
+    connections: Res<Connections>,
+    q_markers: Query<&Marker>,
+    q_blocks: Query<(&Block, &Name)>,
+    q_switches: Query<&Switch>,
+    portal_settings: Res<PortalSettings>,
+    mut export_messages: MessageReader<ExportSvgMessage>,
+) {
+    let ramp_length = portal_settings.ramp_length;
+    for message in export_messages.read() {
+        println!("Exporting svg to {:?}", message.path);
+        let cells = connections
+            .connection_graph
+            .nodes()
+            .map(|track| track.cell())
+            .collect::<Vec<_>>();
+        let min_x = cells.iter().map(|cell| cell.x).min().unwrap_or(0) - 1;
+        let max_x = cells.iter().map(|cell| cell.x).max().unwrap_or(0) + 1;
+        let min_y = cells.iter().map(|cell| cell.y).min().unwrap_or(0) - 1;
+        let max_y = cells.iter().map(|cell| cell.y).max().unwrap_or(0) + 1;
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+            min_x as f32 * LAYOUT_SCALE,
+            -(max_y + 1) as f32 * LAYOUT_SCALE,
+            (max_x - min_x + 1) as f32 * LAYOUT_SCALE,
+            (max_y - min_y + 1) as f32 * LAYOUT_SCALE,
+        );
+
+        let switches = q_switches
+            .iter()
+            .map(|switch| switch.id())
+            .collect::<Vec<_>>();
+        let mut connection_ids = connections
+            .connection_graph
+            .all_edges()
+            .map(|(_, _, c)| *c)
+            .collect::<Vec<_>>();
+        connection_ids.sort();
+        for connection_id in connection_ids {
+            let connection = connection_id.to_directed(ConnectionDirection::Aligned);
+            let color = if connection_id
+                .directed_connections()
+                .iter()
+                .any(|c| switches.contains(&c.from_track))
+            {
+                Color::from(ORANGE)
+            } else {
+                Color::BLACK
+            };
+            let length = connection.connection_length(ramp_length);
+            let num_points = (length * 10.0).ceil().max(1.0) as usize;
+            let points = (0..=num_points)
+                .map(|i| {
+                    svg_point(
+                        connection
+                            .interpolate_pos(length * i as f32 / num_points as f32, ramp_length),
+                    )
+                })
+                .collect::<Vec<_>>();
+            svg.push_str(&format!(
+                "<path d=\"M {}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>\n",
+                points.join(" L "),
+                svg_color(color),
+                TRACK_INNER_WIDTH,
+            ));
+        }
+
+        let mut blocks = q_blocks.iter().collect::<Vec<_>>();
+        blocks.sort_by_key(|(block, _)| block.id);
+        for (block, name) in blocks {
+            let section = block.section();
+            let center = section.interpolate_pos(section.length(ramp_length) * 0.5, ramp_length)
+                * LAYOUT_SCALE;
+            let width = name.len() as f32 * 7.0 + 8.0;
+            svg.push_str(&format!(
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"16\" fill=\"white\" stroke=\"{}\"/>\n",
+                center.x - width * 0.5,
+                -center.y - 8.0,
+                width,
+                svg_color(Color::from(BLUE)),
+            ));
+            svg.push_str(&format!(
+                "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"12\" text-anchor=\"middle\" dominant-baseline=\"middle\">{}</text>\n",
+                center.x,
+                -center.y,
+                name.as_str(),
+            ));
+        }
+
+        let mut markers = q_markers.iter().collect::<Vec<_>>();
+        markers.sort_by_key(|marker| marker.marker_id());
+        for marker in markers {
+            let position = marker.marker_id().get_vec2() * LAYOUT_SCALE;
+            svg.push_str(&format!(
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"{:.1}\" fill=\"{}\" stroke=\"black\"/>\n",
+                position.x,
+                -position.y,
+                0.05 * LAYOUT_SCALE,
+                svg_color(marker.color.get_display_color()),
+            ));
+        }
+
+        svg.push_str("</svg>\n");
+        std::fs::write(&message.path, svg).unwrap();
     }
 }
 
 #[derive(Message)]
 pub struct DespawnMessage<T: Selectable>(pub T::ID);
 
+#[derive(Resource, Default)]
+pub struct ErrorToast {
+    pub messages: Vec<String>,
+}
+
+impl ErrorToast {
+    pub fn push(&mut self, message: String) {
+        self.messages.push(message);
+    }
+}
+
+fn error_toast_window(mut egui_contexts: EguiContexts, mut error_toast: ResMut<ErrorToast>) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Error")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                for message in error_toast.messages.iter() {
+                    ui.colored_label(egui::Color32::LIGHT_RED, message);
+                    ui.separator();
+                }
+                if ui.button("Dismiss").clicked() {
+                    error_toast.messages.clear();
+                }
+            });
+    }
+}
+
 #[derive(Message)]
 pub struct LoadLayoutMessage {
-    path: PathBuf,
+    pub path: PathBuf,
 }
 
 #[derive(Message)]
 pub struct SaveLayoutMessage {
     path: PathBuf,
+    autosave: bool,
+}
+
+#[derive(Message)]
+pub struct ExportSvgMessage {
+    path: PathBuf,
 }
 
 #[derive(Message)]
 pub struct NewLayoutMessage {}
 
+fn read_layout(path: &PathBuf) -> Result<SerializableLayout, String> {
+    let mut file = std::fs::File::open(path).map_err(|err| err.to_string())?;
+    let mut json = String::new();
+    file.read_to_string(&mut json)
+        .map_err(|err| err.to_string())?;
+    let value = serde_json::from_str::<serde_json::Value>(&json).map_err(|err| err.to_string())?;
+    let version = value["version"].as_u64().unwrap_or(0) as u32;
+    let value = migrate(value, version)?;
+    serde_json::from_value::<SerializableLayout>(value).map_err(|err| err.to_string())
+}
+
 pub fn load_layout(
     world: &mut World,
-    params: &mut SystemState<(Commands, MessageReader<LoadLayoutMessage>)>,
+    params: &mut SystemState<(
+        Commands,
+        MessageReader<LoadLayoutMessage>,
+        ResMut<ErrorToast>,
+        ResMut<NextState<EditorState>>,
+    )>,
 ) {
+    // parse everything first, so a bad file leaves the current layout untouched
+    let layouts = {
+        let (_, mut load_messages, mut error_toast, mut next_state) = params.get_mut(world);
+        load_messages
+            .read()
+            .filter_map(|event| match read_layout(&event.path) {
+                Ok(layout_value) => Some(layout_value),
+                Err(err) => {
+                    println!("Failed to load layout {:?}: {}", event.path, err);
+                    error_toast.push(format!(
+                        "Failed to load layout {}:\n{}",
+                        event.path.display(),
+                        err
+                    ));
+                    next_state.set(EditorState::Edit);
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    if layouts.is_empty() {
+        return;
+    }
+    // hubs keep their ids across loads, so assignments don't move to other hubs
+    let existing_hubs = world
+        .query::<&BLEHub>()
+        .iter(world)
+        .map(|hub| (hub.id, hub.name.clone()))
+        .collect::<Vec<_>>();
     world.run_system_once(new_layout).unwrap();
     {
-        let (mut commands, mut load_messages) = params.get_mut(world);
-        for event in load_messages.read() {
+        let (mut commands, _, _, _) = params.get_mut(world);
+        for mut layout_value in layouts {
             commands.remove_resource::<Connections>();
             commands.remove_resource::<EntityMap>();
             commands.remove_resource::<MarkerMap>();
             commands.insert_resource(EntityMap::default());
             commands.insert_resource(Connections::default());
-            let mut file = std::fs::File::open(event.path.clone()).unwrap();
-            let mut json = String::new();
-            file.read_to_string(&mut json).unwrap();
-            let layout_value: SerializableLayout = serde_json::from_str(&json).unwrap();
+            layout_value.remap_hub_ids(&existing_hubs);
             let marker_map = layout_value.marker_map.clone();
             println!("Sending spawn messages");
             // commands.insert_resource(connections);
@@ -855,6 +2547,11 @@ pub fn load_layout(
                     world.write_message(serialized_switch_motor);
                 });
             }
+            for serialized_signal in layout_value.signals {
+                commands.queue(|world: &mut World| {
+                    world.write_message(serialized_signal);
+                });
+            }
             for destination in layout_value.destinations {
                 commands.queue(|world: &mut World| {
                     world.write_message(destination);
@@ -866,6 +2563,14 @@ pub fn load_layout(
                 });
             }
             commands.insert_resource(marker_map);
+            commands.insert_resource(layout_value.units);
+            commands.insert_resource(layout_value.train_defaults);
+            commands.insert_resource(layout_value.save_settings);
+            commands.insert_resource(layout_value.hub_defaults);
+            commands.insert_resource(layout_value.metadata);
+            commands.insert_resource(layout_value.portal_settings);
+            commands.insert_resource(layout_value.signal_aspects);
+            commands.insert_resource(PendingSelection(layout_value.selection));
         }
     }
     params.apply(world);
@@ -891,6 +2596,32 @@ fn new_layout(
     world.insert_resource(Connections::default());
     world.insert_resource(MarkerMap::default());
     world.insert_resource(TrackLocks::default());
+    world.insert_resource(LayoutUnits::default());
+    world.insert_resource(TrainDefaults::default());
+    world.insert_resource(SaveSettings::default());
+    world.insert_resource(HubDefaults::default());
+    world.insert_resource(LayoutMetadata::default());
+    world.insert_resource(PortalSettings::default());
+    world.insert_resource(SignalAspects::default());
+    world.insert_resource(PendingSelection::default());
+    // resetting the history isn't an edit
+    *world
+        .resource_mut::<EditHistory>()
+        .bypass_change_detection() = EditHistory::default();
+    world.insert_resource(LayoutDirty::default());
+}
+
+fn restore_selection(
+    mut pending: ResMut<PendingSelection>,
+    mut selection_state: ResMut<SelectionState>,
+    entity_map: Res<EntityMap>,
+) {
+    if let Some(id) = pending.0 {
+        if entity_map.get_entity(&id).is_some() {
+            selection_state.selection = Selection::Single(id);
+            pending.0 = None;
+        }
+    }
 }
 
 pub fn close_event(
@@ -930,20 +2661,58 @@ pub struct EditorPlugin;
 
 impl Plugin for EditorPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(PanCamPlugin);
         app.init_state::<EditorState>();
         app.add_computed_state::<ControlState>();
         app.add_sub_state::<ControlStateMode>();
         app.add_message::<LoadLayoutMessage>();
         app.add_message::<SaveLayoutMessage>();
         app.add_message::<NewLayoutMessage>();
+        app.add_message::<ExportSvgMessage>();
         app.insert_resource(HoverState::default());
         app.insert_resource(SelectionState::default());
+        app.insert_resource(PendingSelection::default());
+        app.insert_resource(EditHistory::default());
+        app.insert_resource(Clipboard::default());
+        app.insert_resource(CurrentLayer::default());
+        app.insert_resource(SaveSettings::default());
+        app.insert_resource(LayoutMetadata::default());
         app.insert_resource(InputData::default());
         app.insert_resource(EditorInfo::default());
+        app.insert_resource(ErrorToast::default());
+        app.insert_resource(ViewSettings::default());
         app.insert_resource(MousePosWorld::default());
-        app.add_systems(Startup, spawn_camera);
+        app.insert_resource(ControlPaused::default());
+        app.insert_resource(LayoutDirty::default());
+        app.add_systems(OnExit(ControlState), reset_pause);
         app.add_systems(OnExit(EditorState::Disconnecting), disconnect_finish);
+        app.add_systems(Last, mark_layout_dirty);
+        app.add_systems(
+            Update,
+            (
+                save_layout.run_if(on_message::<SaveLayoutMessage>),
+                export_svg.run_if(on_message::<ExportSvgMessage>),
+                autosave
+                    .run_if(not(in_state(EditorState::DeviceControl)))
+                    // a scripted run shouldn't rotate out the user's autosaves
+                    .run_if(not(resource_exists::<Headless>)),
+                load_layout.run_if(on_message::<LoadLayoutMessage>),
+                new_layout.run_if(on_message::<NewLayoutMessage>),
+                restore_selection.run_if(|pending: Res<PendingSelection>| pending.0.is_some()),
+            ),
+        );
+    }
+}
+
+// the camera, selection and shortcuts, and every panel and window
+pub struct EditorRenderPlugin;
+
+impl Plugin for EditorRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(PanCamPlugin);
+        app.insert_resource(CameraFocus::default());
+        app.insert_resource(MinimapGeometry::default());
+        app.insert_resource(Ruler::default());
+        app.add_systems(Startup, spawn_camera);
         app.add_systems(PreUpdate, update_world_mouse_pos);
         app.add_systems(
             Update,
@@ -956,10 +2725,16 @@ impl Plugin for EditorPlugin {
                     draw_selection,
                 )
                     .chain(),
-                save_layout.run_if(on_message::<SaveLayoutMessage>),
-                load_layout.run_if(on_message::<LoadLayoutMessage>),
-                new_layout.run_if(on_message::<NewLayoutMessage>),
                 close_event.run_if(on_message::<WindowCloseRequested>),
+                update_window_title.run_if(resource_changed::<LayoutMetadata>),
+                undo_redo_shortcut.run_if(in_state(EditorState::Edit)),
+                copy_paste_shortcut.run_if(in_state(EditorState::Edit)),
+                toggle_pause_shortcut.run_if(in_state(ControlState)),
+                (frame_shortcut, animate_camera_focus).chain(),
+                update_minimap_geometry
+                    .run_if(resource_changed::<Connections>.or(resource_changed::<PortalSettings>)),
+                (place_ruler_point.after(finish_hover), draw_ruler)
+                    .run_if(|view: Res<ViewSettings>| view.ruler),
             ),
         );
         app.add_systems(
@@ -972,6 +2747,29 @@ impl Plugin for EditorPlugin {
                 hub_status_window
                     .after(top_panel)
                     .run_if(in_state(EditorState::Disconnecting)),
+                hub_failure_window
+                    .after(top_panel)
+                    .run_if(in_state(EditorState::Edit)),
+                autosave_restore_window
+                    .after(top_panel)
+                    .run_if(in_state(EditorState::Edit))
+                    .run_if(|offer: Res<AutosaveOffer>| offer.path.is_some()),
+                error_toast_window
+                    .after(top_panel)
+                    .run_if(|error_toast: Res<ErrorToast>| !error_toast.messages.is_empty()),
+                reconnect_window
+                    .after(top_panel)
+                    .run_if(in_state(EditorState::Edit))
+                    .run_if(|offer: Res<ReconnectOffer>| !offer.hubs.is_empty()),
+                layout_properties_window
+                    .after(top_panel)
+                    .run_if(|view: Res<ViewSettings>| view.layout_properties),
+                minimap_window
+                    .after(top_panel)
+                    .run_if(|view: Res<ViewSettings>| view.minimap),
+                ruler_window
+                    .after(top_panel)
+                    .run_if(|view: Res<ViewSettings>| view.ruler),
             ),
         );
     }
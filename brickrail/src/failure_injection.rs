@@ -0,0 +1,294 @@
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::EguiPrimaryContextPass;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+
+use crate::{
+    ble::{
+        HubBusy, HubConfigured, HubConnected, HubDownloaded, HubError, HubPrepared, HubReady,
+        HubRunningProgram,
+    },
+    editor::{ControlState, GenericID, ViewSettings},
+    layout::EntityMap,
+    layout_primitives::{HubID, TrainID},
+    schedule::ControlInfo,
+    train::SimulatedSensors,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InjectedFailure {
+    DisconnectHub(HubID),
+    DownloadFailure(HubID),
+    MissMarker(TrainID),
+}
+
+impl std::fmt::Display for InjectedFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InjectedFailure::DisconnectHub(hub) => write!(f, "disconnect {}", hub),
+            InjectedFailure::DownloadFailure(hub) => write!(f, "download_fail {}", hub),
+            InjectedFailure::MissMarker(train) => write!(f, "miss_marker {}", train),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledFailure {
+    pub time: f32,
+    pub failure: InjectedFailure,
+}
+
+impl FromStr for ScheduledFailure {
+    type Err = String;
+
+    // parses lines like "30 disconnect Train0"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split_whitespace().collect::<Vec<_>>();
+        let [time, kind, target] = parts[..] else {
+            return Err(format!("expected '<time> <failure> <target>': {}", s));
+        };
+        let time = time
+            .parse::<f32>()
+            .map_err(|_| format!("invalid time: {}", time))?;
+        let failure = match kind {
+            "disconnect" => {
+                InjectedFailure::DisconnectHub(target.parse().map_err(|e| format!("{}", e))?)
+            }
+            "download_fail" => {
+                InjectedFailure::DownloadFailure(target.parse().map_err(|e| format!("{}", e))?)
+            }
+            "miss_marker" => {
+                let id = target
+                    .strip_prefix("Train")
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| format!("invalid train id: {}", target))?;
+                InjectedFailure::MissMarker(TrainID::new(id))
+            }
+            _ => return Err(format!("unknown failure: {}", kind)),
+        };
+        Ok(Self { time, failure })
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct FailureScript {
+    pub source: String,
+    pub pending: Vec<ScheduledFailure>,
+    pub fired: Vec<ScheduledFailure>,
+    pub errors: Vec<String>,
+}
+
+impl FailureScript {
+    pub fn load_source(&mut self) {
+        self.pending.clear();
+        self.fired.clear();
+        self.errors.clear();
+        for line in self.source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.parse::<ScheduledFailure>() {
+                Ok(failure) => self.pending.push(failure),
+                Err(err) => self.errors.push(err),
+            }
+        }
+        self.pending.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+}
+
+fn inject_failures(
+    mut script: ResMut<FailureScript>,
+    control_info: Res<ControlInfo>,
+    entity_map: Res<EntityMap>,
+    mut sensors: ResMut<SimulatedSensors>,
+    mut commands: Commands,
+) {
+    while script
+        .pending
+        .first()
+        .is_some_and(|failure| failure.time <= control_info.time)
+    {
+        let failure = script.pending.remove(0);
+        warn!(
+            "Injecting failure at {:1.1}s: {}",
+            control_info.time, failure.failure
+        );
+        match &failure.failure {
+            InjectedFailure::DisconnectHub(hub_id) => {
+                if let Some(entity) = entity_map.get_entity(&GenericID::Hub(*hub_id)) {
+                    commands
+                        .entity(entity)
+                        .remove::<(
+                            HubConnected,
+                            HubRunningProgram,
+                            HubConfigured,
+                            HubReady,
+                            HubPrepared,
+                            HubBusy,
+                        )>()
+                        .insert(HubError::ConnectError);
+                }
+            }
+            InjectedFailure::DownloadFailure(hub_id) => {
+                if let Some(entity) = entity_map.get_entity(&GenericID::Hub(*hub_id)) {
+                    commands
+                        .entity(entity)
+                        .remove::<(HubDownloaded, HubRunningProgram, HubPrepared, HubBusy)>()
+                        .insert(HubError::ProgramError);
+                }
+            }
+            InjectedFailure::MissMarker(train_id) => {
+                sensors.miss_next_marker(*train_id);
+            }
+        }
+        script.fired.push(failure);
+    }
+}
+
+fn failure_injection_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    mut script: ResMut<FailureScript>,
+    control_info: Res<ControlInfo>,
+) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Failure injection")
+            .open(&mut view_settings.failure_injection)
+            .default_width(300.0)
+            .show(ctx, |ui| {
+                ui.label("One failure per line: <time> <failure> <target>");
+                ui.label("Failures: disconnect <hub>, download_fail <hub>, miss_marker <train>");
+                ui.add(
+                    egui::TextEdit::multiline(&mut script.source)
+                        .hint_text("30 disconnect Train0")
+                        .desired_rows(4),
+                );
+                if ui.button("Load script").clicked() {
+                    script.load_source();
+                }
+                for err in script.errors.iter() {
+                    ui.colored_label(egui::Color32::LIGHT_RED, err);
+                }
+                ui.separator();
+                ui.label(format!("Control time: {:1.1}s", control_info.time));
+                for failure in script.fired.iter() {
+                    ui.label(format!("fired {:1.1}s {}", failure.time, failure.failure));
+                }
+                for failure in script.pending.iter() {
+                    ui.label(format!("pending {:1.1}s {}", failure.time, failure.failure));
+                }
+            });
+    }
+}
+
+pub struct FailureInjectionPlugin;
+
+impl Plugin for FailureInjectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(FailureScript::default());
+        app.add_systems(
+            Update,
+            inject_failures
+                .run_if(in_state(ControlState))
+                .run_if(|script: Res<FailureScript>| !script.pending.is_empty()),
+        );
+    }
+}
+
+pub struct FailureInjectionRenderPlugin;
+
+impl Plugin for FailureInjectionRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            EguiPrimaryContextPass,
+            failure_injection_window.run_if(|view: Res<ViewSettings>| view.failure_injection),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ble::{BLEHub, HubBatteryPolicy},
+        ble_train::BLETrain,
+        editor::ControlState,
+        layout_primitives::*,
+        train::{EmergencyStop, IsolatedTrain, Train, TrainDefaults, apply_isolation},
+    };
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[test]
+    fn test_parse_script() {
+        let mut script = FailureScript::default();
+        script.source =
+            "45 miss_marker Train2\n# comment\n30 disconnect Train0\nbogus\n".to_string();
+        script.load_source();
+        assert_eq!(
+            script.pending,
+            vec![
+                ScheduledFailure {
+                    time: 30.0,
+                    failure: InjectedFailure::DisconnectHub(HubID::new(0, HubType::Train)),
+                },
+                ScheduledFailure {
+                    time: 45.0,
+                    failure: InjectedFailure::MissMarker(TrainID::new(2)),
+                },
+            ]
+        );
+        assert_eq!(script.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_disconnect_stops_train() {
+        let mut world = World::new();
+        world.insert_resource(ControlInfo::default());
+        world.insert_resource(SimulatedSensors::default());
+        world.insert_resource(IsolatedTrain::default());
+        world.insert_resource(HubBatteryPolicy::default());
+        world.insert_resource(EmergencyStop::default());
+        world.insert_resource(State::new(ControlState));
+        let mut script = FailureScript::default();
+        script.source = "0 disconnect Train0".to_string();
+        script.load_source();
+        world.insert_resource(script);
+
+        let hub_id = HubID::new(0, HubType::Train);
+        let train_id = TrainID::new(0);
+        let track = TrackID::new(CellID::new(0, 0, 0), Orientation::EW);
+        let block_id = BlockID::new(
+            track.get_directed(TrackDirection::First),
+            track.get_directed(TrackDirection::Last),
+        );
+        let train = Train::at_block_id(
+            train_id,
+            block_id.to_logical(BlockDirection::Aligned, Facing::Forward),
+            &TrainDefaults::default(),
+        );
+        let mut ble_train = BLETrain::new(train_id);
+        ble_train.master_hub.hub_id = Some(hub_id);
+        let mut entity_map = EntityMap::default();
+        let hub_entity = world.spawn((BLEHub::new(hub_id), HubConnected)).id();
+        entity_map.add_hub(hub_id, hub_entity);
+        let train_entity = world.spawn((train, ble_train)).id();
+        entity_map.add_train(train_id, train_entity);
+        world.insert_resource(entity_map);
+
+        world.run_system_once(apply_isolation).unwrap();
+        assert!(!world.get::<Train>(train_entity).unwrap().is_hub_lost());
+
+        world.run_system_once(inject_failures).unwrap();
+        assert!(world.get::<HubError>(hub_entity).is_some());
+        world.run_system_once(apply_isolation).unwrap();
+        let train = world.get::<Train>(train_entity).unwrap();
+        assert!(train.is_hub_lost());
+        assert_eq!(train.get_speed(), 0.0);
+
+        world.entity_mut(hub_entity).remove::<HubError>();
+        world.run_system_once(apply_isolation).unwrap();
+        assert!(!world.get::<Train>(train_entity).unwrap().is_hub_lost());
+    }
+}
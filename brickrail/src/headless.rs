@@ -0,0 +1,251 @@
+use std::{path::PathBuf, time::Duration};
+
+use bevy::{
+    app::PluginsState, platform::collections::HashMap, prelude::*, state::app::StatesPlugin,
+    time::TimeUpdateStrategy,
+};
+
+use crate::{
+    destination::DestinationArrivalMessage,
+    editor::{ControlStateMode, EditorState, LoadLayoutMessage},
+    layout_primitives::{ScheduleID, TrainID},
+    persistent_hub_state::PersistentHubState,
+    schedule::AssignedSchedule,
+    train::{Deadlocks, Train},
+};
+
+// every update advances the simulation by the same step, independent of how fast it runs
+const SIM_TICK: Duration = Duration::from_micros(16_667);
+// ten simulated minutes
+const DEFAULT_TICKS: u32 = 36_000;
+// updates to let the spawn messages of a loaded layout settle
+const LOAD_TICKS: u32 = 10;
+
+/// Present while running without a window.
+#[derive(Resource, Debug)]
+pub struct Headless;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadlessArgs {
+    pub layout: PathBuf,
+    // assigned to every train, otherwise the schedules saved with the layout are used
+    pub schedule: Option<ScheduleID>,
+    pub ticks: u32,
+}
+
+impl HeadlessArgs {
+    // `--headless <layout> [--schedule <id>] [--ticks <n>]`, None without `--headless`
+    pub fn parse(args: &[String]) -> Option<Result<Self, String>> {
+        let index = args.iter().position(|arg| arg == "--headless")?;
+        let value_of = |flag: &str| {
+            args.iter()
+                .position(|arg| arg == flag)
+                .map(|i| args.get(i + 1).ok_or(format!("{} needs a value", flag)))
+        };
+        let parse = || {
+            let layout = args
+                .get(index + 1)
+                .filter(|arg| !arg.starts_with("--"))
+                .ok_or("--headless needs a layout path")?;
+            let schedule = match value_of("--schedule") {
+                Some(value) => Some(ScheduleID::new(
+                    value?
+                        .parse()
+                        .map_err(|_| "--schedule needs a schedule number")?,
+                )),
+                None => None,
+            };
+            let ticks = match value_of("--ticks") {
+                Some(value) => value?
+                    .parse()
+                    .map_err(|_| "--ticks needs a number of updates")?,
+                None => DEFAULT_TICKS,
+            };
+            Ok(HeadlessArgs {
+                layout: PathBuf::from(layout),
+                schedule,
+                ticks,
+            })
+        };
+        Some(parse())
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+struct TrainStats {
+    arrivals: HashMap<TrainID, usize>,
+}
+
+fn count_arrivals(
+    mut arrival_messages: MessageReader<DestinationArrivalMessage>,
+    mut stats: ResMut<TrainStats>,
+) {
+    for arrival in arrival_messages.read() {
+        *stats.arrivals.entry(arrival.train_id).or_default() += 1;
+    }
+}
+
+fn print_stats(world: &mut World, args: &HeadlessArgs) {
+    let simulated = SIM_TICK.as_secs_f32() * args.ticks as f32;
+    println!(
+        "Simulated {:.0}s of {} in {} updates",
+        simulated,
+        args.layout.display(),
+        args.ticks
+    );
+    let stats = world.remove_resource::<TrainStats>().unwrap_or_default();
+    let deadlocks = world.resource::<Deadlocks>().cycles.clone();
+    let mut trains = world
+        .query::<(&Train, Option<&AssignedSchedule>)>()
+        .iter(world)
+        .map(|(train, schedule)| (train.id, schedule.cloned()))
+        .collect::<Vec<_>>();
+    trains.sort_by_key(|(id, _)| *id);
+    for (id, schedule) in trains {
+        let mut line = format!(
+            "{}: {} arrivals",
+            id,
+            stats.arrivals.get(&id).copied().unwrap_or(0)
+        );
+        if let Some(schedule) = schedule.filter(|schedule| schedule.schedule_id.is_some()) {
+            line += &format!(", at stop {}", schedule.current_stop_index);
+            if schedule.finished {
+                line += ", schedule finished";
+            }
+        }
+        if deadlocks.iter().any(|cycle| cycle.contains(&id)) {
+            line += ", deadlocked";
+        }
+        println!("{}", line);
+    }
+    for cycle in deadlocks {
+        println!("Deadlock: {:?}", cycle);
+    }
+}
+
+fn build_app(add_plugins: impl FnOnce(&mut App)) -> App {
+    let mut app = App::new();
+    // no window, renderer, egui or input, the render plugins are only added with a window
+    app.add_plugins((MinimalPlugins, StatesPlugin));
+    // a scripted run shouldn't touch the hub state of the editor
+    app.insert_resource(PersistentHubState::default());
+    add_plugins(&mut app);
+    app.insert_resource(Headless);
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(SIM_TICK));
+    app.insert_resource(TrainStats::default());
+    app.add_systems(
+        Update,
+        count_arrivals.run_if(on_message::<DestinationArrivalMessage>),
+    );
+
+    while app.plugins_state() == PluginsState::Adding {
+        bevy::tasks::tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+    app
+}
+
+fn load_layout(app: &mut App, path: &PathBuf) {
+    app.world_mut()
+        .write_message(LoadLayoutMessage { path: path.clone() });
+    for _ in 0..LOAD_TICKS {
+        app.update();
+    }
+}
+
+fn start_control(app: &mut App, mode: ControlStateMode) {
+    app.world_mut()
+        .resource_mut::<NextState<EditorState>>()
+        .set(EditorState::VirtualControl);
+    app.update();
+    // only exists once the control state was entered
+    app.world_mut()
+        .resource_mut::<NextState<ControlStateMode>>()
+        .set(mode);
+}
+
+pub fn run(args: HeadlessArgs, add_plugins: impl FnOnce(&mut App)) {
+    let mut app = build_app(add_plugins);
+    load_layout(&mut app, &args.layout);
+    if let Some(schedule_id) = args.schedule {
+        let mut query = app.world_mut().query::<&mut AssignedSchedule>();
+        for mut schedule in query.iter_mut(app.world_mut()) {
+            *schedule = AssignedSchedule {
+                schedule_id: Some(schedule_id),
+                ..Default::default()
+            };
+        }
+    }
+
+    start_control(&mut app, ControlStateMode::Schedule);
+    for _ in 0..args.ticks {
+        app.update();
+    }
+
+    print_stats(app.world_mut(), &args);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{block::Block, schedule::ControlInfo};
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_headless_args() {
+        assert_eq!(
+            HeadlessArgs::parse(&args("brickrail --simulate-hubs")),
+            None
+        );
+        assert_eq!(
+            HeadlessArgs::parse(&args("brickrail --headless layout.json --ticks 60")),
+            Some(Ok(HeadlessArgs {
+                layout: PathBuf::from("layout.json"),
+                schedule: None,
+                ticks: 60,
+            }))
+        );
+        let parsed = HeadlessArgs::parse(&args("brickrail --headless a.json --schedule 2"));
+        assert_eq!(parsed.unwrap().unwrap().schedule, Some(ScheduleID::new(2)));
+        assert!(
+            HeadlessArgs::parse(&args("brickrail --headless --ticks 60"))
+                .unwrap()
+                .is_err()
+        );
+        assert!(
+            HeadlessArgs::parse(&args("brickrail --headless a.json --ticks"))
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_run_layout_headless() {
+        let mut app = build_app(crate::add_layout_plugins);
+        let layout =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../test-layouts/loop-test.json");
+        load_layout(&mut app, &layout);
+        let world = app.world_mut();
+        assert_eq!(world.query::<&Block>().iter(world).count(), 3);
+        assert_eq!(world.query::<&Train>().iter(world).count(), 1);
+
+        start_control(&mut app, ControlStateMode::Random);
+        let ticks = 600;
+        for _ in 0..ticks {
+            app.update();
+        }
+        // the control clock follows the fixed simulation step
+        let control_time = app.world().resource::<ControlInfo>().time;
+        let expected = SIM_TICK.as_secs_f32() * ticks as f32;
+        assert!(
+            (control_time - expected).abs() <= 2.0 * SIM_TICK.as_secs_f32(),
+            "{} != {}",
+            control_time,
+            expected
+        );
+    }
+}
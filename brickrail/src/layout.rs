@@ -1,21 +1,64 @@
 use std::panic;
 
 use crate::crossing::{LevelCrossing, SetCrossingPositionMessage};
-use crate::editor::GenericID;
+use crate::editor::{GenericID, ViewSettings};
 use crate::layout_primitives::*;
 use crate::marker::MarkerKey;
+use crate::routing::{self, RouteConstraints, RoutePreferences};
 use crate::section::LogicalSection;
 use crate::switch::{SetSwitchPositionMessage, Switch};
 use crate::switch_motor::MotorPosition;
-use crate::track::{LAYOUT_SCALE, TrackLogicalFilter};
+use crate::track::{LAYOUT_SCALE, PortalSettings, TrackLogicalFilter};
 use bevy::color::palettes::css::{GOLD, GREEN, ORANGE};
 use bevy::ecs::query::{QueryData, QueryFilter};
-use bevy::platform::collections::HashMap;
 use bevy::platform::collections::hash_map::OccupiedError;
+use bevy::platform::collections::{HashMap, HashSet};
 use bevy::prelude::*;
 use petgraph::graphmap::{DiGraphMap, UnGraphMap};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json_any_key::any_key_map;
+use strum::IntoEnumIterator;
+
+// lengths are divided by the cell length, so it has to stay positive
+pub const MIN_CELL_LENGTH: f32 = 0.001;
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayoutUnits {
+    #[serde(deserialize_with = "deserialize_cell_length")]
+    pub cell_length: f32,
+    pub unit_name: String,
+}
+
+impl Default for LayoutUnits {
+    fn default() -> Self {
+        Self {
+            cell_length: 1.0,
+            unit_name: "cells".to_string(),
+        }
+    }
+}
+
+fn deserialize_cell_length<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    Ok(f32::deserialize(deserializer)?.max(MIN_CELL_LENGTH))
+}
+
+impl LayoutUnits {
+    pub fn to_units(&self, cells: f32) -> f32 {
+        cells * self.cell_length
+    }
+
+    pub fn format_length(&self, cells: f32) -> String {
+        format!("{:1.2} {}", self.to_units(cells), self.unit_name)
+    }
+
+    pub fn format_speed(&self, cells_per_second: f32) -> String {
+        format!(
+            "{:1.2} {}/s",
+            self.to_units(cells_per_second),
+            self.unit_name
+        )
+    }
+}
 
 #[derive(Resource, Default, Clone, PartialEq, Eq)]
 pub struct TrackLocks {
@@ -69,7 +112,7 @@ impl TrackLocks {
             .and_then(|e| switches.get(*e).ok())
         {
             let position = directed_connection.to_track.get_switch_position();
-            for (id_option, pos) in switch.iter_motor_positions(&position) {
+            for (id_option, pos) in switch.shared_motor_positions(&position, switches, entity_map) {
                 if let Some(id) = id_option {
                     if let Some((_, locked_pos)) = self.locked_switch_motors.get(id) {
                         if locked_pos != &pos {
@@ -82,6 +125,47 @@ impl TrackLocks {
         return true;
     }
 
+    // trains whose locks keep train from locking section
+    pub fn blocking_trains(
+        &self,
+        train: &TrainID,
+        section: &LogicalSection,
+        switches: &Query<&Switch>,
+        entity_map: &EntityMap,
+    ) -> HashSet<TrainID> {
+        let mut blocking = HashSet::new();
+        for track in section.tracks.iter() {
+            for colliding_track in track.track().colliding_tracks() {
+                if let Some(locked_train) = self.locked_tracks.get(&colliding_track) {
+                    if locked_train != train {
+                        blocking.insert(*locked_train);
+                    }
+                }
+            }
+        }
+        for connection in section.connection_iter() {
+            let directed_connection = connection.to_directed();
+            let Some(switch) = entity_map
+                .switches
+                .get(&directed_connection.from_track)
+                .and_then(|e| switches.get(*e).ok())
+            else {
+                continue;
+            };
+            let position = directed_connection.to_track.get_switch_position();
+            for (id_option, pos) in switch.shared_motor_positions(&position, switches, entity_map) {
+                if let Some((locked_train, locked_pos)) =
+                    id_option.and_then(|id| self.locked_switch_motors.get(&id))
+                {
+                    if locked_pos != &pos && locked_train != train {
+                        blocking.insert(*locked_train);
+                    }
+                }
+            }
+        }
+        blocking
+    }
+
     pub fn lock(
         &mut self,
         train: &TrainID,
@@ -105,7 +189,9 @@ impl TrackLocks {
             if let Some(entity) = entity_map.switches.get(&directed_connection.from_track) {
                 let position = directed_connection.to_track.get_switch_position();
                 let switch = switches.get(*entity).unwrap();
-                for (id_option, pos) in switch.iter_motor_positions(&position) {
+                for (id_option, pos) in
+                    switch.shared_motor_positions(&position, switches, entity_map)
+                {
                     if let Some(id) = id_option {
                         match self
                             .locked_switch_motors
@@ -133,6 +219,32 @@ impl TrackLocks {
         }
     }
 
+    // locks the whole section if possible, leaves the locks untouched otherwise
+    pub fn try_lock(
+        &mut self,
+        train: &TrainID,
+        section: &LogicalSection,
+        entity_map: &EntityMap,
+        switches: &Query<&Switch>,
+        crossings: &Query<&LevelCrossing>,
+        set_switch_position: &mut MessageWriter<SetSwitchPositionMessage>,
+        set_crossing_position: &mut MessageWriter<SetCrossingPositionMessage>,
+    ) -> bool {
+        if !self.can_lock(train, section, switches, entity_map) {
+            return false;
+        }
+        self.lock(
+            train,
+            section,
+            entity_map,
+            switches,
+            crossings,
+            set_switch_position,
+            set_crossing_position,
+        );
+        true
+    }
+
     pub fn unlock_all(&mut self, train: &TrainID) {
         self.locked_tracks
             .retain(|_, locked_train| locked_train != train);
@@ -141,6 +253,20 @@ impl TrackLocks {
     }
 }
 
+#[derive(Resource, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalAspects {
+    #[serde(with = "any_key_map")]
+    pub aspects: HashMap<BlockID, SignalAspect>,
+}
+
+impl SignalAspects {
+    pub fn is_stop(&self, block: &BlockID) -> bool {
+        self.aspects
+            .get(block)
+            .is_some_and(|aspect| aspect.is_stop())
+    }
+}
+
 #[derive(Resource, Default)]
 pub struct EntityMap {
     pub tracks: HashMap<TrackID, Entity>,
@@ -148,7 +274,7 @@ pub struct EntityMap {
     pub connections_inner: HashMap<DirectedTrackConnectionID, Entity>,
     pub connections_path: HashMap<DirectedTrackConnectionID, Entity>,
     pub switches: HashMap<DirectedTrackID, Entity>,
-    pub markers: HashMap<TrackID, Entity>,
+    pub markers: HashMap<MarkerID, Entity>,
     pub blocks: HashMap<BlockID, Entity>,
     pub trains: HashMap<TrainID, Entity>,
     pub wagons: HashMap<WagonID, Entity>,
@@ -184,7 +310,7 @@ impl EntityMap {
             GenericID::Switch(switch_id) => self.switches.get(switch_id).copied(),
             GenericID::Block(block_id) => self.blocks.get(block_id).copied(),
             GenericID::Train(train_id) => self.trains.get(train_id).copied(),
-            GenericID::Marker(track_id) => self.markers.get(track_id).copied(),
+            GenericID::Marker(marker_id) => self.markers.get(marker_id).copied(),
             GenericID::Hub(hub_id) => self.hubs.get(hub_id).copied(),
             GenericID::Destination(dest_id) => self.destinations.get(dest_id).copied(),
             GenericID::Schedule(schedule_id) => self.schedules.get(schedule_id).copied(),
@@ -234,9 +360,22 @@ impl EntityMap {
         self.wagons.try_insert(wagon, entity).unwrap();
     }
 
-    pub fn add_marker(&mut self, track: TrackID, entity: Entity) {
-        // println!("Adding marker {:?} to {:?}", track, entity);
-        self.markers.try_insert(track, entity).unwrap();
+    pub fn add_marker(&mut self, marker: MarkerID, entity: Entity) {
+        // println!("Adding marker {:?} to {:?}", marker, entity);
+        self.markers.try_insert(marker, entity).unwrap();
+    }
+
+    pub fn markers_on_track(&self, track: TrackID) -> Vec<(MarkerID, Entity)> {
+        MarkerSlot::iter()
+            .map(|slot| MarkerID::new(track, slot))
+            .filter_map(|id| Some((id, *self.markers.get(&id)?)))
+            .collect()
+    }
+
+    pub fn free_marker_slot(&self, track: TrackID) -> Option<MarkerSlot> {
+        [MarkerSlot::Center, MarkerSlot::Start, MarkerSlot::End]
+            .into_iter()
+            .find(|slot| !self.markers.contains_key(&MarkerID::new(track, *slot)))
     }
 
     pub fn add_hub(&mut self, hub: HubID, entity: Entity) {
@@ -265,8 +404,8 @@ impl EntityMap {
         self.connections_path.remove(&connection);
     }
 
-    pub fn remove_marker(&mut self, track: TrackID) {
-        self.markers.remove(&track);
+    pub fn remove_marker(&mut self, marker: MarkerID) {
+        self.markers.remove(&marker);
     }
 
     pub fn remove_block(&mut self, block: BlockID) {
@@ -363,18 +502,29 @@ pub struct MarkerMap {
 }
 
 impl MarkerMap {
-    pub fn get_marker_key(
+    // keys for the markers on a track in travel order, the last markers carry the block roles
+    pub fn get_marker_keys(
         &self,
         logical_track: &LogicalTrackID,
         target_block: &LogicalBlockID,
-    ) -> MarkerKey {
+        num_markers: usize,
+    ) -> Vec<MarkerKey> {
+        let mut roles = vec![];
+        if self.enter_markers.get(logical_track) == Some(target_block) {
+            roles.push(MarkerKey::Enter);
+        }
         if self.in_markers.get(logical_track) == Some(target_block) {
-            MarkerKey::In
-        } else if self.enter_markers.get(logical_track) == Some(target_block) {
-            MarkerKey::Enter
-        } else {
-            MarkerKey::None
+            roles.push(MarkerKey::In);
+        }
+        let mut keys = vec![MarkerKey::None; num_markers];
+        for (key, role) in keys.iter_mut().rev().zip(roles.into_iter().rev()) {
+            *key = role;
         }
+        keys
+    }
+
+    pub fn has_in_marker(&self, logical_block: &LogicalBlockID) -> bool {
+        self.in_markers.values().any(|b| b == logical_block)
     }
 
     pub fn register_marker(
@@ -450,6 +600,7 @@ impl<'a> Iterator for ConnectionIterator<'a> {
 pub struct Connections {
     pub logical_graph: DiGraphMap<LogicalTrackID, ()>,
     pub connection_graph: UnGraphMap<TrackID, TrackConnectionID>,
+    pub blocked_tracks: HashSet<LogicalTrackID>,
 }
 
 impl Connections {
@@ -546,6 +697,29 @@ impl Connections {
             .map(|logical_connection| logical_connection.to_directed().to_connection())
     }
 
+    // shortest drivable distance between the centers of two tracks, ignoring reversals
+    pub fn track_distance(&self, from: TrackID, to: TrackID, ramp_length: f32) -> Option<f32> {
+        if from == to {
+            return Some(0.0);
+        }
+        from.logical_tracks()
+            .into_iter()
+            .filter(|logical| self.logical_graph.contains_node(*logical))
+            .flat_map(|start| {
+                petgraph::algo::dijkstra(&self.logical_graph, start, None, |(a, b, _)| {
+                    if a.track() == b.track() {
+                        return f32::INFINITY;
+                    }
+                    DirectedTrackConnectionID::new(a.dirtrack, b.dirtrack)
+                        .connection_length(ramp_length)
+                })
+            })
+            .filter(|(logical, _)| logical.track() == to)
+            .map(|(_, cost)| cost)
+            .filter(|cost| cost.is_finite())
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
     pub fn iter_next_tracks(
         &self,
         track: LogicalTrackID,
@@ -620,6 +794,28 @@ impl Connections {
         }
     }
 
+    pub fn disconnect_tracks_simple(&mut self, connection: &TrackConnectionID) {
+        self.connection_graph
+            .remove_edge(connection.track_a().track, connection.track_b().track);
+        for logical in connection.logical_connections() {
+            self.logical_graph
+                .remove_edge(logical.from_track, logical.to_track);
+        }
+    }
+
+    pub fn iter_portals(&self) -> impl Iterator<Item = TrackConnectionID> + '_ {
+        self.connection_graph
+            .all_edges()
+            .map(|(_, _, connection)| *connection)
+            .filter(|connection| !connection.is_continuous())
+    }
+
+    pub fn iter_unconnected_dirtracks(&self) -> impl Iterator<Item = DirectedTrackID> + '_ {
+        self.connection_graph
+            .nodes()
+            .filter_map(|track| self.get_unconnected_dirtrack(track))
+    }
+
     pub fn connect_tracks(&mut self, track_a: &LogicalTrackID, track_b: &LogicalTrackID) {
         assert!(
             self.logical_graph.contains_node(track_a.clone())
@@ -652,29 +848,27 @@ impl Connections {
         connections
     }
 
+    // tracks that branch into more than one connection, like the ones switches are spawned on
+    pub fn is_switch(&self, dirtrack: DirectedTrackID) -> bool {
+        self.get_directed_connections_from(dirtrack).len() > 1
+    }
+
     pub fn dijkstra(
         &self,
         start: LogicalBlockID,
         targets: &[LogicalBlockID],
         avoid_locked: Option<(&TrainID, &TrackLocks, &Query<&Switch>, &EntityMap)>,
-        prefer_facing: Option<Facing>,
+        prefer_facing: FacingPreference,
+        preferences: RoutePreferences,
     ) -> HashMap<LogicalBlockID, f32> {
-        let start_node = start.default_in_marker_track();
-        let result =
-            petgraph::algo::dijkstra(&self.logical_graph, start_node, None, |(a, b, _)| {
-                edge_cost(a, b, avoid_locked, prefer_facing)
-            });
-        let target_nodes = targets
-            .iter()
-            .map(|target| (target.default_in_marker_track(), target))
-            .collect::<HashMap<_, _>>();
-        let mut filtered_result = HashMap::new();
-        for (track, cost) in result.iter() {
-            if let Some(block) = target_nodes.get(track) {
-                filtered_result.insert(**block, *cost);
-            }
+        let can_pass = avoid_locked.map(lock_filter);
+        let is_switch = |dirtrack| self.is_switch(dirtrack);
+        let mut constraints = RouteConstraints::new(prefer_facing, &self.blocked_tracks)
+            .with_preferences(preferences, &is_switch);
+        if let Some(can_pass) = &can_pass {
+            constraints = constraints.with_can_pass(can_pass);
         }
-        filtered_result
+        routing::dijkstra(&self.logical_graph, start, targets, &constraints)
     }
 
     pub fn find_route_section(
@@ -682,54 +876,85 @@ impl Connections {
         start: LogicalBlockID,
         target: LogicalBlockID,
         avoid_locked: Option<(&TrainID, &TrackLocks, &Query<&Switch>, &EntityMap)>,
-        prefer_facing: Option<Facing>,
+        prefer_facing: FacingPreference,
+        preferences: RoutePreferences,
+        avoided_tracks: &HashSet<TrackID>,
     ) -> Option<LogicalSection> {
-        let start_track = start.default_in_marker_track();
-        let target_track = target.default_in_marker_track();
-        match petgraph::algo::astar(
-            &self.logical_graph,
-            start_track,
-            |track| track == target_track,
-            |(a, b, _)| edge_cost(a, b, avoid_locked, prefer_facing),
-            |track| {
-                let delta = track.cell().get_delta_vec(&target_track.cell());
-                delta.x.abs() + delta.y.abs()
-            },
-        ) {
-            Some((_, path)) => Some(LogicalSection { tracks: path }),
-            None => None,
+        let can_pass = avoid_locked.map(lock_filter);
+        let is_switch = |dirtrack| self.is_switch(dirtrack);
+        let mut constraints = RouteConstraints::new(prefer_facing, &self.blocked_tracks)
+            .with_preferences(preferences, &is_switch)
+            .with_avoided_tracks(avoided_tracks);
+        if let Some(can_pass) = &can_pass {
+            constraints = constraints.with_can_pass(can_pass);
         }
+        routing::find_route_section(&self.logical_graph, start, target, &constraints)
+    }
+
+    // chains routes through the waypoints in order
+    pub fn find_route_via(
+        &self,
+        start: LogicalBlockID,
+        waypoints: &[LogicalBlockID],
+        target: LogicalBlockID,
+        avoid_locked: Option<(&TrainID, &TrackLocks, &Query<&Switch>, &EntityMap)>,
+        prefer_facing: FacingPreference,
+        preferences: RoutePreferences,
+        avoided_tracks: &HashSet<TrackID>,
+    ) -> Option<LogicalSection> {
+        let mut section = LogicalSection::new();
+        let mut from = start;
+        for to in waypoints.iter().chain([&target]) {
+            let leg = self.find_route_section(
+                from,
+                *to,
+                avoid_locked,
+                prefer_facing,
+                preferences,
+                avoided_tracks,
+            )?;
+            match section.tracks.last() {
+                None => section = leg,
+                Some(last) => {
+                    if leg.tracks.first() != Some(last) {
+                        warn!("Route via {:?} is not continuous at {:?}", to, last);
+                        return None;
+                    }
+                    section.tracks.extend(leg.tracks.into_iter().skip(1));
+                }
+            }
+            from = *to;
+        }
+        Some(section)
     }
 }
 
-fn edge_cost(
-    a: LogicalTrackID,
-    b: LogicalTrackID,
-    avoid_locked: Option<(&TrainID, &TrackLocks, &Query<&Switch>, &EntityMap)>,
-    prefer_facing: Option<Facing>,
-) -> f32 {
-    let mut cost = 1.0;
-    if let Some((train, locks, switches, entity_map)) = avoid_locked {
-        if !locks.can_lock_track(train, &b.track())
-            || !locks.can_lock_connection(
+fn lock_filter<'a, 'w, 's, 'd>(
+    (train, locks, switches, entity_map): (
+        &'a TrainID,
+        &'a TrackLocks,
+        &'a Query<'w, 's, &'d Switch>,
+        &'a EntityMap,
+    ),
+) -> impl Fn(LogicalTrackID, LogicalTrackID) -> bool + 'a {
+    move |a, b| {
+        locks.can_lock_track(train, &b.track())
+            && locks.can_lock_connection(
                 train,
                 &LogicalTrackConnectionID::new(a, b),
                 switches,
                 entity_map,
             )
-        {
-            cost += f32::INFINITY;
-        }
     }
-    if let Some(facing) = prefer_facing {
-        if b.facing != facing {
-            cost += 10000.0;
-        }
-    }
-    cost
 }
 
-fn draw_layout_graph(mut gizmos: Gizmos, connections: Res<Connections>, time: Res<Time>) {
+fn draw_layout_graph(
+    mut gizmos: Gizmos,
+    connections: Res<Connections>,
+    portal_settings: Res<PortalSettings>,
+    time: Res<Time>,
+) {
+    let ramp_length = portal_settings.ramp_length;
     let dist = time.elapsed_secs() % 1.0;
     for track in connections.logical_graph.nodes() {
         track
@@ -744,9 +969,12 @@ fn draw_layout_graph(mut gizmos: Gizmos, connections: Res<Connections>, time: Re
         }
         .to_directed();
         connection.draw_with_gizmos(&mut gizmos, LAYOUT_SCALE, Color::from(GOLD));
-        let pos = connection.interpolate_pos(dist * connection.connection_length());
+        let pos = connection.interpolate_pos(
+            dist * connection.connection_length(ramp_length),
+            ramp_length,
+        );
         gizmos.circle_2d(pos * LAYOUT_SCALE, 0.05 * LAYOUT_SCALE, Color::from(GREEN));
-        let pos_unnormalized = connection.interpolate_pos(dist);
+        let pos_unnormalized = connection.interpolate_pos(dist, ramp_length);
         gizmos.circle_2d(
             pos_unnormalized * LAYOUT_SCALE,
             0.05 * LAYOUT_SCALE,
@@ -755,14 +983,469 @@ fn draw_layout_graph(mut gizmos: Gizmos, connections: Res<Connections>, time: Re
     }
 }
 
+fn draw_direction_arrows(mut gizmos: Gizmos, connections: Res<Connections>) {
+    let mut drawn = HashSet::new();
+    for (from_track, to_track, _) in connections.logical_graph.all_edges() {
+        let connection = LogicalTrackConnectionID {
+            from_track,
+            to_track,
+        }
+        .to_directed();
+        if drawn.insert(connection) {
+            connection.draw_arrow_with_gizmos(&mut gizmos, LAYOUT_SCALE, Color::from(GOLD));
+        }
+    }
+}
+
 pub struct LayoutPlugin;
 
 impl Plugin for LayoutPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(EntityMap::default());
         app.insert_resource(TrackLocks::default());
+        app.insert_resource(SignalAspects::default());
         app.insert_resource(Connections::default());
         app.insert_resource(MarkerMap::default());
+        app.insert_resource(LayoutUnits::default());
+    }
+}
+
+pub struct LayoutRenderPlugin;
+
+impl Plugin for LayoutRenderPlugin {
+    fn build(&self, app: &mut App) {
         // app.add_systems(Update, draw_layout_graph);
+        app.add_systems(
+            Update,
+            draw_direction_arrows.run_if(|view: Res<ViewSettings>| view.direction_arrows),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::ecs::system::SystemState;
+
+    fn line(cells: impl Iterator<Item = i32>, direction: TrackDirection) -> LogicalSection {
+        LogicalSection {
+            tracks: cells
+                .map(|x| {
+                    TrackID::new(CellID::new(x, 0, 0), Orientation::EW)
+                        .get_directed(direction)
+                        .get_logical(Facing::Forward)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_cell_length_stays_positive() {
+        let units: LayoutUnits =
+            serde_json::from_str(r#"{"cell_length": 0.0, "unit_name": "m"}"#).unwrap();
+        assert_eq!(units.cell_length, MIN_CELL_LENGTH);
+        let units: LayoutUnits =
+            serde_json::from_str(r#"{"cell_length": 0.25, "unit_name": "m"}"#).unwrap();
+        assert_eq!(units.cell_length, 0.25);
+    }
+
+    #[test]
+    fn test_track_distance_along_line() {
+        let mut connections = Connections::default();
+        let tracks = (0..5)
+            .map(|x| TrackID::new(CellID::new(x, 0, 0), Orientation::EW))
+            .collect::<Vec<_>>();
+        for track in tracks.iter() {
+            connections.add_filtered_track(*track, &TrackLogicalFilter::default());
+        }
+        for pair in tracks.windows(2) {
+            connections.connect_tracks_simple(&TrackConnectionID::new(
+                pair[0].get_directed(TrackDirection::First),
+                pair[1].get_directed(TrackDirection::First),
+            ));
+        }
+        assert_eq!(
+            connections.track_distance(tracks[0], tracks[0], RAMP_LENGTH),
+            Some(0.0)
+        );
+        let distance = connections
+            .track_distance(tracks[0], tracks[3], RAMP_LENGTH)
+            .unwrap();
+        assert!((distance - 3.0).abs() < 1e-4);
+        assert_eq!(
+            connections.track_distance(tracks[4], tracks[1], RAMP_LENGTH),
+            connections.track_distance(tracks[1], tracks[4], RAMP_LENGTH)
+        );
+        let isolated = TrackID::new(CellID::new(0, 5, 0), Orientation::EW);
+        connections.add_filtered_track(isolated, &TrackLogicalFilter::default());
+        assert_eq!(
+            connections.track_distance(tracks[0], isolated, RAMP_LENGTH),
+            None
+        );
+    }
+
+    #[test]
+    fn test_block_fits_train() {
+        use crate::block::Block;
+        use crate::section::DirectedSection;
+        use crate::train::TrainDefaults;
+
+        let block = Block::new(DirectedSection {
+            tracks: (0..3)
+                .map(|x| {
+                    TrackID::new(CellID::new(x, 0, 0), Orientation::EW)
+                        .get_directed(TrackDirection::First)
+                })
+                .collect(),
+        });
+        assert!(block.fits(2.0, RAMP_LENGTH));
+        assert!(!block.fits(2.5, RAMP_LENGTH));
+
+        // the middle connection is a ramp
+        let ramp_block = Block::new(DirectedSection {
+            tracks: [(0, 0), (1, 0), (2, 1)]
+                .into_iter()
+                .map(|(x, l)| {
+                    TrackID::new(CellID::new(x, 0, l), Orientation::EW)
+                        .get_directed(TrackDirection::First)
+                })
+                .collect(),
+        });
+        assert!(!ramp_block.fits(2.5, 1.0));
+        assert!(ramp_block.fits(2.5, 2.0));
+
+        let mut defaults = TrainDefaults::default();
+        assert!(defaults.allows_block(&block, 2.5, RAMP_LENGTH));
+        defaults.fit_blocks_only = true;
+        assert!(!defaults.allows_block(&block, 2.5, RAMP_LENGTH));
+        assert!(defaults.allows_block(&block, 1.5, RAMP_LENGTH));
+    }
+
+    // two trains heading towards each other on a single line
+    #[test]
+    fn test_reservation_stops_other_train() {
+        let mut world = World::new();
+        world.init_resource::<Messages<SetSwitchPositionMessage>>();
+        world.init_resource::<Messages<SetCrossingPositionMessage>>();
+        let mut state = SystemState::<(
+            Query<&Switch>,
+            Query<&LevelCrossing>,
+            MessageWriter<SetSwitchPositionMessage>,
+            MessageWriter<SetCrossingPositionMessage>,
+        )>::new(&mut world);
+        let (switches, crossings, mut set_switch, mut set_crossing) = state.get_mut(&mut world);
+        let entity_map = EntityMap::default();
+        let leader = TrainID::new(0);
+        let other = TrainID::new(1);
+
+        let leader_route = line(0..12, TrackDirection::First);
+        let other_route = line((0..12).rev(), TrackDirection::Last);
+        let other_position = line(10..12, TrackDirection::Last);
+        // without a reservation the other train gets as far as track 4
+        let other_leg = other_route.section_ahead(1, 6.5, RAMP_LENGTH);
+        assert_eq!(other_leg.len(), 7);
+
+        let mut locks = TrackLocks::default();
+        let position = leader_route.section_ahead(2, 1.5, RAMP_LENGTH);
+        locks.lock(
+            &leader,
+            &position,
+            &entity_map,
+            &switches,
+            &crossings,
+            &mut set_switch,
+            &mut set_crossing,
+        );
+        locks.lock(
+            &other,
+            &other_position,
+            &entity_map,
+            &switches,
+            &crossings,
+            &mut set_switch,
+            &mut set_crossing,
+        );
+        assert!(locks.can_lock(&other, &other_leg, &switches, &entity_map));
+
+        let reserve = leader_route.section_ahead(3, 3.5, RAMP_LENGTH);
+        assert_eq!(reserve.len(), 4);
+        assert!(locks.try_lock(
+            &leader,
+            &reserve,
+            &entity_map,
+            &switches,
+            &crossings,
+            &mut set_switch,
+            &mut set_crossing,
+        ));
+        assert!(!locks.can_lock(&other, &other_leg, &switches, &entity_map));
+        // the other train stops right before the reserved zone
+        let short_leg = other_route.section_ahead(1, 3.5, RAMP_LENGTH);
+        assert!(locks.can_lock(&other, &short_leg, &switches, &entity_map));
+        let into_reserve = other_route.section_ahead(1, 4.5, RAMP_LENGTH);
+        assert!(!locks.can_lock(&other, &into_reserve, &switches, &entity_map));
+
+        // a blocked reservation falls back to the locks the leader already holds
+        let before = locks.clone();
+        let blocked = leader_route.section_ahead(6, 6.5, RAMP_LENGTH);
+        assert!(!locks.try_lock(
+            &leader,
+            &blocked,
+            &entity_map,
+            &switches,
+            &crossings,
+            &mut set_switch,
+            &mut set_crossing,
+        ));
+        assert!(locks == before);
+    }
+
+    // a follower routed into the blocks of a leader on the same line
+    #[test]
+    fn test_follower_stops_behind_leader() {
+        use crate::block::Block;
+        use crate::marker::{Marker, MarkerColor};
+        use crate::route::build_route;
+        use crate::section::DirectedSection;
+
+        let mut world = World::new();
+        world.init_resource::<Messages<SetSwitchPositionMessage>>();
+        world.init_resource::<Messages<SetCrossingPositionMessage>>();
+        let mut entity_map = EntityMap::default();
+        let mut marker_map = MarkerMap::default();
+        // four blocks of three tracks, one track apart
+        for start in [0, 4, 8, 12] {
+            let section = DirectedSection {
+                tracks: (start..start + 3)
+                    .map(|x| {
+                        TrackID::new(CellID::new(x, 0, 0), Orientation::EW)
+                            .get_directed(TrackDirection::First)
+                    })
+                    .collect(),
+            };
+            let block = Block::new(section);
+            let logical_id = block
+                .id
+                .to_logical(BlockDirection::Aligned, Facing::Forward);
+            let in_track = logical_id.default_in_marker_track();
+            let marker = Marker::new(in_track.track(), MarkerColor::Red);
+            let marker_id = marker.marker_id();
+            entity_map.add_marker(marker_id, world.spawn(marker).id());
+            marker_map.register_marker(in_track, MarkerKey::In, logical_id);
+            entity_map.add_block(block.id, world.spawn(block).id());
+        }
+
+        let mut state = SystemState::<(
+            Query<&Marker>,
+            Query<&Block>,
+            Query<&Switch>,
+            Query<&LevelCrossing>,
+            MessageWriter<SetSwitchPositionMessage>,
+            MessageWriter<SetCrossingPositionMessage>,
+        )>::new(&mut world);
+        let (markers, blocks, switches, crossings, mut set_switch, mut set_crossing) =
+            state.get_mut(&mut world);
+        let leader = TrainID::new(0);
+        let follower = TrainID::new(1);
+        let mut leader_route = build_route(
+            leader,
+            &line(6..15, TrackDirection::First),
+            &markers,
+            &blocks,
+            &entity_map,
+            &marker_map,
+            RAMP_LENGTH,
+        );
+        let mut follower_route = build_route(
+            follower,
+            &line(2..11, TrackDirection::First),
+            &markers,
+            &blocks,
+            &entity_map,
+            &marker_map,
+            RAMP_LENGTH,
+        );
+
+        // same steps as update_train_route
+        let mut locks = TrackLocks::default();
+        for route in [&mut leader_route, &mut follower_route] {
+            route.update_intentions(&locks, &switches, &entity_map, None, false);
+            route.update_locks(
+                &mut locks,
+                &entity_map,
+                &mut set_switch,
+                &mut set_crossing,
+                &switches,
+                &crossings,
+                1,
+                4.0,
+            );
+        }
+        assert_eq!(leader_route.leg_index(), 1);
+        assert!(follower_route.is_blocked());
+        let locked_by = |train: TrainID| {
+            locks
+                .locked_tracks
+                .iter()
+                .filter(|(_, locked)| **locked == train)
+                .map(|(track, _)| track.cell().x)
+                .collect::<Vec<_>>()
+        };
+        let leader_rear = locked_by(leader).into_iter().min().unwrap();
+        let follower_front = locked_by(follower).into_iter().max().unwrap();
+        assert!(follower_front < leader_rear);
+
+        // once the leader is gone the follower continues
+        locks.unlock_all(&leader);
+        follower_route.update_intentions(&locks, &switches, &entity_map, None, false);
+        assert!(!follower_route.is_blocked());
+        assert_eq!(follower_route.leg_index(), 1);
+    }
+
+    // equal offset steps give equal distances where legs meet, also with two markers on a track
+    #[test]
+    fn test_route_offsets_even_across_legs() {
+        use crate::block::Block;
+        use crate::marker::{Marker, MarkerColor};
+        use crate::route::build_route;
+        use crate::section::DirectedSection;
+
+        let mut world = World::new();
+        let mut entity_map = EntityMap::default();
+        let mut marker_map = MarkerMap::default();
+        for start in [0, 4, 8] {
+            let section = DirectedSection {
+                tracks: (start..start + 3)
+                    .map(|x| {
+                        TrackID::new(CellID::new(x, 0, 0), Orientation::EW)
+                            .get_directed(TrackDirection::First)
+                    })
+                    .collect(),
+            };
+            let block = Block::new(section);
+            let logical_id = block
+                .id
+                .to_logical(BlockDirection::Aligned, Facing::Forward);
+            let in_track = logical_id.default_in_marker_track();
+            for (slot, key) in [
+                (MarkerSlot::Start, MarkerKey::Enter),
+                (MarkerSlot::End, MarkerKey::In),
+            ] {
+                let marker = Marker::at_slot(in_track.track(), slot, MarkerColor::Red);
+                let marker_id = marker.marker_id();
+                entity_map.add_marker(marker_id, world.spawn(marker).id());
+                marker_map.register_marker(in_track, key, logical_id);
+            }
+            entity_map.add_block(block.id, world.spawn(block).id());
+        }
+
+        let mut state = SystemState::<(Query<&Marker>, Query<&Block>)>::new(&mut world);
+        let (markers, blocks) = state.get(&world);
+        let mut route = build_route(
+            TrainID::new(0),
+            &line(2..11, TrackDirection::First),
+            &markers,
+            &blocks,
+            &entity_map,
+            &marker_map,
+            RAMP_LENGTH,
+        );
+
+        let positions = (-4..=24)
+            .map(|i| route.interpolate_offset(i as f32 * 0.25))
+            .collect::<Vec<_>>();
+        let step = positions[0].distance(positions[1]);
+        assert!(step > 0.0);
+        for pair in positions.windows(2) {
+            assert!((pair[0].distance(pair[1]) - step).abs() < step * 1e-3);
+        }
+
+        // moving on to the next leg keeps the train where it is
+        let before = route.interpolate_offset(0.0);
+        route.next_leg().unwrap();
+        assert!(route.interpolate_offset(0.0).distance(before) < step * 1e-3);
+    }
+
+    #[test]
+    fn test_track_filter_round_trip() {
+        use crate::track::{SpawnTrackMessage, Track, make_track_filters_verbose};
+
+        let mut track = Track::from_id(TrackID::new(CellID::new(0, 0, 0), Orientation::EW));
+        let disabled = LogicalDiscriminator {
+            direction: TrackDirection::Last,
+            facing: Facing::Backward,
+        };
+        track.logical_filter.filters.insert(disabled, false);
+        let tracks = vec![
+            SpawnTrackMessage(track.clone()),
+            SpawnTrackMessage(Track::from_id(TrackID::new(
+                CellID::new(1, 0, 0),
+                Orientation::EW,
+            ))),
+        ];
+
+        for verbose in [false, true] {
+            let mut val = serde_json::to_value(&tracks).unwrap();
+            if verbose {
+                make_track_filters_verbose(&mut val).unwrap();
+            }
+            let loaded = serde_json::from_value::<Vec<SpawnTrackMessage>>(val).unwrap();
+            assert_eq!(
+                loaded[0].0.logical_filter.filters,
+                track.logical_filter.filters
+            );
+            assert!(loaded[1].0.logical_filter.is_default());
+        }
+    }
+
+    #[test]
+    fn test_marker_keys_on_shared_track() {
+        let track = TrackID::new(CellID::new(0, 0, 0), Orientation::EW);
+        let logical = track
+            .get_directed(TrackDirection::First)
+            .get_logical(Facing::Forward);
+        let block = BlockID::new(
+            TrackID::new(CellID::new(-1, 0, 0), Orientation::EW).get_directed(TrackDirection::Last),
+            track.get_directed(TrackDirection::First),
+        )
+        .to_logical(BlockDirection::Aligned, Facing::Forward);
+        let mut marker_map = MarkerMap::default();
+        marker_map.register_marker(logical, MarkerKey::Enter, block);
+        marker_map.register_marker(logical, MarkerKey::In, block);
+
+        assert_eq!(
+            marker_map.get_marker_keys(&logical, &block, 2),
+            vec![MarkerKey::Enter, MarkerKey::In]
+        );
+        assert_eq!(
+            marker_map.get_marker_keys(&logical, &block, 3),
+            vec![MarkerKey::None, MarkerKey::Enter, MarkerKey::In]
+        );
+        // a single marker stops the train
+        assert_eq!(
+            marker_map.get_marker_keys(&logical, &block, 1),
+            vec![MarkerKey::In]
+        );
+        let other = block
+            .block
+            .to_logical(BlockDirection::Opposite, Facing::Forward);
+        assert_eq!(
+            marker_map.get_marker_keys(&logical, &other, 2),
+            vec![MarkerKey::None, MarkerKey::None]
+        );
+    }
+
+    #[test]
+    fn test_signal_aspects_round_trip() {
+        let block = BlockID::new(
+            TrackID::new(CellID::new(0, 0, 0), Orientation::EW).get_directed(TrackDirection::Last),
+            TrackID::new(CellID::new(3, 0, 0), Orientation::EW).get_directed(TrackDirection::First),
+        );
+        let mut signals = SignalAspects::default();
+        signals.aspects.insert(block, SignalAspect::Red);
+        let json = serde_json::to_string(&signals).unwrap();
+        let loaded = serde_json::from_str::<SignalAspects>(&json).unwrap();
+        assert!(loaded == signals);
+        assert!(loaded.is_stop(&block));
     }
 }
@@ -1,22 +1,48 @@
 use crate::{
-    ble::BLEHub,
+    ble::{BLEHub, FromIOMessage, HubMessageMessage},
+    block::Block,
     editor::{DespawnMessage, SelectionState, SpawnHubMessage},
     layout::EntityMap,
     layout_primitives::*,
     selectable::Selectable,
     switch::Switch,
-    switch_motor::SpawnPulseMotorMessage,
+    switch_motor::{MotorPosition, SpawnPulseMotorMessage},
 };
 use bevy::{ecs::component::Mutable, prelude::*};
 use bevy_egui::egui::{self, Layout, Ui};
 use bevy_inspector_egui::bevy_egui;
+use pybricks_ble::io_hub::IOMessage;
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug)]
+pub enum LayoutData {
+    SwitchConfirm { port: u8, position: MotorPosition },
+}
+
+impl FromIOMessage for LayoutData {
+    fn from_io_message(msg: &IOMessage) -> Option<Self> {
+        match msg {
+            IOMessage::Data { id, data } => match id {
+                0 => Some(LayoutData::SwitchConfirm {
+                    port: *data.get(0)?,
+                    position: MotorPosition::from_u8(*data.get(1)?)?,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
 #[derive(Component, Debug, Reflect, Serialize, Deserialize, Clone)]
 pub struct LayoutDevice {
     pub id: LayoutDeviceID,
     pub hub_id: Option<HubID>,
     pub port: Option<HubPort>,
+    // stable identity of the assigned hub, only kept in sync when saving
+    #[serde(default)]
+    #[reflect(ignore)]
+    pub hub_name: Option<String>,
 }
 
 impl LayoutDevice {
@@ -25,6 +51,7 @@ impl LayoutDevice {
             id,
             hub_id: None,
             port: None,
+            hub_name: None,
         }
     }
 
@@ -149,6 +176,7 @@ fn despawn_layout_device(
     mut entity_map: ResMut<EntityMap>,
     mut commands: Commands,
     mut q_switches: Query<&mut Switch>,
+    mut q_blocks: Query<&mut Block>,
 ) {
     for event in messages.read() {
         for mut switch in q_switches.iter_mut() {
@@ -157,11 +185,16 @@ fn despawn_layout_device(
                     motor.take();
                 }
             }
-            if let Some(entity) = entity_map.layout_devices.remove(&event.0) {
-                commands.entity(entity).despawn();
+        }
+        for mut block in q_blocks.iter_mut() {
+            if block.settings.signal == Some(event.0) {
+                block.settings.signal = None;
             }
-            entity_map.remove_layout_device(event.0);
         }
+        if let Some(entity) = entity_map.layout_devices.remove(&event.0) {
+            commands.entity(entity).despawn();
+        }
+        entity_map.remove_layout_device(event.0);
     }
 }
 
@@ -170,6 +203,7 @@ pub struct LayoutDevicePlugin;
 impl Plugin for LayoutDevicePlugin {
     fn build(&self, app: &mut App) {
         app.add_message::<DespawnMessage<LayoutDevice>>();
+        app.add_message::<HubMessageMessage<LayoutData>>();
         app.add_systems(
             Update,
             despawn_layout_device.run_if(on_message::<DespawnMessage<LayoutDevice>>),
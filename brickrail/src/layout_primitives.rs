@@ -9,6 +9,64 @@ use serde_with::{DeserializeFromStr, SerializeDisplay};
 
 use crate::utils::distance_to_segment;
 
+pub const PORTAL_LENGTH: f32 = 0.8;
+
+// default length of portals that change layer, see PortalSettings
+pub const RAMP_LENGTH: f32 = 4.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseIDError {
+    Malformed { kind: &'static str, input: String },
+    InvalidNumber { kind: &'static str, input: String },
+    InvalidName { kind: &'static str, input: String },
+}
+
+impl fmt::Display for ParseIDError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseIDError::Malformed { kind, input } => {
+                write!(f, "malformed {} id: {}", kind, input)
+            }
+            ParseIDError::InvalidNumber { kind, input } => {
+                write!(f, "invalid number in {} id: {}", kind, input)
+            }
+            ParseIDError::InvalidName { kind, input } => {
+                write!(f, "invalid {} id: {}", kind, input)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseIDError {}
+
+// strips the wrapping of a displayed id, e.g. "T(" and ")",
+// and the number of trailing chars before the closing suffix
+fn strip_id<'a>(
+    s: &'a str,
+    prefix: &str,
+    suffix: char,
+    trailing: usize,
+    kind: &'static str,
+) -> Result<&'a str, ParseIDError> {
+    let malformed = || ParseIDError::Malformed {
+        kind,
+        input: s.to_string(),
+    };
+    let inner = s
+        .strip_prefix(prefix)
+        .and_then(|s| s.strip_suffix(suffix))
+        .ok_or_else(malformed)?;
+    if trailing == 0 {
+        return Ok(inner);
+    }
+    let end_index = inner
+        .char_indices()
+        .nth_back(trailing - 1)
+        .map(|(i, _)| i)
+        .ok_or_else(malformed)?;
+    Ok(&inner[..end_index])
+}
+
 #[derive(
     Clone, Copy, PartialEq, Eq, Debug, Reflect, Serialize, Deserialize, Hash, PartialOrd, Ord,
 )]
@@ -34,12 +92,15 @@ impl fmt::Display for ScheduleID {
 pub enum DestinationID {
     Random,
     Specific(usize),
+    // the home block of the train the destination is queued for
+    Home,
 }
 
 impl fmt::Display for DestinationID {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             DestinationID::Random => write!(f, "Random destination"),
+            DestinationID::Home => write!(f, "Home"),
             DestinationID::Specific(id) => write!(f, "Destination{}", id),
         }
     }
@@ -165,6 +226,45 @@ impl HubPort {
     }
 }
 
+#[derive(
+    Clone, Copy, Hash, PartialEq, Eq, Debug, Default, Display, Reflect, Serialize, Deserialize,
+)]
+pub enum HubHardware {
+    CityHub,
+    #[default]
+    TechnicHub,
+    PrimeHub,
+}
+
+impl HubHardware {
+    pub fn iter() -> impl Iterator<Item = HubHardware> {
+        [
+            HubHardware::CityHub,
+            HubHardware::TechnicHub,
+            HubHardware::PrimeHub,
+        ]
+        .iter()
+        .copied()
+    }
+
+    pub fn num_ports(&self) -> u8 {
+        match self {
+            HubHardware::CityHub => 2,
+            HubHardware::TechnicHub => 4,
+            HubHardware::PrimeHub => 6,
+        }
+    }
+
+    pub fn has_port(&self, port: &HubPort) -> bool {
+        port.to_u8() < self.num_ports()
+    }
+
+    pub fn iter_ports(&self) -> impl Iterator<Item = HubPort> + use<> {
+        let num_ports = self.num_ports();
+        HubPort::iter().filter(move |port| port.to_u8() < num_ports)
+    }
+}
+
 #[derive(
     Clone, Copy, Hash, PartialEq, PartialOrd, Ord, Eq, Debug, Reflect, Serialize, Deserialize,
 )]
@@ -204,16 +304,24 @@ impl fmt::Display for HubID {
 }
 
 impl FromStr for HubID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("Train") {
-            Ok(Self::new(s[5..].parse().unwrap(), HubType::Train))
-        } else if s.starts_with("Layout") {
-            Ok(Self::new(s[6..].parse().unwrap(), HubType::Layout))
+        let (kind, number) = if let Some(number) = s.strip_prefix("Train") {
+            (HubType::Train, number)
+        } else if let Some(number) = s.strip_prefix("Layout") {
+            (HubType::Layout, number)
         } else {
-            Err(format!("invalid hub id: {}", s))
-        }
+            return Err(ParseIDError::Malformed {
+                kind: "hub",
+                input: s.to_string(),
+            });
+        };
+        let id = number.parse().map_err(|_| ParseIDError::InvalidNumber {
+            kind: "hub",
+            input: s.to_string(),
+        })?;
+        Ok(Self::new(id, kind))
     }
 }
 
@@ -324,8 +432,8 @@ impl BlockID {
         // println!("parsing block name: {}", name);
         let (track1, track2) = name.split_at(name.find(")-(")? + 1);
         // println!("track1: {}, track2: {}", track1, track2);
-        let track1 = &track1[1..track1.len() - 1];
-        let track2 = &track2[2..track2.len() - 1];
+        let track1 = track1.strip_prefix('(')?.strip_suffix(')')?;
+        let track2 = track2.strip_prefix("-(")?.strip_suffix(')')?;
         // println!("track1: {}, track2: {}", track1, track2);
         Some(Self {
             track1: DirectedTrackID::from_name(track1)?,
@@ -347,13 +455,16 @@ impl fmt::Debug for BlockID {
 }
 
 impl FromStr for BlockID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // strip B[ and ]:
-        let s = &s[2..s.len() - 1];
+        let name = strip_id(s, "B[", ']', 0, "block")?;
         // println!("parsing block id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid block id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "block",
+            input: s.to_string(),
+        })
     }
 }
 
@@ -445,13 +556,16 @@ impl fmt::Display for LogicalBlockID {
 }
 
 impl FromStr for LogicalBlockID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // strip LB[ and ]:
-        let s = &s[3..s.len() - 1];
+        let name = strip_id(s, "LB[", ']', 0, "logical block")?;
         // println!("parsing logical block id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid logical block id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "logical block",
+            input: s.to_string(),
+        })
     }
 }
 
@@ -481,6 +595,10 @@ impl CellID {
         }
     }
 
+    pub fn on_layer(&self, l: i32) -> Self {
+        Self { l, ..*self }
+    }
+
     pub fn cardinal_to(&self, other: &Self) -> Option<Cardinal> {
         Some(Cardinal::from_deltas(other.x - self.x, other.y - self.y)?)
     }
@@ -531,6 +649,68 @@ impl CellID {
     pub fn get_vec2(&self) -> Vec2 {
         Vec2::new(self.x as f32, self.y as f32)
     }
+
+    // offset by the x/y of delta, staying on the same layer
+    pub fn translated(&self, delta: CellID) -> Self {
+        Self {
+            x: self.x + delta.x,
+            y: self.y + delta.y,
+            l: self.l,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SectionTransform {
+    Translate(CellID),
+    // counterclockwise around the center cell
+    Rotate90(CellID),
+    Mirror(MirrorAxis, CellID),
+}
+
+impl SectionTransform {
+    pub fn cell(&self, cell: CellID) -> CellID {
+        match *self {
+            SectionTransform::Translate(delta) => cell.translated(delta),
+            SectionTransform::Rotate90(center) => CellID::new(
+                center.x - (cell.y - center.y),
+                center.y + (cell.x - center.x),
+                cell.l,
+            ),
+            SectionTransform::Mirror(MirrorAxis::Vertical, center) => {
+                CellID::new(2 * center.x - cell.x, cell.y, cell.l)
+            }
+            SectionTransform::Mirror(MirrorAxis::Horizontal, center) => {
+                CellID::new(cell.x, 2 * center.y - cell.y, cell.l)
+            }
+        }
+    }
+
+    pub fn cardinal(&self, cardinal: Cardinal) -> Cardinal {
+        match *self {
+            SectionTransform::Translate(_) => cardinal,
+            SectionTransform::Rotate90(_) => cardinal.rotated_90(),
+            SectionTransform::Mirror(axis, _) => cardinal.mirrored(axis),
+        }
+    }
+
+    // keeps pointing at the transformed cardinal, so the direction may change
+    pub fn dirtrack(&self, dirtrack: DirectedTrackID) -> DirectedTrackID {
+        let (card1, card2) = dirtrack.track.orientation.get_cardinals();
+        let orientation =
+            Orientation::from_cardinals(self.cardinal(card1), self.cardinal(card2)).unwrap();
+        let track = TrackID::new(self.cell(dirtrack.track.cell), orientation);
+        let to_cardinal =
+            self.cardinal(dirtrack.track.orientation.get_cardinal(dirtrack.direction));
+        track.get_directed_to_cardinal(to_cardinal).unwrap()
+    }
+
+    pub fn connection(&self, connection: TrackConnectionID) -> TrackConnectionID {
+        TrackConnectionID::new(
+            self.dirtrack(connection.track_a),
+            self.dirtrack(connection.track_b),
+        )
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -648,6 +828,32 @@ impl Cardinal {
     pub fn get_vec2(&self) -> Vec2 {
         Vec2::new(self.dx() as f32, self.dy() as f32)
     }
+
+    // counterclockwise
+    pub fn rotated_90(&self) -> Self {
+        match self {
+            Cardinal::N => Cardinal::W,
+            Cardinal::W => Cardinal::S,
+            Cardinal::S => Cardinal::E,
+            Cardinal::E => Cardinal::N,
+        }
+    }
+
+    pub fn mirrored(&self, axis: MirrorAxis) -> Self {
+        match (axis, self) {
+            (MirrorAxis::Vertical, Cardinal::E | Cardinal::W) => self.opposite(),
+            (MirrorAxis::Horizontal, Cardinal::N | Cardinal::S) => self.opposite(),
+            _ => *self,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MirrorAxis {
+    // mirrors east and west
+    Vertical,
+    // mirrors north and south
+    Horizontal,
 }
 
 #[derive(
@@ -707,6 +913,17 @@ impl Orientation {
         return None;
     }
 
+    // counterclockwise, advances turn_index by 2 modulo 4
+    pub fn rotated_90(&self) -> Self {
+        let (card1, card2) = self.get_cardinals();
+        Self::from_cardinals(card1.rotated_90(), card2.rotated_90()).unwrap()
+    }
+
+    pub fn mirrored(&self, axis: MirrorAxis) -> Self {
+        let (card1, card2) = self.get_cardinals();
+        Self::from_cardinals(card1.mirrored(axis), card2.mirrored(axis)).unwrap()
+    }
+
     pub fn turn_index(&self) -> i32 {
         match self {
             Self::EW => 4,
@@ -829,6 +1046,13 @@ impl TrackConnectionID {
         [self.track_a, self.track_b]
     }
 
+    pub fn translated(&self, delta: CellID) -> Self {
+        Self::new(
+            self.track_a.translated(delta),
+            self.track_b.translated(delta),
+        )
+    }
+
     pub fn track_a(&self) -> DirectedTrackID {
         self.track_a
     }
@@ -903,13 +1127,16 @@ impl fmt::Debug for TrackConnectionID {
 }
 
 impl FromStr for TrackConnectionID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         // strip C( and ):
-        let s = &s[2..s.len() - 1];
+        let name = strip_id(s, "C(", ')', 0, "track connection")?;
         // println!("parsing track connection id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid track connection id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "track connection",
+            input: s.to_string(),
+        })
     }
 }
 
@@ -948,12 +1175,27 @@ impl DirectedTrackConnectionID {
         self.from_track.to_slot() == self.to_track.from_slot()
     }
 
+    pub fn changes_layer(&self) -> bool {
+        self.from_track.cell().l != self.to_track.cell().l
+    }
+
     pub fn draw_with_gizmos(&self, gizmos: &mut Gizmos, scale: f32, color: Color) {
         let start = self.from_track.get_center_vec2() + self.from_track.get_delta_vec() * 0.2;
         let end = self.to_track.get_center_vec2() - self.to_track.get_delta_vec() * 0.2;
         gizmos.line_2d(start * scale, end * scale, color);
     }
 
+    pub fn draw_arrow_with_gizmos(&self, gizmos: &mut Gizmos, scale: f32, color: Color) {
+        self.draw_with_gizmos(gizmos, scale, color);
+        let start = self.from_track.get_center_vec2() + self.from_track.get_delta_vec() * 0.2;
+        let end = self.to_track.get_center_vec2() - self.to_track.get_delta_vec() * 0.2;
+        let back = (start - end).normalize_or_zero() * 0.1;
+        for angle in [0.5, -0.5] {
+            let head = end + Vec2::from_angle(angle).rotate(back);
+            gizmos.line_2d(end * scale, head * scale, color);
+        }
+    }
+
     pub fn curve_index(&self) -> i32 {
         if !self.is_continuous() {
             return 0;
@@ -961,7 +1203,7 @@ impl DirectedTrackConnectionID {
         ((self.to_track.dir_index() - self.from_track.dir_index() + 12) % 8) - 4
     }
 
-    fn curve_radius(&self) -> f32 {
+    pub fn curve_radius(&self) -> f32 {
         match self.curve_index().abs() {
             0 => 0.0,
             1 => 0.5 + 0.25 * 2.0_f32.sqrt(),
@@ -1001,9 +1243,31 @@ impl DirectedTrackConnectionID {
         (self.from_track.straight_end() - self.to_track.opposite().straight_end()).length()
     }
 
-    pub fn connection_length(&self) -> f32 {
+    pub fn curve_description(&self, ramp_length: f32) -> String {
+        if self.changes_layer() {
+            return format!("ramp {:.2}", self.connection_length(ramp_length));
+        }
         if !self.is_continuous() {
-            return 0.8;
+            return "portal".to_string();
+        }
+        match self.curve_index() {
+            0 => format!("straight {:.2}", self.connection_length(ramp_length)),
+            index => format!(
+                "curve {:+} (R={:.2}) {:.2}",
+                index,
+                self.curve_radius(),
+                self.connection_length(ramp_length)
+            ),
+        }
+    }
+
+    // ramps between layers are as long as the layout's ramp length
+    pub fn connection_length(&self, ramp_length: f32) -> f32 {
+        if self.changes_layer() {
+            return ramp_length;
+        }
+        if !self.is_continuous() {
+            return PORTAL_LENGTH;
         }
         self.from_track.straight_length()
             + self.curve_length()
@@ -1011,15 +1275,22 @@ impl DirectedTrackConnectionID {
             + self.straight_length()
     }
 
-    pub fn interpolate_pos(&self, dist: f32) -> Vec2 {
+    pub fn interpolate_pos(&self, dist: f32, ramp_length: f32) -> Vec2 {
+        if self.changes_layer() {
+            let t = (dist / self.connection_length(ramp_length)).clamp(0.0, 1.0);
+            return self
+                .from_track
+                .get_center_vec2()
+                .lerp(self.to_track.get_center_vec2(), t);
+        }
         if !self.is_continuous() {
-            if dist < self.connection_length() * 0.5 {
+            if dist < self.connection_length(ramp_length) * 0.5 {
                 return self.from_track.interpolate_pos(dist);
             }
             return self
                 .to_track
                 .opposite()
-                .interpolate_pos(self.connection_length() - dist);
+                .interpolate_pos(self.connection_length(ramp_length) - dist);
         }
         if dist < self.from_track.straight_length() {
             return self.from_track.interpolate_pos(dist);
@@ -1031,7 +1302,15 @@ impl DirectedTrackConnectionID {
 
         return self
             .to_track
-            .interpolate_pos(dist - self.connection_length());
+            .interpolate_pos(dist - self.connection_length(ramp_length));
+    }
+
+    // layer height along the connection, ramps climb linearly over their length
+    pub fn interpolate_layer(&self, dist: f32, ramp_length: f32) -> f32 {
+        let from = self.from_track.cell().l as f32;
+        let to = self.to_track.cell().l as f32;
+        let t = (dist / self.connection_length(ramp_length)).clamp(0.0, 1.0);
+        from + (to - from) * t
     }
 
     pub fn to_logical(&self, from_facing: Facing) -> LogicalTrackConnectionID {
@@ -1149,6 +1428,61 @@ impl Facing {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Reflect, Serialize, Deserialize, Display)]
+pub enum SignalAspect {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl SignalAspect {
+    pub fn is_stop(&self) -> bool {
+        *self == SignalAspect::Red
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            SignalAspect::Green => 0,
+            SignalAspect::Yellow => 1,
+            SignalAspect::Red => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Reflect, Serialize, Deserialize, Default)]
+#[reflect(Default)]
+pub enum FacingPreference {
+    #[default]
+    Any,
+    Prefer(Facing),
+    Only(Facing),
+}
+
+impl FacingPreference {
+    pub fn cost(&self, facing: Facing) -> f32 {
+        match self {
+            FacingPreference::Prefer(preferred) if *preferred != facing => 10000.0,
+            _ => 0.0,
+        }
+    }
+
+    pub fn allows(&self, facing: Facing) -> bool {
+        match self {
+            FacingPreference::Only(only) => *only == facing,
+            _ => true,
+        }
+    }
+}
+
+impl From<Option<Facing>> for FacingPreference {
+    fn from(facing: Option<Facing>) -> Self {
+        match facing {
+            Some(facing) => FacingPreference::Prefer(facing),
+            None => FacingPreference::Any,
+        }
+    }
+}
+
 #[derive(
     Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Reflect, SerializeDisplay, DeserializeFromStr,
 )]
@@ -1186,7 +1520,8 @@ impl LogicalTrackID {
 
     pub fn from_name(name: &str) -> Option<Self> {
         // split into last char and rest str:
-        let (dirtrack, facing) = name.split_at(name.len() - 1);
+        let (split_index, _) = name.char_indices().last()?;
+        let (dirtrack, facing) = name.split_at(split_index);
         let facing = Facing::from_name(facing)?;
         let dirtrack = DirectedTrackID::from_name(dirtrack)?;
         Some(Self { dirtrack, facing })
@@ -1235,14 +1570,16 @@ impl fmt::Display for LogicalTrackID {
 }
 
 impl FromStr for LogicalTrackID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // strip L( and ):
-        let end_index = s.char_indices().nth_back(2).map(|(i, _)| i).unwrap();
-        let s = &s[2..end_index];
+        // strip L( and the arrow suffix |*):
+        let name = strip_id(s, "L(", ')', 2, "logical track")?;
         // println!("parsing logical track id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid logical track id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "logical track",
+            input: s.to_string(),
+        })
     }
 }
 
@@ -1277,6 +1614,13 @@ impl DirectedTrackID {
         track.get_directed_to_slot(to_slot)
     }
 
+    pub fn translated(&self, delta: CellID) -> Self {
+        Self {
+            track: self.track.translated(delta),
+            direction: self.direction,
+        }
+    }
+
     pub fn get_switch_position(&self) -> SwitchPosition {
         let opposite_from_slot = self
             .track
@@ -1486,14 +1830,16 @@ impl fmt::Display for DirectedTrackID {
 }
 
 impl FromStr for DirectedTrackID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // strip D( and ):
-        let end_index = s.char_indices().nth_back(2).map(|(i, _)| i).unwrap();
-        let s = &s[2..end_index];
+        // strip D( and the arrow suffix |*):
+        let name = strip_id(s, "D(", ')', 2, "directed track")?;
         // println!("parsing directed track id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid directed track id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "directed track",
+            input: s.to_string(),
+        })
     }
 }
 
@@ -1510,6 +1856,17 @@ impl TrackID {
         Self { cell, orientation }
     }
 
+    pub fn translated(&self, delta: CellID) -> Self {
+        Self {
+            cell: self.cell.translated(delta),
+            orientation: self.orientation,
+        }
+    }
+
+    pub fn cell(&self) -> CellID {
+        self.cell
+    }
+
     pub fn from_slots(slot1: Slot, slot2: Slot) -> Option<Self> {
         let cell = slot1.get_shared_cell(&slot2)?;
         //println!("{:?}", cell);
@@ -1663,14 +2020,89 @@ impl fmt::Display for TrackID {
 }
 
 impl FromStr for TrackID {
-    type Err = String;
+    type Err = ParseIDError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // strip T( and ):
-        let end_index = s.char_indices().nth_back(2).map(|(i, _)| i).unwrap();
-        let s = &s[2..end_index];
+        // strip T( and the arrow suffix |*):
+        let name = strip_id(s, "T(", ')', 2, "track")?;
         // println!("parsing track id: {}", s);
-        Self::from_name(s).ok_or_else(|| format!("invalid track id: {}", s))
+        Self::from_name(name).ok_or_else(|| ParseIDError::InvalidName {
+            kind: "track",
+            input: s.to_string(),
+        })
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Hash,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Default,
+    Reflect,
+    Serialize,
+    Deserialize,
+    EnumIter,
+    Display,
+)]
+pub enum MarkerSlot {
+    Start,
+    #[default]
+    Center,
+    End,
+}
+
+impl MarkerSlot {
+    pub fn offset(&self) -> f32 {
+        match self {
+            MarkerSlot::Start => -0.25,
+            MarkerSlot::Center => 0.0,
+            MarkerSlot::End => 0.25,
+        }
+    }
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            MarkerSlot::Start => MarkerSlot::End,
+            MarkerSlot::Center => MarkerSlot::Center,
+            MarkerSlot::End => MarkerSlot::Start,
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Reflect, Serialize, Deserialize,
+)]
+pub struct MarkerID {
+    pub track: TrackID,
+    pub slot: MarkerSlot,
+}
+
+impl MarkerID {
+    pub fn new(track: TrackID, slot: MarkerSlot) -> Self {
+        Self { track, slot }
+    }
+
+    pub fn get_vec2(&self) -> Vec2 {
+        let dirtrack = self.track.get_directed(TrackDirection::First);
+        dirtrack.get_center_vec2() + dirtrack.tangent() * self.slot.offset()
+    }
+
+    pub fn travel_offset(&self, dirtrack: &DirectedTrackID) -> f32 {
+        match dirtrack.direction {
+            TrackDirection::First => self.slot.offset(),
+            TrackDirection::Last => -self.slot.offset(),
+        }
+    }
+}
+
+impl fmt::Display for MarkerID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "M({}|{})", self.track, self.slot)
     }
 }
 
@@ -1679,6 +2111,184 @@ mod test {
     use super::*;
     use strum::IntoEnumIterator;
 
+    #[test]
+    fn test_rotate_and_mirror_orientation() {
+        let mut orientation = Orientation::NE;
+        for _ in 0..4 {
+            orientation = orientation.rotated_90();
+        }
+        assert_eq!(orientation, Orientation::NE);
+        assert_eq!(
+            Orientation::EW.mirrored(MirrorAxis::Vertical),
+            Orientation::EW
+        );
+        assert_eq!(
+            Orientation::NE.mirrored(MirrorAxis::Vertical),
+            Orientation::NW
+        );
+        for orientation in [Orientation::NE, Orientation::NS, Orientation::SE] {
+            assert_eq!(
+                orientation.rotated_90().turn_index() % 4,
+                (orientation.turn_index() + 2) % 4
+            );
+        }
+
+        let center = CellID::new(1, 1, 0);
+        let dirtrack = TrackID::new(CellID::new(2, 1, 0), Orientation::EW)
+            .get_directed_to_cardinal(Cardinal::E)
+            .unwrap();
+        let rotated = SectionTransform::Rotate90(center).dirtrack(dirtrack);
+        assert_eq!(rotated.cell(), CellID::new(1, 2, 0));
+        assert_eq!(rotated.track.orientation, Orientation::NS);
+        assert_eq!(
+            rotated.track.orientation.get_cardinal(rotated.direction),
+            Cardinal::N
+        );
+    }
+
+    // every continuous connection leaving the tracks of the cell at the origin
+    fn continuous_connections() -> Vec<DirectedTrackConnectionID> {
+        use Orientation::*;
+        let orientations = [NS, NE, NW, SE, SW, EW];
+        let dirtracks = |cell: CellID| {
+            orientations
+                .iter()
+                .flat_map(move |o| TrackID::new(cell, *o).dirtracks())
+                .collect::<Vec<_>>()
+        };
+        let origin = CellID::new(0, 0, 0);
+        let mut connections = vec![];
+        for from_track in dirtracks(origin) {
+            for cardinal in Cardinal::iter() {
+                for to_track in dirtracks(origin.get_neighbor(cardinal)) {
+                    let connection = DirectedTrackConnectionID::new(from_track, to_track);
+                    if connection.is_continuous() {
+                        connections.push(connection);
+                    }
+                }
+            }
+        }
+        connections
+    }
+
+    #[test]
+    fn test_connection_interpolation_even_spacing() {
+        let connections = continuous_connections();
+        let mut curve_indices = connections
+            .iter()
+            .map(|c| c.curve_index())
+            .collect::<Vec<_>>();
+        curve_indices.sort();
+        curve_indices.dedup();
+        assert_eq!(curve_indices, vec![-2, -1, 0, 1, 2]);
+
+        let samples = 32;
+        for connection in connections {
+            let length = connection.connection_length(RAMP_LENGTH);
+            let points = (0..=samples)
+                .map(|i| {
+                    connection.interpolate_pos(length * i as f32 / samples as f32, RAMP_LENGTH)
+                })
+                .collect::<Vec<_>>();
+            assert!(
+                points[0].distance(connection.from_track.get_center_vec2()) < 1e-4,
+                "{:?} doesn't start at its track",
+                connection
+            );
+            assert!(
+                points[samples].distance(connection.to_track.get_center_vec2()) < 1e-4,
+                "{:?} doesn't end at its track",
+                connection
+            );
+            // chords of an arc are a little shorter than the arc itself
+            let step = length / samples as f32;
+            for pair in points.windows(2) {
+                let spacing = pair[0].distance(pair[1]);
+                assert!(
+                    (spacing - step).abs() < step * 1e-2,
+                    "{} spacing {} instead of {} on {:?}",
+                    connection.curve_description(RAMP_LENGTH),
+                    spacing,
+                    step,
+                    connection
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_interpolation_beyond_ends() {
+        for connection in continuous_connections() {
+            let length = connection.connection_length(RAMP_LENGTH);
+            let before = connection.interpolate_pos(-0.25, RAMP_LENGTH);
+            assert!(
+                before.distance(connection.from_track.interpolate_pos(-0.25)) < 1e-4,
+                "{:?} leaves its first track before the start",
+                connection
+            );
+            let after = connection.interpolate_pos(length + 0.25, RAMP_LENGTH);
+            assert!(
+                after.distance(connection.to_track.interpolate_pos(0.25)) < 1e-4,
+                "{:?} leaves its last track after the end",
+                connection
+            );
+        }
+    }
+
+    #[test]
+    fn test_ramp_between_layers() {
+        let lower = TrackID::new(CellID::new(0, 0, 0), Orientation::EW)
+            .get_directed_to_cardinal(Cardinal::E)
+            .unwrap();
+        let upper = TrackID::new(CellID::new(3, 0, 1), Orientation::EW)
+            .get_directed_to_cardinal(Cardinal::E)
+            .unwrap();
+        let ramp = TrackConnectionID::new(lower, upper.opposite());
+        let directed = ramp
+            .directed_connections()
+            .into_iter()
+            .find(|c| c.from_track == lower)
+            .unwrap();
+        assert!(directed.changes_layer());
+        assert!(!directed.is_continuous());
+        let ramp_length = 6.0;
+        assert_eq!(directed.connection_length(ramp_length), ramp_length);
+        let half = ramp_length * 0.5;
+        assert_eq!(directed.interpolate_layer(0.0, ramp_length), 0.0);
+        assert_eq!(directed.interpolate_layer(half, ramp_length), 0.5);
+        assert_eq!(directed.interpolate_layer(half * 3.0, ramp_length), 1.0);
+        assert_eq!(
+            directed.interpolate_pos(half, ramp_length),
+            (lower.get_center_vec2() + upper.get_center_vec2()) * 0.5
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_ids() {
+        assert_eq!(
+            "Train5x".parse::<HubID>(),
+            Err(ParseIDError::InvalidNumber {
+                kind: "hub",
+                input: "Train5x".to_string()
+            })
+        );
+        assert_eq!(
+            "Layout3".parse::<HubID>(),
+            Ok(HubID::new(3, HubType::Layout))
+        );
+        assert!("".parse::<TrackID>().is_err());
+        assert!("T(".parse::<TrackID>().is_err());
+        assert!("B[]".parse::<BlockID>().is_err());
+        assert!("L(x)".parse::<LogicalTrackID>().is_err());
+
+        let track = TrackID::new(CellID::new(1, -2, 0), Orientation::NE);
+        assert_eq!(track.to_string().parse::<TrackID>(), Ok(track));
+        let logical = track
+            .get_directed(TrackDirection::First)
+            .get_logical(Facing::Backward);
+        assert_eq!(logical.to_string().parse::<LogicalTrackID>(), Ok(logical));
+    }
+
     #[test]
     fn test_slot_connectivity() {
         let slot1 = Slot {
@@ -1868,4 +2478,12 @@ mod test {
         assert_eq!(block, LogicalBlockID::from_str(&block.to_string()).unwrap());
         // assert!(false);
     }
+
+    #[test]
+    fn test_hub_hardware_ports() {
+        assert!(HubHardware::CityHub.has_port(&HubPort::B));
+        assert!(!HubHardware::CityHub.has_port(&HubPort::C));
+        assert!(!HubHardware::TechnicHub.has_port(&HubPort::E));
+        assert_eq!(HubHardware::PrimeHub.iter_ports().count(), 6);
+    }
 }
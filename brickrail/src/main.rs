@@ -11,6 +11,8 @@ mod block;
 mod crossing;
 mod destination;
 mod editor;
+mod failure_injection;
+mod headless;
 mod inspector;
 mod layout;
 mod layout_devices;
@@ -20,51 +22,89 @@ mod materials;
 mod persistent_hub_state;
 mod route;
 mod route_modular;
+mod routing;
 mod schedule;
 mod section;
 mod selectable;
+mod signal;
 mod switch;
 mod switch_motor;
 mod track;
 mod track_mesh;
 mod train;
 mod utils;
+mod validation;
 
 fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if let Some(headless_args) = headless::HeadlessArgs::parse(&args) {
+        match headless_args {
+            Ok(headless_args) => headless::run(headless_args, add_layout_plugins),
+            Err(err) => eprintln!("{}", err),
+        }
+        return;
+    }
+
     let file = Path::new("pybricks/programs/mpy/layout_controller.mpy");
     let hash = utils::get_file_hash(file);
     println!("Hash: {}", hash);
     // env::set_var("RUST_BACKTRACE", "1");
     // env::set_var("RUST_LOG", "pybricks_ble=info,brickrail=info,bevy=info");
-    App::new()
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
-            primary_window: Some(Window::default()),
-            close_when_requested: false,
-            ..Default::default()
-        }))
-        .add_plugins(ShapePlugin)
-        .add_plugins(bevy_framepace::FramepacePlugin)
-        .add_plugins(bevy_egui::EguiPlugin::default())
-        .add_plugins(editor::EditorPlugin)
-        .add_plugins(persistent_hub_state::SettingsPlugin)
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins.set(WindowPlugin {
+        primary_window: Some(Window::default()),
+        close_when_requested: false,
+        ..Default::default()
+    }))
+    .add_plugins(bevy_framepace::FramepacePlugin)
+    .add_plugins(persistent_hub_state::SettingsPlugin);
+    add_layout_plugins(&mut app);
+    add_render_plugins(&mut app);
+    // .add_plugins(LogDiagnosticsPlugin::default())
+    app.add_plugins(RenderDiagnosticsPlugin::default()).run();
+}
+
+// the layout and its simulation, shared with headless runs
+fn add_layout_plugins(app: &mut App) {
+    app.add_plugins(editor::EditorPlugin)
         .add_plugins(layout::LayoutPlugin)
         .add_plugins(block::BlockPlugin)
         .add_plugins(track::TrackPlugin)
         .add_plugins(train::TrainPlugin)
         .add_plugins(marker::MarkerPlugin)
         .add_plugins(crossing::CrossingPlugin)
-        .add_plugins(DefaultInspectorConfigPlugin)
         .add_plugins(bevy_tokio_tasks::TokioTasksPlugin::default())
         .add_plugins(ble::BLEPlugin)
         .add_plugins(ble_train::BLETrainPlugin)
         .add_plugins(switch::SwitchPlugin)
         .add_plugins(switch_motor::PulseMotorPlugin)
+        .add_plugins(signal::SignalPlugin)
         .add_plugins(layout_devices::LayoutDevicePlugin)
         .add_plugins(schedule::SchedulePlugin)
         .add_plugins(destination::DestinationPlugin)
-        // .add_plugins(LogDiagnosticsPlugin::default())
-        .add_plugins(RenderDiagnosticsPlugin::default())
+        .add_plugins(validation::ValidationPlugin)
+        .add_plugins(failure_injection::FailureInjectionPlugin)
+        .add_plugins(route_modular::ModularRoutePlugin);
+}
+
+// meshes, gizmos, egui windows and mouse and keyboard editing, only with a window
+fn add_render_plugins(app: &mut App) {
+    app.add_plugins(ShapePlugin)
+        .add_plugins(bevy_egui::EguiPlugin::default())
+        .add_plugins(DefaultInspectorConfigPlugin)
         .add_plugins(materials::MaterialsPlugin)
-        .add_plugins(route_modular::ModularRoutePlugin)
-        .run();
+        .add_plugins(editor::EditorRenderPlugin)
+        .add_plugins(layout::LayoutRenderPlugin)
+        .add_plugins(block::BlockRenderPlugin)
+        .add_plugins(track::TrackRenderPlugin)
+        .add_plugins(train::TrainRenderPlugin)
+        .add_plugins(marker::MarkerRenderPlugin)
+        .add_plugins(crossing::CrossingRenderPlugin)
+        .add_plugins(ble::BLERenderPlugin)
+        .add_plugins(switch::SwitchRenderPlugin)
+        .add_plugins(schedule::ScheduleRenderPlugin)
+        .add_plugins(destination::DestinationRenderPlugin)
+        .add_plugins(validation::ValidationRenderPlugin)
+        .add_plugins(failure_injection::FailureInjectionRenderPlugin)
+        .add_plugins(route_modular::ModularRouteRenderPlugin);
 }
@@ -14,7 +14,7 @@ use crate::route_modular::TrainSpeed;
 use crate::selectable::{Selectable, SelectablePlugin, SelectableType};
 use crate::{
     editor::*,
-    layout::EntityMap,
+    layout::{Connections, EntityMap},
     layout_primitives::*,
     track::{LAYOUT_SCALE, spawn_track},
 };
@@ -103,6 +103,8 @@ pub struct Markers(Vec<Entity>);
 #[derive(Debug, Component, Serialize, Deserialize, Clone, Reflect)]
 pub struct Marker {
     pub track: TrackID,
+    #[serde(default)]
+    pub slot: MarkerSlot,
     pub color: MarkerColor,
     #[serde(with = "any_key_map")]
     pub logical_data: HashMap<LogicalTrackID, LogicalMarkerData>,
@@ -110,17 +112,52 @@ pub struct Marker {
 
 impl Marker {
     pub fn new(track: TrackID, color: MarkerColor) -> Self {
+        Self::at_slot(track, MarkerSlot::Center, color)
+    }
+
+    pub fn at_slot(track: TrackID, slot: MarkerSlot, color: MarkerColor) -> Self {
         let mut logical_data = HashMap::new();
         for logical in track.logical_tracks() {
             logical_data.insert(logical, LogicalMarkerData::default());
         }
         Self {
             track: track,
+            slot: slot,
             color: color,
             logical_data: logical_data,
         }
     }
 
+    pub fn transformed(&self, transform: &SectionTransform) -> Self {
+        let first = transform.dirtrack(self.track.get_directed(TrackDirection::First));
+        let slot = match first.direction {
+            TrackDirection::First => self.slot,
+            TrackDirection::Last => self.slot.opposite(),
+        };
+        let logical_data = self
+            .logical_data
+            .iter()
+            .map(|(logical, data)| {
+                let dirtrack = transform.dirtrack(logical.dirtrack);
+                let logical = LogicalTrackID {
+                    dirtrack,
+                    facing: logical.facing,
+                };
+                (logical, data.clone())
+            })
+            .collect();
+        Self {
+            track: first.track,
+            slot,
+            color: self.color,
+            logical_data,
+        }
+    }
+
+    pub fn marker_id(&self) -> MarkerID {
+        MarkerID::new(self.track, self.slot)
+    }
+
     pub fn get_logical_data(&self, logical: LogicalTrackID) -> Option<&LogicalMarkerData> {
         self.logical_data.get(&logical)
     }
@@ -130,11 +167,7 @@ impl Marker {
     }
 
     pub fn draw_with_gizmos(&self, gizmos: &mut Gizmos) {
-        let position = self
-            .track
-            .get_directed(TrackDirection::First)
-            .get_center_vec2()
-            * LAYOUT_SCALE;
+        let position = self.marker_id().get_vec2() * LAYOUT_SCALE;
         gizmos.circle_2d(position, 0.05 * LAYOUT_SCALE, Color::WHITE);
         gizmos.circle_2d(
             position,
@@ -154,6 +187,7 @@ impl Marker {
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut marker) = markers.get_mut(entity) {
                 ui.label("Inspectable marker lol");
+                ui.label(format!("Slot: {}", marker.slot));
                 ui_for_value(&mut marker.color, ui, &type_registry.read());
                 ui.label("Logical data");
                 for (logical, data) in marker.logical_data.iter_mut() {
@@ -180,18 +214,22 @@ impl Inspectable for Marker {
 
 impl Selectable for Marker {
     type SpawnMessage = MarkerSpawnMessage;
-    type ID = TrackID;
+    type ID = MarkerID;
 
     fn get_type() -> crate::selectable::SelectableType {
         crate::selectable::SelectableType::Marker
     }
 
     fn generic_id(&self) -> GenericID {
-        GenericID::Marker(self.track)
+        GenericID::Marker(self.marker_id())
+    }
+
+    fn removal_action(&self, _connections: &Connections) -> Option<EditAction> {
+        Some(EditAction::RemoveMarker(self.clone()))
     }
 
     fn id(&self) -> Self::ID {
-        self.track
+        self.marker_id()
     }
 
     fn get_depth(&self) -> f32 {
@@ -204,11 +242,7 @@ impl Selectable for Marker {
         _transform: Option<&Transform>,
         _stroke: Option<&Shape>,
     ) -> f32 {
-        self.track
-            .get_directed(TrackDirection::First)
-            .get_center_vec2()
-            .distance(pos)
-            - 0.05
+        self.marker_id().get_vec2().distance(pos) - 0.05
     }
 }
 
@@ -219,11 +253,16 @@ fn create_marker(
     selection_state: Res<SelectionState>,
     mut marker_messages: MessageWriter<MarkerSpawnMessage>,
     keyboard: Res<ButtonInput<KeyCode>>,
+    entity_map: Res<EntityMap>,
+    mut history: ResMut<EditHistory>,
 ) {
     if keyboard.just_pressed(KeyCode::KeyM) {
         if let Selection::Single(GenericID::Track(track_id)) = selection_state.selection {
-            let marker = Marker::new(track_id, MarkerColor::Any);
-            marker_messages.write(MarkerSpawnMessage(marker));
+            if let Some(slot) = entity_map.free_marker_slot(track_id) {
+                let marker = Marker::at_slot(track_id, slot, MarkerColor::Any);
+                history.push(EditAction::AddMarker(marker.clone()));
+                marker_messages.write(MarkerSpawnMessage(marker));
+            }
         }
     }
 }
@@ -232,32 +271,37 @@ pub fn spawn_marker(
     mut commands: Commands,
     mut marker_messages: MessageReader<MarkerSpawnMessage>,
     mut entity_map: ResMut<EntityMap>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
     for event in marker_messages.read() {
         let marker = event.0.clone();
         let track_id = marker.track;
-        let mesh = Circle::new(0.05 * LAYOUT_SCALE).mesh().build();
-        let material = ColorMaterial::from(marker.color.get_display_color());
-        let transform = Transform::from_translation(
-            (marker
-                .track
-                .get_directed(TrackDirection::First)
-                .get_center_vec2()
-                * LAYOUT_SCALE)
-                .extend(25.0),
-        );
+        let marker_id = marker.marker_id();
+        // blocks spawn their in markers too, e.g. when a track deletion is undone
+        if entity_map.markers.contains_key(&marker_id) {
+            continue;
+        }
+        let transform =
+            Transform::from_translation((marker_id.get_vec2() * LAYOUT_SCALE).extend(25.0));
         let entity = commands
-            .spawn((
-                Mesh2d(meshes.add(mesh).into()),
-                transform,
-                MeshMaterial2d(materials.add(material)),
-                marker,
-                MarkerAt(entity_map.tracks[&track_id]),
-            ))
+            .spawn((transform, marker, MarkerAt(entity_map.tracks[&track_id])))
             .id();
-        entity_map.add_marker(track_id, entity);
+        entity_map.add_marker(marker_id, entity);
+    }
+}
+
+fn add_marker_meshes(
+    markers: Query<(Entity, &Marker), Without<Mesh2d>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut commands: Commands,
+) {
+    for (entity, marker) in markers.iter() {
+        let mesh = Circle::new(0.05 * LAYOUT_SCALE).mesh().build();
+        let material = ColorMaterial::from(marker.color.get_display_color());
+        commands.entity(entity).insert((
+            Mesh2d(meshes.add(mesh).into()),
+            MeshMaterial2d(materials.add(material)),
+        ));
     }
 }
 
@@ -269,10 +313,10 @@ fn set_marker_color(
 ) {
     for (marker, material) in markers.iter() {
         let mut color = marker.color.get_display_color();
-        if selection_state.selection == Selection::Single(GenericID::Marker(marker.track)) {
+        if selection_state.selection == Selection::Single(GenericID::Marker(marker.marker_id())) {
             color = Color::from(RED);
         }
-        if hover_state.hover == Some(GenericID::Marker(marker.track)) {
+        if hover_state.hover == Some(GenericID::Marker(marker.marker_id())) {
             color = Color::from(BLUE);
         }
         let material = materials.get_mut(material).unwrap();
@@ -286,10 +330,10 @@ pub fn despawn_marker(
     mut entity_map: ResMut<EntityMap>,
 ) {
     for event in marker_messages.read() {
-        let track_id = event.0;
-        let entity = entity_map.markers.get(&track_id).unwrap().clone();
+        let marker_id = event.0;
+        let entity = entity_map.markers.get(&marker_id).unwrap().clone();
         commands.entity(entity.clone()).despawn();
-        entity_map.remove_marker(track_id);
+        entity_map.remove_marker(marker_id);
     }
 }
 
@@ -304,25 +348,35 @@ pub struct MarkerPlugin;
 impl Plugin for MarkerPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SelectablePlugin::<Marker>::new());
-        app.add_plugins(InspectorPlugin::<Marker>::new());
         app.add_message::<MarkerSpawnMessage>();
         app.add_message::<DespawnMessage<Marker>>();
-        app.add_systems(
-            Update,
-            (
-                create_marker,
-                delete_selection_shortcut::<Marker>,
-                set_marker_color.after(finish_hover),
-            ),
-        );
         app.add_systems(
             PostUpdate,
             (
+                // markers moved with a section may take over ids of removed ones
                 spawn_marker
                     .run_if(on_message::<MarkerSpawnMessage>)
-                    .after(spawn_track),
+                    .after(spawn_track)
+                    .after(despawn_marker),
                 despawn_marker,
             ),
         );
     }
 }
+
+pub struct MarkerRenderPlugin;
+
+impl Plugin for MarkerRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<Marker>::new());
+        app.add_systems(
+            Update,
+            (
+                create_marker,
+                delete_selection_shortcut::<Marker>,
+                set_marker_color.after(finish_hover),
+            ),
+        );
+        app.add_systems(PostUpdate, add_marker_meshes.after(spawn_marker));
+    }
+}
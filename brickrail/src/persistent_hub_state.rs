@@ -1,12 +1,41 @@
+use std::path::PathBuf;
+
 use bevy::{platform::collections::HashMap, prelude::*};
 use serde::{Deserialize, Serialize};
 
-use crate::ble::HubConfiguration;
+use crate::ble::{HubConfiguration, HubConnectionStats};
 
 #[derive(Resource, Debug, Serialize, Deserialize)]
 pub struct PersistentHubState {
     pub program_hashes: HashMap<String, String>,
     pub configs: HashMap<String, HubConfiguration>,
+    // hubs that were connected when the state was last written,
+    // non-empty at launch if the previous session didn't disconnect cleanly
+    #[serde(default)]
+    pub connected_hubs: Vec<String>,
+    #[serde(default)]
+    pub auto_reconnect: bool,
+    // seconds between autosaves, 0 disables autosaving
+    #[serde(default = "default_autosave_interval")]
+    pub autosave_interval: f32,
+    #[serde(default = "default_autosave_keep")]
+    pub autosave_keep: usize,
+    #[serde(default)]
+    pub last_saved_layout: Option<PathBuf>,
+    // connection stability per hub name, to spot a flaky hub over time
+    #[serde(default)]
+    pub hub_stats: HashMap<String, HubConnectionStats>,
+    // where the state is written to, nothing is written without a path
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+fn default_autosave_interval() -> f32 {
+    120.0
+}
+
+fn default_autosave_keep() -> usize {
+    5
 }
 
 impl Default for PersistentHubState {
@@ -14,24 +43,94 @@ impl Default for PersistentHubState {
         Self {
             program_hashes: HashMap::default(),
             configs: HashMap::default(),
+            connected_hubs: Vec::new(),
+            auto_reconnect: false,
+            autosave_interval: default_autosave_interval(),
+            autosave_keep: default_autosave_keep(),
+            last_saved_layout: None,
+            hub_stats: HashMap::default(),
+            path: None,
         }
     }
 }
 
+const HUB_STATE_PATH: &str = "hub_state.json";
+
+#[derive(Resource, Debug, Default)]
+pub struct ReconnectOffer {
+    pub hubs: Vec<String>,
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct AutosaveOffer {
+    pub path: Option<PathBuf>,
+}
+
+pub const AUTOSAVE_DIR: &str = "autosave";
+
+fn modified_time(path: &PathBuf) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+// autosave files, oldest first
+pub fn list_autosaves() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(AUTOSAVE_DIR) else {
+        return vec![];
+    };
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort_by_key(modified_time);
+    paths
+}
+
 impl PersistentHubState {
     fn load_from_disk() -> Self {
         // check if hub_state.json exists, otherwise return default
-        let settings = std::fs::read_to_string("hub_state.json");
-        match settings {
+        let settings = std::fs::read_to_string(HUB_STATE_PATH);
+        let mut state = match settings {
             Ok(state_json) => serde_json::from_str(&state_json).unwrap(),
             Err(_) => PersistentHubState::default(),
-        }
+        };
+        state.path = Some(PathBuf::from(HUB_STATE_PATH));
+        state
     }
 
     pub fn sync_configured_hub(&mut self, hub_name: &str, config: &HubConfiguration) {
         self.configs.insert(hub_name.to_string(), config.clone());
     }
 
+    pub fn set_connected(&mut self, hub_name: &str, connected: bool) -> bool {
+        let position = self.connected_hubs.iter().position(|name| name == hub_name);
+        match (connected, position) {
+            (true, None) => self.connected_hubs.push(hub_name.to_string()),
+            (false, Some(index)) => {
+                self.connected_hubs.remove(index);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn save_to_disk(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let state_json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, state_json).unwrap();
+    }
+
+    // the newest autosave, if it was written after the last manual save
+    pub fn newer_autosave(&self) -> Option<PathBuf> {
+        let autosave = list_autosaves().pop()?;
+        let saved_time = self.last_saved_layout.as_ref().and_then(modified_time);
+        if saved_time.is_some_and(|saved| modified_time(&autosave).is_some_and(|t| t <= saved)) {
+            return None;
+        }
+        Some(autosave)
+    }
+
     pub fn config_matches(&self, hub_name: &str, config: &HubConfiguration) -> bool {
         match self.configs.get(hub_name) {
             Some(stored_config) => stored_config == config,
@@ -42,9 +141,8 @@ impl PersistentHubState {
 
 impl Drop for PersistentHubState {
     fn drop(&mut self) {
-        // save settings to hub_state.json
-        let state_json = serde_json::to_string_pretty(self).unwrap();
-        std::fs::write("hub_state.json", state_json).unwrap();
+        // save settings to hub_state.json, if loaded from there
+        self.save_to_disk();
     }
 }
 
@@ -52,6 +150,13 @@ pub struct SettingsPlugin;
 
 impl Plugin for SettingsPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(PersistentHubState::load_from_disk());
+        let state = PersistentHubState::load_from_disk();
+        app.insert_resource(ReconnectOffer {
+            hubs: state.connected_hubs.clone(),
+        });
+        app.insert_resource(AutosaveOffer {
+            path: state.newer_autosave(),
+        });
+        app.insert_resource(state);
     }
 }
@@ -7,8 +7,10 @@ use itertools::Itertools;
 use crate::block::Block;
 use crate::crossing::LevelCrossing;
 use crate::crossing::SetCrossingPositionMessage;
+use crate::layout::Connections;
 use crate::layout::EntityMap;
 use crate::layout::MarkerMap;
+use crate::layout::SignalAspects;
 use crate::layout::TrackLocks;
 use crate::layout_primitives::*;
 use crate::marker::*;
@@ -59,9 +61,11 @@ pub fn build_route(
     q_blocks: &Query<&Block>,
     entity_map: &EntityMap,
     marker_map: &MarkerMap,
+    ramp_length: f32,
 ) -> Route {
     let mut route = Route::new(train_id);
     route.critical_section = logical_section.clone();
+    route.ramp_length = ramp_length;
     let in_tracks = marker_map.in_markers.keys().cloned().collect_vec();
     let split = logical_section.split_by_tracks_with_overlap(in_tracks);
     assert!(split.len() > 0);
@@ -96,24 +100,50 @@ pub fn build_route(
             travel_section.extend_merge(&from_section);
             travel_section.extend_merge(&critical_path);
         }
+        // a leg that flips only travels in the target block
+        let from_end = travel_section
+            .length(ramp_length)
+            .min(from_section.length(ramp_length));
         travel_section.extend_merge(&to_section);
+        let speed_limits = LegSpeedLimits {
+            from: from_block.settings.speed_limit,
+            from_end,
+            target: target_block.settings.speed_limit,
+            target_start: travel_section.length(ramp_length) - to_section.length(ramp_length),
+        };
         debug!("travel section: {:?}", travel_section);
 
         for logical in critical_path.tracks.iter() {
             debug!("looking for marker at {:?}", logical);
-            if let Some(entity) = entity_map.markers.get(&logical.track()) {
+            let mut track_markers = entity_map
+                .markers_on_track(logical.track())
+                .into_iter()
+                .map(|(id, entity)| (id.travel_offset(&logical.dirtrack), entity))
+                .collect_vec();
+            track_markers.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let keys = marker_map.get_marker_keys(logical, target_id, track_markers.len());
+            for ((offset, entity), key) in track_markers.into_iter().zip(keys) {
                 debug!("found marker at {:?}", logical);
-                let marker = q_markers.get(*entity).unwrap();
+                let marker = q_markers.get(entity).unwrap();
                 let position = travel_section
-                    .length_to(&logical)
-                    .unwrap_or_else(|_| travel_section.length_to(&logical.reversed()).unwrap());
-
+                    .length_to(&logical, ramp_length)
+                    .unwrap_or_else(|_| {
+                        travel_section
+                            .length_to(&logical.reversed(), ramp_length)
+                            .unwrap()
+                    });
+
+                let mut speed = marker.logical_data.get(logical).unwrap().speed;
+                // hubs only know the discrete speeds, so round the limit down to one of those
+                if let Some(limit) = speed_limits.at(position + offset) {
+                    speed = speed.min(TrainSpeed::at_most(limit));
+                }
                 let route_marker = RouteMarkerData {
                     track: logical.clone(),
                     color: marker.color,
-                    speed: marker.logical_data.get(logical).unwrap().speed,
-                    key: marker_map.get_marker_key(logical, target_id),
-                    position: position,
+                    speed,
+                    key,
+                    position: position + offset,
                 };
                 leg_markers.push(route_marker);
             }
@@ -133,6 +163,8 @@ pub fn build_route(
             from_section,
             intention_synced: false,
             greedy: target_block.settings.passthrough,
+            speed_limits,
+            ramp_length,
         };
 
         let block_speed = target_block.settings.speed;
@@ -156,6 +188,7 @@ pub struct Route {
     train_id: TrainID,
     leg_index: usize,
     pub critical_section: LogicalSection,
+    pub ramp_length: f32,
 }
 
 impl Route {
@@ -165,6 +198,7 @@ impl Route {
             train_id: id,
             leg_index: 0,
             critical_section: LogicalSection::new(),
+            ramp_length: RAMP_LENGTH,
         }
     }
 
@@ -175,6 +209,10 @@ impl Route {
             .sum()
     }
 
+    pub fn leg_index(&self) -> usize {
+        self.leg_index
+    }
+
     pub fn num_legs(&self) -> usize {
         self.legs.len()
     }
@@ -216,8 +254,8 @@ impl Route {
         }
         let last_pos = self.get_current_leg().get_signed_pos_from_last();
         self.leg_index += 1;
-        self.get_current_leg_mut()
-            .set_signed_pos_from_first(last_pos);
+        let leg = self.get_current_leg_mut();
+        leg.set_signed_pos_from_first(last_pos + leg.get_signed_first_to_joint());
         return Ok(());
     }
 
@@ -238,13 +276,22 @@ impl Route {
         track_locks: &TrackLocks,
         switches: &Query<&Switch>,
         entity_map: &EntityMap,
+        signals: Option<&SignalAspects>,
+        hold: bool,
     ) {
         let mut free_until = 0;
         for (i, leg) in self.iter_legs_remaining().enumerate() {
+            if hold {
+                break;
+            }
             let section = match leg.get_leg_state() {
                 LegState::Completed => &leg.to_section,
                 _ => &leg.travel_section,
             };
+            // the current leg has already passed its signal
+            if i > 0 && signals.is_some_and(|signals| signals.is_stop(&leg.target_block.block)) {
+                break;
+            }
             if track_locks.can_lock(&self.train_id, section, switches, entity_map) {
                 if !leg.greedy {
                     free_until = i + self.leg_index;
@@ -273,6 +320,21 @@ impl Route {
         }
     }
 
+    // section of the first remaining leg the train can't lock yet
+    pub fn waiting_section(
+        &self,
+        track_locks: &TrackLocks,
+        switches: &Query<&Switch>,
+        entity_map: &EntityMap,
+    ) -> Option<&LogicalSection> {
+        self.iter_legs_remaining()
+            .map(|leg| match leg.get_leg_state() {
+                LegState::Completed => &leg.to_section,
+                _ => &leg.travel_section,
+            })
+            .find(|section| !track_locks.can_lock(&self.train_id, section, switches, entity_map))
+    }
+
     pub fn update_locks(
         &self,
         track_locks: &mut TrackLocks,
@@ -281,6 +343,8 @@ impl Route {
         set_crossing_position: &mut MessageWriter<SetCrossingPositionMessage>,
         switches: &Query<&Switch>,
         crossings: &Query<&LevelCrossing>,
+        margin: usize,
+        reserve_distance: f32,
     ) {
         let current_leg = self.get_current_leg();
         track_locks.unlock_all(&self.train_id);
@@ -328,6 +392,102 @@ impl Route {
                 }
             }
         }
+        if margin > 0 {
+            self.lock_margin(
+                margin,
+                track_locks,
+                entity_map,
+                set_switch_position,
+                set_crossing_position,
+                switches,
+                crossings,
+            );
+        }
+        if reserve_distance > 0.0 {
+            self.lock_reserve(
+                reserve_distance,
+                track_locks,
+                entity_map,
+                set_switch_position,
+                set_crossing_position,
+                switches,
+                crossings,
+            );
+        }
+    }
+
+    // reserve a stretch of the route ahead of the locked track, so the train
+    // doesn't have to slow down at every marker. Keeps the minimal locks if
+    // the stretch is blocked
+    fn lock_reserve(
+        &self,
+        distance: f32,
+        track_locks: &mut TrackLocks,
+        entity_map: &EntityMap,
+        set_switch_position: &mut MessageWriter<SetSwitchPositionMessage>,
+        set_crossing_position: &mut MessageWriter<SetCrossingPositionMessage>,
+        switches: &Query<&Switch>,
+        crossings: &Query<&LevelCrossing>,
+    ) {
+        let Some(last_locked) = self.critical_section.tracks.iter().rposition(|track| {
+            track_locks.locked_tracks.get(&track.track()) == Some(&self.train_id)
+        }) else {
+            return;
+        };
+        let section = self
+            .critical_section
+            .section_ahead(last_locked, distance, self.ramp_length);
+        if section.len() > 1 {
+            track_locks.try_lock(
+                &self.train_id,
+                &section,
+                entity_map,
+                switches,
+                crossings,
+                set_switch_position,
+                set_crossing_position,
+            );
+        }
+    }
+
+    // extend the reservation along the route, stopping at the first track
+    // that can't be locked
+    fn lock_margin(
+        &self,
+        margin: usize,
+        track_locks: &mut TrackLocks,
+        entity_map: &EntityMap,
+        set_switch_position: &mut MessageWriter<SetSwitchPositionMessage>,
+        set_crossing_position: &mut MessageWriter<SetCrossingPositionMessage>,
+        switches: &Query<&Switch>,
+        crossings: &Query<&LevelCrossing>,
+    ) {
+        let tracks = &self.critical_section.tracks;
+        let Some(last_locked) = tracks.iter().rposition(|track| {
+            track_locks.locked_tracks.get(&track.track()) == Some(&self.train_id)
+        }) else {
+            return;
+        };
+        let mut section = LogicalSection::new();
+        section.tracks.push(tracks[last_locked]);
+        for track in tracks.iter().skip(last_locked + 1).take(margin) {
+            section.tracks.push(*track);
+            if !track_locks.can_lock(&self.train_id, &section, switches, entity_map) {
+                section.tracks.pop();
+                break;
+            }
+        }
+        if section.len() > 1 {
+            track_locks.lock(
+                &self.train_id,
+                &section,
+                entity_map,
+                switches,
+                crossings,
+                set_switch_position,
+                set_crossing_position,
+            );
+        }
     }
 
     pub fn advance_sensor(&mut self) -> Result<(), ()> {
@@ -361,6 +521,16 @@ impl Route {
         self.get_current_leg().get_train_state(will_turn)
     }
 
+    // the speed of the train state, clamped by the limits of the blocks the current leg is in
+    pub fn get_target_speed(&self) -> f32 {
+        let leg = self.get_current_leg();
+        let speed = self.get_train_state().get_speed();
+        match leg.speed_limits.at(leg.section_position) {
+            Some(limit) => speed.clamp(-limit, limit),
+            None => speed,
+        }
+    }
+
     pub fn advance_distance(
         &mut self,
         distance: f32,
@@ -376,6 +546,30 @@ impl Route {
     }
 
     pub fn interpolate_offset(&self, offset: f32) -> Vec2 {
+        let (leg, signed_dist) = self.leg_at_offset(offset);
+        leg.interpolate_signed_pos(signed_dist)
+    }
+
+    pub fn interpolate_offset_layer(&self, offset: f32) -> f32 {
+        let (leg, signed_dist) = self.leg_at_offset(offset);
+        leg.interpolate_signed_layer(signed_dist)
+    }
+
+    // first offset from the train in from..to whose position satisfies is_near, sampled every step
+    pub fn offset_near(
+        &self,
+        from: f32,
+        to: f32,
+        step: f32,
+        is_near: impl Fn(Vec2) -> bool,
+    ) -> Option<f32> {
+        let steps = ((to - from) / step).ceil().max(0.0) as usize;
+        (0..=steps)
+            .map(|i| (from + i as f32 * step).min(to))
+            .find(|offset| is_near(self.interpolate_offset(*offset)))
+    }
+
+    fn leg_at_offset(&self, offset: f32) -> (&RouteLeg, f32) {
         let mut leg = self.get_current_leg();
         let mut index = leg.leg_index;
         let mut signed_dist = leg.get_signed_pos_from_first() + offset;
@@ -397,7 +591,8 @@ impl Route {
                     let Some(next_leg) = self.legs.get(index) else {
                         break;
                     };
-                    signed_dist += next_leg.get_signed_first_to_last();
+                    signed_dist +=
+                        next_leg.get_signed_first_to_last() - leg.get_signed_first_to_joint();
                     next_leg
                 }
                 LegDistInRange::After => {
@@ -408,7 +603,8 @@ impl Route {
                     let Some(next_leg) = self.legs.get(index) else {
                         break;
                     };
-                    signed_dist -= leg.get_signed_first_to_last();
+                    signed_dist +=
+                        next_leg.get_signed_first_to_joint() - leg.get_signed_first_to_last();
                     next_leg
                 }
                 _ => panic!("Invalid leg dist range {:?}", in_range),
@@ -420,7 +616,7 @@ impl Route {
 
         // println!("final choice: {}", leg.essential_string());
 
-        leg.interpolate_signed_pos(signed_dist)
+        (leg, signed_dist)
     }
 
     pub fn draw_with_gizmos(&self, gizmos: &mut Gizmos) {
@@ -479,6 +675,26 @@ pub enum LegDistInRange {
     After,
 }
 
+// positions are along the travel section of the leg
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct LegSpeedLimits {
+    from: Option<f32>,
+    from_end: f32,
+    target: Option<f32>,
+    target_start: f32,
+}
+
+impl LegSpeedLimits {
+    fn at(&self, position: f32) -> Option<f32> {
+        let from = self.from.filter(|_| position < self.from_end);
+        let target = self.target.filter(|_| position >= self.target_start);
+        match (from, target) {
+            (Some(from), Some(target)) => Some(from.min(target)),
+            (from, target) => from.or(target),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RouteLeg {
     pub to_section: LogicalSection,
@@ -493,6 +709,8 @@ pub struct RouteLeg {
     from_block: LogicalBlockID,
     pub intention_synced: bool,
     greedy: bool,
+    speed_limits: LegSpeedLimits,
+    pub ramp_length: f32,
 }
 
 impl RouteLeg {
@@ -578,7 +796,8 @@ impl RouteLeg {
     }
 
     pub fn get_current_pos(&self) -> Vec2 {
-        self.travel_section.interpolate_pos(self.section_position)
+        self.travel_section
+            .interpolate_pos(self.section_position, self.ramp_length)
     }
 
     pub fn get_target_block_id(&self) -> LogicalBlockID {
@@ -601,6 +820,22 @@ impl RouteLeg {
         self.markers.last().unwrap().position
     }
 
+    // keeps the train short of the physical end when the leg ends at a buffer stop
+    pub fn clamp_to_dead_end(&mut self, connections: &Connections, clearance: f32) {
+        let last = *self.travel_section.tracks.last().unwrap();
+        if connections
+            .iter_next_tracks(last)
+            .any(|next| next != last.reversed())
+        {
+            return;
+        }
+        let limit = (self.travel_section.length(self.ramp_length) - clearance)
+            .max(self.get_last_marker_pos());
+        // position runs negative for trains facing backward, so clamp its magnitude
+        let sign = self.get_final_facing().get_sign();
+        self.section_position = (self.section_position * sign).min(limit) * sign;
+    }
+
     pub fn advance_distance(&mut self, distance: f32) -> Option<usize> {
         let facing_sign = self.get_final_facing().get_sign();
         self.section_position += distance * facing_sign;
@@ -615,7 +850,15 @@ impl RouteLeg {
             offset = -offset;
         }
         self.travel_section
-            .interpolate_pos(self.get_first_marker_pos() + offset)
+            .interpolate_pos(self.get_first_marker_pos() + offset, self.ramp_length)
+    }
+
+    pub fn interpolate_signed_layer(&self, mut offset: f32) -> f32 {
+        if self.get_final_facing() == Facing::Backward {
+            offset = -offset;
+        }
+        self.travel_section
+            .interpolate_layer(self.get_first_marker_pos() + offset, self.ramp_length)
     }
 
     pub fn signed_pos_in_section(&self, dist: f32) -> LegDistInRange {
@@ -660,6 +903,18 @@ impl RouteLeg {
             * self.get_final_facing().get_sign()
     }
 
+    // the previous leg ends at the last marker on the first track, not always the first marker
+    pub fn get_signed_first_to_joint(&self) -> f32 {
+        let first_track = self.markers[0].track;
+        let joint = self
+            .markers
+            .iter()
+            .take_while(|marker| marker.track == first_track)
+            .last()
+            .unwrap();
+        (joint.position - self.get_first_marker_pos()) * self.get_final_facing().get_sign()
+    }
+
     pub fn get_prev_marker_signed_from_first(&self, offset: f32) -> f32 {
         (self.get_previous_marker_pos() - self.get_first_marker_pos())
             * self.get_final_facing().get_sign()
@@ -702,3 +957,40 @@ impl fmt::Display for RouteLeg {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_leg_speed_limits() {
+        assert_eq!(LegSpeedLimits::default().at(1.0), None);
+
+        let leaving = LegSpeedLimits {
+            from: Some(0.5),
+            from_end: 2.0,
+            ..default()
+        };
+        assert_eq!(leaving.at(0.0), Some(0.5));
+        assert_eq!(leaving.at(2.0), None);
+
+        let entering = LegSpeedLimits {
+            target: Some(0.3),
+            target_start: 4.0,
+            ..default()
+        };
+        assert_eq!(entering.at(3.9), None);
+        assert_eq!(entering.at(4.0), Some(0.3));
+
+        // on a short leg both blocks can limit the same position
+        let both = LegSpeedLimits {
+            from: Some(0.5),
+            from_end: 2.0,
+            target: Some(0.8),
+            target_start: 1.0,
+        };
+        assert_eq!(both.at(0.5), Some(0.5));
+        assert_eq!(both.at(1.5), Some(0.5));
+        assert_eq!(both.at(2.5), Some(0.8));
+    }
+}
@@ -12,7 +12,7 @@ use crate::{
     marker::{Marker, MarkerKey, Markers},
     route::RouteMarkerData,
     section::LogicalSection,
-    track::LAYOUT_SCALE,
+    track::{LAYOUT_SCALE, PortalSettings},
     train::MarkerAdvanceMessage,
 };
 
@@ -182,8 +182,10 @@ fn build_route_leg(
     tracks: Query<(Option<&InTrackOf>, Option<&Markers>)>,
     entity_map: Res<EntityMap>,
     markers_query: Query<&Marker>,
+    portal_settings: Res<PortalSettings>,
 ) {
     println!("Building modular route leg...");
+    let ramp_length = portal_settings.ramp_length;
     let critical_path = &critical_paths.get(trigger.entity).unwrap().section;
     let from_track = critical_path.tracks.first().unwrap();
     let to_track = critical_path.tracks.last().unwrap();
@@ -246,22 +248,35 @@ fn build_route_leg(
         let track_entity = entity_map.tracks[&logical.track()];
         let (_, maybe_marker) = tracks.get(track_entity).unwrap();
         if let Some(markers) = maybe_marker {
-            let marker = markers_query
-                .get(*markers.collection().first().unwrap())
-                .unwrap();
-            println!("    marker: {:?}", marker);
-            let position = travel_section
-                .length_to(&logical)
-                .unwrap_or_else(|_| travel_section.length_to(&logical.reversed()).unwrap());
-
-            let route_marker = RouteMarkerData {
-                track: logical.clone(),
-                color: marker.color,
-                speed: marker.logical_data.get(logical).unwrap().speed,
-                key: MarkerKey::None,
-                position: position,
-            };
-            leg_markers.push(route_marker);
+            let mut track_markers = markers
+                .collection()
+                .iter()
+                .map(|entity| markers_query.get(*entity).unwrap())
+                .collect::<Vec<_>>();
+            track_markers.sort_by(|a, b| {
+                let offset_a = a.marker_id().travel_offset(&logical.dirtrack);
+                let offset_b = b.marker_id().travel_offset(&logical.dirtrack);
+                offset_a.total_cmp(&offset_b)
+            });
+            for marker in track_markers {
+                println!("    marker: {:?}", marker);
+                let position = travel_section
+                    .length_to(&logical, ramp_length)
+                    .unwrap_or_else(|_| {
+                        travel_section
+                            .length_to(&logical.reversed(), ramp_length)
+                            .unwrap()
+                    });
+
+                let route_marker = RouteMarkerData {
+                    track: logical.clone(),
+                    color: marker.color,
+                    speed: marker.logical_data.get(logical).unwrap().speed,
+                    key: MarkerKey::None,
+                    position: position + marker.marker_id().travel_offset(&logical.dirtrack),
+                };
+                leg_markers.push(route_marker);
+            }
         }
     }
 
@@ -554,6 +569,14 @@ impl TrainSpeed {
         }
     }
 
+    // the fastest speed within the limit, or the slowest one if none is
+    pub fn at_most(limit: f32) -> Self {
+        [TrainSpeed::Fast, TrainSpeed::Cruise]
+            .into_iter()
+            .find(|speed| speed.get_speed() <= limit)
+            .unwrap_or(TrainSpeed::Slow)
+    }
+
     pub fn as_train_u8(&self) -> u8 {
         match self {
             TrainSpeed::Slow => 2,
@@ -566,12 +589,16 @@ impl TrainSpeed {
 fn debug_draw_train(
     train_query: Query<(&AssignedRouteLeg, &LegPosition)>,
     legs: Query<&RouteLegTravelSection>,
+    portal_settings: Res<PortalSettings>,
     mut gizmos: Gizmos,
 ) {
     for (leg_assigned, leg_position) in train_query.iter() {
         let leg_entity = leg_assigned.0;
         if let Ok(leg_section) = legs.get(leg_entity) {
-            let pos = leg_section.section.interpolate_pos(leg_position.position) * LAYOUT_SCALE;
+            let pos = leg_section
+                .section
+                .interpolate_pos(leg_position.position, portal_settings.ramp_length)
+                * LAYOUT_SCALE;
             gizmos.circle_2d(pos, 10.0, LIME_100);
         }
     }
@@ -605,11 +632,17 @@ impl Plugin for ModularRoutePlugin {
             Update,
             (
                 move_trains,
-                draw_route,
                 assign_first_route_leg,
-                debug_draw_train,
                 (advance_route_leg, advance_marker_index, update_train_state).chain(),
             ),
         );
     }
 }
+
+pub struct ModularRouteRenderPlugin;
+
+impl Plugin for ModularRouteRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (draw_route, debug_draw_train));
+    }
+}
@@ -0,0 +1,396 @@
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::reflect::Reflect;
+use petgraph::graphmap::DiGraphMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{layout::MarkerMap, layout_primitives::*, section::LogicalSection};
+
+pub type LogicalGraph = DiGraphMap<LogicalTrackID, ()>;
+
+// extra cost per edge, to bias routes towards mainline running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Reflect, Serialize, Deserialize)]
+pub struct RoutePreferences {
+    pub curve_penalty: f32,
+    pub switch_penalty: f32,
+}
+
+// plain inputs for route finding, so it can run without a bevy world
+pub struct RouteConstraints<'a> {
+    pub prefer_facing: FacingPreference,
+    pub blocked_tracks: &'a HashSet<LogicalTrackID>,
+    pub can_pass: Option<&'a dyn Fn(LogicalTrackID, LogicalTrackID) -> bool>,
+    pub preferences: RoutePreferences,
+    pub is_switch: Option<&'a dyn Fn(DirectedTrackID) -> bool>,
+    pub avoided_tracks: Option<&'a HashSet<TrackID>>,
+}
+
+impl<'a> RouteConstraints<'a> {
+    pub fn new(
+        prefer_facing: FacingPreference,
+        blocked_tracks: &'a HashSet<LogicalTrackID>,
+    ) -> Self {
+        Self {
+            prefer_facing,
+            blocked_tracks,
+            can_pass: None,
+            preferences: RoutePreferences::default(),
+            is_switch: None,
+            avoided_tracks: None,
+        }
+    }
+
+    pub fn with_avoided_tracks(mut self, avoided_tracks: &'a HashSet<TrackID>) -> Self {
+        self.avoided_tracks = Some(avoided_tracks);
+        self
+    }
+
+    fn avoids(&self, track: &LogicalTrackID) -> bool {
+        self.avoided_tracks
+            .is_some_and(|avoided| avoided.contains(&track.track()))
+    }
+
+    pub fn with_preferences(
+        mut self,
+        preferences: RoutePreferences,
+        is_switch: &'a dyn Fn(DirectedTrackID) -> bool,
+    ) -> Self {
+        self.preferences = preferences;
+        self.is_switch = Some(is_switch);
+        self
+    }
+
+    pub fn with_can_pass(
+        mut self,
+        can_pass: &'a dyn Fn(LogicalTrackID, LogicalTrackID) -> bool,
+    ) -> Self {
+        self.can_pass = Some(can_pass);
+        self
+    }
+
+    pub fn edge_cost(&self, a: LogicalTrackID, b: LogicalTrackID) -> f32 {
+        let mut cost = 1.0;
+        if self.blocked_tracks.contains(&b) {
+            cost += f32::INFINITY;
+        }
+        if let Some(can_pass) = self.can_pass {
+            if !can_pass(a, b) {
+                cost += f32::INFINITY;
+            }
+        }
+        if !self.prefer_facing.allows(b.facing) {
+            cost += f32::INFINITY;
+        }
+        if self.avoids(&b) {
+            cost += f32::INFINITY;
+        }
+        cost += self.prefer_facing.cost(b.facing);
+        if self.preferences.curve_penalty != 0.0
+            && LogicalTrackConnectionID::new(a, b)
+                .to_directed()
+                .curve_index()
+                != 0
+        {
+            cost += self.preferences.curve_penalty;
+        }
+        if let Some(is_switch) = self.is_switch {
+            if self.preferences.switch_penalty != 0.0 && is_switch(a.dirtrack) {
+                cost += self.preferences.switch_penalty;
+            }
+        }
+        cost
+    }
+}
+
+pub fn find_route_section(
+    graph: &LogicalGraph,
+    start: LogicalBlockID,
+    target: LogicalBlockID,
+    constraints: &RouteConstraints,
+) -> Option<LogicalSection> {
+    let start_track = start.default_in_marker_track();
+    let target_track = target.default_in_marker_track();
+    if !constraints.prefer_facing.allows(target_track.facing)
+        || constraints.blocked_tracks.contains(&target_track)
+        || constraints.avoids(&target_track)
+    {
+        return None;
+    }
+    let (_, path) = petgraph::algo::astar(
+        graph,
+        start_track,
+        |track| track == target_track,
+        |(a, b, _)| constraints.edge_cost(a, b),
+        |track| {
+            let delta = track.cell().get_delta_vec(&target_track.cell());
+            delta.x.abs() + delta.y.abs()
+        },
+    )?;
+    if !path
+        .iter()
+        .all(|track| constraints.prefer_facing.allows(track.facing))
+    {
+        return None;
+    }
+    // the start block may have been restricted while the train was in it
+    if path
+        .iter()
+        .skip(1)
+        .any(|track| constraints.blocked_tracks.contains(track))
+    {
+        return None;
+    }
+    if path.iter().any(|track| constraints.avoids(track)) {
+        return None;
+    }
+    Some(LogicalSection { tracks: path })
+}
+
+pub fn dijkstra(
+    graph: &LogicalGraph,
+    start: LogicalBlockID,
+    targets: &[LogicalBlockID],
+    constraints: &RouteConstraints,
+) -> HashMap<LogicalBlockID, f32> {
+    let start_node = start.default_in_marker_track();
+    let result = petgraph::algo::dijkstra(graph, start_node, None, |(a, b, _)| {
+        constraints.edge_cost(a, b)
+    });
+    let target_nodes = targets
+        .iter()
+        .map(|target| (target.default_in_marker_track(), target))
+        .collect::<HashMap<_, _>>();
+    let mut filtered_result = HashMap::new();
+    for (track, cost) in result.iter() {
+        if let Some(block) = target_nodes.get(track) {
+            if constraints.blocked_tracks.contains(track) {
+                continue;
+            }
+            filtered_result.insert(**block, *cost);
+        }
+    }
+    filtered_result
+}
+
+// stable text form of a route, one line per leg as it would be built from
+// the section: leg index, target block and the leg's tracks
+pub fn route_snapshot(section: &LogicalSection, marker_map: &MarkerMap) -> String {
+    let in_tracks = marker_map.in_markers.keys().cloned().collect::<Vec<_>>();
+    let mut snapshot = String::new();
+    for (i, (leg, in_track)) in section
+        .split_by_tracks_with_overlap(in_tracks)
+        .into_iter()
+        .enumerate()
+    {
+        let tracks = leg
+            .tracks
+            .iter()
+            .map(|track| track.get_name())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let target = marker_map.in_markers.get(&in_track).unwrap();
+        snapshot.push_str(&format!("{} {}: {}\n", i, target.get_name(), tracks));
+    }
+    snapshot
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{layout::Connections, marker::MarkerKey, track::TrackLogicalFilter};
+
+    fn straight(x: i32, y: i32) -> LogicalTrackID {
+        TrackID::new(CellID::new(x, y, 0), Orientation::EW)
+            .get_directed(TrackDirection::First)
+            .get_logical(Facing::Forward)
+    }
+
+    fn block(from: LogicalTrackID, to: LogicalTrackID) -> LogicalBlockID {
+        BlockID::new(from.dirtrack, to.dirtrack.opposite())
+            .to_logical(BlockDirection::Aligned, Facing::Forward)
+    }
+
+    fn connect(graph: &mut LogicalGraph, tracks: &[LogicalTrackID]) {
+        for (a, b) in tracks.iter().zip(tracks.iter().skip(1)) {
+            graph.add_edge(*a, *b, ());
+        }
+    }
+
+    // A -> C -> B is the short way, A -> D -> B the detour
+    fn example_graph() -> (LogicalGraph, [LogicalBlockID; 4]) {
+        let a = [straight(0, 0), straight(1, 0)];
+        let c = [straight(3, 0), straight(4, 0)];
+        let d = [straight(3, 2), straight(4, 2), straight(5, 2)];
+        let b = [straight(6, 0), straight(7, 0)];
+        let mut graph = LogicalGraph::new();
+        connect(&mut graph, &[a[0], a[1], c[0], c[1], b[0], b[1]]);
+        connect(&mut graph, &[a[1], d[0], d[1], d[2], b[0]]);
+        let blocks = [
+            block(a[0], a[1]),
+            block(b[0], b[1]),
+            block(c[0], c[1]),
+            block(d[0], d[2]),
+        ];
+        (graph, blocks)
+    }
+
+    struct Fixture {
+        connections: Connections,
+        marker_map: MarkerMap,
+        blocks: HashMap<String, BlockID>,
+    }
+
+    // builds the logical graph like spawning the tracks, connections and blocks would
+    fn load_fixture(text: &str) -> Fixture {
+        let mut connections = Connections::default();
+        let mut marker_map = MarkerMap::default();
+        let mut blocks = HashMap::new();
+        let mut tracks: Vec<TrackID> = vec![];
+        let cell = |x: &str, y: &str| CellID::new(x.parse().unwrap(), y.parse().unwrap(), 0);
+        for line in text.lines().map(str::trim) {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            match parts[..] {
+                ["track", x, y, orientation] => {
+                    let track =
+                        TrackID::new(cell(x, y), Orientation::from_name(orientation).unwrap());
+                    connections.add_filtered_track(track, &TrackLogicalFilter::default());
+                    for other in tracks.iter() {
+                        for a in track.dirtracks() {
+                            for b in other.dirtracks() {
+                                if a.to_slot() == b.to_slot() {
+                                    connections
+                                        .connect_tracks_simple(&TrackConnectionID::new(a, b));
+                                }
+                            }
+                        }
+                    }
+                    tracks.push(track);
+                }
+                ["block", name, x1, y1, x2, y2] => {
+                    let track_at = |cell: CellID| {
+                        *tracks
+                            .iter()
+                            .find(|t| t.get_directed(TrackDirection::First).cell() == cell)
+                            .unwrap()
+                    };
+                    let first = track_at(cell(x1, y1)).get_directed(TrackDirection::First);
+                    let last = track_at(cell(x2, y2)).get_directed(TrackDirection::First);
+                    let block = BlockID::new(first, last.opposite());
+                    for logical_block in block.logical_block_ids() {
+                        let in_track = logical_block.default_in_marker_track();
+                        connections.connect_tracks(&in_track, &in_track.reversed());
+                        marker_map.register_marker(in_track, MarkerKey::In, logical_block);
+                    }
+                    blocks.insert(name.to_string(), block);
+                }
+                _ => assert!(
+                    line.is_empty() || line.starts_with('#'),
+                    "bad line {}",
+                    line
+                ),
+            }
+        }
+        Fixture {
+            connections,
+            marker_map,
+            blocks,
+        }
+    }
+
+    #[test]
+    fn test_route_snapshot() {
+        let fixture = load_fixture(include_str!("../fixtures/routing/line.txt"));
+        let start = fixture.blocks["A"].to_logical(BlockDirection::Aligned, Facing::Forward);
+        let target = fixture.blocks["B"].to_logical(BlockDirection::Aligned, Facing::Forward);
+        let constraints =
+            RouteConstraints::new(FacingPreference::Any, &fixture.connections.blocked_tracks);
+        let section = find_route_section(
+            &fixture.connections.logical_graph,
+            start,
+            target,
+            &constraints,
+        )
+        .unwrap();
+        assert_eq!(
+            route_snapshot(&section, &fixture.marker_map),
+            include_str!("../fixtures/routing/line_a_to_b.snap")
+        );
+    }
+
+    #[test]
+    fn test_route_avoiding_block() {
+        let (graph, [a, b, c, _]) = example_graph();
+        let blocked = HashSet::new();
+
+        let constraints = RouteConstraints::new(FacingPreference::Any, &blocked);
+        let section = find_route_section(&graph, a, b, &constraints).unwrap();
+        assert_eq!(section.len(), 5);
+        assert!(section.tracks.contains(&c.default_in_marker_track()));
+
+        let avoid = c.block;
+        let can_pass = |_, to: LogicalTrackID| {
+            to.dirtrack.track != avoid.track1.track && to.dirtrack.track != avoid.track2.track
+        };
+        let constraints =
+            RouteConstraints::new(FacingPreference::Any, &blocked).with_can_pass(&can_pass);
+        let section = find_route_section(&graph, a, b, &constraints).unwrap();
+        assert_eq!(section.len(), 6);
+        assert!(section.tracks.iter().all(|track| can_pass(*track, *track)));
+    }
+
+    #[test]
+    fn test_route_avoided_tracks() {
+        let (graph, [a, b, c, _]) = example_graph();
+        let blocked = HashSet::new();
+
+        let avoided = HashSet::from_iter([c.block.track1.track, c.block.track2.track]);
+        let constraints =
+            RouteConstraints::new(FacingPreference::Any, &blocked).with_avoided_tracks(&avoided);
+        let section = find_route_section(&graph, a, b, &constraints).unwrap();
+        assert_eq!(section.len(), 6);
+        assert!(!section.tracks.contains(&c.default_in_marker_track()));
+        assert!(find_route_section(&graph, a, c, &constraints).is_none());
+    }
+
+    #[test]
+    fn test_route_preferences() {
+        let (graph, [a, b, c, _]) = example_graph();
+        let blocked = HashSet::new();
+        let switch_track = c.default_in_marker_track().dirtrack;
+        let is_switch = |dirtrack| dirtrack == switch_track;
+
+        let constraints = RouteConstraints::new(FacingPreference::Any, &blocked)
+            .with_preferences(RoutePreferences::default(), &is_switch);
+        let section = find_route_section(&graph, a, b, &constraints).unwrap();
+        assert_eq!(section.len(), 5);
+
+        let preferences = RoutePreferences {
+            curve_penalty: 0.0,
+            switch_penalty: 5.0,
+        };
+        let constraints = RouteConstraints::new(FacingPreference::Any, &blocked)
+            .with_preferences(preferences, &is_switch);
+        let section = find_route_section(&graph, a, b, &constraints).unwrap();
+        assert_eq!(section.len(), 6);
+        assert!(!section.tracks.contains(&c.default_in_marker_track()));
+    }
+
+    #[test]
+    fn test_route_blocked_and_facing() {
+        let (graph, [a, b, c, d]) = example_graph();
+
+        let blocked = HashSet::from_iter([b.default_in_marker_track()]);
+        let constraints = RouteConstraints::new(FacingPreference::Any, &blocked);
+        assert!(find_route_section(&graph, a, b, &constraints).is_none());
+        assert!(find_route_section(&graph, a, c, &constraints).is_some());
+
+        let blocked = HashSet::new();
+        let constraints = RouteConstraints::new(FacingPreference::Only(Facing::Backward), &blocked);
+        assert!(find_route_section(&graph, a, b, &constraints).is_none());
+
+        let constraints = RouteConstraints::new(FacingPreference::Any, &blocked);
+        let costs = dijkstra(&graph, a, &[b, c, d], &constraints);
+        assert_eq!(costs.get(&c), Some(&2.0));
+        assert_eq!(costs.get(&d), Some(&3.0));
+        assert_eq!(costs.get(&b), Some(&4.0));
+    }
+}
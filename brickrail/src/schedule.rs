@@ -3,16 +3,23 @@ use bevy::{
     prelude::*,
 };
 use bevy_inspector_egui::egui::{self, CollapsingHeader, Grid, RichText, Ui};
+use petgraph::graphmap::DiGraphMap;
+use rand::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
 use crate::{
-    destination::Destination,
-    editor::{ControlState, ControlStateMode, GenericID, SelectionState},
+    block::Block,
+    destination::{ArrivalAction, Destination, DestinationArrivalMessage},
+    editor::{ControlState, ControlStateMode, GenericID, SelectionState, control_running},
     inspector::{Inspectable, InspectorPlugin},
     layout::EntityMap,
-    layout_primitives::{DestinationID, ScheduleID},
+    layout_primitives::{BlockID, DestinationID, ScheduleID, TrainID},
     selectable::{Selectable, SelectablePlugin, SelectableType},
-    train::{PlanRouteEvent, QueuedDestination, TargetChoiceStrategy, WaitTime, set_train_route},
+    train::{
+        Idle, PlanRouteEvent, QueuedDestination, TargetChoiceStrategy, Train, WaitTime,
+        set_train_route,
+    },
 };
 
 #[derive(Debug, Component, Clone, Serialize, Deserialize, Default)]
@@ -21,6 +28,11 @@ pub struct AssignedSchedule {
     pub offset: f32,
     #[serde(skip)]
     pub current_stop_index: usize,
+    #[serde(skip)]
+    pub finished: bool,
+    // timed departures that fired while the train was still busy
+    #[serde(skip)]
+    pub pending_departures: VecDeque<DestinationID>,
 }
 
 impl AssignedSchedule {
@@ -30,23 +42,52 @@ impl AssignedSchedule {
         time: f32,
         wait_time: f32,
     ) -> Option<QueuedDestination> {
+        if self.finished || schedule.entries.is_empty() {
+            return None;
+        }
         let current_stop = self.curent_stop(schedule);
 
         if self.next_departure(time, schedule) < 0.0 && wait_time >= current_stop.min_wait {
-            self.current_stop_index += 1;
-            if self.current_stop_index >= schedule.entries.len() {
-                self.current_stop_index = 0;
-            }
-            let current_stop = schedule.entries[self.current_stop_index].clone();
-            return Some(QueuedDestination {
-                dest: current_stop.dest.unwrap(),
-                strategy: TargetChoiceStrategy::Closest,
-                allow_locked: false,
-            });
+            return self.skip_to_next_stop(schedule);
         }
         None
     }
 
+    pub fn skip_to_next_stop(&mut self, schedule: &TrainSchedule) -> Option<QueuedDestination> {
+        if schedule.entries.is_empty() || self.finished {
+            return None;
+        }
+        self.current_stop_index += 1;
+        if self.current_stop_index >= schedule.entries.len() {
+            self.current_stop_index -= 1;
+            match schedule.completion {
+                ScheduleCompletion::Loop => {
+                    self.current_stop_index = 0;
+                }
+                ScheduleCompletion::Stop => {
+                    self.finished = true;
+                    return None;
+                }
+                ScheduleCompletion::ReturnHome => {
+                    self.finished = true;
+                    return Some(QueuedDestination {
+                        dest: DestinationID::Home,
+                        strategy: None,
+                        allow_locked: false,
+                        after: None,
+                    });
+                }
+            }
+        }
+        let current_stop = schedule.entries[self.current_stop_index].clone();
+        Some(QueuedDestination {
+            dest: current_stop.dest?,
+            strategy: None,
+            allow_locked: false,
+            after: current_stop.after,
+        })
+    }
+
     pub fn curent_stop(&self, schedule: &TrainSchedule) -> ScheduleEntry {
         schedule.entries[self.current_stop_index].clone()
     }
@@ -78,6 +119,9 @@ pub struct ScheduleEntry {
     pub dest: Option<DestinationID>,
     pub depart_time: f32,
     pub min_wait: f32,
+    // wait until this train has reached the block before departing
+    #[serde(default)]
+    pub after: Option<(TrainID, BlockID)>,
 }
 
 impl Default for ScheduleEntry {
@@ -86,10 +130,64 @@ impl Default for ScheduleEntry {
             dest: None,
             depart_time: 0.0,
             min_wait: 4.0,
+            after: None,
+        }
+    }
+}
+
+// departs at a fixed control time, independent of the stops' cycle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedDeparture {
+    pub dest: Option<DestinationID>,
+    pub depart_at: f32,
+    // departs only once if not positive
+    pub repeat_every: f32,
+}
+
+impl Default for TimedDeparture {
+    fn default() -> Self {
+        Self {
+            dest: None,
+            depart_at: 0.0,
+            repeat_every: 0.0,
+        }
+    }
+}
+
+impl TimedDeparture {
+    // number of departures strictly before `time`
+    fn departures_before(&self, time: f32) -> i64 {
+        if time <= self.depart_at {
+            return 0;
+        }
+        if self.repeat_every <= 0.0 {
+            return 1;
+        }
+        ((time - self.depart_at) / self.repeat_every).ceil() as i64
+    }
+
+    // whether a departure falls in [from, to), several repetitions count once
+    pub fn departs_between(&self, from: f32, to: f32) -> bool {
+        self.departures_before(to) > self.departures_before(from)
+    }
+
+    pub fn next_departure(&self, time: f32) -> Option<f32> {
+        let count = self.departures_before(time);
+        if self.repeat_every <= 0.0 {
+            return (count == 0).then_some(self.depart_at);
         }
+        Some(self.depart_at + count as f32 * self.repeat_every)
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleCompletion {
+    #[default]
+    Loop,
+    Stop,
+    ReturnHome,
+}
+
 #[derive(Debug, Component, Clone, Serialize, Deserialize)]
 pub struct TrainSchedule {
     pub id: ScheduleID,
@@ -98,6 +196,10 @@ pub struct TrainSchedule {
     pub current: usize,
     pub cycle_length: f32,
     pub cycle_offset: f32,
+    #[serde(default)]
+    pub completion: ScheduleCompletion,
+    #[serde(default)]
+    pub departures: Vec<TimedDeparture>,
 }
 
 impl TrainSchedule {
@@ -108,6 +210,8 @@ impl TrainSchedule {
             current: 0,
             cycle_length: 0.0,
             cycle_offset: 0.0,
+            completion: ScheduleCompletion::default(),
+            departures: vec![],
         }
     }
 
@@ -120,6 +224,8 @@ impl TrainSchedule {
             Res<AppTypeRegistry>,
             Query<(&Name, &AssignedSchedule, Option<&WaitTime>)>,
             Res<ControlInfo>,
+            Query<(&Train, Option<&Name>)>,
+            Query<(&Block, Option<&Name>)>,
         )>::new(world);
         let (
             mut schedules,
@@ -129,6 +235,8 @@ impl TrainSchedule {
             _type_registry,
             q_assigned,
             control_info,
+            trains,
+            blocks,
         ) = state.get_mut(world);
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut schedule) = schedules.get_mut(entity) {
@@ -141,6 +249,24 @@ impl TrainSchedule {
                     ui.label("Cycle offset [s]");
                     ui.add(egui::DragValue::new(&mut schedule.cycle_offset));
                     ui.end_row();
+
+                    ui.label("On completion");
+                    egui::ComboBox::from_id_salt("completion")
+                        .selected_text(format!("{:?}", schedule.completion))
+                        .show_ui(ui, |ui| {
+                            for completion in [
+                                ScheduleCompletion::Loop,
+                                ScheduleCompletion::Stop,
+                                ScheduleCompletion::ReturnHome,
+                            ] {
+                                ui.selectable_value(
+                                    &mut schedule.completion,
+                                    completion,
+                                    format!("{:?}", completion),
+                                );
+                            }
+                        });
+                    ui.end_row();
                 });
                 ui.heading("Stops");
                 let mut remove_stop = None;
@@ -162,6 +288,27 @@ impl TrainSchedule {
                             ui.label("Minimum wait time [s]");
                             ui.add(egui::DragValue::new(&mut entry.min_wait));
                             ui.end_row();
+                            ui.label("After train");
+                            let mut after_train = entry.after.map(|(train, _)| train);
+                            ui.push_id("after_train", |ui| {
+                                Train::selector_option(&trains, ui, &mut after_train)
+                            });
+                            ui.end_row();
+                            let after_block = entry
+                                .after
+                                .map(|(_, block)| block)
+                                .or_else(|| blocks.iter().map(|(block, _)| block.id).min());
+                            entry.after = match (after_train, after_block) {
+                                (Some(train), Some(mut block)) => {
+                                    ui.label("reached block");
+                                    ui.push_id("after_block", |ui| {
+                                        Block::selector(&blocks, ui, &mut block)
+                                    });
+                                    ui.end_row();
+                                    Some((train, block))
+                                }
+                                _ => None,
+                            };
                             if ui.button("Remove stop").clicked() {
                                 remove_stop = Some(i);
                             }
@@ -174,6 +321,46 @@ impl TrainSchedule {
                 if ui.button("Add stop").clicked() {
                     schedule.entries.push(ScheduleEntry::default());
                 }
+                ui.heading("Timed departures");
+                let mut remove_departure = None;
+                for (i, departure) in schedule.departures.iter_mut().enumerate() {
+                    CollapsingHeader::new(format!(
+                        "Departure {}: {}",
+                        i + 1,
+                        Destination::label_from_query(&departure.dest, &destinations)
+                    ))
+                    .id_salt(("departure", i))
+                    .show(ui, |ui| {
+                        Grid::new("departure").show(ui, |ui| {
+                            ui.label("Destination");
+                            Destination::selector_option(&destinations, ui, &mut departure.dest);
+                            ui.end_row();
+                            ui.label("Depart at [s]");
+                            ui.add(egui::DragValue::new(&mut departure.depart_at));
+                            ui.end_row();
+                            ui.label("Repeat every [s]");
+                            ui.add(
+                                egui::DragValue::new(&mut departure.repeat_every)
+                                    .range(0.0..=f32::MAX),
+                            );
+                            ui.end_row();
+                            if let Some(next) = departure.next_departure(control_info.time) {
+                                ui.label("Next departure");
+                                ui.label(format!("{:1.1}", next));
+                                ui.end_row();
+                            }
+                            if ui.button("Remove departure").clicked() {
+                                remove_departure = Some(i);
+                            }
+                        });
+                    });
+                }
+                if let Some(i) = remove_departure {
+                    schedule.departures.remove(i);
+                }
+                if ui.button("Add departure").clicked() {
+                    schedule.departures.push(TimedDeparture::default());
+                }
                 ui.separator();
                 ui.label(RichText::new("Assigned trains").heading().strong());
                 for (name, assigned, wait_option) in q_assigned.iter() {
@@ -182,6 +369,15 @@ impl TrainSchedule {
                         continue;
                     }
                     ui.label(RichText::new(format!("{}", name)).heading());
+                    if !assigned.pending_departures.is_empty() {
+                        ui.label(format!(
+                            "Pending departures: {}",
+                            assigned.pending_departures.len()
+                        ));
+                    }
+                    if schedule.entries.is_empty() {
+                        continue;
+                    }
                     let cycle_time = assigned.cycle_time(control_info.time, &schedule);
                     let current_stop = assigned.curent_stop(&schedule);
                     let next_departure = assigned.next_departure(control_info.time, &schedule);
@@ -198,6 +394,9 @@ impl TrainSchedule {
                     ));
                     ui.label(format!("Next departure: {:1.1}", next_departure));
                     ui.label(format!("Cycle time: {:1.1}", cycle_time,));
+                    if assigned.finished {
+                        ui.label("Schedule completed");
+                    }
                     if let Some(wait_time) = wait_option {
                         ui.label(format!("Wait time: {:1.1}", wait_time.time));
                     }
@@ -272,6 +471,7 @@ impl SpawnScheduleMessageQuery<'_, '_> {
 pub struct ControlInfo {
     pub time: f32,
     pub wait_time: f32,
+    pub idle_time: f32,
 }
 
 impl Default for ControlInfo {
@@ -279,12 +479,46 @@ impl Default for ControlInfo {
         Self {
             time: 0.0,
             wait_time: 4.0,
+            idle_time: 10.0,
         }
     }
 }
 
+// `--seed <n>` fixes the seed of random control, e.g. to replay a run that went wrong
+fn seed_from_args() -> Option<u64> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let index = args.iter().position(|arg| arg == "--seed")?;
+    args.get(index + 1)?.parse().ok()
+}
+
+/// Seed for the random choices made while controlling the layout.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct RandomSeed(pub u64);
+
+impl Default for RandomSeed {
+    fn default() -> Self {
+        Self(seed_from_args().unwrap_or_else(rand::random))
+    }
+}
+
+/// Reseeded from `RandomSeed` whenever control starts, so the same seed and layout make the
+/// same choices.
+#[derive(Resource)]
+pub struct ControlRng(pub StdRng);
+
+impl ControlRng {
+    pub fn from_seed(seed: RandomSeed) -> Self {
+        Self(StdRng::seed_from_u64(seed.0))
+    }
+}
+
+fn reseed_control_rng(seed: Res<RandomSeed>, mut rng: ResMut<ControlRng>) {
+    println!("Random control seed: {}", seed.0);
+    *rng = ControlRng::from_seed(*seed);
+}
+
 fn assign_random_routes(
-    q_wait_time: Query<(Entity, &WaitTime), Without<QueuedDestination>>,
+    q_wait_time: Query<(Entity, &WaitTime), (Without<QueuedDestination>, Without<Idle>)>,
     mut commands: Commands,
     control_info: Res<ControlInfo>,
 ) {
@@ -294,8 +528,9 @@ fn assign_random_routes(
             println!("Assigning random route to {:?}", entity);
             commands.entity(entity).insert(QueuedDestination {
                 dest: DestinationID::Random,
-                strategy: TargetChoiceStrategy::Random,
+                strategy: Some(TargetChoiceStrategy::Random),
                 allow_locked: false,
+                after: None,
             });
             assigned_destination = true;
         }
@@ -305,6 +540,20 @@ fn assign_random_routes(
     }
 }
 
+fn update_idle_trains(
+    mut q_idle: Query<(Entity, &mut Idle)>,
+    time: Res<Time>,
+    control_info: Res<ControlInfo>,
+    mut commands: Commands,
+) {
+    for (entity, mut idle) in q_idle.iter_mut() {
+        idle.time += time.delta_secs();
+        if idle.time > control_info.idle_time {
+            commands.entity(entity).remove::<Idle>();
+        }
+    }
+}
+
 fn spawn_schedule(
     mut commands: Commands,
     mut messages: MessageReader<SpawnScheduleMessage>,
@@ -356,26 +605,337 @@ fn update_schedules(
     }
 }
 
+// warns about trains whose stops wait for each other in a cycle
+fn check_schedule_dependencies(
+    q_schedules: Query<&TrainSchedule>,
+    q_assignments: Query<(&Train, &AssignedSchedule)>,
+    entity_map: Res<EntityMap>,
+) {
+    let mut waits = vec![];
+    for (train, assigned_schedule) in q_assignments.iter() {
+        let Some(schedule) = assigned_schedule
+            .schedule_id
+            .and_then(|id| entity_map.query_get(&q_schedules, &GenericID::Schedule(id)))
+        else {
+            continue;
+        };
+        for (other, _) in schedule.entries.iter().filter_map(|entry| entry.after) {
+            waits.push((train.id, other));
+        }
+    }
+    for cycle in dependency_cycles(waits) {
+        warn!("Circular schedule dependency between trains {:?}", cycle);
+    }
+}
+
+// pairs (a, b) mean a stop of train a waits for train b
+fn dependency_cycles(waits: impl IntoIterator<Item = (TrainID, TrainID)>) -> Vec<Vec<TrainID>> {
+    let waits_for = DiGraphMap::<TrainID, ()>::from_edges(waits);
+    petgraph::algo::tarjan_scc(&waits_for)
+        .into_iter()
+        .filter(|component| {
+            component.len() > 1 || waits_for.contains_edge(component[0], component[0])
+        })
+        .collect()
+}
+
+// control time up to which timed departures were queued
+#[derive(Resource, Debug, Default)]
+struct DepartureClock(Option<f32>);
+
+fn reset_departure_clock(mut clock: ResMut<DepartureClock>, control_info: Res<ControlInfo>) {
+    clock.0 = Some(control_info.time);
+}
+
+fn queue_timed_departures(
+    control_info: Res<ControlInfo>,
+    mut clock: ResMut<DepartureClock>,
+    q_schedules: Query<&TrainSchedule>,
+    mut q_assignments: Query<(
+        Entity,
+        &mut AssignedSchedule,
+        Has<WaitTime>,
+        Has<QueuedDestination>,
+    )>,
+    entity_map: Res<EntityMap>,
+    mut commands: Commands,
+) {
+    let from = clock.0.unwrap_or(control_info.time);
+    let to = control_info.time;
+    clock.0 = Some(to);
+    let mut assigned_destination = false;
+    for (entity, mut assigned_schedule, waiting, queued) in q_assignments.iter_mut() {
+        let Some(schedule) = assigned_schedule
+            .schedule_id
+            .and_then(|id| entity_map.query_get(&q_schedules, &GenericID::Schedule(id)))
+        else {
+            continue;
+        };
+        // keep the order of departure times if several fire in the same tick
+        let mut departed = schedule
+            .departures
+            .iter()
+            .filter(|departure| departure.departs_between(from, to))
+            .filter_map(|departure| Some((departure.next_departure(from)?, departure.dest?)))
+            .collect::<Vec<_>>();
+        departed.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assigned_schedule
+            .pending_departures
+            .extend(departed.into_iter().map(|(_, dest)| dest));
+
+        if !waiting || queued {
+            continue;
+        }
+        if let Some(dest) = assigned_schedule.pending_departures.pop_front() {
+            commands.entity(entity).insert(QueuedDestination {
+                dest,
+                strategy: None,
+                allow_locked: false,
+                after: None,
+            });
+            assigned_destination = true;
+        }
+    }
+    if assigned_destination {
+        commands.trigger(PlanRouteEvent {});
+    }
+}
+
+fn run_arrival_actions(
+    mut arrival_messages: MessageReader<DestinationArrivalMessage>,
+    q_destinations: Query<&Destination>,
+    q_schedules: Query<&TrainSchedule>,
+    mut q_assignments: Query<&mut AssignedSchedule>,
+    entity_map: Res<EntityMap>,
+    mut commands: Commands,
+) {
+    let mut assigned_destination = false;
+    for arrival in arrival_messages.read() {
+        let Some(destination) =
+            entity_map.query_get(&q_destinations, &GenericID::Destination(arrival.dest))
+        else {
+            continue;
+        };
+        for action in destination.arrival_actions.iter() {
+            match action {
+                ArrivalAction::DispatchTrain {
+                    train: Some(train_id),
+                    dest: Some(dest),
+                } => {
+                    let Some(entity) = entity_map.get_entity(&GenericID::Train(*train_id)) else {
+                        continue;
+                    };
+                    println!(
+                        "Arrival of {:?} dispatches {:?}",
+                        arrival.train_id, train_id
+                    );
+                    commands.entity(entity).insert(QueuedDestination {
+                        dest: *dest,
+                        strategy: None,
+                        allow_locked: false,
+                        after: None,
+                    });
+                    assigned_destination = true;
+                }
+                ArrivalAction::AdvanceSchedule {
+                    train: Some(train_id),
+                } => {
+                    let Some(entity) = entity_map.get_entity(&GenericID::Train(*train_id)) else {
+                        continue;
+                    };
+                    let Ok(mut assigned_schedule) = q_assignments.get_mut(entity) else {
+                        continue;
+                    };
+                    let Some(schedule) = assigned_schedule.schedule_id.and_then(|id| {
+                        entity_map.query_get(&q_schedules, &GenericID::Schedule(id))
+                    }) else {
+                        continue;
+                    };
+                    if let Some(queued_dest) = assigned_schedule.skip_to_next_stop(schedule) {
+                        commands.entity(entity).insert(queued_dest);
+                        assigned_destination = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if assigned_destination {
+        commands.trigger(PlanRouteEvent {});
+    }
+}
+
 pub struct SchedulePlugin;
 
 impl Plugin for SchedulePlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(ControlInfo::default());
+        let seed = RandomSeed::default();
+        app.insert_resource(ControlRng::from_seed(seed));
+        app.insert_resource(seed);
         app.add_plugins(SelectablePlugin::<TrainSchedule>::new());
-        app.add_plugins(InspectorPlugin::<TrainSchedule>::new());
         app.add_message::<SpawnScheduleMessage>();
+        app.insert_resource(DepartureClock::default());
+        app.add_systems(OnEnter(ControlState), reseed_control_rng);
+        app.add_systems(
+            OnEnter(ControlStateMode::Schedule),
+            (reset_departure_clock, check_schedule_dependencies),
+        );
         app.add_systems(
             Update,
             (
-                update_time.run_if(in_state(ControlState)),
-                assign_random_routes
+                update_time
+                    .run_if(in_state(ControlState))
+                    .run_if(control_running),
+                (
+                    update_idle_trains.run_if(control_running),
+                    assign_random_routes,
+                )
+                    .chain()
                     .run_if(in_state(ControlStateMode::Random))
                     .before(set_train_route),
                 update_schedules
                     .run_if(in_state(ControlStateMode::Schedule))
                     .before(set_train_route),
+                queue_timed_departures
+                    .run_if(in_state(ControlStateMode::Schedule))
+                    .after(update_time)
+                    .after(update_schedules)
+                    .before(set_train_route),
                 spawn_schedule.run_if(on_message::<SpawnScheduleMessage>),
+                run_arrival_actions
+                    .run_if(in_state(ControlState))
+                    .run_if(on_message::<DestinationArrivalMessage>)
+                    .before(set_train_route),
             ),
         );
     }
 }
+
+pub struct ScheduleRenderPlugin;
+
+impl Plugin for ScheduleRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<TrainSchedule>::new());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{layout_primitives::*, train::TrainDefaults};
+    use bevy::{ecs::system::RunSystemOnce, platform::collections::HashSet};
+
+    #[test]
+    fn test_timed_departure_crossing() {
+        let once = TimedDeparture {
+            dest: None,
+            depart_at: 10.0,
+            repeat_every: 0.0,
+        };
+        assert!(!once.departs_between(0.0, 10.0));
+        assert!(once.departs_between(9.9, 10.1));
+        assert!(!once.departs_between(10.1, 100.0));
+        assert_eq!(once.next_departure(5.0), Some(10.0));
+        assert_eq!(once.next_departure(11.0), None);
+
+        let repeating = TimedDeparture {
+            dest: None,
+            depart_at: 290.0,
+            repeat_every: 300.0,
+        };
+        assert!(!repeating.departs_between(0.0, 290.0));
+        assert!(repeating.departs_between(289.0, 291.0));
+        assert!(repeating.departs_between(589.5, 590.5));
+        assert!(!repeating.departs_between(591.0, 889.0));
+        // a long tick spanning several repetitions
+        assert!(repeating.departs_between(200.0, 1000.0));
+        assert_eq!(repeating.next_departure(291.0), Some(590.0));
+        assert_eq!(repeating.next_departure(0.0), Some(290.0));
+    }
+
+    #[test]
+    fn test_dependency_cycles() {
+        let [a, b, c] = [0, 1, 2].map(TrainID::new);
+        assert!(dependency_cycles([(a, b), (b, c)]).is_empty());
+        let mut cycles = dependency_cycles([(a, b), (b, a), (c, a)]);
+        assert_eq!(cycles.len(), 1);
+        cycles[0].sort();
+        assert_eq!(cycles[0], vec![a, b]);
+        assert_eq!(dependency_cycles([(c, c)]), vec![vec![c]]);
+    }
+
+    #[test]
+    fn test_after_gate() {
+        let track = TrackID::new(CellID::new(0, 0, 0), Orientation::EW);
+        let block_a = BlockID::new(
+            track.get_directed(TrackDirection::First),
+            track.get_directed(TrackDirection::Last),
+        );
+        let track = TrackID::new(CellID::new(4, 0, 0), Orientation::EW);
+        let block_b = BlockID::new(
+            track.get_directed(TrackDirection::First),
+            track.get_directed(TrackDirection::Last),
+        );
+        let other = Train::at_block_id(
+            TrainID::new(1),
+            block_a.to_logical(BlockDirection::Aligned, Facing::Forward),
+            &TrainDefaults::default(),
+        );
+        let mut queue = QueuedDestination {
+            dest: DestinationID::Home,
+            strategy: None,
+            allow_locked: false,
+            after: None,
+        };
+        assert!(!queue.is_gated(Some(&other)));
+        queue.after = Some((other.id, block_b));
+        assert!(queue.is_gated(Some(&other)));
+        // dispatched anyway when the other train is gone
+        assert!(!queue.is_gated(None));
+        queue.after = Some((other.id, block_a));
+        assert!(!queue.is_gated(Some(&other)));
+    }
+
+    #[test]
+    fn test_seed_reproduces_random_targets() {
+        let blocks = (0..5)
+            .map(|x| {
+                let track = TrackID::new(CellID::new(x * 4, 0, 0), Orientation::EW);
+                BlockID::new(
+                    track.get_directed(TrackDirection::First),
+                    track.get_directed(TrackDirection::Last),
+                )
+            })
+            .collect::<Vec<_>>();
+        let train = Train::at_block_id(
+            TrainID::new(0),
+            blocks[0].to_logical(BlockDirection::Aligned, Facing::Forward),
+            &TrainDefaults::default(),
+        );
+        let targets = || blocks.iter().map(|block_id| (*block_id, ())).collect();
+        // a few consecutive dispatches, as a run would draw them
+        let run = |rng: &mut ControlRng| {
+            (0..3)
+                .map(|_| train.shuffle_targets(targets(), rng))
+                .collect::<Vec<_>>()
+        };
+
+        let mut orders = HashSet::new();
+        for seed in 0..10 {
+            let first = run(&mut ControlRng::from_seed(RandomSeed(seed)));
+            let second = run(&mut ControlRng::from_seed(RandomSeed(seed)));
+            assert_eq!(first, second);
+            orders.insert(first);
+        }
+        assert!(orders.len() > 1);
+
+        // entering control again starts over from the seed
+        let mut world = World::new();
+        world.insert_resource(RandomSeed(3));
+        world.insert_resource(ControlRng::from_seed(RandomSeed(3)));
+        let before = run(&mut *world.resource_mut::<ControlRng>());
+        world.run_system_once(reseed_control_rng).unwrap();
+        assert_eq!(run(&mut *world.resource_mut::<ControlRng>()), before);
+    }
+}
@@ -62,20 +62,39 @@ impl LogicalSection {
             .map(|(a, b)| DirectedTrackConnectionID::new(a.dirtrack, b.dirtrack))
     }
 
-    pub fn length(&self) -> f32 {
+    pub fn length(&self, ramp_length: f32) -> f32 {
         self.directed_connection_iter()
-            .map(|c| c.connection_length())
+            .map(|c| c.connection_length(ramp_length))
             .sum()
     }
 
-    pub fn length_to(&self, track: &LogicalTrackID) -> Result<f32, ()> {
+    // the tracks from index on, as far as they fit into distance
+    pub fn section_ahead(&self, index: usize, distance: f32, ramp_length: f32) -> LogicalSection {
+        let mut section = LogicalSection::new();
+        let Some(first) = self.tracks.get(index) else {
+            return section;
+        };
+        section.tracks.push(*first);
+        let mut length = 0.0;
+        for (a, b) in self.tracks[index..].iter().tuple_windows() {
+            length += DirectedTrackConnectionID::new(a.dirtrack, b.dirtrack)
+                .connection_length(ramp_length);
+            if length > distance {
+                break;
+            }
+            section.tracks.push(*b);
+        }
+        section
+    }
+
+    pub fn length_to(&self, track: &LogicalTrackID, ramp_length: f32) -> Result<f32, ()> {
         println!("length_to {:?}", track);
         let mut length = 0.0;
         if track == self.tracks.first().ok_or(())? {
             return Ok(0.0);
         }
         for connection in self.directed_connection_iter() {
-            length += connection.connection_length();
+            length += connection.connection_length(ramp_length);
             if connection.to_track == track.dirtrack {
                 return Ok(length);
             }
@@ -83,22 +102,40 @@ impl LogicalSection {
         return Err(());
     }
 
-    pub fn interpolate_pos(&self, mut pos: f32) -> Vec2 {
+    pub fn interpolate_pos(&self, mut pos: f32, ramp_length: f32) -> Vec2 {
         if self.tracks.len() == 1 {
             return self.tracks.first().unwrap().dirtrack.interpolate_pos(pos);
         }
         let mut last_pos = pos;
         let mut last_connection = self.directed_connection_iter().next().unwrap();
         for connection in self.directed_connection_iter() {
-            let length = connection.connection_length();
+            let length = connection.connection_length(ramp_length);
+            if pos <= length {
+                return connection.interpolate_pos(pos, ramp_length);
+            }
+            last_connection = connection;
+            last_pos = pos;
+            pos -= length;
+        }
+        return last_connection.interpolate_pos(last_pos, ramp_length);
+    }
+
+    pub fn interpolate_layer(&self, mut pos: f32, ramp_length: f32) -> f32 {
+        if self.tracks.len() == 1 {
+            return self.tracks.first().unwrap().dirtrack.cell().l as f32;
+        }
+        let mut last_pos = pos;
+        let mut last_connection = self.directed_connection_iter().next().unwrap();
+        for connection in self.directed_connection_iter() {
+            let length = connection.connection_length(ramp_length);
             if pos <= length {
-                return connection.interpolate_pos(pos);
+                return connection.interpolate_layer(pos, ramp_length);
             }
             last_connection = connection;
             last_pos = pos;
             pos -= length;
         }
-        return last_connection.interpolate_pos(last_pos);
+        return last_connection.interpolate_layer(last_pos, ramp_length);
     }
 
     pub fn is_connected(&self) -> bool {
@@ -194,8 +231,10 @@ impl DirectedSection {
         self.tracks.len()
     }
 
-    pub fn length(&self) -> f32 {
-        self.connection_iter().map(|c| c.connection_length()).sum()
+    pub fn length(&self, ramp_length: f32) -> f32 {
+        self.connection_iter()
+            .map(|c| c.connection_length(ramp_length))
+            .sum()
     }
 
     pub fn distance_to(&self, pos: Vec2) -> f32 {
@@ -222,22 +261,40 @@ impl DirectedSection {
             .map(|(a, b)| DirectedTrackConnectionID::new(*a, *b))
     }
 
-    pub fn interpolate_pos(&self, mut pos: f32) -> Vec2 {
+    pub fn interpolate_pos(&self, mut pos: f32, ramp_length: f32) -> Vec2 {
         if self.tracks.len() == 1 {
             return self.tracks.first().unwrap().interpolate_pos(pos);
         }
         let mut last_pos = pos;
         let mut last_connection = self.connection_iter().next().unwrap();
         for connection in self.connection_iter() {
-            let length = connection.connection_length();
+            let length = connection.connection_length(ramp_length);
+            if pos <= length {
+                return connection.interpolate_pos(pos, ramp_length);
+            }
+            last_connection = connection;
+            last_pos = pos;
+            pos -= length;
+        }
+        return last_connection.interpolate_pos(last_pos, ramp_length);
+    }
+
+    pub fn interpolate_layer(&self, mut pos: f32, ramp_length: f32) -> f32 {
+        if self.tracks.len() == 1 {
+            return self.tracks.first().unwrap().cell().l as f32;
+        }
+        let mut last_pos = pos;
+        let mut last_connection = self.connection_iter().next().unwrap();
+        for connection in self.connection_iter() {
+            let length = connection.connection_length(ramp_length);
             if pos <= length {
-                return connection.interpolate_pos(pos);
+                return connection.interpolate_layer(pos, ramp_length);
             }
             last_connection = connection;
             last_pos = pos;
             pos -= length;
         }
-        return last_connection.interpolate_pos(last_pos);
+        return last_connection.interpolate_layer(last_pos, ramp_length);
     }
 
     pub fn to_block_id(&self) -> BlockID {
@@ -5,8 +5,8 @@ use bevy_inspector_egui::egui::{self, ComboBox};
 use bevy_prototype_lyon::prelude::*;
 
 use crate::{
-    editor::{DespawnMessage, GenericID, finish_hover, init_hover, update_hover},
-    layout::EntityMap,
+    editor::{DespawnMessage, EditAction, GenericID, finish_hover, init_hover, update_hover},
+    layout::{Connections, EntityMap},
 };
 
 pub struct SelectablePlugin<T: Selectable> {
@@ -77,6 +77,16 @@ pub trait Selectable: Sync + Send + 'static + Component {
         None
     }
 
+    // items on other layers than the current one can't be hovered
+    fn layer(&self) -> Option<i32> {
+        None
+    }
+
+    // the edit that restores this item when its removal is undone
+    fn removal_action(&self, _connections: &Connections) -> Option<EditAction> {
+        None
+    }
+
     fn selector_option(
         query: &Query<(&Self, Option<&Name>)>,
         ui: &mut egui::Ui,
@@ -0,0 +1,235 @@
+use crate::{
+    ble::{BLEHub, HubConfiguration, HubDeviceStateMessage},
+    block::Block,
+    editor::{DespawnMessage, EditorState, SelectionState, SpawnHubMessage},
+    layout::{EntityMap, SignalAspects, TrackLocks},
+    layout_devices::{DeviceComponent, LayoutDevice, SpawnDeviceID, select_device_id},
+    layout_primitives::*,
+};
+use bevy::{ecs::system::SystemState, platform::collections::HashMap, prelude::*};
+use bevy_egui::egui::Ui;
+use bevy_inspector_egui::bevy_egui;
+use bevy_inspector_egui::reflect_inspector::ui_for_value;
+use serde::{Deserialize, Serialize};
+
+// tells the layout controller to treat the port as a signal instead of a switch motor
+const SIGNAL_DEVICE_TYPE: u32 = 2;
+
+#[derive(Debug, Reflect, Serialize, Deserialize, Clone, Component)]
+pub struct Signal {
+    #[serde(skip)]
+    #[reflect(ignore)]
+    pub aspect: Option<SignalAspect>,
+    pub green_brightness: u16,
+    pub yellow_brightness: u16,
+    pub red_brightness: u16,
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Self {
+            aspect: None,
+            green_brightness: 0,
+            yellow_brightness: 50,
+            red_brightness: 100,
+        }
+    }
+}
+
+impl Signal {
+    pub fn aspect_hub_state(
+        device: &LayoutDevice,
+        aspect: &SignalAspect,
+    ) -> Option<HubDeviceStateMessage> {
+        Some(HubDeviceStateMessage {
+            hub_id: device.hub_id?,
+            state_id: device.port?.to_u8(),
+            state: aspect.to_u8(),
+        })
+    }
+
+    pub fn hub_configuration(&self, device: &LayoutDevice) -> HashMap<HubID, HubConfiguration> {
+        if device.hub_id.is_none() {
+            return HashMap::new();
+        }
+
+        // brightness is indexed by the aspect the hub receives as device state
+        let address_offset = 8 + device.port.unwrap().to_u8() * 4;
+        let mut config = HubConfiguration::default();
+        config.add_value(address_offset + 0, self.green_brightness as u32);
+        config.add_value(address_offset + 1, self.yellow_brightness as u32);
+        config.add_value(address_offset + 2, self.red_brightness as u32);
+        config.add_value(address_offset + 3, SIGNAL_DEVICE_TYPE);
+
+        let mut map = HashMap::new();
+        map.insert(device.hub_id.unwrap(), config);
+        map
+    }
+
+    // manually set aspects win, otherwise red while any train holds the block
+    pub fn block_aspect(
+        block: &Block,
+        locks: &TrackLocks,
+        aspects: &SignalAspects,
+    ) -> SignalAspect {
+        if let Some(aspect) = aspects.aspects.get(&block.id) {
+            return *aspect;
+        }
+        if block
+            .section()
+            .tracks
+            .iter()
+            .any(|track| locks.locked_tracks.contains_key(&track.track))
+        {
+            SignalAspect::Red
+        } else {
+            SignalAspect::Green
+        }
+    }
+
+    pub fn inspector(ui: &mut Ui, world: &mut World) {
+        let mut state = SystemState::<(
+            Query<&mut Block>,
+            ResMut<EntityMap>,
+            ResMut<SelectionState>,
+            Res<AppTypeRegistry>,
+            Query<&BLEHub>,
+            MessageWriter<SpawnHubMessage>,
+            MessageWriter<SpawnSignalMessage>,
+            MessageWriter<DespawnMessage<LayoutDevice>>,
+            Query<(&mut Signal, &mut LayoutDevice)>,
+        )>::new(world);
+        let (
+            mut blocks,
+            mut entity_map,
+            mut selection_state,
+            type_registry,
+            hubs,
+            mut spawn_messages,
+            mut spawn_devices,
+            mut despawn_devices,
+            mut devices,
+        ) = state.get_mut(world);
+        if let Some(entity) = selection_state.get_entity(&entity_map) {
+            if let Ok(mut block) = blocks.get_mut(entity) {
+                ui.heading("Signal");
+                select_device_id(
+                    ui,
+                    &mut block.settings.signal,
+                    &mut devices,
+                    &mut spawn_devices,
+                    &mut despawn_devices,
+                    &mut entity_map,
+                    &hubs,
+                );
+                if let Some(signal_id) = block.settings.signal {
+                    if let Some(entity) = entity_map.layout_devices.get(&signal_id).copied() {
+                        if let Ok((mut signal, mut device)) = devices.get_mut(entity) {
+                            device.inspector(
+                                ui,
+                                &hubs,
+                                &mut spawn_messages,
+                                &mut entity_map,
+                                &mut selection_state,
+                            );
+                            ui_for_value(signal.as_mut(), ui, &type_registry.read());
+                        }
+                    }
+                }
+                ui.separator();
+            }
+        }
+        state.apply(world);
+    }
+}
+
+impl DeviceComponent for Signal {
+    type SpawnMessage = SpawnSignalMessage;
+
+    fn new_id(entity_map: &mut EntityMap) -> LayoutDeviceID {
+        entity_map.new_layout_device_id(LayoutDeviceType::Signal)
+    }
+}
+
+#[derive(Debug, Reflect, Serialize, Deserialize, Clone, Message)]
+pub struct SpawnSignalMessage {
+    pub device: LayoutDevice,
+    pub signal: Signal,
+}
+
+impl SpawnDeviceID for SpawnSignalMessage {
+    fn from_id(id: LayoutDeviceID) -> Self {
+        Self {
+            device: LayoutDevice::from_id(id),
+            signal: Signal::default(),
+        }
+    }
+}
+
+fn spawn_signal(
+    mut messages: MessageReader<SpawnSignalMessage>,
+    mut commands: Commands,
+    mut entity_map: ResMut<EntityMap>,
+) {
+    for event in messages.read() {
+        let entity = commands
+            .spawn((event.device.clone(), event.signal.clone()))
+            .id();
+        entity_map.layout_devices.insert(event.device.id, entity);
+    }
+}
+
+fn update_signal_aspects(
+    q_blocks: Query<&Block>,
+    mut q_signals: Query<(&mut Signal, &LayoutDevice)>,
+    entity_map: Res<EntityMap>,
+    track_locks: Res<TrackLocks>,
+    aspects: Res<SignalAspects>,
+    mut hub_commands: MessageWriter<HubDeviceStateMessage>,
+    editor_state: Res<State<EditorState>>,
+) {
+    for block in q_blocks.iter() {
+        let Some(signal_id) = block.settings.signal else {
+            continue;
+        };
+        let Some(entity) = entity_map.layout_devices.get(&signal_id) else {
+            continue;
+        };
+        let Ok((mut signal, device)) = q_signals.get_mut(*entity) else {
+            continue;
+        };
+        let aspect = Signal::block_aspect(block, &track_locks, &aspects);
+        if signal.aspect == Some(aspect) {
+            continue;
+        }
+        if editor_state.get().ble_commands_enabled() {
+            if let Some(command) = Signal::aspect_hub_state(device, &aspect) {
+                hub_commands.write(command);
+            }
+        }
+        signal.aspect = Some(aspect);
+    }
+}
+
+// the hubs start out dark, so resend every aspect once they're running
+fn reset_signal_aspects(mut q_signals: Query<&mut Signal>) {
+    for mut signal in q_signals.iter_mut() {
+        signal.aspect = None;
+    }
+}
+
+pub struct SignalPlugin;
+
+impl Plugin for SignalPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<SpawnSignalMessage>();
+        app.add_systems(OnEnter(EditorState::DeviceControl), reset_signal_aspects);
+        app.add_systems(
+            Update,
+            (
+                spawn_signal.run_if(on_message::<SpawnSignalMessage>),
+                update_signal_aspects,
+            ),
+        );
+    }
+}
@@ -1,15 +1,16 @@
 use bevy::color::palettes::css::{BLUE, GRAY, MAGENTA};
 use bevy::ecs::system::SystemParam;
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
 use bevy::{color::palettes::css::RED, ecs::system::SystemState};
-use bevy_egui::egui::Ui;
+use bevy_egui::egui::{self, Color32, Ui};
 use bevy_inspector_egui::bevy_egui;
 use bevy_prototype_lyon::prelude::*;
 use bevy_prototype_lyon::prelude::{LineCap, StrokeOptions};
 use lyon_tessellation::path::Path;
 use serde::{Deserialize, Serialize};
 
-use crate::ble::HubDeviceStateMessage;
+use crate::ble::{HubCommandMessage, HubDeviceStateMessage};
 use crate::editor::{HoverState, Selection, finish_hover};
 use crate::inspector::{Inspectable, InspectorPlugin};
 use crate::materials::TrackPathMaterial;
@@ -18,8 +19,11 @@ use crate::track::{PATH_WIDTH, build_connection_path_extents};
 use crate::track_mesh::{MeshType, TrackMeshPlugin};
 use crate::{
     ble::BLEHub,
-    editor::{DespawnMessage, EditorState, GenericID, SelectionState, SpawnHubMessage},
-    layout::EntityMap,
+    editor::{
+        DespawnMessage, EditAction, EditHistory, EditorState, GenericID, SelectionState,
+        SpawnHubMessage,
+    },
+    layout::{Connections, EntityMap},
     layout_devices::{LayoutDevice, select_device_id},
     layout_primitives::*,
     switch_motor::{MotorPosition, PulseMotor, SpawnPulseMotorMessage},
@@ -31,6 +35,12 @@ pub struct Switch {
     id: DirectedTrackID,
     positions: Vec<SwitchPosition>,
     pub motors: Vec<Option<LayoutDeviceID>>,
+    // switch whose motors also drive this one, e.g. the other half of a crossover
+    #[serde(default)]
+    pub shared_with: Option<DirectedTrackID>,
+    // drive the shared motors to the opposite position
+    #[serde(default)]
+    pub invert: bool,
 }
 
 impl Switch {
@@ -39,6 +49,8 @@ impl Switch {
             id,
             positions: Vec::new(),
             motors: Vec::new(),
+            shared_with: None,
+            invert: false,
         };
         switch.set_positions(positions);
         switch
@@ -91,10 +103,45 @@ impl Switch {
         panic!("Invalid motor positions");
     }
 
-    pub fn iter_motor_positions(
-        &self,
+    // the switch whose motors drive this one, and whether its positions are mirrored
+    pub fn driver<'a>(
+        &'a self,
+        switches: &'a Query<&Switch>,
+        entity_map: &EntityMap,
+    ) -> (&'a Switch, bool) {
+        match self
+            .shared_with
+            .and_then(|id| entity_map.switches.get(&id))
+            .and_then(|entity| switches.get(*entity).ok())
+        {
+            Some(driver) => (driver, self.invert),
+            None => (self, false),
+        }
+    }
+
+    pub fn shared_motor_positions<'a>(
+        &'a self,
         pos: &SwitchPosition,
-    ) -> impl Iterator<Item = (&Option<LayoutDeviceID>, MotorPosition)> {
+        switches: &'a Query<&Switch>,
+        entity_map: &EntityMap,
+    ) -> impl Iterator<Item = (&'a Option<LayoutDeviceID>, MotorPosition)> + use<'a> {
+        let (driver, invert) = self.driver(switches, entity_map);
+        let driver_pos = if invert { pos.opposite() } else { *pos };
+        if driver.positions.contains(&driver_pos) {
+            driver.iter_motor_positions(&driver_pos)
+        } else {
+            warn!(
+                "Switch {:?} can't drive {:?} to {:?}",
+                driver.id, self.id, driver_pos
+            );
+            self.iter_motor_positions(pos)
+        }
+    }
+
+    pub fn iter_motor_positions<'a>(
+        &'a self,
+        pos: &SwitchPosition,
+    ) -> impl Iterator<Item = (&'a Option<LayoutDeviceID>, MotorPosition)> + use<'a> {
         let pos_index = self.positions.iter().position(|p| p == pos).unwrap();
         self.motors
             .iter()
@@ -125,6 +172,9 @@ impl Switch {
             MessageWriter<DespawnMessage<LayoutDevice>>,
             Query<(&mut PulseMotor, &mut LayoutDevice)>,
             MessageWriter<SetSwitchPositionMessage>,
+            ResMut<EditHistory>,
+            MessageWriter<HubCommandMessage>,
+            Res<State<EditorState>>,
         )>::new(world);
         let (
             mut switches,
@@ -137,7 +187,14 @@ impl Switch {
             mut despawn_devices,
             mut devices,
             mut set_switch_position,
+            mut history,
+            mut hub_commands,
+            editor_state,
         ) = state.get_mut(world);
+        let switch_ids = switches
+            .iter()
+            .map(|switch| (switch.id, switch.positions.len(), switch.shared_with))
+            .collect::<Vec<_>>();
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut switch) = switches.get_mut(entity) {
                 ui.heading("Switch");
@@ -152,7 +209,91 @@ impl Switch {
                         }
                     }
                 });
+                let motors = switch
+                    .motors
+                    .iter()
+                    .map(|motor_id| {
+                        let entity = entity_map.layout_devices.get(motor_id.as_ref()?)?;
+                        devices.get(*entity).ok().map(|(motor, _)| motor)
+                    })
+                    .collect::<Vec<_>>();
+                let commanded = switch.get_position(
+                    &motors
+                        .iter()
+                        .copied()
+                        .map(|motor| Some(motor?.position))
+                        .collect::<Vec<_>>(),
+                );
+                if motors
+                    .iter()
+                    .copied()
+                    .any(|motor| motor.is_some_and(|motor| motor.reported_position.is_some()))
+                {
+                    let actual = switch.get_position(
+                        &motors
+                            .iter()
+                            .copied()
+                            .map(|motor| motor?.reported_position)
+                            .collect::<Vec<_>>(),
+                    );
+                    let text = format!(
+                        "commanded={}, actual={}",
+                        commanded.map_or("Unknown".to_string(), |p| p.to_string()),
+                        actual.map_or("Unknown".to_string(), |p| p.to_string()),
+                    );
+                    if commanded == actual {
+                        ui.label(text);
+                    } else {
+                        ui.colored_label(Color32::RED, text);
+                    }
+                }
                 ui.separator();
+                let own_id = switch.id;
+                let num_positions = switch.positions.len();
+                // only one level of sharing, so a driver can't be shared itself
+                let driven = switch_ids
+                    .iter()
+                    .filter(|(_, _, driver)| *driver == Some(own_id))
+                    .count();
+                let sharing_before = (switch.shared_with, switch.invert);
+                let Switch {
+                    shared_with,
+                    invert,
+                    ..
+                } = switch.as_mut();
+                ui.horizontal(|ui| {
+                    ui.label("Shared with");
+                    if driven > 0 && shared_with.is_none() {
+                        ui.label(format!("None, drives {} other switches", driven));
+                        return;
+                    }
+                    egui::ComboBox::from_id_salt("shared_with")
+                        .selected_text(shared_with.map_or("None".to_string(), |id| id.to_string()))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(shared_with, None, "None");
+                            for (id, len, driver) in switch_ids.iter() {
+                                if *id != own_id && *len == num_positions && driver.is_none() {
+                                    ui.selectable_value(shared_with, Some(*id), id.to_string());
+                                }
+                            }
+                        });
+                });
+                if shared_with.is_some() {
+                    ui.checkbox(invert, "Invert");
+                }
+                if (*shared_with, *invert) != sharing_before {
+                    history.push(EditAction::EditSwitchSharing {
+                        id: own_id,
+                        before: sharing_before,
+                        after: (*shared_with, *invert),
+                    });
+                }
+                if let Some(driver) = shared_with {
+                    ui.label(format!("Driven by the motors of {}", driver));
+                    return;
+                }
+                ui.separator();
+                let motors_before = switch.motors.clone();
                 for (i, motor_id) in &mut switch.motors.iter_mut().enumerate() {
                     ui.push_id(i, |ui| {
                         ui.heading(format!("Motor {:}", i));
@@ -175,13 +316,26 @@ impl Switch {
                                         &mut entity_map,
                                         &mut selection_state,
                                     );
-                                    motor.inspector(ui, &type_registry.read());
+                                    motor.inspector(
+                                        ui,
+                                        &type_registry.read(),
+                                        &device,
+                                        &mut hub_commands,
+                                        editor_state.get().ble_commands_enabled(),
+                                    );
                                 }
                             }
                         }
                     });
                     ui.separator();
                 }
+                if switch.motors != motors_before {
+                    history.push(EditAction::EditSwitch {
+                        id: switch.id,
+                        before: motors_before,
+                        after: switch.motors.clone(),
+                    });
+                }
             }
         }
     }
@@ -257,12 +411,45 @@ impl SpawnSwitchMessageQuery<'_, '_> {
     }
 }
 
+// switches removed with their connections, taken over again when a switch with
+// the same positions comes back, e.g. when undoing a track deletion
+#[derive(Resource, Debug, Default)]
+pub struct RestoredSwitches(HashMap<DirectedTrackID, Switch>);
+
+impl RestoredSwitches {
+    pub fn insert(&mut self, switch: Switch) {
+        self.0.insert(switch.id, switch);
+    }
+
+    fn take(&mut self, id: &DirectedTrackID, positions: &Vec<SwitchPosition>) -> Option<Switch> {
+        let mut positions = positions.clone();
+        positions.sort();
+        if self.0.get(id)?.positions != positions {
+            return None;
+        }
+        self.0.remove(id)
+    }
+}
+
 #[derive(Debug, Message)]
 pub struct UpdateSwitchTurnsMessage {
     pub id: DirectedTrackID,
     pub positions: Vec<SwitchPosition>,
 }
 
+impl UpdateSwitchTurnsMessage {
+    pub fn from_connections(id: DirectedTrackID, connections: &Connections) -> Self {
+        Self {
+            id,
+            positions: connections
+                .get_directed_connections_from(id)
+                .iter()
+                .map(|c| c.get_switch_position())
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Message)]
 pub struct SetSwitchPositionMessage {
     pub id: DirectedTrackID,
@@ -280,7 +467,9 @@ pub fn update_switch_position(
     for update in messages.read() {
         if let Some(entity) = entity_map.switches.get(&update.id) {
             let switch = switches.get(*entity).unwrap();
-            for (motor_id, position) in switch.iter_motor_positions(&update.position) {
+            for (motor_id, position) in
+                switch.shared_motor_positions(&update.position, &switches, &entity_map)
+            {
                 if let Some(motor_id) = motor_id {
                     let entity = entity_map.layout_devices.get(motor_id).unwrap();
                     let (mut motor, device) = switch_motors.get_mut(*entity).unwrap();
@@ -309,53 +498,68 @@ pub fn update_switch_turns(
     entity_map: Res<EntityMap>,
     mut commands: Commands,
     switch_connections: Query<(Entity, &SwitchConnection)>,
-    mut switch_materials: ResMut<Assets<TrackPathMaterial>>,
+    mut restored: ResMut<RestoredSwitches>,
+    mut applied: Local<HashMap<DirectedTrackID, Vec<SwitchPosition>>>,
 ) {
+    // every message carries the full set of positions, so only the last one per track matters
+    let mut updates: HashMap<DirectedTrackID, &Vec<SwitchPosition>> = HashMap::new();
     for update in messages.read() {
-        if update.positions.len() > 1 {
-            if let Some(entity) = entity_map.switches.get(&update.id) {
+        updates.insert(update.id, &update.positions);
+    }
+    // skip positions applied in an earlier frame, unless the switch went away in between
+    updates.retain(|id, positions| {
+        applied.get(id) != Some(*positions)
+            || (positions.len() > 1) != entity_map.switches.contains_key(id)
+            || restored.0.contains_key(id)
+    });
+    for (id, positions) in updates.iter() {
+        applied.insert(*id, (*positions).clone());
+    }
+    let mut existing: HashMap<DirectedTrackID, Vec<(Entity, SwitchPosition)>> = HashMap::new();
+    for (entity, connection) in switch_connections.iter() {
+        let from_track = connection.connection.from_track;
+        if updates.contains_key(&from_track) {
+            existing
+                .entry(from_track)
+                .or_default()
+                .push((entity, connection.connection.to_track.get_switch_position()));
+        }
+    }
+
+    for (id, positions) in updates {
+        if positions.len() > 1 {
+            if let Some(entity) = entity_map.switches.get(&id) {
                 let mut switch = switches.get_mut(*entity).unwrap();
-                switch.set_positions(update.positions.clone());
+                switch.set_positions(positions.clone());
+                if let Some(restored) = restored.take(&id, positions) {
+                    *switch = restored;
+                }
             } else {
                 switch_spawn_messages.write(SpawnSwitchMessage {
-                    switch: Switch::new(update.id, update.positions.clone()),
+                    switch: restored
+                        .take(&id, positions)
+                        .unwrap_or_else(|| Switch::new(id, positions.clone())),
                     name: None,
                 });
             }
         } else {
-            if let Some(entity) = entity_map.switches.get(&update.id) {
+            if let Some(entity) = entity_map.switches.get(&id) {
                 let switch = switches.get(entity.clone()).unwrap();
                 despawn_switch_messages.write(DespawnMessage(switch.id()));
             }
         }
-        for (entity, connection) in switch_connections.iter() {
-            if update.id == connection.connection.from_track {
-                if !update
-                    .positions
-                    .contains(&connection.connection.to_track.get_switch_position())
-                {
-                    commands.entity(entity).despawn();
-                }
-            }
-        }
-        let mut matched_positions = update.positions.clone();
-        for (_, connection) in switch_connections.iter() {
-            if update.id == connection.connection.from_track {
-                matched_positions
-                    .retain(|pos| pos != &connection.connection.to_track.get_switch_position());
+        let mut matched_positions = positions.clone();
+        for (entity, position) in existing.remove(&id).unwrap_or_default() {
+            if !positions.contains(&position) {
+                commands.entity(entity).despawn();
             }
+            matched_positions.retain(|pos| pos != &position);
         }
-        if let Some(switch_entity) = entity_map.switches.get(&update.id) {
+        if let Some(switch_entity) = entity_map.switches.get(&id) {
             for pos in matched_positions {
-                let connection = update.id.get_switch_connection(&pos);
+                let connection = id.get_switch_connection(&pos);
                 commands.entity(*switch_entity).with_children(|builder| {
-                    builder.spawn((
-                        SwitchConnection::new(connection),
-                        MeshMaterial2d(switch_materials.add(TrackPathMaterial {
-                            color: LinearRgba::from(GRAY),
-                            direction: 0,
-                        })),
-                    ));
+                    builder.spawn(SwitchConnection::new(connection));
                 });
             }
         }
@@ -377,7 +581,6 @@ pub fn spawn_switch(
     mut commands: Commands,
     mut messages: MessageReader<SpawnSwitchMessage>,
     mut entity_map: ResMut<EntityMap>,
-    mut switch_materials: ResMut<Assets<TrackPathMaterial>>,
 ) {
     for spawn_event in messages.read() {
         let switch = spawn_event.switch.clone();
@@ -400,13 +603,7 @@ pub fn spawn_switch(
                     .iter()
                     .map(|pos| switch.id.get_switch_connection(pos))
                 {
-                    builder.spawn((
-                        SwitchConnection::new(connection),
-                        MeshMaterial2d(switch_materials.add(TrackPathMaterial {
-                            color: LinearRgba::from(GRAY),
-                            direction: 0,
-                        })),
-                    ));
+                    builder.spawn(SwitchConnection::new(connection));
                 }
             })
             .id();
@@ -414,6 +611,27 @@ pub fn spawn_switch(
     }
 }
 
+fn add_switch_materials(
+    connections: Query<
+        Entity,
+        (
+            With<SwitchConnection>,
+            Without<MeshMaterial2d<TrackPathMaterial>>,
+        ),
+    >,
+    mut path_materials: ResMut<Assets<TrackPathMaterial>>,
+    mut commands: Commands,
+) {
+    for entity in connections.iter() {
+        commands
+            .entity(entity)
+            .insert(MeshMaterial2d(path_materials.add(TrackPathMaterial {
+                color: LinearRgba::from(GRAY),
+                direction: 0,
+            })));
+    }
+}
+
 #[derive(Component, Debug)]
 pub struct SwitchConnection {
     pub connection: DirectedTrackConnectionID,
@@ -453,7 +671,7 @@ impl MeshType for SwitchConnection {
     fn interpolate(&self, dist: f32) -> Vec2 {
         self.id()
             .to_connection(CellID::new(0, 0, 0))
-            .interpolate_pos(dist)
+            .interpolate_pos(dist, RAMP_LENGTH)
     }
 }
 
@@ -474,7 +692,8 @@ fn update_switch_shapes(
         let switch = switches
             .get(entity_map.switches[&connection.connection.from_track])
             .unwrap();
-        let positions = switch
+        let (driver, invert) = switch.driver(&switches, &entity_map);
+        let positions = driver
             .motors
             .iter()
             .map(|motor_id| {
@@ -484,7 +703,13 @@ fn update_switch_shapes(
                     .map(|motor| motor.position.clone())
             })
             .collect::<Vec<Option<MotorPosition>>>();
-        let position = switch.get_position(&positions);
+        let position = driver.get_position(&positions).map(|position| {
+            if invert {
+                position.opposite()
+            } else {
+                position
+            }
+        });
         let mut color;
         if position == Some(connection.connection.get_switch_position()) {
             color = Color::from(MAGENTA);
@@ -526,24 +751,40 @@ pub struct SwitchPlugin;
 impl Plugin for SwitchPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SelectablePlugin::<Switch>::new());
-        app.add_plugins(InspectorPlugin::<Switch>::new());
+        app.insert_resource(RestoredSwitches::default());
         app.add_message::<SpawnSwitchMessage>();
         app.add_message::<UpdateSwitchTurnsMessage>();
         app.add_message::<SetSwitchPositionMessage>();
         app.add_message::<DespawnMessage<Switch>>();
-        app.add_plugins(TrackMeshPlugin::<SwitchConnection>::default());
         app.add_systems(
             Update,
             (
                 spawn_switch.run_if(on_message::<SpawnSwitchMessage>),
-                update_switch_shapes.after(finish_hover),
                 update_switch_turns
                     .after(spawn_connection)
                     .run_if(on_message::<UpdateSwitchTurnsMessage>),
                 update_switch_position.run_if(on_message::<SetSwitchPositionMessage>),
-                // draw_switches,
                 despawn_switch.run_if(on_message::<DespawnMessage<Switch>>),
             ),
         );
     }
 }
+
+pub struct SwitchRenderPlugin;
+
+impl Plugin for SwitchRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<Switch>::new());
+        app.add_plugins(TrackMeshPlugin::<SwitchConnection>::default());
+        app.add_systems(
+            Update,
+            (
+                add_switch_materials
+                    .after(spawn_switch)
+                    .after(update_switch_turns),
+                update_switch_shapes.after(finish_hover),
+                // draw_switches,
+            ),
+        );
+    }
+}
@@ -1,11 +1,11 @@
 use crate::{
-    ble::{HubCommandMessage, HubConfiguration, HubDeviceStateMessage},
+    ble::{HubCommandMessage, HubConfiguration, HubDeviceStateMessage, HubMessageMessage},
     layout::EntityMap,
-    layout_devices::{DeviceComponent, LayoutDevice, SpawnDeviceID},
+    layout_devices::{DeviceComponent, LayoutData, LayoutDevice, SpawnDeviceID},
     layout_primitives::*,
 };
 use bevy::{platform::collections::HashMap, prelude::*, reflect::TypeRegistry};
-use bevy_egui::egui::Ui;
+use bevy_egui::egui::{self, Ui};
 use bevy_inspector_egui::bevy_egui;
 
 use bevy_inspector_egui::{
@@ -48,6 +48,15 @@ impl MotorPosition {
             Self::Right => 1,
         }
     }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Left),
+            1 => Some(Self::Right),
+            2 => Some(Self::Unknown),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Reflect, Serialize, Deserialize, Clone, Component, InspectorOptions)]
@@ -56,27 +65,112 @@ impl MotorPosition {
 pub struct PulseMotor {
     #[serde(skip)]
     pub position: MotorPosition,
+    #[serde(skip)]
+    #[reflect(ignore)]
+    pub reported_position: Option<MotorPosition>,
     #[serde(default)]
     pub pulse_duration: u16,
     pub pulse_strength: u16,
     #[serde(default)]
     pub polarity: MotorPolarity,
+    // move to calibrated angles instead of pulsing, needs a motor with a rotation sensor
+    #[serde(default)]
+    pub servo: bool,
+    #[serde(default = "default_left_angle")]
+    #[reflect(ignore)]
+    pub left_angle: i16,
+    #[serde(default)]
+    #[reflect(ignore)]
+    pub center_angle: i16,
+    #[serde(default = "default_right_angle")]
+    #[reflect(ignore)]
+    pub right_angle: i16,
+}
+
+fn default_left_angle() -> i16 {
+    -45
+}
+
+fn default_right_angle() -> i16 {
+    45
 }
 
 impl Default for PulseMotor {
     fn default() -> Self {
         Self {
             position: MotorPosition::Unknown,
+            reported_position: None,
             pulse_duration: 300,
             pulse_strength: 60,
             polarity: MotorPolarity::Normal,
+            servo: false,
+            left_angle: default_left_angle(),
+            center_angle: 0,
+            right_angle: default_right_angle(),
         }
     }
 }
 
 impl PulseMotor {
-    pub fn inspector(&mut self, ui: &mut Ui, type_registry: &TypeRegistry) {
+    pub fn inspector(
+        &mut self,
+        ui: &mut Ui,
+        type_registry: &TypeRegistry,
+        device: &LayoutDevice,
+        hub_commands: &mut MessageWriter<HubCommandMessage>,
+        can_test: bool,
+    ) {
         ui_for_value(self, ui, type_registry);
+        if !self.servo {
+            return;
+        }
+        ui.label("Calibration (degrees)");
+        for (label, position) in [
+            ("Left", MotorPosition::Left),
+            ("Center", MotorPosition::Unknown),
+            ("Right", MotorPosition::Right),
+        ] {
+            ui.horizontal(|ui| {
+                ui.label(label);
+                ui.add(egui::Slider::new(self.angle_mut(&position), -180..=180));
+                if ui
+                    .add_enabled(can_test, egui::Button::new("Test"))
+                    .clicked()
+                {
+                    for command in self.test_commands(device, &position) {
+                        hub_commands.write(command);
+                    }
+                }
+            });
+        }
+    }
+
+    // the servo rests at the center angle while the position is unknown
+    fn angle_mut(&mut self, position: &MotorPosition) -> &mut i16 {
+        match position {
+            MotorPosition::Left => &mut self.left_angle,
+            MotorPosition::Right => &mut self.right_angle,
+            MotorPosition::Unknown => &mut self.center_angle,
+        }
+    }
+
+    // stores the current calibration before moving, so it can be checked without reconfiguring
+    fn test_commands(
+        &self,
+        device: &LayoutDevice,
+        position: &MotorPosition,
+    ) -> Vec<HubCommandMessage> {
+        let mut commands = vec![];
+        for (hub_id, config) in self.hub_configuration(device) {
+            for (address, value) in config.iter() {
+                commands.push(HubCommandMessage::input(
+                    hub_id,
+                    Input::store_uint(*address, *value),
+                ));
+            }
+        }
+        commands.extend(Self::switch_command(device, position));
+        commands
     }
 
     pub fn switch_command(
@@ -111,6 +205,12 @@ impl PulseMotor {
         config.add_value(address_offset + 0, self.pulse_strength as u32);
         config.add_value(address_offset + 1, self.pulse_duration as u32);
         config.add_value(address_offset + 2, self.polarity.to_u32());
+        // indexed by the motor position the hub receives
+        let angle_offset = 32 + device.port.unwrap().to_u8() * 4;
+        config.add_value(angle_offset + 0, self.left_angle as i32 as u32);
+        config.add_value(angle_offset + 1, self.right_angle as i32 as u32);
+        config.add_value(angle_offset + 2, self.center_angle as i32 as u32);
+        config.add_value(angle_offset + 3, self.servo as u32);
 
         let mut map = HashMap::new();
         map.insert(device.hub_id.unwrap(), config);
@@ -155,6 +255,25 @@ fn spawn_pulse_motor(
     }
 }
 
+fn update_reported_positions(
+    mut messages: MessageReader<HubMessageMessage<LayoutData>>,
+    mut q_motors: Query<(&mut PulseMotor, &LayoutDevice)>,
+) {
+    for message in messages.read() {
+        match message.data {
+            LayoutData::SwitchConfirm { port, position } => {
+                for (mut motor, device) in q_motors.iter_mut() {
+                    if device.hub_id == Some(message.id)
+                        && device.port.map(|p| p.to_u8()) == Some(port)
+                    {
+                        motor.reported_position = Some(position);
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub struct PulseMotorPlugin;
 
 impl Plugin for PulseMotorPlugin {
@@ -162,7 +281,10 @@ impl Plugin for PulseMotorPlugin {
         app.add_message::<SpawnPulseMotorMessage>();
         app.add_systems(
             Update,
-            spawn_pulse_motor.run_if(on_message::<SpawnPulseMotorMessage>),
+            (
+                spawn_pulse_motor.run_if(on_message::<SpawnPulseMotorMessage>),
+                update_reported_positions.run_if(on_message::<HubMessageMessage<LayoutData>>),
+            ),
         );
     }
 }
@@ -2,15 +2,17 @@ use crate::{
     block::{Block, BlockCreateMessage},
     crossing::{LevelCrossing, SpawnCrossingMessage},
     editor::{
-        DespawnMessage, EditorState, GenericID, HoverState, MousePosWorld, Selection,
-        SelectionState, delete_selection_shortcut, finish_hover,
+        CurrentLayer, DespawnMessage, EditAction, EditHistory, EditWriters, EditorState,
+        ErrorToast, GenericID, HoverState, InputData, MousePosWorld, Selection, SelectionState,
+        ViewSettings, finish_hover, undo_redo_shortcut,
     },
     inspector::{Inspectable, InspectorPlugin},
-    layout::{Connections, EntityMap, TrackLocks},
+    layout::{Connections, EntityMap, LayoutUnits, TrackLocks},
     layout_primitives::*,
     marker::{Marker, MarkerColor, MarkerSpawnMessage},
     materials::{TrackBaseMaterial, TrackInnerMaterial, TrackPathMaterial},
     route::LegState,
+    section::DirectedSection,
     selectable::{Selectable, SelectablePlugin, SelectableType},
     switch::{Switch, UpdateSwitchTurnsMessage},
     track_mesh::{MeshType, TrackMeshPlugin},
@@ -20,9 +22,10 @@ use crate::{
 use bevy::{
     color::palettes::css::*, ecs::system::SystemState, math::vec4, platform::collections::HashSet,
 };
-use bevy::{platform::collections::HashMap, prelude::*};
-use bevy_egui::egui::Ui;
-use bevy_inspector_egui::bevy_egui;
+use bevy::{platform::collections::HashMap, prelude::*, window::PrimaryWindow};
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{self, ComboBox, Grid, Ui};
+use bevy_inspector_egui::bevy_egui::{self, EguiPrimaryContextPass};
 use bevy_prototype_lyon::prelude::*;
 use lyon_tessellation::{
     LineCap, StrokeOptions,
@@ -30,21 +33,96 @@ use lyon_tessellation::{
     path::{BuilderWithAttributes, Path},
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use strum::IntoEnumIterator;
 
 pub const TRACK_WIDTH: f32 = 10.0;
 pub const TRACK_INNER_WIDTH: f32 = 6.0;
 pub const PATH_WIDTH: f32 = TRACK_WIDTH * 0.25;
 pub const LAYOUT_SCALE: f32 = 40.0;
+// small enough to keep the shape ordering within a layer intact
+pub const LAYER_Z_OFFSET: f32 = 0.05;
+
+fn layer_z(l: i32) -> f32 {
+    l as f32 * LAYER_Z_OFFSET
+}
+
+// tint of the track base on each layer, dimmed when not the current layer
+fn layer_color(l: i32, current_layer: i32) -> LinearRgba {
+    let color = if l == 0 {
+        Color::WHITE
+    } else {
+        Color::hsl((l * 70).rem_euclid(360) as f32, 0.6, 0.8)
+    };
+    if l == current_layer {
+        color.into()
+    } else {
+        color.darker(0.5).into()
+    }
+}
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortalSettings {
+    // length of portals between layers, in cells
+    pub ramp_length: f32,
+}
+
+impl Default for PortalSettings {
+    fn default() -> Self {
+        Self {
+            ramp_length: RAMP_LENGTH,
+        }
+    }
+}
 
 #[derive(Resource, Default)]
 struct TrackBuildState {
     hover_cells: Vec<CellID>,
     hover_track: Option<TrackID>,
     portal_entrance: Option<DirectedTrackID>,
+    portal_exit: Option<DirectedTrackID>,
+    // edits of the current stroke, undone together
+    stroke: Vec<EditAction>,
+    // free track end the stroke will connect to when released
+    snap_target: Option<DirectedTrackID>,
+}
+
+// how close the cursor has to be to a free track end to snap to it, in cells
+const SNAP_DISTANCE: f32 = 0.6;
+
+// the cell a new track has to occupy to connect to the free end of dirtrack
+fn snap_entry_cell(dirtrack: DirectedTrackID) -> CellID {
+    dirtrack
+        .cell()
+        .get_neighbor(dirtrack.track.orientation.get_cardinal(dirtrack.direction))
 }
 
+fn find_snap_target(
+    connections: &Connections,
+    pos: Vec2,
+    layer: i32,
+    exclude: Option<TrackID>,
+) -> Option<DirectedTrackID> {
+    connections
+        .connection_graph
+        .nodes()
+        .filter(|track| Some(*track) != exclude)
+        .filter(|track| track.cell().l == layer && track.cell().get_vec2().distance(pos) < 1.5)
+        .flat_map(|track| track.dirtracks())
+        .filter(|dirtrack| {
+            !connections
+                .connection_graph
+                .edges(dirtrack.track)
+                .any(|(_, _, connection)| connection.tracks().contains(dirtrack))
+        })
+        .map(|dirtrack| (dirtrack, dirtrack.to_slot().get_vec2().distance(pos)))
+        .filter(|(_, dist)| *dist < SNAP_DISTANCE)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(dirtrack, _)| dirtrack)
+}
+
+// shapes are built at the origin cell and never change layer, so the ramp length doesn't matter
 pub fn build_connection_path(dirconnection: DirectedTrackConnectionID) -> Path {
-    let length = dirconnection.connection_length() * 0.5;
+    let length = dirconnection.connection_length(RAMP_LENGTH) * 0.5;
     build_connection_path_extents(dirconnection, 0.0, length)
 }
 
@@ -59,7 +137,7 @@ pub fn build_connection_path_extents(
 ) -> Path {
     let mut path_builder = BuilderWithAttributes::new(2);
     path_builder.begin(
-        vec_point(dirconnection.interpolate_pos(from) * LAYOUT_SCALE),
+        vec_point(dirconnection.interpolate_pos(from, RAMP_LENGTH) * LAYOUT_SCALE),
         &[from, 0.0],
     );
     let num_segments = match dirconnection.curve_index() {
@@ -70,12 +148,12 @@ pub fn build_connection_path_extents(
     for i in 0..(num_segments + 1) {
         let dist = from + epsilon + i as f32 * (to - from - epsilon) / num_segments as f32;
         path_builder.line_to(
-            vec_point(dirconnection.interpolate_pos(dist) * LAYOUT_SCALE),
+            vec_point(dirconnection.interpolate_pos(dist, RAMP_LENGTH) * LAYOUT_SCALE),
             &[dist, dist / (to - from)],
         );
     }
     path_builder.line_to(
-        vec_point(dirconnection.interpolate_pos(to) * LAYOUT_SCALE),
+        vec_point(dirconnection.interpolate_pos(to, RAMP_LENGTH) * LAYOUT_SCALE),
         &[to, 1.0],
     );
     path_builder.end(false);
@@ -97,23 +175,63 @@ impl TrackBuildState {
                 self.hover_cells[2],
             ) {
                 if !connections.has_track(track_id) {
-                    track_message_writer.write(SpawnTrackMessage(Track::from_id(track_id)));
+                    let track = Track::from_id(track_id);
+                    self.stroke.push(EditAction::SpawnTrack(track.clone()));
+                    track_message_writer.write(SpawnTrackMessage(track));
                 }
-                if let Some(track_b) = self.hover_track {
-                    if let Some(connection_id) = track_b.get_connection_to(track_id) {
-                        if !connections.has_connection(&connection_id) {
-                            connection_message_writer.write(SpawnConnectionMessage {
-                                id: connection_id,
-                                update_switches: true,
-                            });
-                        }
-                    }
-                }
-                self.hover_track = Some(track_id);
+                self.connect_hover_track(track_id, connections, connection_message_writer);
             }
             self.hover_cells.remove(0);
         }
     }
+
+    fn connect_hover_track(
+        &mut self,
+        track_id: TrackID,
+        connections: &Connections,
+        connection_message_writer: &mut MessageWriter<SpawnConnectionMessage>,
+    ) {
+        if let Some(track_b) = self.hover_track {
+            if let Some(connection_id) = track_b.get_connection_to(track_id) {
+                if !connections.has_connection(&connection_id) {
+                    self.stroke.push(EditAction::SpawnConnection(connection_id));
+                    connection_message_writer.write(SpawnConnectionMessage {
+                        id: connection_id,
+                        update_switches: true,
+                    });
+                }
+            }
+        }
+        self.hover_track = Some(track_id);
+    }
+
+    // whether the stroke can be routed to the entry cell of target and join it there
+    fn can_snap_to(&self, target: DirectedTrackID) -> bool {
+        let Some(last) = self.hover_cells.last() else {
+            return false;
+        };
+        let entry = snap_entry_cell(target);
+        let line = bresenham_line((last.x, last.y), (entry.x, entry.y));
+        let before_entry = match line.len() {
+            0 => self
+                .hover_cells
+                .iter()
+                .rev()
+                .nth(1)
+                .map(|cell| (cell.x, cell.y)),
+            1 => Some((last.x, last.y)),
+            n => Some(line[n - 2]),
+        };
+        let Some(before_entry) = before_entry else {
+            return false;
+        };
+        let Some(entry_slot) =
+            CellID::new(before_entry.0, before_entry.1, entry.l).get_shared_slot(&entry)
+        else {
+            return false;
+        };
+        entry_slot.can_connect_to(&target.to_slot())
+    }
 }
 
 pub fn track_section_inspector(ui: &mut Ui, world: &mut World) {
@@ -122,18 +240,60 @@ pub fn track_section_inspector(ui: &mut Ui, world: &mut World) {
         Res<SelectionState>,
         Res<AppTypeRegistry>,
         MessageWriter<BlockCreateMessage>,
+        Res<LayoutUnits>,
+        ResMut<EditHistory>,
+        MessageWriter<TransformSectionMessage>,
+        Res<PortalSettings>,
     )>::new(world);
-    let (_entity_map, selection_state, _type_registry, mut spawn_messages) = state.get_mut(world);
+    let (
+        _entity_map,
+        selection_state,
+        _type_registry,
+        mut spawn_messages,
+        units,
+        mut history,
+        mut transform_messages,
+        portal_settings,
+    ) = state.get_mut(world);
     if let Selection::Section(section) = &selection_state.selection {
         ui.label("Section inspector");
         ui.separator();
         ui.label(format!("Tracks: {}", section.len()));
+        ui.label(format!(
+            "Length: {}",
+            units.format_length(section.length(portal_settings.ramp_length))
+        ));
         ui.separator();
         if ui.button("Create block").clicked() {
             let block = Block::new(section.clone());
+            history.push(EditAction::CreateBlock(block.clone()));
             spawn_messages.write(BlockCreateMessage(block));
         }
         ui.separator();
+        // rotate and mirror around the centroid cell of the section
+        let sum = section
+            .tracks
+            .iter()
+            .fold(Vec2::ZERO, |sum, track| sum + track.cell().get_vec2());
+        let center = CellID::from_vec2(sum / section.len() as f32);
+        ui.horizontal(|ui| {
+            for (label, transform) in [
+                ("Rotate 90°", SectionTransform::Rotate90(center)),
+                (
+                    "Mirror ↔",
+                    SectionTransform::Mirror(MirrorAxis::Vertical, center),
+                ),
+                (
+                    "Mirror ↕",
+                    SectionTransform::Mirror(MirrorAxis::Horizontal, center),
+                ),
+            ] {
+                if ui.button(label).clicked() {
+                    transform_messages.write(TransformSectionMessage(transform));
+                }
+            }
+        });
+        ui.separator();
     }
 }
 
@@ -185,46 +345,23 @@ pub fn spawn_connection(
     mut entity_map: ResMut<EntityMap>,
     mut event_reader: MessageReader<SpawnConnectionMessage>,
     mut switch_update_messages: MessageWriter<UpdateSwitchTurnsMessage>,
-    mut base_materials: ResMut<Assets<TrackBaseMaterial>>,
-    mut inner_materials: ResMut<Assets<TrackInnerMaterial>>,
-    mut path_materials: ResMut<Assets<TrackPathMaterial>>,
 ) {
     for spawn_connection in event_reader.read() {
         let connection_id = spawn_connection.id;
+        if connections.has_connection(&connection_id) {
+            continue;
+        }
         for directed in connection_id.directed_connections() {
-            let base_material = MeshMaterial2d(base_materials.add(TrackBaseMaterial {
-                color: LinearRgba::from(WHITE),
-            }));
-            let outer_entity = commands
-                .spawn((TrackShapeOuter::new(directed), base_material))
-                .id();
-            let inner_material = MeshMaterial2d(inner_materials.add(TrackInnerMaterial {
-                color: LinearRgba::from(BLACK),
-            }));
-            let inner_entity = commands
-                .spawn((TrackShapeInner::new(directed), inner_material))
-                .id();
-            let path_material = MeshMaterial2d(path_materials.add(TrackPathMaterial {
-                color: BLACK.with_alpha(0.0).into(),
-                direction: 0,
-            }));
-            let path_entity = commands
-                .spawn((TrackShapePath::new(directed), path_material))
-                .id();
+            let outer_entity = commands.spawn(TrackShapeOuter::new(directed)).id();
+            let inner_entity = commands.spawn(TrackShapeInner::new(directed)).id();
+            let path_entity = commands.spawn(TrackShapePath::new(directed)).id();
             connections.connect_tracks_simple(&connection_id);
             entity_map.add_connection(directed, outer_entity, inner_entity, path_entity);
         }
 
         if spawn_connection.update_switches {
             for track_id in connection_id.tracks() {
-                let existing_connections = connections.get_directed_connections_from(track_id);
-                let event = UpdateSwitchTurnsMessage {
-                    id: track_id,
-                    positions: existing_connections
-                        .iter()
-                        .map(|c| c.get_switch_position())
-                        .collect::<Vec<SwitchPosition>>(),
-                };
+                let event = UpdateSwitchTurnsMessage::from_connections(track_id, &connections);
                 println!("{:?}", event);
                 switch_update_messages.write(event);
             }
@@ -232,6 +369,182 @@ pub fn spawn_connection(
     }
 }
 
+// every connection gets its own materials, they are recolored one by one
+fn add_connection_materials(
+    q_outer: Query<
+        Entity,
+        (
+            With<TrackShapeOuter>,
+            Without<MeshMaterial2d<TrackBaseMaterial>>,
+        ),
+    >,
+    q_inner: Query<
+        Entity,
+        (
+            With<TrackShapeInner>,
+            Without<MeshMaterial2d<TrackInnerMaterial>>,
+        ),
+    >,
+    q_path: Query<
+        Entity,
+        (
+            With<TrackShapePath>,
+            Without<MeshMaterial2d<TrackPathMaterial>>,
+        ),
+    >,
+    mut base_materials: ResMut<Assets<TrackBaseMaterial>>,
+    mut inner_materials: ResMut<Assets<TrackInnerMaterial>>,
+    mut path_materials: ResMut<Assets<TrackPathMaterial>>,
+    mut commands: Commands,
+) {
+    for entity in q_outer.iter() {
+        commands
+            .entity(entity)
+            .insert(MeshMaterial2d(base_materials.add(TrackBaseMaterial {
+                color: LinearRgba::from(WHITE),
+            })));
+    }
+    for entity in q_inner.iter() {
+        commands
+            .entity(entity)
+            .insert(MeshMaterial2d(inner_materials.add(TrackInnerMaterial {
+                color: LinearRgba::from(BLACK),
+            })));
+    }
+    for entity in q_path.iter() {
+        commands
+            .entity(entity)
+            .insert(MeshMaterial2d(path_materials.add(TrackPathMaterial {
+                color: BLACK.with_alpha(0.0).into(),
+                direction: 0,
+            })));
+    }
+}
+
+#[derive(Message, Debug, Clone)]
+pub struct DespawnConnectionMessage {
+    pub id: TrackConnectionID,
+}
+
+fn despawn_connection(
+    mut commands: Commands,
+    mut connections: ResMut<Connections>,
+    mut entity_map: ResMut<EntityMap>,
+    mut event_reader: MessageReader<DespawnConnectionMessage>,
+    mut switch_update_messages: MessageWriter<UpdateSwitchTurnsMessage>,
+) {
+    for despawn_connection in event_reader.read() {
+        let connection_id = despawn_connection.id;
+        if !connections.has_connection(&connection_id) {
+            continue;
+        }
+        for directed in connection_id.directed_connections() {
+            for map in [
+                &entity_map.connections_outer,
+                &entity_map.connections_inner,
+                &entity_map.connections_path,
+            ] {
+                if let Some(entity) = map.get(&directed) {
+                    commands.entity(*entity).despawn();
+                }
+            }
+            entity_map.remove_connection(directed);
+        }
+        connections.disconnect_tracks_simple(&connection_id);
+
+        for track_id in connection_id.tracks() {
+            switch_update_messages.write(UpdateSwitchTurnsMessage::from_connections(
+                track_id,
+                &connections,
+            ));
+        }
+    }
+}
+
+fn portal_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    connections: Res<Connections>,
+    mut track_build_state: ResMut<TrackBuildState>,
+    mut portal_settings: ResMut<PortalSettings>,
+    mut connection_spawner: MessageWriter<SpawnConnectionMessage>,
+    mut connection_despawner: MessageWriter<DespawnConnectionMessage>,
+    mut history: ResMut<EditHistory>,
+) {
+    let Ok(ctx) = egui_contexts.ctx_mut().cloned() else {
+        return;
+    };
+    egui::Window::new("Portals")
+        .open(&mut view_settings.portals)
+        .default_width(250.0)
+        .show(&ctx, |ui| {
+            let mut portals = connections.iter_portals().collect::<Vec<_>>();
+            portals.sort();
+            Grid::new("portals").show(ui, |ui| {
+                for portal in portals {
+                    ui.label(format!("{}", portal.track_a));
+                    ui.label(format!("{}", portal.track_b));
+                    ui.label(
+                        portal
+                            .to_directed(ConnectionDirection::Aligned)
+                            .curve_description(portal_settings.ramp_length),
+                    );
+                    if ui.button("Delete").clicked() {
+                        history.push(EditAction::DespawnConnection(portal));
+                        connection_despawner.write(DespawnConnectionMessage { id: portal });
+                    }
+                    ui.end_row();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Ramp length");
+                ui.add(
+                    egui::DragValue::new(&mut portal_settings.ramp_length)
+                        .speed(0.1)
+                        .range(PORTAL_LENGTH..=20.0)
+                        .suffix(" cells"),
+                );
+            });
+            ui.separator();
+
+            let mut open_ends = connections.iter_unconnected_dirtracks().collect::<Vec<_>>();
+            open_ends.sort();
+            let state = &mut *track_build_state;
+            for (label, end) in [
+                ("Entrance", &mut state.portal_entrance),
+                ("Exit", &mut state.portal_exit),
+            ] {
+                if end.is_some_and(|track| !open_ends.contains(&track)) {
+                    *end = None;
+                }
+                ComboBox::from_label(label)
+                    .selected_text(end.map_or("None".to_string(), |track| track.to_string()))
+                    .show_ui(ui, |ui| {
+                        for track in open_ends.iter() {
+                            ui.selectable_value(&mut *end, Some(*track), track.to_string());
+                        }
+                    });
+            }
+            match (state.portal_entrance, state.portal_exit) {
+                (Some(entrance), Some(exit)) if entrance != exit => {
+                    if ui.button("Create portal").clicked() {
+                        let connection_id = TrackConnectionID::new(entrance, exit);
+                        history.push(EditAction::SpawnConnection(connection_id));
+                        connection_spawner.write(SpawnConnectionMessage {
+                            id: connection_id,
+                            update_switches: true,
+                        });
+                        state.portal_entrance = None;
+                        state.portal_exit = None;
+                    }
+                }
+                _ => {
+                    ui.label("Select two open track ends");
+                }
+            }
+        });
+}
+
 #[derive(PartialEq, Eq)]
 pub enum TrackShapeType {
     Outer,
@@ -265,7 +578,8 @@ impl MeshType for TrackShapeOuter {
 
     fn base_transform(&self) -> Transform {
         Transform::from_translation(
-            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE).extend(1.0),
+            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE)
+                .extend(1.0 + layer_z(self.id.from_track.cell().l)),
         )
     }
 
@@ -276,7 +590,7 @@ impl MeshType for TrackShapeOuter {
     fn interpolate(&self, dist: f32) -> Vec2 {
         self.id()
             .to_connection(CellID::new(0, 0, 0))
-            .interpolate_pos(dist)
+            .interpolate_pos(dist, RAMP_LENGTH)
     }
 
     fn build_mesh(&self) -> Mesh {
@@ -317,7 +631,8 @@ impl MeshType for TrackShapeInner {
 
     fn base_transform(&self) -> Transform {
         Transform::from_translation(
-            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE).extend(2.0),
+            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE)
+                .extend(2.0 + layer_z(self.id.from_track.cell().l)),
         )
     }
 
@@ -334,7 +649,7 @@ impl MeshType for TrackShapeInner {
     fn interpolate(&self, dist: f32) -> Vec2 {
         self.id()
             .to_connection(CellID::new(0, 0, 0))
-            .interpolate_pos(dist)
+            .interpolate_pos(dist, RAMP_LENGTH)
     }
 }
 
@@ -358,7 +673,8 @@ impl MeshType for TrackShapePath {
 
     fn base_transform(&self) -> Transform {
         Transform::from_translation(
-            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE).extend(19.0),
+            (self.id.from_track.cell().get_vec2() * LAYOUT_SCALE)
+                .extend(19.0 + layer_z(self.id.from_track.cell().l)),
         )
     }
 
@@ -375,7 +691,7 @@ impl MeshType for TrackShapePath {
     fn interpolate(&self, dist: f32) -> Vec2 {
         self.id()
             .to_connection(CellID::new(0, 0, 0))
-            .interpolate_pos(dist)
+            .interpolate_pos(dist, RAMP_LENGTH)
     }
 }
 
@@ -399,6 +715,37 @@ impl TrackLogicalFilter {
         // false if any entry is false
         self.filters.iter().all(|(_, value)| *value)
     }
+
+    pub fn verbose_entries(&self) -> Vec<VerboseFilterEntry> {
+        let mut entries = vec![];
+        for facing in [Facing::Forward, Facing::Backward] {
+            for direction in [TrackDirection::First, TrackDirection::Last] {
+                let key = LogicalDiscriminator { direction, facing };
+                entries.push(VerboseFilterEntry {
+                    direction,
+                    facing,
+                    enabled: self.filters.get(&key).copied().unwrap_or(true),
+                });
+            }
+        }
+        entries
+    }
+}
+
+// diff-friendly form of the filter that lists every entry with its state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerboseFilterEntry {
+    pub direction: TrackDirection,
+    pub facing: Facing,
+    pub enabled: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerializedTrackLogicalFilter {
+    // verbose entries have to be tried first, since they also parse as compact keys
+    Verbose(Vec<VerboseFilterEntry>),
+    Compact(Vec<LogicalDiscriminator>),
 }
 
 impl Serialize for TrackLogicalFilter {
@@ -422,14 +769,40 @@ impl<'de> Deserialize<'de> for TrackLogicalFilter {
         D: Deserializer<'de>,
     {
         let mut filter = Self::default();
-        let filtered_keys: Vec<LogicalDiscriminator> = Vec::deserialize(deserializer)?;
-        for key in filtered_keys {
-            filter.filters.insert(key, false);
+        match SerializedTrackLogicalFilter::deserialize(deserializer)? {
+            SerializedTrackLogicalFilter::Compact(filtered_keys) => {
+                for key in filtered_keys {
+                    filter.filters.insert(key, false);
+                }
+            }
+            SerializedTrackLogicalFilter::Verbose(entries) => {
+                for entry in entries {
+                    let key = LogicalDiscriminator {
+                        direction: entry.direction,
+                        facing: entry.facing,
+                    };
+                    filter.filters.insert(key, entry.enabled);
+                }
+            }
         }
         Ok(filter)
     }
 }
 
+// rewrites the serialized logical filters of a list of tracks into their verbose form
+pub fn make_track_filters_verbose(tracks: &mut serde_json::Value) -> serde_json::Result<()> {
+    let Some(tracks) = tracks.as_array_mut() else {
+        return Ok(());
+    };
+    for track in tracks {
+        if let Some(filter) = track.get_mut("logical_filter") {
+            let logical_filter = serde_json::from_value::<TrackLogicalFilter>(filter.clone())?;
+            *filter = serde_json::to_value(logical_filter.verbose_entries())?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Message)]
 pub struct SpawnTrackMessage(pub Track);
 
@@ -454,7 +827,7 @@ impl<'de> Deserialize<'de> for SpawnTrackMessage {
         let value = serde_json::Value::deserialize(deserializer)?;
         let track = match serde_json::from_value::<TrackID>(value.clone()) {
             Ok(id) => Track::from_id(id),
-            Err(_) => serde_json::from_value::<Track>(value).unwrap(),
+            Err(_) => serde_json::from_value::<Track>(value).map_err(serde::de::Error::custom)?,
         };
         Ok(SpawnTrackMessage(track))
     }
@@ -485,6 +858,8 @@ impl Track {
             ResMut<Connections>,
             ResMut<TrackBuildState>,
             MessageWriter<SpawnConnectionMessage>,
+            ResMut<EditHistory>,
+            Res<PortalSettings>,
         )>::new(world);
         let (
             mut tracks,
@@ -496,18 +871,25 @@ impl Track {
             mut connections,
             mut track_build_state,
             mut connection_spawner,
+            mut history,
+            portal_settings,
         ) = state.get_mut(world);
         if let Some(entity) = selection_state.get_entity(&entity_map) {
             if let Ok(mut track) = tracks.get_mut(entity) {
                 ui.label("Inspectable track lol");
-                if !entity_map.markers.contains_key(&track.id) {
-                    if ui.button("Add Marker").clicked() {
-                        let id = track.id.clone();
-
-                        let marker = Marker::new(id, MarkerColor::Red);
-                        marker_spawner.write(MarkerSpawnMessage(marker));
+                ui.horizontal(|ui| {
+                    ui.label("Add Marker");
+                    for slot in MarkerSlot::iter() {
+                        let id = MarkerID::new(track.id, slot);
+                        if !entity_map.markers.contains_key(&id) {
+                            if ui.button(slot.to_string()).clicked() {
+                                let marker = Marker::at_slot(track.id, slot, MarkerColor::Red);
+                                history.push(EditAction::AddMarker(marker.clone()));
+                                marker_spawner.write(MarkerSpawnMessage(marker));
+                            }
+                        }
                     }
-                }
+                });
                 if !entity_map.crossings.contains_key(&track.id) {
                     if ui.button("Add Crossing").clicked() {
                         let id = track.id.clone();
@@ -534,6 +916,27 @@ impl Track {
                     connections.add_filtered_track(track_id, &track.logical_filter)
                 }
                 ui.separator();
+                ui.heading("Connections");
+                let mut track_connections = connections
+                    .connection_graph
+                    .edges(track_id)
+                    .map(|(_, _, connection)| {
+                        connection
+                            .directed_connections()
+                            .into_iter()
+                            .find(|directed| directed.from_track.track == track_id)
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                track_connections.sort();
+                for connection in track_connections {
+                    ui.label(format!(
+                        "{} to {}",
+                        connection.curve_description(portal_settings.ramp_length),
+                        connection.to_track.get_name()
+                    ));
+                }
+                ui.separator();
                 match track_build_state.portal_entrance {
                     None => {
                         if let Some(directed) = connections.get_unconnected_dirtrack(track_id) {
@@ -545,9 +948,19 @@ impl Track {
                     Some(entrance) => {
                         if let Some(directed) = connections.get_unconnected_dirtrack(track_id) {
                             if directed != entrance {
-                                if ui.button("Set as portal exit").clicked() {
+                                let label = if entrance.cell().l != directed.cell().l {
+                                    format!(
+                                        "Set as ramp exit (layer {} to {})",
+                                        entrance.cell().l,
+                                        directed.cell().l
+                                    )
+                                } else {
+                                    "Set as portal exit".to_string()
+                                };
+                                if ui.button(label).clicked() {
                                     let connection_id = TrackConnectionID::new(entrance, directed);
                                     track_build_state.portal_entrance = None;
+                                    history.push(EditAction::SpawnConnection(connection_id));
                                     connection_spawner.write(SpawnConnectionMessage {
                                         id: connection_id,
                                         update_switches: true,
@@ -594,6 +1007,10 @@ impl Selectable for Track {
         GenericID::Track(self.id)
     }
 
+    fn layer(&self) -> Option<i32> {
+        Some(self.id.cell().l)
+    }
+
     fn id(&self) -> Self::ID {
         self.id
     }
@@ -634,6 +1051,7 @@ fn init_draw_track(
     mouse_buttons: Res<ButtonInput<MouseButton>>,
     mouse_world_pos: Res<MousePosWorld>,
     hover_state: Res<HoverState>,
+    current_layer: Res<CurrentLayer>,
 ) {
     if mouse_buttons.just_pressed(MouseButton::Right) {
         match hover_state.hover {
@@ -647,7 +1065,8 @@ fn init_draw_track(
                 return;
             }
         }
-        let first_cell = CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE);
+        let first_cell =
+            CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE).on_layer(current_layer.0);
         track_build_state.hover_cells.push(first_cell);
     }
 }
@@ -655,10 +1074,352 @@ fn init_draw_track(
 fn exit_draw_track(
     mut track_build_state: ResMut<TrackBuildState>,
     mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mut history: ResMut<EditHistory>,
+    mut connections: ResMut<Connections>,
+    mut track_message_writer: MessageWriter<SpawnTrackMessage>,
+    mut connection_message_writer: MessageWriter<SpawnConnectionMessage>,
 ) {
     if mouse_buttons.just_released(MouseButton::Right) {
+        // finish the stroke into the snapped track end
+        if let Some(target) = track_build_state.snap_target.take() {
+            if track_build_state.hover_cells.last() == Some(&snap_entry_cell(target)) {
+                track_build_state.hover_cells.push(target.cell());
+                track_build_state.build(
+                    &mut connections,
+                    &mut track_message_writer,
+                    &mut connection_message_writer,
+                );
+                track_build_state.connect_hover_track(
+                    target.track,
+                    &connections,
+                    &mut connection_message_writer,
+                );
+            }
+        }
         track_build_state.hover_cells = vec![];
         track_build_state.hover_track = None;
+        let stroke = std::mem::take(&mut track_build_state.stroke);
+        if !stroke.is_empty() {
+            history.push(EditAction::Group(stroke));
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct SectionDragState {
+    start: Option<CellID>,
+    // cells that blocked the last move, flashed until the timer runs out
+    overlap: Vec<CellID>,
+    flash_timer: f32,
+}
+
+impl SectionDragState {
+    fn delta(&self, mouse_pos: Vec2) -> Option<CellID> {
+        let start = self.start?;
+        let mouse_cell = CellID::from_vec2(mouse_pos / LAYOUT_SCALE);
+        Some(CellID::new(
+            mouse_cell.x - start.x,
+            mouse_cell.y - start.y,
+            0,
+        ))
+    }
+}
+
+fn drag_section(
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    mouse_world_pos: Res<MousePosWorld>,
+    input_data: Res<InputData>,
+    hover_state: Res<HoverState>,
+    selection_state: Res<SelectionState>,
+    mut drag_state: ResMut<SectionDragState>,
+    mut transform_messages: MessageWriter<TransformSectionMessage>,
+) {
+    let Selection::Section(section) = &selection_state.selection else {
+        drag_state.start = None;
+        return;
+    };
+    if mouse_buttons.just_pressed(MouseButton::Left)
+        && keyboard_buttons.pressed(KeyCode::ShiftLeft)
+        && !input_data.mouse_over_ui
+    {
+        if let Some(GenericID::Track(track_id)) = hover_state.hover {
+            if section.has_track(&track_id) {
+                drag_state.start = Some(CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE));
+            }
+        }
+    }
+    if !mouse_buttons.just_released(MouseButton::Left) {
+        return;
+    }
+    let Some(delta) = drag_state.delta(mouse_world_pos.pos) else {
+        return;
+    };
+    drag_state.start = None;
+    if delta.x != 0 || delta.y != 0 {
+        transform_messages.write(TransformSectionMessage(SectionTransform::Translate(delta)));
+    }
+}
+
+#[derive(Message, Debug, Clone)]
+pub struct TransformSectionMessage(pub SectionTransform);
+
+impl Track {
+    fn transformed(&self, transform: &SectionTransform) -> Self {
+        let mut logical_filter = TrackLogicalFilter::default();
+        for (key, value) in self.logical_filter.filters.iter() {
+            let direction = transform
+                .dirtrack(self.id.get_directed(key.direction))
+                .direction;
+            logical_filter.filters.insert(
+                LogicalDiscriminator {
+                    direction,
+                    facing: key.facing,
+                },
+                *value,
+            );
+        }
+        Self {
+            id: transform
+                .dirtrack(self.id.get_directed(TrackDirection::First))
+                .track,
+            logical_filter,
+        }
+    }
+}
+
+// deletes the tracks with the blocks and markers on them. The switches next to
+// them lose their settings with the connections, so those are kept for the undo
+pub fn tracks_removal_action(
+    tracks: &[&Track],
+    connections: &Connections,
+    q_blocks: &Query<&Block>,
+    q_markers: &Query<&Marker>,
+    writers: &EditWriters,
+) -> EditAction {
+    let ids = tracks.iter().map(|track| track.id).collect::<Vec<_>>();
+    let mut switch_ids = vec![];
+    for id in ids.iter() {
+        switch_ids.extend(id.dirtracks());
+        for (_, _, connection) in connections.connection_graph.edges(*id) {
+            for dirtrack in connection.tracks() {
+                switch_ids.extend([dirtrack, dirtrack.opposite()]);
+            }
+        }
+    }
+    switch_ids.sort();
+    switch_ids.dedup();
+    let mut markers = q_markers
+        .iter()
+        .filter(|marker| ids.contains(&marker.track))
+        .cloned()
+        .collect::<Vec<_>>();
+    markers.sort_by_key(|marker| marker.marker_id());
+    let mut blocks = q_blocks
+        .iter()
+        .filter(|block| ids.iter().any(|id| block.section().has_track(id)))
+        .cloned()
+        .collect::<Vec<_>>();
+    blocks.sort_by_key(|block| block.id);
+
+    let mut actions = switch_ids
+        .iter()
+        .filter_map(|id| writers.switch(id))
+        .cloned()
+        .map(EditAction::RemoveSwitch)
+        .collect::<Vec<_>>();
+    actions.extend(markers.into_iter().map(EditAction::RemoveMarker));
+    actions.extend(blocks.into_iter().map(EditAction::DespawnBlock));
+    for track in tracks {
+        let track_connections = connections
+            .connection_graph
+            .edges(track.id)
+            .map(|(_, _, connection)| *connection)
+            .collect();
+        actions.push(EditAction::DespawnTrack(
+            (*track).clone(),
+            track_connections,
+        ));
+    }
+    EditAction::Group(actions)
+}
+
+fn delete_track_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    mut selection_state: ResMut<SelectionState>,
+    q_tracks: Query<&Track>,
+    q_blocks: Query<&Block>,
+    q_markers: Query<&Marker>,
+    entity_map: Res<EntityMap>,
+    connections: Res<Connections>,
+    mut history: ResMut<EditHistory>,
+    mut writers: EditWriters,
+) {
+    if !keyboard_buttons.just_pressed(KeyCode::Delete) {
+        return;
+    }
+    let Selection::Single(GenericID::Track(track_id)) = selection_state.selection else {
+        return;
+    };
+    let Some(track) = entity_map
+        .tracks
+        .get(&track_id)
+        .and_then(|entity| q_tracks.get(*entity).ok())
+    else {
+        return;
+    };
+    let action = tracks_removal_action(&[track], &connections, &q_blocks, &q_markers, &writers);
+    action.apply(&mut writers);
+    history.push(action);
+    selection_state.selection = Selection::None;
+}
+
+// respawns the selected section's tracks and their connections transformed,
+// aborting if the result would overlap tracks outside the section
+fn transform_section(
+    mut transform_messages: MessageReader<TransformSectionMessage>,
+    mut drag_state: ResMut<SectionDragState>,
+    mut selection_state: ResMut<SelectionState>,
+    connections: Res<Connections>,
+    q_tracks: Query<&Track>,
+    q_blocks: Query<&Block>,
+    q_markers: Query<&Marker>,
+    entity_map: Res<EntityMap>,
+    mut history: ResMut<EditHistory>,
+    mut error_toast: ResMut<ErrorToast>,
+    mut writers: EditWriters,
+) {
+    for TransformSectionMessage(transform) in transform_messages.read() {
+        let Selection::Section(section) = selection_state.selection.clone() else {
+            continue;
+        };
+        let mut moved = section.tracks.iter().map(|t| t.track).collect::<Vec<_>>();
+        moved.sort();
+        moved.dedup();
+        let overlap = moved
+            .iter()
+            .map(|track| {
+                transform
+                    .dirtrack(track.get_directed(TrackDirection::First))
+                    .track
+            })
+            .filter(|track| connections.has_track(*track) && !moved.contains(track))
+            .map(|track| track.get_directed(TrackDirection::First).cell())
+            .collect::<Vec<_>>();
+        if !overlap.is_empty() {
+            error_toast.push(format!(
+                "Can't transform section, {} tracks overlap",
+                overlap.len()
+            ));
+            drag_state.overlap = overlap;
+            drag_state.flash_timer = 1.0;
+            continue;
+        }
+        // blocks move along if they are entirely on the section
+        let blocks = q_blocks
+            .iter()
+            .filter(|block| moved.iter().any(|id| block.section().has_track(id)))
+            .collect::<Vec<_>>();
+        if let Some(block) = blocks.iter().find(|block| {
+            block
+                .section()
+                .tracks
+                .iter()
+                .any(|track| !moved.contains(&track.track))
+        }) {
+            error_toast.push(format!(
+                "Can't transform section, block {} is only partly on it",
+                block.id
+            ));
+            continue;
+        }
+        let transformed_blocks = blocks
+            .iter()
+            .map(|block| block.transformed(transform))
+            .collect::<Vec<_>>();
+        // despawning and creating the same block id in one frame would lose its markers
+        if transformed_blocks
+            .iter()
+            .any(|block| entity_map.blocks.contains_key(&block.id))
+        {
+            error_toast.push("Can't transform section onto its own blocks".to_string());
+            continue;
+        }
+
+        let tracks = moved
+            .iter()
+            .map(|track_id| q_tracks.get(entity_map.tracks[track_id]).unwrap())
+            .collect::<Vec<_>>();
+        let mut actions = vec![tracks_removal_action(
+            &tracks,
+            &connections,
+            &q_blocks,
+            &q_markers,
+            &writers,
+        )];
+        let mut spawns = vec![];
+        let mut internal_connections = vec![];
+        for (track_id, track) in moved.iter().zip(tracks.iter()) {
+            spawns.push(EditAction::SpawnTrack(track.transformed(transform)));
+            for (_, other, connection) in connections.connection_graph.edges(*track_id) {
+                if moved.contains(&other) && !internal_connections.contains(connection) {
+                    internal_connections.push(*connection);
+                }
+            }
+        }
+        actions.extend(spawns);
+        actions.extend(
+            internal_connections
+                .iter()
+                .map(|connection| EditAction::SpawnConnection(transform.connection(*connection))),
+        );
+        actions.extend(transformed_blocks.into_iter().map(EditAction::CreateBlock));
+        actions.extend(
+            q_markers
+                .iter()
+                .filter(|marker| moved.contains(&marker.track))
+                .map(|marker| EditAction::AddMarker(marker.transformed(transform))),
+        );
+        let action = EditAction::Group(actions);
+        action.apply(&mut writers);
+        history.push(action);
+
+        selection_state.selection = Selection::Section(DirectedSection {
+            tracks: section
+                .tracks
+                .iter()
+                .map(|track| transform.dirtrack(*track))
+                .collect(),
+        });
+    }
+}
+
+fn draw_section_drag(
+    mut drag_state: ResMut<SectionDragState>,
+    selection_state: Res<SelectionState>,
+    mouse_world_pos: Res<MousePosWorld>,
+    time: Res<Time>,
+    mut gizmos: Gizmos,
+) {
+    if let (Some(delta), Selection::Section(section)) = (
+        drag_state.delta(mouse_world_pos.pos),
+        &selection_state.selection,
+    ) {
+        for track in section.tracks.iter() {
+            track
+                .translated(delta)
+                .draw_with_gizmos(&mut gizmos, LAYOUT_SCALE, Color::from(GRAY));
+        }
+    }
+    if drag_state.flash_timer > 0.0 {
+        drag_state.flash_timer -= time.delta_secs();
+        for cell in drag_state.overlap.iter() {
+            gizmos.circle_2d(
+                cell.get_vec2() * LAYOUT_SCALE,
+                LAYOUT_SCALE * 0.4,
+                Color::from(RED),
+            );
+        }
     }
 }
 
@@ -674,9 +1435,23 @@ fn update_draw_track(
         return;
     }
     let start = (last_cell.unwrap().x, last_cell.unwrap().y);
-    let mouse_cell = CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE);
-    for point in bresenham_line(start, (mouse_cell.x, mouse_cell.y)).iter() {
-        let cell = CellID::new(point.0, point.1, 0);
+    let layer = last_cell.unwrap().l;
+    let mouse_pos = mouse_world_pos.pos / LAYOUT_SCALE;
+    // near a free track end, steer the stroke into the cell that connects to it
+    let snap_target = find_snap_target(
+        &connections,
+        mouse_pos,
+        layer,
+        track_build_state.hover_track,
+    )
+    .filter(|target| track_build_state.can_snap_to(*target));
+    track_build_state.snap_target = snap_target;
+    let target_cell = match snap_target {
+        Some(target) => snap_entry_cell(target),
+        None => CellID::from_vec2(mouse_pos),
+    };
+    for point in bresenham_line(start, (target_cell.x, target_cell.y)).iter() {
+        let cell = CellID::new(point.0, point.1, layer);
         track_build_state.hover_cells.push(cell);
         // println!("{:?}", track_build_state.hover_cells);
         track_build_state.build(
@@ -713,6 +1488,129 @@ fn draw_build_cells(
             dirtrack.draw_with_gizmos(&mut gizmos, scale, Color::from(RED))
         }
     }
+
+    if let Some(target) = track_build_state.snap_target {
+        target.draw_with_gizmos(&mut gizmos, scale, Color::from(LIME));
+        gizmos.circle_2d(
+            target.to_slot().get_vec2() * scale,
+            LAYOUT_SCALE * 0.2,
+            Color::from(LIME),
+        );
+    }
+}
+
+// beyond this many lines per axis the grid is too dense to be useful
+const MAX_GRID_LINES: i32 = 200;
+// labels are only drawn for cells this close to the cursor
+const GRID_LABEL_RADIUS: i32 = 3;
+
+fn toggle_grid_shortcut(
+    keyboard_buttons: Res<ButtonInput<KeyCode>>,
+    selection_state: Res<SelectionState>,
+    mut view_settings: ResMut<ViewSettings>,
+    mut egui_contexts: EguiContexts,
+) {
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    // G on a selected train opens the block picker instead
+    if let Selection::Single(GenericID::Train(_)) = selection_state.selection {
+        return;
+    }
+    if !keyboard_buttons.just_pressed(KeyCode::KeyG) {
+        return;
+    }
+    if keyboard_buttons.pressed(KeyCode::ShiftLeft) {
+        view_settings.grid_labels = !view_settings.grid_labels;
+        view_settings.grid |= view_settings.grid_labels;
+    } else {
+        view_settings.grid = !view_settings.grid;
+    }
+}
+
+// cells covered by the camera viewport, inclusive
+fn visible_cells(
+    q_camera: &Query<(&Camera, &GlobalTransform)>,
+    q_window: &Query<&Window, With<PrimaryWindow>>,
+) -> Option<(IVec2, IVec2)> {
+    let (camera, camera_transform) = q_camera.single().ok()?;
+    let window = q_window.single().ok()?;
+    let corner_a = camera
+        .viewport_to_world_2d(camera_transform, Vec2::ZERO)
+        .ok()?;
+    let corner_b = camera
+        .viewport_to_world_2d(camera_transform, window.size())
+        .ok()?;
+    let min = corner_a.min(corner_b) / LAYOUT_SCALE;
+    let max = corner_a.max(corner_b) / LAYOUT_SCALE;
+    Some((min.round().as_ivec2(), max.round().as_ivec2()))
+}
+
+fn draw_grid(
+    mut gizmos: Gizmos,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+) {
+    let Some((min, max)) = visible_cells(&q_camera, &q_window) else {
+        return;
+    };
+    let count = max - min + IVec2::ONE;
+    if count.x > MAX_GRID_LINES || count.y > MAX_GRID_LINES {
+        return;
+    }
+    let color = Color::from(GRAY).with_alpha(0.3);
+    // cells are centered on integer coordinates, so their borders sit halfway between
+    let low = (min.as_vec2() - 0.5) * LAYOUT_SCALE;
+    let high = (max.as_vec2() + 0.5) * LAYOUT_SCALE;
+    for x in min.x..=max.x + 1 {
+        let line_x = (x as f32 - 0.5) * LAYOUT_SCALE;
+        gizmos.line_2d(Vec2::new(line_x, low.y), Vec2::new(line_x, high.y), color);
+    }
+    for y in min.y..=max.y + 1 {
+        let line_y = (y as f32 - 0.5) * LAYOUT_SCALE;
+        gizmos.line_2d(Vec2::new(low.x, line_y), Vec2::new(high.x, line_y), color);
+    }
+}
+
+fn draw_grid_labels(
+    mut egui_contexts: EguiContexts,
+    mouse_world_pos: Res<MousePosWorld>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+) {
+    let Some((min, max)) = visible_cells(&q_camera, &q_window) else {
+        return;
+    };
+    let Ok((camera, camera_transform)) = q_camera.single() else {
+        return;
+    };
+    let Ok(ctx) = egui_contexts.ctx_mut() else {
+        return;
+    };
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    let mouse_cell = CellID::from_vec2(mouse_world_pos.pos / LAYOUT_SCALE);
+    let radius = IVec2::splat(GRID_LABEL_RADIUS);
+    let center = IVec2::new(mouse_cell.x, mouse_cell.y);
+    let low = (center - radius).max(min);
+    let high = (center + radius).min(max);
+    for x in low.x..=high.x {
+        for y in low.y..=high.y {
+            let cell = CellID::new(x, y, 0);
+            let pos = cell.get_vec2() * LAYOUT_SCALE;
+            if let Ok(screen_pos) = camera.world_to_viewport(camera_transform, pos.extend(0.0)) {
+                painter.text(
+                    egui::pos2(screen_pos.x, screen_pos.y),
+                    egui::Align2::CENTER_CENTER,
+                    format!("{},{}", x, y),
+                    egui::FontId::proportional(10.0),
+                    egui::Color32::GRAY,
+                );
+            }
+        }
+    }
 }
 
 fn update_path_track(
@@ -729,7 +1627,10 @@ fn update_path_track(
 ) {
     let mut route_connections = HashSet::new();
     for train in trains.iter() {
-        for leg in train.get_route().iter_legs_remaining() {
+        let Some(route) = train.get_route() else {
+            continue;
+        };
+        for leg in route.iter_legs_remaining() {
             let section = match leg.get_leg_state() {
                 LegState::Completed => &leg.to_section,
                 _ => &leg.travel_section,
@@ -786,6 +1687,23 @@ fn update_path_track(
     }
 }
 
+fn update_layer_colors(
+    q_outer: Query<(Ref<TrackShapeOuter>, &MeshMaterial2d<TrackBaseMaterial>)>,
+    current_layer: Res<CurrentLayer>,
+    mut base_materials: ResMut<Assets<TrackBaseMaterial>>,
+) {
+    for (connection, material_handle) in q_outer.iter() {
+        if !current_layer.is_changed() && !connection.is_added() {
+            continue;
+        }
+        let color = layer_color(connection.id.from_track.cell().l, current_layer.0);
+        let material = base_materials.get_mut(material_handle).unwrap();
+        if material.color != color {
+            material.color = color;
+        }
+    }
+}
+
 fn update_inner_track(
     mut q_strokes: Query<(
         &TrackShapeInner,
@@ -794,13 +1712,19 @@ fn update_inner_track(
     )>,
     hover_state: Res<HoverState>,
     selection_state: Res<SelectionState>,
+    current_layer: Res<CurrentLayer>,
     mut inner_materials: ResMut<Assets<TrackInnerMaterial>>,
 ) {
-    if !selection_state.is_changed() && !hover_state.is_changed() {
+    if !selection_state.is_changed() && !hover_state.is_changed() && !current_layer.is_changed() {
         return;
     }
     for (connection, mut transform, material_handle) in q_strokes.iter_mut() {
         let z = connection.base_transform().translation.z;
+        if connection.id.from_track.cell().l != current_layer.0 {
+            inner_materials.get_mut(material_handle).unwrap().color = LinearRgba::from(DIM_GRAY);
+            transform.translation.z = z;
+            continue;
+        }
         if hover_state.hover == Some(GenericID::Track(connection.id.from_track.track)) {
             inner_materials.get_mut(material_handle).unwrap().color = LinearRgba::from(RED);
             transform.translation.z = z + 0.5;
@@ -871,15 +1795,10 @@ fn despawn_track(
         connections.remove_track(track_id);
         entity_map.remove_track(track_id);
 
+        other_dirtracks.sort();
+        other_dirtracks.dedup();
         for directed in other_dirtracks {
-            let existing_connections = connections.get_directed_connections_from(directed);
-            let event = UpdateSwitchTurnsMessage {
-                id: directed,
-                positions: existing_connections
-                    .iter()
-                    .map(|c| c.get_switch_position())
-                    .collect::<Vec<SwitchPosition>>(),
-            };
+            let event = UpdateSwitchTurnsMessage::from_connections(directed, &connections);
             println!("{:?}", event);
             switch_update_messages.write(event);
         }
@@ -903,15 +1822,48 @@ pub struct TrackPlugin;
 impl Plugin for TrackPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(TrackBuildState::default());
+        app.insert_resource(SectionDragState::default());
+        app.insert_resource(PortalSettings::default());
+        app.add_plugins(SelectablePlugin::<Track>::new());
+        app.add_message::<SpawnTrackMessage>();
+        app.add_message::<SpawnConnectionMessage>();
+        app.add_message::<DespawnConnectionMessage>();
+        app.add_message::<TransformSectionMessage>();
+        app.add_message::<DespawnMessage<Track>>();
+        app.add_systems(
+            Update,
+            (
+                transform_section
+                    .run_if(on_message::<TransformSectionMessage>)
+                    .after(drag_section),
+                // so moved tracks are despawned before their replacements spawn
+                despawn_track
+                    .after(transform_section)
+                    .after(undo_redo_shortcut),
+                despawn_connection.run_if(on_message::<DespawnConnectionMessage>),
+            ),
+        );
+        app.add_systems(
+            PostUpdate,
+            (
+                spawn_track.run_if(on_message::<SpawnTrackMessage>),
+                spawn_connection
+                    .run_if(on_message::<SpawnConnectionMessage>)
+                    .after(spawn_track),
+            ),
+        );
+    }
+}
+
+pub struct TrackRenderPlugin;
+
+impl Plugin for TrackRenderPlugin {
+    fn build(&self, app: &mut App) {
         app.add_plugins(TrackMeshPlugin::<TrackShapeOuter>::default());
         app.add_plugins(TrackMeshPlugin::<TrackShapeInner>::default());
         app.add_plugins(TrackMeshPlugin::<TrackShapePath>::default());
-        app.add_plugins(SelectablePlugin::<Track>::new());
         app.add_plugins(InspectorPlugin::<Track>::new());
         app.add_plugins(InspectorPlugin::<TrackSectionSelection>::new());
-        app.add_message::<SpawnTrackMessage>();
-        app.add_message::<SpawnConnectionMessage>();
-        app.add_message::<DespawnMessage<Track>>();
         app.add_observer(update_path_track);
         app.add_systems(
             Update,
@@ -920,19 +1872,26 @@ impl Plugin for TrackPlugin {
                 exit_draw_track.run_if(in_state(EditorState::Edit)),
                 update_draw_track.run_if(in_state(EditorState::Edit)),
                 update_inner_track.after(finish_hover),
+                update_layer_colors,
                 draw_build_cells.run_if(in_state(EditorState::Edit)),
-                delete_selection_shortcut::<Track>.run_if(in_state(EditorState::Edit)),
-                despawn_track,
+                delete_track_shortcut.run_if(in_state(EditorState::Edit)),
+                drag_section.run_if(in_state(EditorState::Edit)),
+                draw_section_drag.run_if(in_state(EditorState::Edit)),
+                toggle_grid_shortcut.run_if(in_state(EditorState::Edit)),
+                draw_grid
+                    .run_if(in_state(EditorState::Edit))
+                    .run_if(|view: Res<ViewSettings>| view.grid),
             ),
         );
         app.add_systems(
-            PostUpdate,
+            EguiPrimaryContextPass,
             (
-                spawn_track.run_if(on_message::<SpawnTrackMessage>),
-                spawn_connection
-                    .run_if(on_message::<SpawnConnectionMessage>)
-                    .after(spawn_track),
+                portal_window.run_if(|view: Res<ViewSettings>| view.portals),
+                draw_grid_labels
+                    .run_if(in_state(EditorState::Edit))
+                    .run_if(|view: Res<ViewSettings>| view.grid && view.grid_labels),
             ),
         );
+        app.add_systems(PostUpdate, add_connection_materials.after(spawn_connection));
     }
 }
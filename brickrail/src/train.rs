@@ -1,30 +1,36 @@
 use crate::{
-    ble::HubCommandMessage,
+    ble::{BLEHub, HubBattery, HubBatteryPolicy, HubCommandMessage, HubError, HubReconnecting},
     ble_train::BLETrain,
     block::{Block, spawn_block},
     crossing::{LevelCrossing, SetCrossingPositionMessage},
-    destination::{BlockDirectionFilter, Destination},
+    destination::{
+        BlockDirectionFilter, Destination, DestinationArrivalMessage, DestinationUsage,
+        TargetDistribution,
+    },
     editor::*,
     inspector::{Inspectable, InspectorPlugin},
-    layout::{Connections, EntityMap, MarkerMap, TrackLocks},
+    layout::{Connections, EntityMap, LayoutUnits, MarkerMap, SignalAspects, TrackLocks},
     layout_primitives::*,
     marker::Marker,
-    route::{LegState, Route, build_route},
+    route::{LegIntention, LegState, Route, build_route},
     route_modular::{AssignedRoute, AssignedRouteLeg, ModularRoute, ModularRouteLeg},
-    route_modular::{ModularTrain, ProxyTrainOf, ProxyTrains, TrainState},
-    schedule::{AssignedSchedule, ControlInfo, TrainSchedule},
+    route_modular::{ModularTrain, ProxyTrainOf, ProxyTrains, TrainSpeed, TrainState},
+    routing::{RoutePreferences, route_snapshot},
+    schedule::{AssignedSchedule, ControlInfo, ControlRng, RandomSeed, TrainSchedule},
     section::LogicalSection,
     selectable::{Selectable, SelectablePlugin, SelectableType},
     switch::{SetSwitchPositionMessage, Switch},
-    track::LAYOUT_SCALE,
+    track::{LAYER_Z_OFFSET, LAYOUT_SCALE, PortalSettings},
 };
 use bevy::{
     color::palettes::css::{ORANGE, RED, YELLOW},
     ecs::system::{SystemParam, SystemState},
+    platform::collections::{HashMap, HashSet},
 };
 use bevy::{input::keyboard, prelude::*};
-use bevy_egui::egui::Ui;
-use bevy_inspector_egui::bevy_egui;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui::{self, Ui};
+use bevy_inspector_egui::bevy_egui::{self, EguiPrimaryContextPass};
 use bevy_inspector_egui::reflect_inspector::ui_for_value;
 use bevy_prototype_lyon::{
     draw::Stroke,
@@ -32,24 +38,37 @@ use bevy_prototype_lyon::{
     path::ShapePath,
     prelude::{LineCap, ShapeBuilder, ShapeBuilderBase, StrokeOptions},
 };
+use petgraph::graphmap::DiGraphMap;
 use rand::prelude::*;
-use serde::{Deserialize, Serialize};
+use rfd::FileDialog;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::VecDeque;
 
 const TRAIN_WIDTH: f32 = 0.3;
 const WAGON_DIST: f32 = 0.7;
 const WAGON_LENGTH: f32 = 0.6;
+const SHUNT_SPEED: f32 = 0.5;
+const SHUNT_RESOLUTION: f32 = 0.05;
+// change of the manual throttle per second while an arrow key is held
+const THROTTLE_RATE: f32 = 4.0;
+// chance of picking a recently visited block relative to the others
+const RECENT_BLOCK_WEIGHT: f32 = 0.2;
 
 #[derive(Resource, Default, Debug)]
 pub struct TrainDragState {
     train_id: Option<TrainID>,
     target: Option<LogicalBlockID>,
     target_facing: Facing,
+    // blocks the route has to pass before the target, appended with shift+click
+    waypoints: Vec<LogicalBlockID>,
     pub route: Option<Route>,
 }
 
 #[derive(Component, Debug)]
 pub struct TrainWagon {
     pub id: WagonID,
+    length: f32,
+    width: f32,
 }
 
 impl Selectable for TrainWagon {
@@ -84,7 +103,7 @@ impl Selectable for TrainWagon {
             .truncate()
             / LAYOUT_SCALE;
 
-        let extent = Vec2::new(WAGON_DIST * 0.6, TRAIN_WIDTH * 0.5);
+        let extent = Vec2::new(self.length * 0.7, self.width * 0.5);
         let vec_to_closest_corner = pos_local.abs() - extent;
 
         vec_to_closest_corner.max(Vec2::ZERO).length()
@@ -102,19 +121,19 @@ struct TrainWagonBundle {
 }
 
 impl TrainWagonBundle {
-    fn new(id: WagonID) -> Self {
+    fn new(id: WagonID, length: f32, width: f32) -> Self {
         let path = ShapePath::new()
-            .move_to(-Vec2::X * 0.5 * (WAGON_LENGTH - TRAIN_WIDTH) * LAYOUT_SCALE)
-            .line_to(Vec2::X * 0.5 * (WAGON_LENGTH - TRAIN_WIDTH) * LAYOUT_SCALE);
+            .move_to(-Vec2::X * 0.5 * (length - width) * LAYOUT_SCALE)
+            .line_to(Vec2::X * 0.5 * (length - width) * LAYOUT_SCALE);
         let stroke = Stroke {
             color: Color::from(YELLOW),
             options: StrokeOptions::default()
-                .with_line_width(TRAIN_WIDTH * LAYOUT_SCALE)
+                .with_line_width(width * LAYOUT_SCALE)
                 .with_line_cap(LineCap::Round),
         };
         let shape = ShapeBuilder::with(&path).stroke(stroke).build();
         Self {
-            wagon: TrainWagon { id },
+            wagon: TrainWagon { id, length, width },
             shape: shape,
         }
     }
@@ -124,7 +143,145 @@ impl TrainWagonBundle {
 struct TrainSettings {
     num_wagons: usize,
     home: Option<LogicalBlockID>,
-    prefer_facing: Option<Facing>,
+    #[serde(default, deserialize_with = "deserialize_facing_preference")]
+    prefer_facing: FacingPreference,
+    #[serde(default)]
+    obey_signals: bool,
+    #[serde(default = "default_wagon_dist")]
+    wagon_dist: f32,
+    #[serde(default = "default_wagon_length")]
+    wagon_length: f32,
+    #[serde(default = "default_train_width")]
+    train_width: f32,
+    // for dispatches that don't ask for a strategy themselves
+    #[serde(default)]
+    default_strategy: Option<TargetChoiceStrategy>,
+    // seconds during which the block the train last departed from can't be a target
+    #[serde(default)]
+    reselect_cooldown: f32,
+    #[serde(default)]
+    unreachable_policy: UnreachablePolicy,
+    // length of route ahead the train reserves beyond what it needs, in cells
+    #[serde(default)]
+    reserve_distance: f32,
+    // additional look-ahead in multiples of the train length
+    #[serde(default = "default_reserve_train_lengths")]
+    reserve_train_lengths: f32,
+    #[serde(default)]
+    route_preferences: RoutePreferences,
+    // blocks that routes must not pass through
+    #[serde(default)]
+    #[reflect(ignore)]
+    avoid_blocks: HashSet<BlockID>,
+    // number of recently left blocks random targets steer away from
+    #[serde(default = "default_history_length")]
+    history_length: usize,
+    // how fast the speed follows the target while speeding up and slowing down, in 1/s
+    #[serde(default = "default_accel")]
+    accel: f32,
+    #[serde(default = "default_decel")]
+    decel: f32,
+    // slows the train proportional to its speed, in 1/s
+    #[serde(default = "default_drag")]
+    drag: f32,
+}
+
+// what to do when no target of a queued destination can be reached
+// with the current layout, regardless of locks
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Reflect, Serialize, Deserialize)]
+pub enum UnreachablePolicy {
+    #[default]
+    Hold,
+    Skip,
+    Alert,
+}
+
+fn default_history_length() -> usize {
+    2
+}
+
+fn default_reserve_train_lengths() -> f32 {
+    1.0
+}
+
+fn default_accel() -> f32 {
+    2.8
+}
+
+fn default_decel() -> f32 {
+    2.8
+}
+
+fn default_drag() -> f32 {
+    0.5
+}
+
+fn default_wagon_dist() -> f32 {
+    WAGON_DIST
+}
+
+fn default_wagon_length() -> f32 {
+    WAGON_LENGTH
+}
+
+fn default_train_width() -> f32 {
+    TRAIN_WIDTH
+}
+
+fn deserialize_facing_preference<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<FacingPreference, D::Error> {
+    // older layouts stored the preference as an optional facing
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SerializedFacingPreference {
+        Legacy(Option<Facing>),
+        Current(FacingPreference),
+    }
+    Ok(
+        match SerializedFacingPreference::deserialize(deserializer)? {
+            SerializedFacingPreference::Legacy(facing) => facing.into(),
+            SerializedFacingPreference::Current(preference) => preference,
+        },
+    )
+}
+
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct TrainDefaults {
+    pub prefer_facing: FacingPreference,
+    #[serde(default = "default_dead_end_clearance")]
+    pub dead_end_clearance: f32,
+    // extra tracks reserved ahead of what the route needs
+    #[serde(default)]
+    pub reservation_margin: usize,
+    // only blocks marked as reversal points let trains change ends
+    #[serde(default)]
+    pub reversal_points_only: bool,
+    // destinations skip blocks shorter than the train
+    #[serde(default)]
+    pub fit_blocks_only: bool,
+}
+
+fn default_dead_end_clearance() -> f32 {
+    0.3
+}
+
+impl Default for TrainDefaults {
+    fn default() -> Self {
+        Self {
+            prefer_facing: FacingPreference::default(),
+            dead_end_clearance: default_dead_end_clearance(),
+            reservation_margin: 0,
+            reversal_points_only: false,
+            fit_blocks_only: false,
+        }
+    }
+}
+
+impl TrainDefaults {
+    pub fn allows_block(&self, block: &Block, train_length: f32, ramp_length: f32) -> bool {
+        !self.fit_blocks_only || block.fits(train_length, ramp_length)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,10 +337,25 @@ pub struct Train {
     settings: TrainSettings,
     #[serde(skip)]
     wagons: Vec<WagonID>,
+    // hold at the next block while another train is isolated or a hub battery is low
+    #[serde(skip)]
+    held: bool,
+    // stopped where it is because one of its hubs lost the connection
+    #[serde(skip)]
+    hub_lost: bool,
+    #[serde(skip)]
+    last_blocks: VecDeque<LogicalBlockID>,
+    // speed set from the keyboard, replaces the speed of the route's train state
+    #[serde(skip)]
+    throttle: Option<f32>,
 }
 
 impl Train {
-    pub fn at_block_id(train_id: TrainID, logical_block_id: LogicalBlockID) -> Train {
+    pub fn at_block_id(
+        train_id: TrainID,
+        logical_block_id: LogicalBlockID,
+        defaults: &TrainDefaults,
+    ) -> Train {
         let train = Train {
             id: train_id,
             position: Position::Block(logical_block_id),
@@ -195,105 +367,327 @@ impl Train {
             settings: TrainSettings {
                 num_wagons: 3,
                 home: None,
-                prefer_facing: None,
+                prefer_facing: defaults.prefer_facing,
+                obey_signals: false,
+                wagon_dist: WAGON_DIST,
+                wagon_length: WAGON_LENGTH,
+                train_width: TRAIN_WIDTH,
+                default_strategy: None,
+                reselect_cooldown: 0.0,
+                unreachable_policy: UnreachablePolicy::Hold,
+                reserve_distance: 0.0,
+                reserve_train_lengths: default_reserve_train_lengths(),
+                route_preferences: RoutePreferences::default(),
+                avoid_blocks: HashSet::new(),
+                history_length: default_history_length(),
+                accel: default_accel(),
+                decel: default_decel(),
+                drag: default_drag(),
             },
             wagons: vec![],
+            held: false,
+            hub_lost: false,
+            last_blocks: VecDeque::new(),
+            throttle: None,
         };
         train
     }
 
-    pub fn get_logical_block_id(&self) -> LogicalBlockID {
-        self.get_route().get_current_leg().get_target_block_id()
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, TrainState::Run { .. })
+    }
+
+    pub fn is_hub_lost(&self) -> bool {
+        self.hub_lost
+    }
+
+    // the hubs can't be told anything, so the train stops in place
+    fn set_hub_lost(&mut self, hub_lost: bool) {
+        if hub_lost && !self.hub_lost {
+            warn!("Train {:?} lost a hub connection, stopping", self.id);
+            self.speed = 0.0;
+            if let Some(route) = self.get_route_mut() {
+                let leg_index = route.leg_index();
+                for leg in route.iter_legs_mut().skip(leg_index) {
+                    leg.intention = LegIntention::Stop;
+                }
+            }
+        }
+        self.hub_lost = hub_lost;
+    }
+
+    // from the front of the first wagon to the back of the last
+    pub fn length(&self) -> f32 {
+        self.settings.wagon_dist * self.settings.num_wagons as f32 + self.settings.wagon_length
+    }
+
+    // points along the body from front to back with their layer, empty without a route
+    pub fn body_positions(&self, step: f32) -> Vec<(Vec2, f32)> {
+        let Some(route) = self.get_route() else {
+            return vec![];
+        };
+        let samples = (self.length() / step).ceil().max(1.0) as usize;
+        (0..=samples)
+            .map(|i| {
+                let offset = -self.length() * i as f32 / samples as f32;
+                (
+                    route.interpolate_offset(offset),
+                    route.interpolate_offset_layer(offset),
+                )
+            })
+            .collect()
+    }
+
+    pub fn get_logical_block_id(&self) -> Option<LogicalBlockID> {
+        match &self.position {
+            Position::Route(route) => Some(route.get_current_leg().get_target_block_id()),
+            Position::Block(block_id) => Some(*block_id),
+            Position::Storage => None,
+        }
+    }
+
+    fn remember_block(&mut self, block: LogicalBlockID) {
+        if self.last_blocks.back() == Some(&block) {
+            return;
+        }
+        self.last_blocks.push_back(block);
+        while self.last_blocks.len() > self.settings.history_length {
+            self.last_blocks.pop_front();
+        }
+    }
+
+    fn visited_recently(&self, block_id: BlockID) -> bool {
+        self.last_blocks.iter().any(|block| block.block == block_id)
     }
 
-    pub fn get_route(&self) -> &Route {
+    // weighted shuffle, recently visited blocks tend to end up last
+    pub fn shuffle_targets<T>(
+        &self,
+        targets: Vec<(BlockID, T)>,
+        rng: &mut ControlRng,
+    ) -> Vec<(BlockID, T)> {
+        let mut keyed = targets
+            .into_iter()
+            .map(|(block_id, target)| {
+                let weight = if self.visited_recently(block_id) {
+                    RECENT_BLOCK_WEIGHT
+                } else {
+                    1.0
+                };
+                (rng.0.random::<f32>().powf(1.0 / weight), block_id, target)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keyed
+            .into_iter()
+            .map(|(_, block_id, target)| (block_id, target))
+            .collect()
+    }
+
+    // tracks of the avoided blocks, except the one the train starts from
+    fn avoided_tracks(
+        &self,
+        start: LogicalBlockID,
+        q_blocks: &Query<&Block>,
+        entity_map: &EntityMap,
+    ) -> HashSet<TrackID> {
+        self.settings
+            .avoid_blocks
+            .iter()
+            .filter(|block_id| **block_id != start.block)
+            .filter_map(|block_id| entity_map.blocks.get(block_id))
+            .filter_map(|entity| q_blocks.get(*entity).ok())
+            .flat_map(|block| block.section().tracks.iter().map(|track| track.track))
+            .collect()
+    }
+
+    pub fn get_route(&self) -> Option<&Route> {
         match &self.position {
-            Position::Route(route) => route,
-            _ => panic!("Train {:?} has no route", self.id),
+            Position::Route(route) => Some(route),
+            _ => None,
         }
     }
 
-    pub fn get_route_mut(&mut self) -> &mut Route {
+    // whether the train has entered or stopped in the block
+    pub fn has_reached(&self, block_id: BlockID) -> bool {
+        match &self.position {
+            Position::Route(route) => {
+                let leg = route.get_current_leg();
+                leg.get_target_block_id().block == block_id && leg.get_leg_state() != LegState::None
+            }
+            Position::Block(logical_block_id) => logical_block_id.block == block_id,
+            Position::Storage => false,
+        }
+    }
+
+    pub fn get_route_mut(&mut self) -> Option<&mut Route> {
         match &mut self.position {
-            Position::Route(route) => route,
-            _ => panic!("Train {:?} has no route", self.id),
+            Position::Route(route) => Some(route),
+            _ => None,
         }
     }
 
     pub fn advance_sensor(&mut self) {
-        let route = self.get_route_mut();
+        let Some(route) = self.get_route_mut() else {
+            return;
+        };
         route.advance_sensor().expect("Failed to advance sensor");
 
         self.set_seek_target();
     }
 
     fn set_seek_target(&mut self) {
-        let route = self.get_route();
+        let Some(route) = self.get_route() else {
+            return;
+        };
         let current_pos = route.get_current_leg().get_signed_pos_from_first();
         let prev_marker_pos = route
             .get_current_leg()
-            .get_prev_marker_signed_from_first(WAGON_DIST);
+            .get_prev_marker_signed_from_first(self.settings.wagon_dist);
 
         self.seek_pos = prev_marker_pos - current_pos;
         // shift by how much the train will be out of phase after seeking
         // so seeking basically undoes the phase shift
-        self.seek_pos -= (self.seek_pos + (1.0 - self.in_place_cycle) * WAGON_DIST) % WAGON_DIST;
+        let wagon_dist = self.settings.wagon_dist;
+        self.seek_pos -= (self.seek_pos + (1.0 - self.in_place_cycle) * wagon_dist) % wagon_dist;
     }
 
     fn traverse_route(
         &mut self,
         delta: f32,
         advance_messages: &mut MessageWriter<MarkerAdvanceMessage>,
+        smoothing: &SpeedSmoothing,
     ) {
-        let target_speed = self.state.get_speed();
-        self.speed += ((target_speed - self.speed) * 2.8 - self.speed * 0.5) * delta;
+        let target_speed = match self.get_route() {
+            Some(route) => route.get_target_speed(),
+            None => self.state.get_speed(),
+        };
+        // the throttle only slows the train down, so the route still slows it for limits
+        // and stops it at the end of its locked track
+        let target_speed = match self.throttle {
+            Some(throttle) => throttle.min(target_speed.abs()) * target_speed.signum(),
+            None => target_speed,
+        };
+        self.speed += smoothing.acceleration(&self.settings, self.speed, target_speed) * delta;
         let dist = delta * self.speed;
-        self.get_route_mut()
-            .advance_distance(dist, advance_messages);
-        self.state = self.get_route().get_train_state();
+        let Some(route) = self.get_route_mut() else {
+            return;
+        };
+        route.advance_distance(dist, advance_messages);
+        self.state = route.get_train_state();
         // self.speed = self.state.get_speed();
         // println!("Train state: {:?}, {:?}", self.state, self.speed);
         // println!("Route: {:?}", self.route.get_current_leg().section_position);
     }
 
-    fn traverse_route_passive(&mut self, delta: f32) {
-        let target_speed = self.get_route().get_train_state().get_speed();
-        self.speed += ((target_speed - self.speed) * 2.8 - self.speed * 0.5) * delta;
+    fn traverse_route_passive(&mut self, delta: f32, tuning: &MotionTuning) {
+        let wagon_dist = self.settings.wagon_dist;
+        // a third of a wagon short of the next marker, 0.2 for the default wagons
+        let stop_offset = -self.settings.wagon_length / 3.0;
+        // match on the field directly so speed and seek state stay borrowable
+        let Position::Route(route) = &mut self.position else {
+            return;
+        };
+        let target_speed = route.get_target_speed();
+        self.speed += tuning
+            .passive
+            .acceleration(&self.settings, self.speed, target_speed)
+            * delta;
 
-        let route = self.get_route_mut();
         let current_pos = route.get_current_leg().get_signed_pos_from_first();
         let mut move_mod = 1.0;
 
         let travel_sign = target_speed.signum();
         if let Some(next_marker_pos) = route
             .get_current_leg()
-            .get_next_marker_signed_from_first(-0.2)
+            .get_next_marker_signed_from_first(stop_offset)
         {
             let dist = (next_marker_pos - current_pos) * travel_sign;
-            move_mod = dist.clamp(0.0, WAGON_DIST) / WAGON_DIST;
+            move_mod = dist.clamp(0.0, wagon_dist) / wagon_dist;
         }
 
-        self.seek_speed += (self.seek_pos * 40.0 - self.seek_speed * 10.0) * delta;
+        self.seek_speed +=
+            (self.seek_pos * tuning.seek_stiffness - self.seek_speed * tuning.seek_damping) * delta;
         let move_speed = self.speed * move_mod + self.seek_speed;
 
-        self.in_place_cycle += delta * (self.speed - move_speed) / WAGON_DIST;
+        self.in_place_cycle += delta * (self.speed - move_speed) / wagon_dist;
         self.in_place_cycle = self.in_place_cycle.rem_euclid(1.0);
         self.seek_pos -= self.seek_speed * delta;
         let new_pos = current_pos + move_speed * delta;
-        self.get_route_mut()
+        route
             .get_current_leg_mut()
             .set_signed_pos_from_first(new_pos);
     }
 
+    fn can_shunt(&self) -> bool {
+        match &self.position {
+            Position::Route(route) => route.is_completed() && route.num_legs() == 1,
+            _ => false,
+        }
+    }
+
+    // moves the stopped train within its own (locked) block section,
+    // returns the distance actually moved
+    fn shunt(&mut self, distance: f32) -> f32 {
+        let Some(route) = self.get_route_mut() else {
+            return 0.0;
+        };
+        let leg = route.get_current_leg_mut();
+        let start = leg.get_signed_pos_from_first();
+        let sign = leg.get_final_facing().get_sign();
+        let length = leg.travel_section.length(leg.ramp_length);
+        leg.section_position = (leg.section_position + distance * sign).clamp(0.0, length);
+        leg.get_signed_pos_from_first() - start
+    }
+
+    fn shunt_distance_to(&self, pos: Vec2) -> f32 {
+        let Some(route) = self.get_route() else {
+            return 0.0;
+        };
+        let leg = route.get_current_leg();
+        let length = leg.travel_section.length(leg.ramp_length);
+        let steps = (length / SHUNT_RESOLUTION).ceil().max(1.0) as usize;
+        let closest = (0..=steps)
+            .map(|i| (i as f32 * SHUNT_RESOLUTION).min(length))
+            .min_by(|a, b| {
+                let dist_a = leg
+                    .travel_section
+                    .interpolate_pos(*a, leg.ramp_length)
+                    .distance(pos);
+                let dist_b = leg
+                    .travel_section
+                    .interpolate_pos(*b, leg.ramp_length)
+                    .distance(pos);
+                dist_a.total_cmp(&dist_b)
+            })
+            .unwrap();
+        (closest - leg.section_position) * leg.get_final_facing().get_sign()
+    }
+
     pub fn inspector(ui: &mut Ui, world: &mut World) {
         let mut state = SystemState::<(
-            Query<(&mut Train, Option<&mut AssignedSchedule>)>,
+            Query<(
+                &mut Train,
+                Option<&mut AssignedSchedule>,
+                Option<&UnreachableDestination>,
+            )>,
             Query<(&TrainSchedule, Option<&Name>)>,
             ResMut<EntityMap>,
             Res<SelectionState>,
             Res<AppTypeRegistry>,
             Commands,
             Res<ControlInfo>,
+            Res<LayoutUnits>,
+            Res<State<EditorState>>,
+            ResMut<ShuntingSettings>,
+            ResMut<IsolatedTrain>,
+            Res<MarkerMap>,
+            Res<Deadlocks>,
+            Query<(&Block, &Name)>,
         )>::new(world);
         let (
             mut trains,
@@ -303,16 +697,111 @@ impl Train {
             type_registry,
             mut commands,
             control_info,
+            units,
+            editor_state,
+            mut shunting,
+            mut isolated,
+            marker_map,
+            deadlocks,
+            q_blocks,
         ) = state.get_mut(world);
         if let Some(entity) = selection_state.get_entity(&entity_map) {
-            if let Ok((mut train, schedule_option)) = trains.get_mut(entity) {
+            if let Ok((mut train, schedule_option, unreachable)) = trains.get_mut(entity) {
+                ui.label(format!("Speed: {}", units.format_speed(train.speed)));
+                if let Some(throttle) = train.throttle {
+                    ui.label(format!("Throttle: {}", units.format_speed(throttle)));
+                }
+                if editor_state.get() == &EditorState::DeviceControl {
+                    ui.label("The arrow key throttle only drives virtual trains");
+                }
+                let mut isolate = isolated.0 == Some(train.id);
+                if ui
+                    .checkbox(&mut isolate, "Isolate")
+                    .on_hover_text("Hold all other trains at their next block")
+                    .changed()
+                {
+                    isolated.0 = isolate.then_some(train.id);
+                }
+                if let Some(other) = isolated.0.filter(|id| *id != train.id) {
+                    ui.label(format!("Held while {} is isolated", other));
+                }
+                if train.hub_lost {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        "Stopped, a hub lost the connection",
+                    );
+                }
+                if let Some(cycle) = deadlocks.cycle_of(&train.id) {
+                    let others = cycle
+                        .iter()
+                        .filter(|id| **id != train.id)
+                        .map(|id| id.to_string())
+                        .collect::<Vec<_>>();
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        format!("Deadlocked with {}", others.join(", ")),
+                    );
+                }
+                if let Some(route) = train.get_route() {
+                    if ui
+                        .button("Copy route")
+                        .on_hover_text("Copy the route's legs as text")
+                        .clicked()
+                    {
+                        ui.ctx()
+                            .copy_text(route_snapshot(&route.critical_section, &marker_map));
+                    }
+                }
                 if ui_for_value(&mut train.settings, ui, &type_registry.read()) {
                     train.update_wagon_entities(&mut commands, &mut entity_map);
                 }
+                ui.collapsing("Avoided blocks", |ui| {
+                    let mut names = q_blocks
+                        .iter()
+                        .map(|(block, name)| (block.id, name.to_string()))
+                        .collect::<Vec<_>>();
+                    names.sort_by(|a, b| a.1.cmp(&b.1));
+                    let mut removed = None;
+                    for (block_id, name) in names.iter() {
+                        if !train.settings.avoid_blocks.contains(block_id) {
+                            continue;
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label(name);
+                            if ui.button("Remove").clicked() {
+                                removed = Some(*block_id);
+                            }
+                        });
+                    }
+                    if let Some(block_id) = removed {
+                        train.settings.avoid_blocks.remove(&block_id);
+                    }
+                    let mut added = None;
+                    egui::ComboBox::from_id_salt("avoid_block")
+                        .selected_text("Add block")
+                        .show_ui(ui, |ui| {
+                            for (block_id, name) in names.iter() {
+                                if !train.settings.avoid_blocks.contains(block_id) {
+                                    ui.selectable_value(&mut added, Some(*block_id), name);
+                                }
+                            }
+                        });
+                    if let Some(block_id) = added {
+                        train.settings.avoid_blocks.insert(block_id);
+                    }
+                });
                 ui.separator();
                 ui.heading("Schedule");
+                if let Some(UnreachableDestination(dest)) = unreachable {
+                    ui.colored_label(egui::Color32::LIGHT_RED, format!("{} is unreachable", dest));
+                }
                 if let Some(mut schedule) = schedule_option {
+                    let previous = schedule.schedule_id;
                     TrainSchedule::selector_option(&schedules, ui, &mut schedule.schedule_id);
+                    if schedule.schedule_id != previous {
+                        schedule.current_stop_index = 0;
+                        schedule.finished = false;
+                    }
                     if let Some(sched) = schedule.schedule_id {
                         let actual_schedule = schedules
                             .get(entity_map.get_entity(&GenericID::Schedule(sched)).unwrap())
@@ -327,6 +816,34 @@ impl Train {
                     commands.entity(entity).insert(AssignedSchedule::default());
                 }
                 ui.separator();
+                if editor_state.get() == &EditorState::VirtualControl {
+                    ui.heading("Shunting");
+                    let mut distance = units.to_units(shunting.distance);
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Distance ({})", units.unit_name));
+                        ui.add(egui::DragValue::new(&mut distance).speed(0.05));
+                        if ui.button("Car length").clicked() {
+                            distance = units.to_units(train.settings.wagon_dist);
+                        }
+                    });
+                    shunting.distance = distance / units.cell_length;
+                    ui.add_enabled_ui(train.can_shunt(), |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.button("Jog back").clicked() {
+                                commands.entity(entity).insert(ShuntingMove {
+                                    remaining: -shunting.distance,
+                                });
+                            }
+                            if ui.button("Jog forward").clicked() {
+                                commands.entity(entity).insert(ShuntingMove {
+                                    remaining: shunting.distance,
+                                });
+                            }
+                        });
+                    });
+                    ui.label("Shift+click in the block to jog there");
+                    ui.separator();
+                }
             }
         }
         state.apply(world);
@@ -339,12 +856,23 @@ impl Train {
         commands: &mut Commands,
         entity_map: &mut ResMut<EntityMap>,
     ) {
+        self.settings.wagon_dist = self.settings.wagon_dist.max(0.1);
+        self.settings.train_width = self.settings.train_width.max(0.05);
+        let length = self.settings.wagon_length;
+        let width = self.settings.train_width;
+        for wagon_id in &self.wagons {
+            // rebuild the shapes in case the wagon dimensions changed
+            let entity = entity_map.wagons.get(wagon_id).unwrap();
+            commands
+                .entity(*entity)
+                .insert(TrainWagonBundle::new(*wagon_id, length, width));
+        }
         while self.wagons.len() < self.settings.num_wagons + 1 {
             let wagon_id = WagonID {
                 train: self.id,
                 index: self.wagons.len(),
             };
-            let wagon = TrainWagonBundle::new(wagon_id);
+            let wagon = TrainWagonBundle::new(wagon_id, length, width);
             let entity = commands.spawn(wagon).id();
             entity_map.add_wagon(wagon_id, entity);
             self.wagons.push(wagon_id);
@@ -453,15 +981,20 @@ fn update_wagons(
         if hover_state.hover == Some(GenericID::Train(train.id)) {
             color = Color::from(RED);
         }
+        let Some(route) = train.get_route() else {
+            continue;
+        };
         for wagon_id in &train.wagons {
             let wagon_entity = entity_map.wagons.get(wagon_id).unwrap();
             let (mut transform, mut shape) = q_wagons.get_mut(*wagon_entity).unwrap();
-            let offset = -WAGON_DIST * (wagon_id.index as f32);
-            let offset2 = offset + train.in_place_cycle * WAGON_DIST;
-            let pos = train.get_route().interpolate_offset(offset2);
-            let pos2 = train.get_route().interpolate_offset(offset2 + 0.01);
+            let offset = -train.settings.wagon_dist * (wagon_id.index as f32);
+            let offset2 = offset + train.in_place_cycle * train.settings.wagon_dist;
+            let pos = route.interpolate_offset(offset2);
+            let pos2 = route.interpolate_offset(offset2 + 0.01);
             let angle = -(pos2 - pos).angle_to(Vec2::X);
-            transform.translation = pos.extend(20.0) * LAYOUT_SCALE;
+            // climbs with the track on ramps between layers
+            let layer = route.interpolate_offset_layer(offset2);
+            transform.translation = pos.extend(20.0 + layer * LAYER_Z_OFFSET) * LAYOUT_SCALE;
             transform.rotation = Quat::from_rotation_z(angle);
 
             let mut alpha = 1.0;
@@ -478,14 +1011,19 @@ fn update_wagons(
 
 fn draw_train(mut gizmos: Gizmos, q_trains: Query<&Train>) {
     for train in q_trains.iter() {
-        let pos = train.get_route().interpolate_offset(0.0);
+        let Some(route) = train.get_route() else {
+            continue;
+        };
+        let pos = route.interpolate_offset(0.0);
         gizmos.circle_2d(pos * LAYOUT_SCALE, 0.03 * LAYOUT_SCALE, Color::BLACK);
     }
 }
 
 fn draw_train_route(mut gizmos: Gizmos, q_trains: Query<&Train>) {
     for train in q_trains.iter() {
-        train.get_route().draw_with_gizmos(&mut gizmos);
+        if let Some(route) = train.get_route() {
+            route.draw_with_gizmos(&mut gizmos);
+        }
     }
 }
 
@@ -497,6 +1035,59 @@ fn draw_locked_tracks(mut gizmos: Gizmos, track_locks: Res<TrackLocks>) {
     }
 }
 
+fn draw_lock_labels(
+    mut egui_contexts: EguiContexts,
+    track_locks: Res<TrackLocks>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    switches: Query<&Switch>,
+) {
+    let Ok((camera, camera_transform)) = q_camera.single() else {
+        return;
+    };
+    let Ok(ctx) = egui_contexts.ctx_mut() else {
+        return;
+    };
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    let draw_label = |pos: Vec2, text: String| {
+        if let Ok(screen_pos) =
+            camera.world_to_viewport(camera_transform, (pos * LAYOUT_SCALE).extend(0.0))
+        {
+            painter.text(
+                egui::pos2(screen_pos.x, screen_pos.y),
+                egui::Align2::CENTER_CENTER,
+                text,
+                egui::FontId::proportional(12.0),
+                egui::Color32::LIGHT_RED,
+            );
+        }
+    };
+    for (track, train) in track_locks.locked_tracks.iter() {
+        draw_label(track.dirtracks()[0].get_center_vec2(), train.to_string());
+    }
+    for switch in switches.iter() {
+        let locks = switch
+            .motors
+            .iter()
+            .filter_map(|motor| {
+                motor
+                    .as_ref()
+                    .and_then(|id| track_locks.locked_switch_motors.get(id))
+            })
+            .collect::<Vec<_>>();
+        if let Some((train, _)) = locks.first() {
+            let positions = locks
+                .iter()
+                .map(|(_, position)| format!("{:?}", position))
+                .collect::<Vec<_>>()
+                .join(", ");
+            draw_label(
+                switch.id().get_center_vec2() + Vec2::new(0.0, 0.25),
+                format!("{}: {}", train, positions),
+            );
+        }
+    }
+}
+
 fn init_drag_train(
     mouse_buttons: Res<ButtonInput<MouseButton>>,
     mut train_drag_state: ResMut<TrainDragState>,
@@ -507,6 +1098,7 @@ fn init_drag_train(
             train_drag_state.train_id = Some(*train_id);
             train_drag_state.target = None;
             train_drag_state.target_facing = Facing::Forward;
+            train_drag_state.waypoints.clear();
             hover_state.filter = HoverFilter::Blocks;
         }
     }
@@ -531,6 +1123,7 @@ fn exit_drag_train(
         }
         train_drag_state.train_id = None;
         train_drag_state.route = None;
+        train_drag_state.waypoints.clear();
         hover_state.filter = HoverFilter::All;
         for entity in hover_route.iter() {
             commands.entity(entity).despawn();
@@ -538,6 +1131,154 @@ fn exit_drag_train(
     }
 }
 
+#[derive(Resource, Debug, Default)]
+struct BlockPicker {
+    train_id: Option<TrainID>,
+    search: String,
+    message: Option<String>,
+}
+
+fn open_block_picker(
+    keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
+    selection_state: Res<SelectionState>,
+    mut picker: ResMut<BlockPicker>,
+    mut egui_contexts: EguiContexts,
+) {
+    if picker.train_id.is_some() {
+        return;
+    }
+    // don't steal the key from text fields
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    if keyboard_input.just_pressed(keyboard::KeyCode::KeyG) {
+        if let Selection::Single(GenericID::Train(train_id)) = selection_state.selection {
+            *picker = BlockPicker {
+                train_id: Some(train_id),
+                ..default()
+            };
+        }
+    }
+}
+
+fn close_block_picker(mut picker: ResMut<BlockPicker>) {
+    *picker = BlockPicker::default();
+}
+
+fn block_picker_window(
+    mut egui_contexts: EguiContexts,
+    mut picker: ResMut<BlockPicker>,
+    q_blocks: Query<(&Block, &Name)>,
+    q_block_data: Query<&Block>,
+    q_trains: Query<&Train>,
+    q_markers: Query<&Marker>,
+    switches: Query<&Switch>,
+    entity_map: Res<EntityMap>,
+    connections: Res<Connections>,
+    track_locks: Res<TrackLocks>,
+    marker_map: Res<MarkerMap>,
+    portal_settings: Res<PortalSettings>,
+    mut set_train_route: MessageWriter<SetTrainRouteMessage>,
+) {
+    let Some(train_id) = picker.train_id else {
+        return;
+    };
+    let Ok(ctx) = &egui_contexts.ctx_mut().cloned() else {
+        return;
+    };
+    let search = picker.search.to_lowercase();
+    let mut matches = q_blocks
+        .iter()
+        .filter(|(block, _)| !block.settings.passthrough)
+        .filter(|(_, name)| name.as_str().to_lowercase().contains(&search))
+        .map(|(block, name)| (block.id, name.to_string()))
+        .collect::<Vec<_>>();
+    matches.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut open = true;
+    let mut picked = None;
+    egui::Window::new(format!("Send {} to block", train_id))
+        .open(&mut open)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_TOP, (0.0, 60.0))
+        .show(ctx, |ui| {
+            let response = ui.text_edit_singleline(&mut picker.search);
+            response.request_focus();
+            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                picked = matches.first().map(|(id, _)| *id);
+            }
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    for (id, name) in matches.iter() {
+                        if ui.selectable_label(false, name).clicked() {
+                            picked = Some(*id);
+                        }
+                    }
+                });
+            if let Some(message) = &picker.message {
+                ui.colored_label(egui::Color32::LIGHT_RED, message);
+            }
+        });
+    if !open || ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+        picker.train_id = None;
+        return;
+    }
+    let Some(block_id) = picked else {
+        return;
+    };
+    let Some(train) = entity_map
+        .get_entity(&GenericID::Train(train_id))
+        .and_then(|entity| q_trains.get(entity).ok())
+    else {
+        picker.train_id = None;
+        return;
+    };
+    let Some(start) = train.get_logical_block_id() else {
+        picker.message = Some(format!("{} is not in a block", train_id));
+        return;
+    };
+    let avoided = train.avoided_tracks(start, &q_block_data, &entity_map);
+    let route = [BlockDirection::Aligned, BlockDirection::Opposite]
+        .into_iter()
+        .map(|direction| block_id.to_logical(direction, Facing::Forward))
+        .filter(|target| *target != start)
+        .filter_map(|target| {
+            connections.find_route_section(
+                start,
+                target,
+                Some((&train_id, &track_locks, &switches, &entity_map)),
+                train.settings.prefer_facing,
+                train.settings.route_preferences,
+                &avoided,
+            )
+        })
+        .map(|section| {
+            build_route(
+                train_id,
+                &section,
+                &q_markers,
+                &q_block_data,
+                &entity_map,
+                &marker_map,
+                portal_settings.ramp_length,
+            )
+        })
+        .min_by_key(|route| route.total_length());
+    match route {
+        Some(route) => {
+            set_train_route.write(SetTrainRouteMessage { train_id, route });
+            picker.train_id = None;
+        }
+        None => {
+            picker.message = Some(format!("No route to {}", block_id));
+        }
+    }
+}
+
 #[derive(Event, Debug)]
 pub struct LocksChangedEvent {}
 
@@ -551,24 +1292,60 @@ fn assign_destination_route(
     entity_map: Res<EntityMap>,
     connections: Res<Connections>,
     track_locks: Res<TrackLocks>,
-    q_trains: Query<(&Train, &QueuedDestination)>,
+    q_trains: Query<(
+        Entity,
+        &Train,
+        &QueuedDestination,
+        Option<&DepartedBlock>,
+        Option<&UnreachableDestination>,
+    )>,
+    q_all_trains: Query<&Train>,
     q_markers: Query<&Marker>,
     switches: Query<&Switch>,
-    marker_map: Res<MarkerMap>,
+    (marker_map, defaults, portal_settings): (
+        Res<MarkerMap>,
+        Res<TrainDefaults>,
+        Res<PortalSettings>,
+    ),
     mut set_train_route: MessageWriter<SetTrainRouteMessage>,
+    mut destination_usage: ResMut<DestinationUsage>,
+    control_info: Res<ControlInfo>,
+    mut rng: ResMut<ControlRng>,
+    mut commands: Commands,
 ) {
-    for (train, queue) in q_trains.iter() {
-        if !train.get_route().is_blocked() {
-            if !train.get_route().is_completed() {
+    // query order isn't stable across runs, but the rng draws have to be
+    let mut trains = q_trains.iter().collect::<Vec<_>>();
+    trains.sort_by_key(|(_, train, ..)| train.id);
+    for (entity, train, queue, maybe_departed, unreachable) in trains {
+        if train.held {
+            continue;
+        }
+        if let Some((other_id, _)) = queue.after {
+            let other = entity_map.query_get(&q_all_trains, &GenericID::Train(other_id));
+            if other.is_none() {
+                warn!(
+                    "Train {:?} waits for missing train {:?}, dispatching anyway",
+                    train.id, other_id
+                );
+            }
+            if queue.is_gated(other) {
                 continue;
             }
-            if train.get_route().num_legs() > 1 {
+        }
+        let Some(route) = train.get_route() else {
+            continue;
+        };
+        if !route.is_blocked() {
+            if !route.is_completed() {
+                continue;
+            }
+            if route.num_legs() > 1 {
                 continue;
             }
         }
 
         let train_id = train.id;
-        let start = train.get_logical_block_id();
+        let start = route.get_current_leg().get_target_block_id();
         let destination = match queue.dest {
             DestinationID::Specific(_) => q_destinations
                 .get(
@@ -579,20 +1356,58 @@ fn assign_destination_route(
                 .unwrap(),
             DestinationID::Random => &Destination {
                 id: DestinationID::Random,
-                blocks: q_blocks
-                    .iter()
-                    .filter_map(|block| {
-                        if block.settings.passthrough {
-                            return None;
-                        }
-                        Some((block.id, BlockDirectionFilter::Any, None))
-                    })
-                    .collect(),
+                blocks: {
+                    let mut blocks = q_blocks
+                        .iter()
+                        .filter_map(|block| {
+                            if block.settings.passthrough {
+                                return None;
+                            }
+                            Some((block.id, BlockDirectionFilter::Any, None))
+                        })
+                        .collect::<Vec<_>>();
+                    blocks.sort_by_key(|(block_id, ..)| *block_id);
+                    blocks
+                },
+                arrival_actions: vec![],
+                distribution: TargetDistribution::Independent,
             },
+            DestinationID::Home => {
+                let Some(home) = train.settings.home else {
+                    println!("Train {:?} has no home, dropping destination", train_id);
+                    commands.entity(entity).remove::<QueuedDestination>();
+                    continue;
+                };
+                let direction = match home.direction {
+                    BlockDirection::Aligned => BlockDirectionFilter::Aligned,
+                    BlockDirection::Opposite => BlockDirectionFilter::Opposite,
+                };
+                &Destination {
+                    id: DestinationID::Home,
+                    blocks: vec![(home.block, direction, Some(home.facing))],
+                    arrival_actions: vec![],
+                    distribution: TargetDistribution::Independent,
+                }
+            }
         };
 
+        let cooling_down = maybe_departed
+            .filter(|departed| control_info.time - departed.time < train.settings.reselect_cooldown)
+            .map(|departed| departed.block);
+
+        let avoided = train.avoided_tracks(start, &q_blocks, &entity_map);
+        let fits = |block_id: BlockID| {
+            entity_map
+                .query_get(&q_blocks, &GenericID::Block(block_id))
+                .is_none_or(|block| {
+                    defaults.allows_block(block, train.length(), portal_settings.ramp_length)
+                })
+        };
         let mut routes = vec![];
         for (block_id, dir, _) in destination.blocks.iter() {
+            if cooling_down == Some(*block_id) || !fits(*block_id) {
+                continue;
+            }
             for direction in dir.iter_directions() {
                 let target = block_id.to_logical(*direction, Facing::Forward);
                 if target == start {
@@ -603,6 +1418,8 @@ fn assign_destination_route(
                     target,
                     Some((&train_id, &track_locks, &switches, &entity_map)),
                     train.settings.prefer_facing,
+                    train.settings.route_preferences,
+                    &avoided,
                 ) {
                     let route = build_route(
                         train_id,
@@ -611,25 +1428,73 @@ fn assign_destination_route(
                         &q_blocks,
                         &entity_map,
                         &marker_map,
+                        portal_settings.ramp_length,
                     );
-                    routes.push(route);
+                    routes.push((*block_id, route));
                 }
             }
         }
-        match queue.strategy {
+        let strategy = queue
+            .strategy
+            .or(train.settings.default_strategy)
+            .unwrap_or(TargetChoiceStrategy::Closest);
+        match strategy {
             TargetChoiceStrategy::Closest => {
-                routes.sort_by_key(|route| route.total_length());
+                routes.sort_by_key(|(_, route)| route.total_length());
             }
             TargetChoiceStrategy::Random => {
-                routes.shuffle(&mut rand::rng());
+                routes = train.shuffle_targets(routes, &mut rng);
             }
         }
+        routes.sort_by_key(|(block_id, _)| destination_usage.rank(destination, *block_id));
 
-        if let Some(route) = routes.first().cloned() {
+        if let Some((block_id, route)) = routes.first().cloned() {
+            if unreachable.is_some() {
+                commands.entity(entity).remove::<UnreachableDestination>();
+            }
+            destination_usage.record(queue.dest, block_id);
+            commands.entity(entity).insert(DepartedBlock {
+                block: start.block,
+                time: control_info.time,
+            });
             set_train_route.write(SetTrainRouteMessage {
                 train_id,
                 route: route,
             });
+            commands.entity(entity).insert(HeadingTo(queue.dest));
+        } else if queue.dest == DestinationID::Random {
+            // nothing reachable right now, dwell instead of replanning on every lock change
+            println!("No destination available for train {:?}, idling", train_id);
+            commands
+                .entity(entity)
+                .remove::<QueuedDestination>()
+                .insert(Idle::default());
+            continue;
+        } else if !destination.blocks.iter().any(|(block_id, dir, _)| {
+            fits(*block_id)
+                && dir.iter_directions().any(|direction| {
+                    let target = block_id.to_logical(*direction, Facing::Forward);
+                    target != start
+                        && connections
+                            .find_route_section(
+                                start,
+                                target,
+                                None,
+                                train.settings.prefer_facing,
+                                train.settings.route_preferences,
+                                &avoided,
+                            )
+                            .is_some()
+                })
+        }) {
+            // don't let this train hold up planning for the others
+            if unreachable.map(|flag| flag.0) != Some(queue.dest) {
+                warn!("{} is unreachable for train {:?}", queue.dest, train_id);
+                commands
+                    .entity(entity)
+                    .insert(UnreachableDestination(queue.dest));
+            }
+            continue;
         } else {
             println!("No route found for train {:?}", train_id);
         }
@@ -637,11 +1502,68 @@ fn assign_destination_route(
     }
 }
 
+fn handle_unreachable_destinations(
+    mut q_trains: Query<
+        (
+            Entity,
+            &Train,
+            &UnreachableDestination,
+            Option<&mut AssignedSchedule>,
+        ),
+        Changed<UnreachableDestination>,
+    >,
+    q_schedules: Query<&TrainSchedule>,
+    entity_map: Res<EntityMap>,
+    mut commands: Commands,
+) {
+    let mut replan = false;
+    for (entity, train, unreachable, assigned_schedule) in q_trains.iter_mut() {
+        match train.settings.unreachable_policy {
+            UnreachablePolicy::Hold => {}
+            UnreachablePolicy::Skip => {
+                let schedule = assigned_schedule.and_then(|assigned| {
+                    let id = assigned.schedule_id?;
+                    let entity = entity_map.get_entity(&GenericID::Schedule(id))?;
+                    Some((assigned, q_schedules.get(entity).ok()?))
+                });
+                let Some((mut assigned, schedule)) = schedule else {
+                    continue;
+                };
+                println!(
+                    "Skipping unreachable {} for train {:?}",
+                    unreachable.0, train.id
+                );
+                commands.entity(entity).remove::<UnreachableDestination>();
+                match assigned.skip_to_next_stop(schedule) {
+                    Some(queued_dest) => {
+                        commands.entity(entity).insert(queued_dest);
+                    }
+                    None => {
+                        commands.entity(entity).remove::<QueuedDestination>();
+                    }
+                }
+                replan = true;
+            }
+            UnreachablePolicy::Alert => {
+                error!(
+                    "Train {:?} stopped, {} is unreachable",
+                    train.id, unreachable.0
+                );
+                commands.entity(entity).remove::<QueuedDestination>();
+            }
+        }
+    }
+    if replan {
+        commands.trigger(PlanRouteEvent {});
+    }
+}
+
 #[derive(Component)]
 struct HoverRoute;
 
 fn update_drag_train(
     mouse_buttons: Res<ButtonInput<MouseButton>>,
+    keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
     mut train_drag_state: ResMut<TrainDragState>,
     mouse_pos: Res<MousePosWorld>,
     hover_state: Res<HoverState>,
@@ -653,6 +1575,7 @@ fn update_drag_train(
     q_markers: Query<&Marker>,
     switches: Query<&Switch>,
     marker_map: Res<MarkerMap>,
+    portal_settings: Res<PortalSettings>,
     mut commands: Commands,
     hover_route: Query<Entity, With<HoverRoute>>,
 ) {
@@ -660,10 +1583,18 @@ fn update_drag_train(
     if train_drag_state.train_id.is_none() {
         return;
     }
-    let old_target = train_drag_state.target.clone();
+    let mut old_target = train_drag_state.target.clone();
     if mouse_buttons.just_pressed(MouseButton::Left) {
-        train_drag_state.target_facing = train_drag_state.target_facing.opposite();
-        println!("Target facing: {:?}", train_drag_state.target_facing)
+        if keyboard_input.pressed(keyboard::KeyCode::ShiftLeft) {
+            if let Some(target) = train_drag_state.target {
+                println!("Adding waypoint {:?}", target);
+                train_drag_state.waypoints.push(target);
+                old_target = None;
+            }
+        } else {
+            train_drag_state.target_facing = train_drag_state.target_facing.opposite();
+            println!("Target facing: {:?}", train_drag_state.target_facing)
+        }
     }
     if let Some(GenericID::Block(block_id)) = hover_state.hover {
         let block = q_blocks
@@ -682,15 +1613,21 @@ fn update_drag_train(
         let train = q_trains
             .get(entity_map.get_entity(&GenericID::Train(train_id)).unwrap())
             .unwrap();
-        let start = train.get_logical_block_id();
         for entity in hover_route.iter() {
             commands.entity(entity).despawn();
         }
-        if let Some(logical_section) = connections.find_route_section(
+        let Some(start) = train.get_logical_block_id() else {
+            train_drag_state.route = None;
+            return;
+        };
+        if let Some(logical_section) = connections.find_route_via(
             start,
+            &train_drag_state.waypoints,
             train_drag_state.target.unwrap(),
             Some((&train_id, &track_locks, &switches, &entity_map)),
             train.settings.prefer_facing,
+            train.settings.route_preferences,
+            &train.avoided_tracks(start, &q_blocks, &entity_map),
         ) {
             // println!("Section: {:?}", section);
             commands.spawn((
@@ -706,6 +1643,7 @@ fn update_drag_train(
                 &q_blocks,
                 &entity_map,
                 &marker_map,
+                portal_settings.ramp_length,
             );
             train_drag_state.route = Some(route);
         } else {
@@ -743,35 +1681,410 @@ impl WaitTime {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum TargetChoiceStrategy {
-    Random,
-    Closest,
-}
-
-#[derive(Debug, Component)]
-pub struct QueuedDestination {
-    pub dest: DestinationID,
-    pub strategy: TargetChoiceStrategy,
-    pub allow_locked: bool,
+// scales the accel, decel and drag of every train
+#[derive(Debug, Clone, Copy)]
+pub struct SpeedSmoothing {
+    pub response: f32,
+    pub drag: f32,
 }
 
-#[derive(Debug, Message)]
-pub struct SetTrainRouteMessage {
-    train_id: TrainID,
-    route: Route,
+impl SpeedSmoothing {
+    fn acceleration(&self, settings: &TrainSettings, speed: f32, target_speed: f32) -> f32 {
+        let speeding_up = target_speed * speed >= 0.0 && target_speed.abs() > speed.abs();
+        let response = if speeding_up {
+            settings.accel
+        } else {
+            settings.decel
+        };
+        (target_speed - speed) * response * self.response - speed * settings.drag * self.drag
+    }
 }
 
-fn tick_wait_time(mut q_times: Query<&mut WaitTime>, time: Res<Time>) {
-    for mut wait_time in q_times.iter_mut() {
-        wait_time.time += time.delta_secs();
-        if (wait_time.time - time.delta_secs()) % 1.0 > wait_time.time % 1.0 {
-            debug!("Wait time: {:1.0}s", wait_time.time);
+impl Default for SpeedSmoothing {
+    fn default() -> Self {
+        Self {
+            response: 1.0,
+            drag: 1.0,
         }
     }
 }
 
-pub fn set_train_route(
+#[derive(Resource, Debug, Clone)]
+pub struct MotionTuning {
+    pub active: SpeedSmoothing,
+    pub passive: SpeedSmoothing,
+    pub seek_stiffness: f32,
+    pub seek_damping: f32,
+    // in virtual control, move trains passively and fire marker advances
+    // from a simulated position, like a real hub would
+    pub discrete_sensors: bool,
+}
+
+impl Default for MotionTuning {
+    fn default() -> Self {
+        Self {
+            active: SpeedSmoothing::default(),
+            passive: SpeedSmoothing::default(),
+            seek_stiffness: 40.0,
+            seek_damping: 10.0,
+            discrete_sensors: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SimulatedSensor {
+    leg: (LogicalBlockID, usize),
+    distance: f32,
+    fired: bool,
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct SimulatedSensors {
+    trains: HashMap<TrainID, SimulatedSensor>,
+    missed: HashSet<TrainID>,
+}
+
+impl SimulatedSensors {
+    pub fn miss_next_marker(&mut self, train: TrainID) {
+        self.missed.insert(train);
+    }
+}
+
+// while set, all other trains stop at their next block and get no new routes
+#[derive(Resource, Debug, Default)]
+pub struct IsolatedTrain(pub Option<TrainID>);
+
+pub fn apply_isolation(
+    isolated: Res<IsolatedTrain>,
+    mut q_trains: Query<(&mut Train, Option<&BLETrain>)>,
+    q_hubs: Query<(&BLEHub, &HubBattery)>,
+    q_lost_hubs: Query<&BLEHub, Or<(With<HubError>, With<HubReconnecting>)>>,
+    battery_policy: Res<HubBatteryPolicy>,
+    emergency_stop: Res<EmergencyStop>,
+    control_state: Option<Res<State<ControlState>>>,
+    mut commands: Commands,
+) {
+    let low_hubs = q_hubs
+        .iter()
+        .filter(|(_, battery)| battery.low && battery_policy.stop_trains)
+        .map(|(hub, _)| hub.id)
+        .collect::<HashSet<_>>();
+    // hubs that were never connected don't count outside of control
+    let lost_hubs = match control_state {
+        Some(_) => q_lost_hubs.iter().map(|hub| hub.id).collect(),
+        None => HashSet::new(),
+    };
+    let mut changed = false;
+    for (mut train, ble_train) in q_trains.iter_mut() {
+        let hub_lost = ble_train
+            .is_some_and(|ble_train| ble_train.iter_all_hubs().any(|id| lost_hubs.contains(id)));
+        if train.hub_lost != hub_lost {
+            train.set_hub_lost(hub_lost);
+            changed = true;
+        }
+        let held = ble_train.is_some_and(|ble_train| {
+            isolated.0.is_some_and(|id| id != train.id)
+                || ble_train.iter_all_hubs().any(|id| low_hubs.contains(id))
+        }) || emergency_stop.engaged
+            || hub_lost;
+        if train.held != held {
+            train.held = held;
+            changed = true;
+        }
+    }
+    if changed {
+        commands.trigger(LocksChangedEvent {});
+    }
+}
+
+// latched until released explicitly, keeps all trains stopped
+#[derive(Resource, Debug, Default)]
+pub struct EmergencyStop {
+    pub engaged: bool,
+}
+
+#[derive(Message, Debug, Clone)]
+pub struct EmergencyStopMessage {
+    pub engage: bool,
+}
+
+fn emergency_stop_released(emergency_stop: Res<EmergencyStop>) -> bool {
+    !emergency_stop.engaged
+}
+
+fn emergency_stop_shortcut(
+    keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
+    mut emergency_stop_messages: MessageWriter<EmergencyStopMessage>,
+    mut egui_contexts: EguiContexts,
+) {
+    // escape also closes the block picker, which has the keyboard focus
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    if keyboard_input.just_pressed(keyboard::KeyCode::Escape) {
+        emergency_stop_messages.write(EmergencyStopMessage { engage: true });
+    }
+}
+
+fn handle_emergency_stop(
+    mut emergency_stop_messages: MessageReader<EmergencyStopMessage>,
+    mut emergency_stop: ResMut<EmergencyStop>,
+    mut q_trains: Query<(&mut Train, Option<&BLETrain>)>,
+    editor_state: Res<State<EditorState>>,
+    mut hub_commands: MessageWriter<HubCommandMessage>,
+    mut commands: Commands,
+) {
+    for message in emergency_stop_messages.read() {
+        if message.engage == emergency_stop.engaged {
+            continue;
+        }
+        emergency_stop.engaged = message.engage;
+        let device_control = editor_state.get() == &EditorState::DeviceControl;
+        if message.engage {
+            warn!("Emergency stop");
+        } else {
+            info!("Emergency stop released");
+        }
+        for (mut train, ble_train) in q_trains.iter_mut() {
+            train.held = message.engage;
+            if message.engage {
+                train.speed = 0.0;
+            }
+            let Some(route) = train.get_route_mut() else {
+                continue;
+            };
+            let leg_index = route.leg_index();
+            for (i, leg) in route.iter_legs_mut().enumerate() {
+                if !message.engage {
+                    // resync everything, the hubs were stopped out of band
+                    leg.intention_synced = false;
+                    continue;
+                }
+                if i >= leg_index {
+                    leg.intention = LegIntention::Stop;
+                }
+                leg.intention_synced = true;
+            }
+            if !message.engage || !device_control {
+                continue;
+            }
+            let Some(ble_train) = ble_train else {
+                continue;
+            };
+            for leg_index in leg_index..route.num_legs() {
+                let commands = ble_train.set_leg_intention(leg_index as u8, LegIntention::Stop);
+                for input in commands.hub_messages {
+                    hub_commands.write(input);
+                }
+            }
+            // setting the intention of the current leg makes the hub run to its end
+            for input in ble_train.stop_command().hub_messages {
+                hub_commands.write(input);
+            }
+        }
+        commands.trigger(LocksChangedEvent {});
+    }
+}
+
+#[derive(Resource, Debug)]
+pub struct ShuntingSettings {
+    pub distance: f32,
+}
+
+impl Default for ShuntingSettings {
+    fn default() -> Self {
+        Self {
+            distance: WAGON_DIST,
+        }
+    }
+}
+
+#[derive(Debug, Component)]
+pub struct ShuntingMove {
+    pub remaining: f32,
+}
+
+fn shunt_to_click(
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
+    mouse_pos: Res<MousePosWorld>,
+    selection_state: Res<SelectionState>,
+    input_data: Res<InputData>,
+    entity_map: Res<EntityMap>,
+    q_trains: Query<&Train>,
+    mut commands: Commands,
+) {
+    // shift+click while dragging a route adds waypoints instead
+    if input_data.mouse_over_ui
+        || !mouse_buttons.just_pressed(MouseButton::Left)
+        || !keyboard_input.pressed(keyboard::KeyCode::ShiftLeft)
+        || mouse_buttons.pressed(MouseButton::Right)
+    {
+        return;
+    }
+    if let Selection::Single(GenericID::Train(train_id)) = selection_state.selection {
+        let entity = entity_map.get_entity(&GenericID::Train(train_id)).unwrap();
+        let train = q_trains.get(entity).unwrap();
+        if train.can_shunt() {
+            commands.entity(entity).insert(ShuntingMove {
+                remaining: train.shunt_distance_to(mouse_pos.pos / LAYOUT_SCALE),
+            });
+        }
+    }
+}
+
+fn update_shunting_moves(
+    mut q_trains: Query<(Entity, &mut Train, &mut ShuntingMove)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, mut train, mut shunting_move) in q_trains.iter_mut() {
+        if !train.can_shunt() {
+            commands.entity(entity).remove::<ShuntingMove>();
+            continue;
+        }
+        let step = (SHUNT_SPEED * time.delta_secs()).min(shunting_move.remaining.abs());
+        let moved = train.shunt(step * shunting_move.remaining.signum());
+        shunting_move.remaining -= moved;
+        if shunting_move.remaining.abs() < 1e-4 || moved.abs() < step * 0.5 {
+            commands.entity(entity).remove::<ShuntingMove>();
+        }
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct ManualThrottle {
+    train_id: Option<TrainID>,
+}
+
+fn manual_throttle(
+    keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
+    selection_state: Res<SelectionState>,
+    entity_map: Res<EntityMap>,
+    time: Res<Time>,
+    mut manual_throttle: ResMut<ManualThrottle>,
+    mut q_trains: Query<&mut Train>,
+    mut egui_contexts: EguiContexts,
+) {
+    let selected = match selection_state.selection {
+        Selection::Single(GenericID::Train(train_id)) => Some(train_id),
+        _ => None,
+    };
+    if manual_throttle.train_id != selected {
+        // hand the previous train back to the route
+        if let Some(train_id) = manual_throttle.train_id.take() {
+            if let Some(entity) = entity_map.get_entity(&GenericID::Train(train_id)) {
+                if let Ok(mut train) = q_trains.get_mut(entity) {
+                    train.throttle = None;
+                }
+            }
+        }
+    }
+    let Some(train_id) = selected else {
+        return;
+    };
+    if egui_contexts
+        .ctx_mut()
+        .is_ok_and(|ctx| ctx.wants_keyboard_input())
+    {
+        return;
+    }
+    let entity = entity_map.get_entity(&GenericID::Train(train_id)).unwrap();
+    let mut train = q_trains.get_mut(entity).unwrap();
+    let mut change = 0.0;
+    if keyboard_input.pressed(keyboard::KeyCode::ArrowUp) {
+        change += THROTTLE_RATE * time.delta_secs();
+    }
+    if keyboard_input.pressed(keyboard::KeyCode::ArrowDown) {
+        change -= THROTTLE_RATE * time.delta_secs();
+    }
+    if change != 0.0 {
+        let throttle = train.throttle.unwrap_or(train.speed.abs()) + change;
+        train.throttle = Some(throttle.clamp(0.0, TrainSpeed::Fast.get_speed()));
+        manual_throttle.train_id = Some(train_id);
+    }
+    if keyboard_input.just_pressed(keyboard::KeyCode::Space) {
+        train.throttle = Some(0.0);
+        train.speed = 0.0;
+        manual_throttle.train_id = Some(train_id);
+    }
+}
+
+fn release_manual_throttle(
+    mut manual_throttle: ResMut<ManualThrottle>,
+    mut q_trains: Query<&mut Train>,
+) {
+    manual_throttle.train_id = None;
+    for mut train in q_trains.iter_mut() {
+        train.throttle = None;
+    }
+}
+
+#[derive(Debug, Component, Default)]
+pub struct Idle {
+    pub time: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Reflect, Serialize, Deserialize)]
+pub enum TargetChoiceStrategy {
+    Random,
+    Closest,
+}
+
+// the destination the current route was planned for
+#[derive(Debug, Component)]
+pub struct HeadingTo(pub DestinationID);
+
+#[derive(Debug, Component)]
+pub struct DepartedBlock {
+    block: BlockID,
+    time: f32,
+}
+
+// flags a queued destination none of whose blocks can be reached
+#[derive(Debug, Component)]
+pub struct UnreachableDestination(pub DestinationID);
+
+#[derive(Debug, Component)]
+pub struct QueuedDestination {
+    pub dest: DestinationID,
+    // falls back to the train's default strategy, then the closest target
+    pub strategy: Option<TargetChoiceStrategy>,
+    pub allow_locked: bool,
+    // only dispatched once the other train has reached the block
+    pub after: Option<(TrainID, BlockID)>,
+}
+
+impl QueuedDestination {
+    // a missing other train doesn't hold the dispatch
+    pub fn is_gated(&self, other: Option<&Train>) -> bool {
+        match (self.after, other) {
+            (Some((_, block_id)), Some(other)) => !other.has_reached(block_id),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Message)]
+pub struct SetTrainRouteMessage {
+    train_id: TrainID,
+    route: Route,
+}
+
+fn tick_wait_time(mut q_times: Query<&mut WaitTime>, time: Res<Time>) {
+    for mut wait_time in q_times.iter_mut() {
+        wait_time.time += time.delta_secs();
+        if (wait_time.time - time.delta_secs()) % 1.0 > wait_time.time % 1.0 {
+            debug!("Wait time: {:1.0}s", wait_time.time);
+        }
+    }
+}
+
+pub fn set_train_route(
     mut q_trains: Query<(&mut Train, &mut BLETrain, Option<&ProxyTrains>)>,
     switches: Query<&Switch>,
     entity_map: Res<EntityMap>,
@@ -783,6 +2096,8 @@ pub fn set_train_route(
     mut commands: Commands,
     crossings: Query<&LevelCrossing>,
     mut set_crossing_position: MessageWriter<SetCrossingPositionMessage>,
+    signals: Res<SignalAspects>,
+    defaults: Res<TrainDefaults>,
 ) {
     for event in route_messages.read() {
         let mut route = event.route.clone();
@@ -796,14 +2111,16 @@ pub fn set_train_route(
             commands.entity(train_entity).remove::<QueuedDestination>();
         }
         let (mut train, ble_train, proxy_trains) = q_trains.get_mut(train_entity).unwrap();
+        if let Some(block) = train.get_logical_block_id() {
+            train.remember_block(block);
+        }
         // println!("Dropping train {:?} on block {:?}", train_id, block_id);
         route.pretty_print();
-        route.get_current_leg_mut().set_signed_pos_from_last(
-            train
-                .get_route()
-                .get_current_leg()
-                .get_signed_pos_from_last(),
-        );
+        if let Some(old_route) = train.get_route() {
+            route
+                .get_current_leg_mut()
+                .set_signed_pos_from_last(old_route.get_current_leg().get_signed_pos_from_last());
+        }
         let route_entity = commands
             .spawn(ModularRoute {
                 logical_section: route.critical_section.clone(),
@@ -828,13 +2145,15 @@ pub fn set_train_route(
             &mut set_switch_position,
             &crossings,
             &mut set_crossing_position,
+            &signals,
+            defaults.reservation_margin,
         ) {
             commands.trigger(LocksChangedEvent {});
         }
         train.set_seek_target();
 
         if editor_state.get().ble_commands_enabled() {
-            let commands = ble_train.download_route(&train.get_route());
+            let commands = ble_train.download_route(train.get_route().unwrap());
             for input in commands.hub_messages {
                 info!("Sending {:?}", input);
                 hub_commands.write(input);
@@ -848,13 +2167,14 @@ fn create_train_shortcut(
     mut train_messages: MessageWriter<SpawnTrainMessage>,
     entity_map: Res<EntityMap>,
     selection_state: Res<SelectionState>,
+    train_defaults: Res<TrainDefaults>,
 ) {
     if keyboard_input.just_pressed(keyboard::KeyCode::KeyT) {
         if let Selection::Single(GenericID::Block(block_id)) = &selection_state.selection {
             // println!("Creating train at block {:?}", block_id);
             let logical_block_id = block_id.to_logical(BlockDirection::Aligned, Facing::Forward);
             let train_id = entity_map.new_train_id();
-            let train = Train::at_block_id(train_id, logical_block_id);
+            let train = Train::at_block_id(train_id, logical_block_id, &train_defaults);
             train_messages.write(SpawnTrainMessage {
                 train,
                 ble_train: None,
@@ -877,7 +2197,11 @@ fn spawn_train(
     switches: Query<&Switch>,
     crossings: Query<&LevelCrossing>,
     mut set_crossing_position: MessageWriter<SetCrossingPositionMessage>,
+    signals: Res<SignalAspects>,
+    defaults: Res<TrainDefaults>,
+    portal_settings: Res<PortalSettings>,
 ) {
+    let ramp_length = portal_settings.ramp_length;
     for spawn_train in train_messages.read() {
         let serialized_train = spawn_train.clone();
         let mut train = serialized_train.train;
@@ -888,7 +2212,39 @@ fn spawn_train(
             Position::Block(block_id) => block_id,
             Position::Route(_) => panic!("Can't spawn train with route"),
         };
+        let block_id = if marker_map.has_in_marker(&block_id) {
+            block_id
+        } else {
+            let flipped = LogicalBlockID {
+                facing: block_id.facing.opposite(),
+                ..block_id
+            };
+            if marker_map.has_in_marker(&flipped) {
+                println!(
+                    "Warning: {:?} has no in marker for facing {:?}, spawning train {:?} facing {:?} instead",
+                    block_id, block_id.facing, train.id, flipped.facing
+                );
+                flipped
+            } else {
+                println!(
+                    "Warning: {:?} has no in marker, route of train {:?} may be broken",
+                    block_id, train.id
+                );
+                block_id
+            }
+        };
         println!("spawning at block {:?}", block_id);
+        if let Some(block) = entity_map.query_get(&q_blocks, &GenericID::Block(block_id.block)) {
+            if !block.fits(train.length(), ramp_length) {
+                warn!(
+                    "Train {:?} is {:.2} long, block {:?} only {:.2}",
+                    train.id,
+                    train.length(),
+                    block_id.block,
+                    block.length(ramp_length)
+                );
+            }
+        }
         let train_id = spawn_train.train.id;
         let mut block_critical_path = LogicalSection::new();
         block_critical_path
@@ -907,6 +2263,7 @@ fn spawn_train(
             &q_blocks,
             &entity_map,
             &marker_map,
+            ramp_length,
         );
         train.position = Position::Route(route);
         if update_train_route(
@@ -917,6 +2274,8 @@ fn spawn_train(
             &mut set_switch_position,
             &crossings,
             &mut set_crossing_position,
+            &signals,
+            defaults.reservation_margin,
         ) {
             commands.trigger(LocksChangedEvent {});
         }
@@ -961,11 +2320,18 @@ fn block_route(
     q_blocks: &Query<&Block>,
     entity_map: &EntityMap,
     marker_map: &MarkerMap,
+    ramp_length: f32,
 ) -> Route {
     let mut section = LogicalSection::new();
     section.tracks.push(block_id.default_in_marker_track());
     let route = build_route(
-        train_id, &section, q_markers, q_blocks, entity_map, marker_map,
+        train_id,
+        &section,
+        q_markers,
+        q_blocks,
+        entity_map,
+        marker_map,
+        ramp_length,
     );
     route
 }
@@ -975,11 +2341,16 @@ fn despawn_train(
     mut entity_map: ResMut<EntityMap>,
     mut despawn_messages: MessageReader<DespawnMessage<Train>>,
     mut track_locks: ResMut<TrackLocks>,
+    mut isolated: ResMut<IsolatedTrain>,
 ) {
     for event in despawn_messages.read() {
         let train_id = event.0;
         let entity = entity_map.trains.get(&train_id).unwrap();
         track_locks.unlock_all(&train_id);
+        // otherwise the remaining trains stay held with nothing to release them
+        if isolated.0 == Some(train_id) {
+            isolated.0 = None;
+        }
         commands.entity(*entity).despawn();
         entity_map.remove_train(train_id);
     }
@@ -989,9 +2360,20 @@ fn update_virtual_trains(
     mut q_trains: Query<&mut Train>,
     time: Res<Time>,
     mut advance_messages: MessageWriter<MarkerAdvanceMessage>,
+    tuning: Res<MotionTuning>,
+    connections: Res<Connections>,
+    defaults: Res<TrainDefaults>,
 ) {
     for mut train in q_trains.iter_mut() {
-        train.traverse_route(time.delta_secs(), &mut advance_messages);
+        if train.hub_lost {
+            continue;
+        }
+        train.traverse_route(time.delta_secs(), &mut advance_messages, &tuning.active);
+        if let Some(route) = train.get_route_mut() {
+            route
+                .get_current_leg_mut()
+                .clamp_to_dead_end(&connections, defaults.dead_end_clearance);
+        }
     }
 }
 
@@ -1003,25 +2385,152 @@ fn update_train_route(
     set_switch_position: &mut MessageWriter<SetSwitchPositionMessage>,
     crossings: &Query<&LevelCrossing>,
     set_crossing_position: &mut MessageWriter<SetCrossingPositionMessage>,
+    signals: &SignalAspects,
+    reservation_margin: usize,
 ) -> bool {
-    train
-        .get_route_mut()
-        .update_intentions(track_locks, switches, entity_map);
+    let signals = train.settings.obey_signals.then_some(signals);
+    let held = train.held;
+    let reserve_distance =
+        train.settings.reserve_distance + train.settings.reserve_train_lengths * train.length();
+    let Some(route) = train.get_route_mut() else {
+        return false;
+    };
+    route.update_intentions(track_locks, switches, entity_map, signals, held);
     let old_locks = track_locks.clone();
-    train.get_route().update_locks(
+    route.update_locks(
         track_locks,
         entity_map,
         set_switch_position,
         set_crossing_position,
         switches,
         crossings,
+        reservation_margin,
+        reserve_distance,
     );
     *track_locks != old_locks
 }
 
-fn update_virtual_trains_passive(mut q_trains: Query<&mut Train>, time: Res<Time>) {
+fn simulate_sensor_advances(
+    q_trains: Query<&Train>,
+    time: Res<Time>,
+    mut sensors: ResMut<SimulatedSensors>,
+    mut advance_messages: MessageWriter<MarkerAdvanceMessage>,
+) {
+    let sensors = sensors.as_mut();
+    for train in q_trains.iter() {
+        let Some(route) = train.get_route() else {
+            sensors.trains.remove(&train.id);
+            continue;
+        };
+        let leg = route.get_current_leg();
+        let Some(next_marker_pos) = leg.get_next_marker_pos() else {
+            sensors.trains.remove(&train.id);
+            continue;
+        };
+        let key = (leg.get_target_block_id(), leg.index);
+        let sensor = sensors.trains.entry(train.id).or_insert(SimulatedSensor {
+            leg: key,
+            distance: 0.0,
+            fired: false,
+        });
+        if sensor.leg != key {
+            sensor.leg = key;
+            sensor.distance = 0.0;
+            sensor.fired = false;
+        }
+        if sensor.fired {
+            continue;
+        }
+        sensor.distance += train.speed.abs() * time.delta_secs();
+        if sensor.distance >= (next_marker_pos - leg.get_previous_marker_pos()).abs() {
+            sensor.fired = true;
+            if sensors.missed.remove(&train.id) {
+                warn!("Simulated sensor of {} missed a marker", train.id);
+                continue;
+            }
+            advance_messages.write(MarkerAdvanceMessage {
+                id: train.id,
+                index: leg.index + 1,
+            });
+        }
+    }
+}
+
+fn update_virtual_trains_passive(
+    mut q_trains: Query<&mut Train>,
+    time: Res<Time>,
+    tuning: Res<MotionTuning>,
+    connections: Res<Connections>,
+    defaults: Res<TrainDefaults>,
+) {
     for mut train in q_trains.iter_mut() {
-        train.traverse_route_passive(time.delta_secs());
+        if train.hub_lost {
+            continue;
+        }
+        train.traverse_route_passive(time.delta_secs(), &tuning);
+        if let Some(route) = train.get_route_mut() {
+            route
+                .get_current_leg_mut()
+                .clamp_to_dead_end(&connections, defaults.dead_end_clearance);
+        }
+    }
+}
+
+fn motion_tuning_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    mut tuning: ResMut<MotionTuning>,
+) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Motion tuning")
+            .open(&mut view_settings.motion_tuning)
+            .show(ctx, |ui| {
+                let tuning = &mut *tuning;
+                egui::Grid::new("motion_tuning").show(ui, |ui| {
+                    for (label, smoothing) in [
+                        ("Virtual", &mut tuning.active),
+                        ("Device", &mut tuning.passive),
+                    ] {
+                        ui.label(format!("{} response", label))
+                            .on_hover_text("Scales the accel and decel of every train");
+                        ui.add(
+                            egui::DragValue::new(&mut smoothing.response)
+                                .speed(0.01)
+                                .range(0.0..=10.0),
+                        );
+                        ui.end_row();
+                        ui.label(format!("{} drag", label))
+                            .on_hover_text("Scales the drag of every train");
+                        ui.add(
+                            egui::DragValue::new(&mut smoothing.drag)
+                                .speed(0.01)
+                                .range(0.0..=10.0),
+                        );
+                        ui.end_row();
+                    }
+                    ui.label("Seek stiffness");
+                    ui.add(
+                        egui::DragValue::new(&mut tuning.seek_stiffness)
+                            .speed(0.5)
+                            .range(0.0..=1000.0),
+                    );
+                    ui.end_row();
+                    ui.label("Seek damping");
+                    ui.add(
+                        egui::DragValue::new(&mut tuning.seek_damping)
+                            .speed(0.1)
+                            .range(0.0..=1000.0),
+                    );
+                    ui.end_row();
+                });
+                ui.checkbox(&mut tuning.discrete_sensors, "Discrete virtual sensors")
+                    .on_hover_text(
+                        "In virtual control, advance trains only on simulated sensor events",
+                    );
+                if ui.button("Reset").clicked() {
+                    *tuning = MotionTuning::default();
+                }
+            });
     }
 }
 
@@ -1029,15 +2538,17 @@ fn trigger_manual_sensor_advance(
     mut messages: MessageWriter<MarkerAdvanceMessage>,
     keyboard_input: Res<ButtonInput<keyboard::KeyCode>>,
     selection_state: Res<SelectionState>,
-    mut trains: Query<&mut Train>,
+    trains: Query<&Train>,
     entity_map: Res<EntityMap>,
 ) {
     if keyboard_input.just_pressed(keyboard::KeyCode::KeyN) {
         if let Selection::Single(GenericID::Train(train_id)) = selection_state.selection {
-            let mut train = trains
-                .get_mut(entity_map.get_entity(&GenericID::Train(train_id)).unwrap())
+            let train = trains
+                .get(entity_map.get_entity(&GenericID::Train(train_id)).unwrap())
                 .unwrap();
-            let route = train.get_route_mut();
+            let Some(route) = train.get_route() else {
+                return;
+            };
             if route.get_current_leg().get_leg_state() != LegState::Completed {
                 println!("Advancing marker");
                 messages.write(MarkerAdvanceMessage {
@@ -1049,8 +2560,193 @@ fn trigger_manual_sensor_advance(
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorLogEntry {
+    // seconds since control started
+    pub time: f32,
+    pub train_id: TrainID,
+    pub index: usize,
+}
+
+/// Sensor advances captured during device control, to replay them in virtual control.
+#[derive(Resource, Debug, Default, Serialize, Deserialize)]
+pub struct SensorLog {
+    // the random control seed of the recorded session
+    pub seed: Option<u64>,
+    pub entries: Vec<SensorLogEntry>,
+    #[serde(skip)]
+    pub record: bool,
+    #[serde(skip)]
+    pub replay: bool,
+    #[serde(skip)]
+    elapsed: f32,
+    #[serde(skip)]
+    recording: bool,
+    #[serde(skip)]
+    replay_index: Option<usize>,
+}
+
+impl SensorLog {
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        std::fs::write(path, json).map_err(|err| err.to_string())
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        serde_json::from_str(&json).map_err(|err| err.to_string())
+    }
+
+    // entries that are due, in the order they were recorded
+    fn next_due(&mut self) -> Option<SensorLogEntry> {
+        let index = self.replay_index?;
+        let entry = self.entries.get(index)?;
+        if entry.time > self.elapsed {
+            return None;
+        }
+        self.replay_index = Some(index + 1);
+        Some(entry.clone())
+    }
+}
+
+fn replaying_sensor_log(log: Res<SensorLog>) -> bool {
+    log.replay_index.is_some()
+}
+
+fn start_sensor_recording(mut log: ResMut<SensorLog>, seed: Res<RandomSeed>) {
+    log.elapsed = 0.0;
+    if log.record {
+        println!("Recording sensor advances");
+        log.entries.clear();
+        log.seed = Some(seed.0);
+        log.recording = true;
+    }
+}
+
+fn start_sensor_replay(mut log: ResMut<SensorLog>) {
+    log.elapsed = 0.0;
+    if log.replay && !log.entries.is_empty() {
+        println!("Replaying {} sensor advances", log.entries.len());
+        log.replay_index = Some(0);
+    }
+}
+
+fn stop_sensor_log(mut log: ResMut<SensorLog>) {
+    log.recording = false;
+    log.replay_index = None;
+}
+
+fn tick_sensor_log(mut log: ResMut<SensorLog>, time: Res<Time>) {
+    log.elapsed += time.delta_secs();
+}
+
+fn record_sensor_advances(
+    mut log: ResMut<SensorLog>,
+    mut advance_messages: MessageReader<MarkerAdvanceMessage>,
+) {
+    for advance in advance_messages.read() {
+        if !log.recording {
+            continue;
+        }
+        let time = log.elapsed;
+        log.entries.push(SensorLogEntry {
+            time,
+            train_id: advance.id,
+            index: advance.index,
+        });
+    }
+}
+
+fn replay_sensor_advances(
+    mut log: ResMut<SensorLog>,
+    q_trains: Query<&Train>,
+    entity_map: Res<EntityMap>,
+    mut advance_messages: MessageWriter<MarkerAdvanceMessage>,
+) {
+    while let Some(entry) = log.next_due() {
+        let route = entity_map
+            .get_entity(&GenericID::Train(entry.train_id))
+            .and_then(|entity| q_trains.get(entity).ok())
+            .and_then(|train| train.get_route());
+        // the replay diverged from the recording, e.g. because a different route was picked
+        if route.is_none_or(|route| route.get_current_leg().index + 1 != entry.index) {
+            warn!(
+                "Skipping replayed sensor advance {} of {} at {:1.1}s",
+                entry.index, entry.train_id, entry.time
+            );
+            continue;
+        }
+        advance_messages.write(MarkerAdvanceMessage {
+            id: entry.train_id,
+            index: entry.index,
+        });
+    }
+    if log.replay_index == Some(log.entries.len()) {
+        println!("Sensor log replay finished");
+        log.replay_index = None;
+    }
+}
+
+fn sensor_log_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    mut log: ResMut<SensorLog>,
+    mut seed: ResMut<RandomSeed>,
+) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Sensor log")
+            .open(&mut view_settings.sensor_log)
+            .show(ctx, |ui| {
+                ui.checkbox(&mut log.record, "Record in device control");
+                if ui
+                    .checkbox(&mut log.replay, "Replay in virtual control")
+                    .changed()
+                    && log.replay
+                {
+                    // the same seed makes random control pick the recorded routes
+                    if let Some(recorded) = log.seed {
+                        seed.0 = recorded;
+                    }
+                }
+                ui.label(format!("{} sensor advances", log.entries.len()));
+                if let Some(entry) = log.entries.last() {
+                    ui.label(format!("Duration: {:1.1}s", entry.time));
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        if let Some(path) =
+                            FileDialog::new().add_filter("json", &["json"]).save_file()
+                        {
+                            if let Err(err) = log.save(&path) {
+                                error!("Failed to save sensor log: {}", err);
+                            }
+                        }
+                    }
+                    if ui.button("Load").clicked() {
+                        if let Some(path) =
+                            FileDialog::new().add_filter("json", &["json"]).pick_file()
+                        {
+                            match SensorLog::load(&path) {
+                                Ok(loaded) => {
+                                    log.seed = loaded.seed;
+                                    log.entries = loaded.entries;
+                                    if log.replay {
+                                        if let Some(recorded) = log.seed {
+                                            seed.0 = recorded;
+                                        }
+                                    }
+                                }
+                                Err(err) => error!("Failed to load sensor log: {}", err),
+                            }
+                        }
+                    }
+                });
+            });
+    }
+}
+
 fn sensor_advance(
-    mut q_trains: Query<&mut Train, With<BLETrain>>,
+    mut q_trains: Query<(&mut Train, Option<&HeadingTo>), With<BLETrain>>,
     q_markers: Query<&Marker>,
     q_blocks: Query<&Block>,
     marker_map: Res<MarkerMap>,
@@ -1063,14 +2759,21 @@ fn sensor_advance(
     mut set_train_route: MessageWriter<SetTrainRouteMessage>,
     crossings: Query<&LevelCrossing>,
     mut set_crossing_position: MessageWriter<SetCrossingPositionMessage>,
+    signals: Res<SignalAspects>,
+    mut arrival_messages: MessageWriter<DestinationArrivalMessage>,
+    defaults: Res<TrainDefaults>,
 ) {
     for advance in ble_sensor_advance_messages.read() {
         info!("Advancing sensor for train {:?}", advance.id);
         let train_entity = entity_map
             .get_entity(&GenericID::Train(advance.id))
             .unwrap();
-        let mut train = q_trains.get_mut(train_entity).unwrap();
-        assert_eq!(advance.index, train.get_route().get_current_leg().index + 1);
+        let (mut train, heading_to) = q_trains.get_mut(train_entity).unwrap();
+        let Some(route) = train.get_route() else {
+            warn!("Sensor advance for train {:?} without a route", advance.id);
+            continue;
+        };
+        assert_eq!(advance.index, route.get_current_leg().index + 1);
         train.advance_sensor();
         if update_train_route(
             &mut train,
@@ -1080,20 +2783,31 @@ fn sensor_advance(
             &mut set_switch_position,
             &crossings,
             &mut set_crossing_position,
+            &signals,
+            defaults.reservation_margin,
         ) {
             commands.trigger(LocksChangedEvent {});
         }
 
-        if train.get_route().is_completed() {
+        let route = train.get_route().unwrap();
+        if route.is_completed() {
             println!("Train {:?} completed route", train.id);
             commands.entity(train_entity).insert(WaitTime::new());
+            if let Some(HeadingTo(dest)) = heading_to {
+                arrival_messages.write(DestinationArrivalMessage {
+                    train_id: train.id,
+                    dest: *dest,
+                });
+                commands.entity(train_entity).remove::<HeadingTo>();
+            }
             let route = block_route(
-                train.get_route().get_current_leg().get_target_block_id(),
+                route.get_current_leg().get_target_block_id(),
                 train.id,
                 &q_markers,
                 &q_blocks,
                 &entity_map,
                 &marker_map,
+                route.ramp_length,
             );
             set_train_route.write(SetTrainRouteMessage {
                 train_id: train.id,
@@ -1113,6 +2827,8 @@ fn update_routes(
     mut commands: Commands,
     crossings: Query<&LevelCrossing>,
     mut set_crossing_position: MessageWriter<SetCrossingPositionMessage>,
+    signals: Res<SignalAspects>,
+    defaults: Res<TrainDefaults>,
 ) {
     for mut train in q_trains.iter_mut() {
         if update_train_route(
@@ -1123,6 +2839,8 @@ fn update_routes(
             &mut set_switch_position,
             &crossings,
             &mut set_crossing_position,
+            &signals,
+            defaults.reservation_margin,
         ) {
             commands.trigger(LocksChangedEvent {});
             return;
@@ -1132,12 +2850,72 @@ fn update_routes(
     commands.trigger(PlanRouteEvent {});
 }
 
+#[derive(Resource, Debug, Default)]
+pub struct Deadlocks {
+    // trains waiting on each other's locks in a cycle
+    pub cycles: Vec<Vec<TrainID>>,
+}
+
+impl Deadlocks {
+    pub fn cycle_of(&self, train: &TrainID) -> Option<&Vec<TrainID>> {
+        self.cycles.iter().find(|cycle| cycle.contains(train))
+    }
+}
+
+fn detect_deadlock(
+    q_trains: Query<&Train>,
+    track_locks: Res<TrackLocks>,
+    switches: Query<&Switch>,
+    entity_map: Res<EntityMap>,
+    mut deadlocks: ResMut<Deadlocks>,
+    mut commands: Commands,
+) {
+    // edge a -> b: train a waits on track locked by train b
+    let mut wait_for = DiGraphMap::<TrainID, ()>::new();
+    for train in q_trains.iter() {
+        let Some(route) = train.get_route() else {
+            continue;
+        };
+        let Some(section) = route.waiting_section(&track_locks, &switches, &entity_map) else {
+            continue;
+        };
+        for blocking in track_locks.blocking_trains(&train.id, section, &switches, &entity_map) {
+            wait_for.add_edge(train.id, blocking, ());
+        }
+    }
+    let mut cycles = petgraph::algo::tarjan_scc(&wait_for)
+        .into_iter()
+        .filter(|component| component.len() > 1)
+        .map(|mut component| {
+            component.sort();
+            component
+        })
+        .collect::<Vec<_>>();
+    cycles.sort();
+    if cycles == deadlocks.cycles {
+        return;
+    }
+    for cycle in cycles.iter() {
+        if !deadlocks.cycles.contains(cycle) {
+            warn!("Deadlock between trains {:?}", cycle);
+        }
+    }
+    let new_deadlock = cycles.iter().any(|cycle| !deadlocks.cycles.contains(cycle));
+    deadlocks.cycles = cycles;
+    if new_deadlock {
+        // gives blocked trains the chance to route around the conflict
+        commands.trigger(PlanRouteEvent {});
+    }
+}
+
 fn sync_intentions(
-    mut q_trains: Query<(&mut Train, &BLETrain)>,
+    mut q_trains: Query<(&mut Train, &mut BLETrain)>,
     mut hub_commands: MessageWriter<HubCommandMessage>,
 ) {
-    for (mut train, ble_train) in q_trains.iter_mut() {
-        let route = train.get_route_mut();
+    for (mut train, mut ble_train) in q_trains.iter_mut() {
+        let Some(route) = train.get_route_mut() else {
+            continue;
+        };
         for (leg_index, leg) in route.iter_legs_mut().enumerate() {
             if leg.intention_synced {
                 continue;
@@ -1155,45 +2933,143 @@ fn sync_intentions(
     }
 }
 
+// held and paused trains keep their run, so their lights stay on
+fn sync_train_lights(
+    mut q_trains: Query<(&Train, &mut BLETrain)>,
+    mut hub_commands: MessageWriter<HubCommandMessage>,
+) {
+    for (train, mut ble_train) in q_trains.iter_mut() {
+        if let Some(commands) = ble_train.sync_lights(train.is_running()) {
+            for input in commands.hub_messages {
+                hub_commands.write(input);
+            }
+        }
+    }
+}
+
+// device trains drive on their own, so they are stopped while paused
+fn pause_device_trains(
+    paused: Res<ControlPaused>,
+    mut q_trains: Query<(&mut Train, &BLETrain)>,
+    mut hub_commands: MessageWriter<HubCommandMessage>,
+) {
+    for (mut train, ble_train) in q_trains.iter_mut() {
+        if paused.0 {
+            for input in ble_train.stop_command().hub_messages {
+                hub_commands.write(input);
+            }
+        } else if let Some(route) = train.get_route_mut() {
+            // resending the intentions restores the state of the current leg
+            for leg in route.iter_legs_mut() {
+                leg.intention_synced = false;
+            }
+        }
+    }
+}
+
 pub struct TrainPlugin;
 
 impl Plugin for TrainPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(SelectablePlugin::<Train>::new());
         app.add_plugins(SelectablePlugin::<TrainWagon>::new());
-        app.add_plugins(InspectorPlugin::<Train>::new());
         app.register_type::<Facing>();
+        app.register_type::<FacingPreference>();
         app.insert_resource(TrainDragState::default());
+        app.insert_resource(TrainDefaults::default());
+        app.insert_resource(ShuntingSettings::default());
+        app.insert_resource(IsolatedTrain::default());
+        app.insert_resource(BlockPicker::default());
+        app.insert_resource(MotionTuning::default());
+        app.insert_resource(SimulatedSensors::default());
+        app.insert_resource(Deadlocks::default());
+        app.insert_resource(SensorLog::default());
+        app.insert_resource(ManualThrottle::default());
+        app.insert_resource(EmergencyStop::default());
+        app.add_message::<EmergencyStopMessage>();
+        app.add_systems(OnExit(ControlStateMode::Manual), release_manual_throttle);
+        app.add_systems(OnEnter(EditorState::DeviceControl), start_sensor_recording);
+        app.add_systems(OnEnter(EditorState::VirtualControl), start_sensor_replay);
+        app.add_systems(OnExit(ControlState), (stop_sensor_log, close_block_picker));
         app.add_message::<SetTrainRouteMessage>();
         app.add_observer(assign_destination_route);
         app.add_observer(update_routes);
         app.add_systems(
             Update,
             (
-                create_train_shortcut,
-                delete_selection_shortcut::<Train>,
                 despawn_train.run_if(on_message::<DespawnMessage<Train>>),
-                draw_train,
-                update_wagons.after(finish_hover),
-                // draw_train_route.after(draw_hover_route),
-                // draw_locked_tracks.after(draw_train_route),
-                // draw_hover_route,
-                init_drag_train.after(finish_hover),
-                exit_drag_train,
-                tick_wait_time.run_if(in_state(ControlState)),
+                tick_wait_time
+                    .run_if(in_state(ControlState))
+                    .run_if(control_running),
+                handle_unreachable_destinations.run_if(in_state(ControlState)),
                 set_train_route.run_if(on_message::<SetTrainRouteMessage>),
-                update_drag_train.after(finish_hover),
                 update_virtual_trains
                     .run_if(in_state(EditorState::VirtualControl))
+                    .run_if(control_running)
+                    .run_if(emergency_stop_released)
+                    .run_if(|tuning: Res<MotionTuning>| !tuning.discrete_sensors)
+                    .run_if(not(replaying_sensor_log))
                     .after(sensor_advance),
+                update_shunting_moves
+                    .run_if(in_state(EditorState::VirtualControl))
+                    .run_if(control_running)
+                    .after(update_virtual_trains),
                 update_virtual_trains_passive
-                    .run_if(in_state(EditorState::DeviceControl))
+                    .run_if(
+                        in_state(EditorState::DeviceControl).or(in_state(
+                            EditorState::VirtualControl,
+                        )
+                        .and(
+                            (|tuning: Res<MotionTuning>| tuning.discrete_sensors)
+                                .or(replaying_sensor_log),
+                        )),
+                    )
+                    .run_if(control_running)
+                    .run_if(emergency_stop_released)
                     .after(sensor_advance),
+                simulate_sensor_advances
+                    .run_if(in_state(EditorState::VirtualControl))
+                    .run_if(control_running)
+                    .run_if(|tuning: Res<MotionTuning>| tuning.discrete_sensors)
+                    .run_if(not(replaying_sensor_log))
+                    .after(update_virtual_trains_passive),
                 sensor_advance.run_if(on_message::<MarkerAdvanceMessage>),
                 sync_intentions
                     .run_if(in_state(EditorState::DeviceControl))
+                    .run_if(control_running)
+                    .run_if(emergency_stop_released)
                     .after(update_virtual_trains_passive),
-                trigger_manual_sensor_advance.run_if(in_state(EditorState::DeviceControl)),
+                sync_train_lights
+                    .run_if(in_state(EditorState::DeviceControl))
+                    .after(sync_intentions),
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                apply_isolation,
+                handle_emergency_stop
+                    .run_if(on_message::<EmergencyStopMessage>)
+                    .after(emergency_stop_shortcut)
+                    .before(apply_isolation),
+                pause_device_trains
+                    .run_if(in_state(EditorState::DeviceControl))
+                    .run_if(resource_changed::<ControlPaused>)
+                    .before(sync_intentions),
+                tick_sensor_log
+                    .run_if(in_state(ControlState))
+                    .run_if(control_running),
+                record_sensor_advances
+                    .run_if(in_state(EditorState::DeviceControl))
+                    .run_if(on_message::<MarkerAdvanceMessage>),
+                replay_sensor_advances
+                    .run_if(in_state(EditorState::VirtualControl))
+                    .run_if(replaying_sensor_log)
+                    .after(tick_sensor_log)
+                    .before(sensor_advance),
+                detect_deadlock
+                    .run_if(in_state(ControlState))
+                    .run_if(resource_changed::<TrackLocks>),
             ),
         );
         app.add_systems(
@@ -1204,3 +3080,49 @@ impl Plugin for TrainPlugin {
         );
     }
 }
+
+pub struct TrainRenderPlugin;
+
+impl Plugin for TrainRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(InspectorPlugin::<Train>::new());
+        app.add_systems(
+            Update,
+            (
+                create_train_shortcut,
+                delete_selection_shortcut::<Train>,
+                draw_train,
+                update_wagons.after(finish_hover),
+                // draw_train_route.after(draw_hover_route),
+                draw_locked_tracks.run_if(|view: Res<ViewSettings>| view.locks),
+                // draw_hover_route,
+                init_drag_train.after(finish_hover),
+                exit_drag_train,
+                update_drag_train.after(finish_hover),
+                shunt_to_click
+                    .after(finish_hover)
+                    .before(update_shunting_moves)
+                    .run_if(in_state(EditorState::VirtualControl))
+                    .run_if(control_running),
+                trigger_manual_sensor_advance.run_if(in_state(EditorState::DeviceControl)),
+                // hubs only know the discrete route speeds
+                manual_throttle
+                    .run_if(in_state(ControlStateMode::Manual))
+                    .run_if(in_state(EditorState::VirtualControl)),
+                emergency_stop_shortcut.run_if(in_state(ControlState)),
+                open_block_picker.run_if(in_state(ControlState)),
+            ),
+        );
+        app.add_systems(
+            EguiPrimaryContextPass,
+            (
+                draw_lock_labels.run_if(|view: Res<ViewSettings>| view.locks),
+                motion_tuning_window.run_if(|view: Res<ViewSettings>| view.motion_tuning),
+                sensor_log_window.run_if(|view: Res<ViewSettings>| view.sensor_log),
+                block_picker_window
+                    .run_if(in_state(ControlState))
+                    .run_if(|picker: Res<BlockPicker>| picker.train_id.is_some()),
+            ),
+        );
+    }
+}
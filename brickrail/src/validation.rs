@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::EguiPrimaryContextPass;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+
+use crate::{
+    ble::BLEHub,
+    block::Block,
+    editor::{Selection, SelectionState, ViewSettings},
+    layout::{Connections, MarkerMap},
+    layout_devices::LayoutDevice,
+    layout_primitives::*,
+    marker::Marker,
+    section::DirectedSection,
+    selectable::Selectable,
+    switch::Switch,
+};
+
+#[derive(Debug, Clone)]
+pub enum ValidationScope {
+    Layout,
+    Section(DirectedSection),
+}
+
+impl ValidationScope {
+    fn tracks(&self, blocks: &[&Block]) -> Option<HashSet<TrackID>> {
+        match self {
+            ValidationScope::Layout => None,
+            ValidationScope::Section(section) => {
+                let mut tracks: HashSet<TrackID> = section
+                    .tracks
+                    .iter()
+                    .map(|dirtrack| dirtrack.track)
+                    .collect();
+                for block in blocks {
+                    let block_tracks = &block.section().tracks;
+                    if block_tracks.iter().any(|t| tracks.contains(&t.track)) {
+                        tracks.extend(block_tracks.iter().map(|t| t.track));
+                    }
+                }
+                Some(tracks)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutIssue {
+    DeadEnd(DirectedTrackID),
+    MissingInMarker(LogicalBlockID),
+    SwitchWithoutMotor(DirectedTrackID),
+    InvalidSharedSwitch(DirectedTrackID),
+    MarkerOutsideBlock(MarkerID),
+    PortNotOnHub(LayoutDeviceID, HubPort, HubHardware),
+}
+
+impl std::fmt::Display for LayoutIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LayoutIssue::DeadEnd(track) => write!(f, "Dead end at {}", track),
+            LayoutIssue::MissingInMarker(block) => write!(f, "No in marker for {}", block),
+            LayoutIssue::SwitchWithoutMotor(switch) => {
+                write!(f, "Switch {} has unassigned motors", switch)
+            }
+            LayoutIssue::InvalidSharedSwitch(switch) => {
+                write!(
+                    f,
+                    "Switch {} is shared with a switch that is missing or shared itself",
+                    switch
+                )
+            }
+            LayoutIssue::MarkerOutsideBlock(marker) => {
+                write!(f, "Marker {} is not part of a block", marker)
+            }
+            LayoutIssue::PortNotOnHub(device, port, hardware) => {
+                write!(
+                    f,
+                    "Device {} uses port {} missing on {}",
+                    device, port, hardware
+                )
+            }
+        }
+    }
+}
+
+pub fn validate(
+    scope: &ValidationScope,
+    connections: &Connections,
+    marker_map: &MarkerMap,
+    blocks: &[&Block],
+    switches: &[&Switch],
+    markers: &[&Marker],
+    devices: &[&LayoutDevice],
+    hubs: &[&BLEHub],
+) -> Vec<LayoutIssue> {
+    let scope_tracks = scope.tracks(blocks);
+    let in_scope = |track: &TrackID| {
+        scope_tracks
+            .as_ref()
+            .map_or(true, |tracks| tracks.contains(track))
+    };
+    let mut issues = Vec::new();
+
+    let mut tracks = connections.connection_graph.nodes().collect::<Vec<_>>();
+    tracks.sort();
+    for track in tracks.iter().filter(|track| in_scope(track)) {
+        for dirtrack in track.dirtracks() {
+            let logical_tracks = dirtrack
+                .logical_tracks()
+                .into_iter()
+                .filter(|logical| connections.logical_graph.contains_node(*logical))
+                .collect::<Vec<_>>();
+            if !logical_tracks.is_empty()
+                && logical_tracks
+                    .iter()
+                    .all(|logical| connections.iter_next_tracks(*logical).next().is_none())
+            {
+                issues.push(LayoutIssue::DeadEnd(dirtrack));
+            }
+        }
+    }
+
+    for block in blocks {
+        if !in_scope(&block.id.track1.track) {
+            continue;
+        }
+        for logical_block in block.id.logical_block_ids() {
+            if !marker_map.has_in_marker(&logical_block) {
+                issues.push(LayoutIssue::MissingInMarker(logical_block));
+            }
+        }
+    }
+
+    for switch in switches {
+        if in_scope(&switch.id().track)
+            && switch.shared_with.is_none()
+            && switch.motors.iter().any(|motor| motor.is_none())
+        {
+            issues.push(LayoutIssue::SwitchWithoutMotor(switch.id()));
+        }
+        // only one level of sharing, so chains and cycles are rejected
+        if let Some(driver_id) = switch.shared_with {
+            let driver = switches.iter().find(|other| other.id() == driver_id);
+            if in_scope(&switch.id().track)
+                && driver.is_none_or(|driver| driver.shared_with.is_some())
+            {
+                issues.push(LayoutIssue::InvalidSharedSwitch(switch.id()));
+            }
+        }
+    }
+
+    for marker in markers {
+        if in_scope(&marker.track)
+            && !blocks
+                .iter()
+                .any(|block| block.section().has_track(&marker.track))
+        {
+            issues.push(LayoutIssue::MarkerOutsideBlock(marker.marker_id()));
+        }
+    }
+
+    // devices aren't tied to tracks, so only check them for the whole layout
+    if matches!(scope, ValidationScope::Layout) {
+        for device in devices {
+            let (Some(hub_id), Some(port)) = (device.hub_id, device.port) else {
+                continue;
+            };
+            let Some(hub) = hubs.iter().find(|hub| hub.id == hub_id) else {
+                continue;
+            };
+            if !hub.hardware.has_port(&port) {
+                issues.push(LayoutIssue::PortNotOnHub(device.id, port, hub.hardware));
+            }
+        }
+    }
+
+    issues
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct ValidationReport {
+    pub scope: Option<String>,
+    pub issues: Vec<LayoutIssue>,
+}
+
+#[derive(Message, Debug, Clone)]
+pub struct ValidateLayoutMessage {
+    pub scope: ValidationScope,
+}
+
+fn validate_layout(
+    mut messages: MessageReader<ValidateLayoutMessage>,
+    connections: Res<Connections>,
+    marker_map: Res<MarkerMap>,
+    q_blocks: Query<&Block>,
+    q_switches: Query<&Switch>,
+    q_markers: Query<&Marker>,
+    q_devices: Query<&LayoutDevice>,
+    q_hubs: Query<&BLEHub>,
+    mut report: ResMut<ValidationReport>,
+) {
+    for message in messages.read() {
+        let mut blocks = q_blocks.iter().collect::<Vec<_>>();
+        blocks.sort_by_key(|block| block.id);
+        let switches = q_switches.iter().collect::<Vec<_>>();
+        let markers = q_markers.iter().collect::<Vec<_>>();
+        let mut devices = q_devices.iter().collect::<Vec<_>>();
+        devices.sort_by_key(|device| device.id);
+        let hubs = q_hubs.iter().collect::<Vec<_>>();
+        report.issues = validate(
+            &message.scope,
+            &connections,
+            &marker_map,
+            &blocks,
+            &switches,
+            &markers,
+            &devices,
+            &hubs,
+        );
+        report.scope = Some(match &message.scope {
+            ValidationScope::Layout => "layout".to_string(),
+            ValidationScope::Section(section) => format!("section of {} tracks", section.len()),
+        });
+    }
+}
+
+fn validation_window(
+    mut egui_contexts: EguiContexts,
+    mut view_settings: ResMut<ViewSettings>,
+    selection_state: Res<SelectionState>,
+    report: Res<ValidationReport>,
+    mut validate_messages: MessageWriter<ValidateLayoutMessage>,
+) {
+    if let Ok(ctx) = &egui_contexts.ctx_mut().cloned() {
+        egui::Window::new("Validation")
+            .open(&mut view_settings.validation)
+            .default_width(250.0)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Validate layout").clicked() {
+                        validate_messages.write(ValidateLayoutMessage {
+                            scope: ValidationScope::Layout,
+                        });
+                    }
+                    let section = match &selection_state.selection {
+                        Selection::Section(section) => Some(section.clone()),
+                        _ => None,
+                    };
+                    ui.add_enabled_ui(section.is_some(), |ui| {
+                        if ui.button("Validate selection").clicked() {
+                            validate_messages.write(ValidateLayoutMessage {
+                                scope: ValidationScope::Section(section.unwrap()),
+                            });
+                        }
+                    });
+                });
+                ui.separator();
+                if let Some(scope) = &report.scope {
+                    ui.label(format!("{} issues in {}", report.issues.len(), scope));
+                    for issue in &report.issues {
+                        ui.label(issue.to_string());
+                    }
+                }
+            });
+    }
+}
+
+pub struct ValidationPlugin;
+
+impl Plugin for ValidationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<ValidateLayoutMessage>();
+        app.insert_resource(ValidationReport::default());
+        app.add_systems(
+            Update,
+            validate_layout.run_if(on_message::<ValidateLayoutMessage>),
+        );
+    }
+}
+
+pub struct ValidationRenderPlugin;
+
+impl Plugin for ValidationRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            EguiPrimaryContextPass,
+            validation_window.run_if(|view: Res<ViewSettings>| view.validation),
+        );
+    }
+}
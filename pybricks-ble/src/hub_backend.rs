@@ -0,0 +1,259 @@
+use std::{error::Error, future::Future, path::Path};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    pybricks_hub::{BLEAdapter, CancelToken, DownloadProgress, HubStatus, PybricksHub},
+    simulated_hub::SimulatedHub,
+    tcp_hub::TcpHub,
+};
+
+/// The operations `IOHub` needs from a pybricks hub, independent of how it is reached.
+pub trait HubBackend {
+    fn name(&self) -> Option<String>;
+
+    fn discover_name(&self) -> impl Future<Output = Result<String, Box<dyn Error>>> + Send;
+
+    fn discover(&mut self, name: &str) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn subscribe_output(&mut self) -> Result<broadcast::Receiver<u8>, Box<dyn Error>>;
+
+    fn subscribe_status(&mut self) -> Result<broadcast::Receiver<HubStatus>, Box<dyn Error>>;
+
+    fn connect(&mut self) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn rssi(&self) -> impl Future<Output = Result<Option<i16>, Box<dyn Error>>> + Send;
+
+    fn disconnect(&self) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn download_program<T: DownloadProgress + std::fmt::Debug + Send>(
+        &self,
+        path: &Path,
+        progress_sender: Option<broadcast::Sender<T>>,
+        cancel: Option<&CancelToken>,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn start_program(&self) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn stop_program(&self) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    fn write_stdin(
+        &self,
+        data: &Vec<u8>,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    // only backends that download in chunks care about these
+    fn set_download_chunk_size(&mut self, _chunk_size: Option<usize>) {}
+
+    fn set_resume_downloads(&mut self, _resume: bool) {}
+}
+
+impl HubBackend for PybricksHub {
+    fn name(&self) -> Option<String> {
+        PybricksHub::name(self)
+    }
+
+    async fn discover_name(&self) -> Result<String, Box<dyn Error>> {
+        let adapter = BLEAdapter::new().await?;
+        adapter.discover_hub_name().await
+    }
+
+    async fn discover(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        PybricksHub::discover(self, name).await
+    }
+
+    fn subscribe_output(&mut self) -> Result<broadcast::Receiver<u8>, Box<dyn Error>> {
+        PybricksHub::subscribe_output(self)
+    }
+
+    fn subscribe_status(&mut self) -> Result<broadcast::Receiver<HubStatus>, Box<dyn Error>> {
+        PybricksHub::subscribe_status(self)
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        PybricksHub::connect(self).await
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, Box<dyn Error>> {
+        PybricksHub::rssi(self).await
+    }
+
+    async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
+        PybricksHub::disconnect(self).await
+    }
+
+    async fn download_program<T: DownloadProgress + std::fmt::Debug + Send>(
+        &self,
+        path: &Path,
+        progress_sender: Option<broadcast::Sender<T>>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), Box<dyn Error>> {
+        PybricksHub::download_program(self, path, progress_sender, cancel).await
+    }
+
+    async fn start_program(&self) -> Result<(), Box<dyn Error>> {
+        PybricksHub::start_program(self).await
+    }
+
+    async fn stop_program(&self) -> Result<(), Box<dyn Error>> {
+        PybricksHub::stop_program(self).await
+    }
+
+    async fn write_stdin(&self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        PybricksHub::write_stdin(self, data).await
+    }
+
+    fn set_download_chunk_size(&mut self, chunk_size: Option<usize>) {
+        PybricksHub::set_download_chunk_size(self, chunk_size)
+    }
+
+    fn set_resume_downloads(&mut self, resume: bool) {
+        PybricksHub::set_resume_downloads(self, resume)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackendKind {
+    #[default]
+    Ble,
+    Simulated,
+    Tcp,
+}
+
+/// Selects the backend at runtime, so `IOHub` doesn't need to be generic.
+pub enum Backend {
+    Ble(PybricksHub),
+    Simulated(SimulatedHub),
+    Tcp(TcpHub),
+}
+
+impl Backend {
+    pub fn new(kind: BackendKind) -> Self {
+        match kind {
+            BackendKind::Ble => Backend::Ble(PybricksHub::new()),
+            BackendKind::Simulated => Backend::Simulated(SimulatedHub::new()),
+            BackendKind::Tcp => Backend::Tcp(TcpHub::new()),
+        }
+    }
+}
+
+impl HubBackend for Backend {
+    fn name(&self) -> Option<String> {
+        match self {
+            Backend::Ble(hub) => HubBackend::name(hub),
+            Backend::Simulated(hub) => hub.name(),
+            Backend::Tcp(hub) => hub.name(),
+        }
+    }
+
+    async fn discover_name(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::discover_name(hub).await,
+            Backend::Simulated(hub) => hub.discover_name().await,
+            Backend::Tcp(hub) => hub.discover_name().await,
+        }
+    }
+
+    async fn discover(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::discover(hub, name).await,
+            Backend::Simulated(hub) => hub.discover(name).await,
+            Backend::Tcp(hub) => hub.discover(name).await,
+        }
+    }
+
+    fn subscribe_output(&mut self) -> Result<broadcast::Receiver<u8>, Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::subscribe_output(hub),
+            Backend::Simulated(hub) => hub.subscribe_output(),
+            Backend::Tcp(hub) => hub.subscribe_output(),
+        }
+    }
+
+    fn subscribe_status(&mut self) -> Result<broadcast::Receiver<HubStatus>, Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::subscribe_status(hub),
+            Backend::Simulated(hub) => hub.subscribe_status(),
+            Backend::Tcp(hub) => hub.subscribe_status(),
+        }
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::connect(hub).await,
+            Backend::Simulated(hub) => hub.connect().await,
+            Backend::Tcp(hub) => hub.connect().await,
+        }
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::rssi(hub).await,
+            Backend::Simulated(hub) => hub.rssi().await,
+            Backend::Tcp(hub) => hub.rssi().await,
+        }
+    }
+
+    async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::disconnect(hub).await,
+            Backend::Simulated(hub) => hub.disconnect().await,
+            Backend::Tcp(hub) => hub.disconnect().await,
+        }
+    }
+
+    async fn download_program<T: DownloadProgress + std::fmt::Debug + Send>(
+        &self,
+        path: &Path,
+        progress_sender: Option<broadcast::Sender<T>>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => {
+                HubBackend::download_program(hub, path, progress_sender, cancel).await
+            }
+            Backend::Simulated(hub) => hub.download_program(path, progress_sender, cancel).await,
+            Backend::Tcp(hub) => hub.download_program(path, progress_sender, cancel).await,
+        }
+    }
+
+    async fn start_program(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::start_program(hub).await,
+            Backend::Simulated(hub) => hub.start_program().await,
+            Backend::Tcp(hub) => hub.start_program().await,
+        }
+    }
+
+    async fn stop_program(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::stop_program(hub).await,
+            Backend::Simulated(hub) => hub.stop_program().await,
+            Backend::Tcp(hub) => hub.stop_program().await,
+        }
+    }
+
+    async fn write_stdin(&self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::Ble(hub) => HubBackend::write_stdin(hub, data).await,
+            Backend::Simulated(hub) => hub.write_stdin(data).await,
+            Backend::Tcp(hub) => hub.write_stdin(data).await,
+        }
+    }
+
+    fn set_download_chunk_size(&mut self, chunk_size: Option<usize>) {
+        match self {
+            Backend::Ble(hub) => HubBackend::set_download_chunk_size(hub, chunk_size),
+            Backend::Simulated(hub) => hub.set_download_chunk_size(chunk_size),
+            Backend::Tcp(hub) => hub.set_download_chunk_size(chunk_size),
+        }
+    }
+
+    fn set_resume_downloads(&mut self, resume: bool) {
+        match self {
+            Backend::Ble(hub) => HubBackend::set_resume_downloads(hub, resume),
+            Backend::Simulated(hub) => hub.set_resume_downloads(resume),
+            Backend::Tcp(hub) => hub.set_resume_downloads(resume),
+        }
+    }
+}
@@ -3,6 +3,7 @@ use tokio::{
     sync::{
         broadcast,
         mpsc::{self, UnboundedSender},
+        oneshot,
     },
     task::JoinSet,
     time::timeout,
@@ -10,30 +11,42 @@ use tokio::{
 use tracing::{debug, error, info, trace};
 
 use crate::{
-    pybricks_hub::{BLEAdapter, DownloadProgress, HubStatus, PybricksHub},
+    hub_backend::{Backend, BackendKind, HubBackend},
+    pybricks_hub::{CancelToken, DownloadProgress, HubStatus},
     unpack_u16_little,
 };
-use std::{error::Error, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-const IN_ID_END: u8 = 10;
-const IN_ID_MSG_ACK: u8 = 6;
-const IN_ID_RPC: u8 = 17;
-const IN_ID_SYS: u8 = 18;
+pub(crate) const IN_ID_END: u8 = 10;
+pub(crate) const IN_ID_MSG_ACK: u8 = 6;
+pub(crate) const IN_ID_RPC: u8 = 17;
+pub(crate) const IN_ID_SYS: u8 = 18;
 const IN_ID_STORE: u8 = 19;
-const IN_ID_MSG_ERR: u8 = 21;
+pub(crate) const IN_ID_MSG_ERR: u8 = 21;
 const IN_ID_BROADCAST_CMD: u8 = 22;
+pub(crate) const IN_ID_RPC_CALL: u8 = 23;
 
-const OUT_ID_END: u8 = 10;
-const OUT_ID_MSG_ACK: u8 = 6;
-const OUT_ID_DATA: u8 = 17;
-const OUT_ID_SYS: u8 = 18;
-const OUT_ID_MSG_ERR: u8 = 21;
+pub(crate) const OUT_ID_END: u8 = 10;
+pub(crate) const OUT_ID_MSG_ACK: u8 = 6;
+pub(crate) const OUT_ID_DATA: u8 = 17;
+pub(crate) const OUT_ID_SYS: u8 = 18;
+pub(crate) const OUT_ID_MSG_ERR: u8 = 21;
 const OUT_ID_DUMP: u8 = 20;
 
 const SYS_CODE_STOP: u8 = 0;
-const SYS_CODE_READY: u8 = 1;
-const SYS_CODE_ALIVE: u8 = 2;
+pub(crate) const SYS_CODE_READY: u8 = 1;
+pub(crate) const SYS_CODE_ALIVE: u8 = 2;
 const SYS_CODE_VERSION: u8 = 3;
+pub(crate) const SYS_CODE_RPC_RESULT: u8 = 4;
 
 pub fn xor_checksum(data: &[u8]) -> u8 {
     let mut checksum = 0xFF;
@@ -108,6 +121,7 @@ enum InputType {
     Store,
     MsgErr,
     BroadcastCMD,
+    RPCCall,
 }
 
 impl InputType {
@@ -119,6 +133,7 @@ impl InputType {
             InputType::Store => IN_ID_STORE,
             InputType::MsgErr => IN_ID_MSG_ERR,
             InputType::BroadcastCMD => IN_ID_BROADCAST_CMD,
+            InputType::RPCCall => IN_ID_RPC_CALL,
         }
     }
 }
@@ -198,6 +213,19 @@ impl Input {
         }
     }
 
+    // like rpc, but the hub answers with the return value, tagged with the request id
+    pub fn rpc_call(funcname: &str, request_id: u32, args: &[u8]) -> Self {
+        let funcname_bytes = funcname.as_bytes();
+        let mut data = vec![xor_checksum(funcname_bytes), mod_checksum(funcname_bytes)];
+        data.extend_from_slice(&request_id.to_be_bytes());
+        data.extend_from_slice(args);
+        Input {
+            input_type: InputType::RPCCall,
+            data,
+            simulated_error: SimulatedError::None,
+        }
+    }
+
     pub fn store_uint(address: u8, mut value: u32) -> Self {
         let mut data = vec![address, 0];
         while value > 0 {
@@ -316,8 +344,31 @@ pub enum IOEvent {
     NameDiscovered(String),
     Status(HubStatus),
     DownloadProgress(f32),
+    Rssi(i16),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    Timeout,
+    // the program was stopped before the hub answered
+    Closed,
+    Queue(String),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "RPC timed out"),
+            RpcError::Closed => write!(f, "RPC canceled before the hub answered"),
+            RpcError::Queue(err) => write!(f, "Failed to queue RPC: {}", err),
+        }
+    }
+}
+
+impl Error for RpcError {}
+
+type PendingRpcs = Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>>;
+
 impl DownloadProgress for IOEvent {
     fn from_normalized(percentage: f32) -> Self {
         IOEvent::DownloadProgress(percentage)
@@ -334,12 +385,13 @@ pub struct IOState {
     long_output: bool,
     next_output_id: u8,
     response_sender: UnboundedSender<Output>,
-    input_queue_sender: UnboundedSender<Input>,
+    input_queue_sender: InputQueueSender,
     input_ack_sender: UnboundedSender<Input>,
     event_sender: broadcast::Sender<IOEvent>,
     tasks: JoinSet<()>,
     simulate_error_output: SimulatedError,
     name: String,
+    pending_rpcs: PendingRpcs,
 }
 
 impl IOState {
@@ -347,16 +399,19 @@ impl IOState {
         name: String,
         input_sender: UnboundedSender<Vec<u8>>,
         event_sender: broadcast::Sender<IOEvent>,
+        pending_rpcs: PendingRpcs,
     ) -> Self {
         let (response_sender, response_receiver) = mpsc::unbounded_channel();
         let (input_queue_sender, input_queue_receiver) = mpsc::unbounded_channel();
         let (input_ack_sender, input_ack_receiver) = mpsc::unbounded_channel();
+        let input_queue_sender = InputQueueSender::new(input_queue_sender);
 
         let mut tasks = JoinSet::new();
         tasks.spawn(Self::input_queue_task(
             input_queue_receiver,
             input_sender.clone(),
             response_receiver,
+            input_queue_sender.depth.clone(),
         ));
 
         tasks.spawn(Self::acknowledge_queue_task(
@@ -380,6 +435,7 @@ impl IOState {
             event_sender,
             tasks,
             simulate_error_output: SimulatedError::None,
+            pending_rpcs,
         };
 
         state
@@ -516,6 +572,19 @@ impl IOState {
         self.queue_acknowledgement(Input::acknowledge(output.output_id.unwrap()))?;
         self.next_output_id = self.next_output_id.wrapping_add(1);
         debug!("Next output ID: {:?}", self.next_output_id);
+        if output.output_type == OutputType::Sys
+            && output.data.len() >= 5
+            && output.data[0] == SYS_CODE_RPC_RESULT
+        {
+            let request_id = u32::from_be_bytes(output.data[1..5].try_into().unwrap());
+            match self.pending_rpcs.lock().unwrap().remove(&request_id) {
+                Some(sender) => {
+                    let _ = sender.send(output.data[5..].to_vec());
+                }
+                None => debug!("Result for unknown or timed out RPC {}", request_id),
+            }
+            return Ok(());
+        }
         match self
             .event_sender
             .send(IOEvent::Message(IOMessage::from_output(output)))
@@ -573,6 +642,7 @@ impl IOState {
         mut input_queue_receiver: mpsc::UnboundedReceiver<Input>,
         input_sender: UnboundedSender<Vec<u8>>,
         mut response_receiver: mpsc::UnboundedReceiver<Output>,
+        depth: Arc<AtomicUsize>,
     ) {
         let mut next_input_id: u8 = 0;
         while let Some(mut input) = input_queue_receiver.recv().await {
@@ -599,6 +669,7 @@ impl IOState {
                 let data = input.to_bytes(next_input_id);
                 input_sender.send(data).unwrap();
             }
+            depth.fetch_sub(1, Ordering::Relaxed);
         }
     }
 
@@ -650,32 +721,75 @@ impl IOState {
     }
 }
 
+/// Sender for the hub input queue that keeps track of how many inputs
+/// are waiting to be sent or acknowledged.
+#[derive(Debug, Clone)]
+pub struct InputQueueSender {
+    sender: UnboundedSender<Input>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl InputQueueSender {
+    fn new(sender: UnboundedSender<Input>) -> Self {
+        Self {
+            sender,
+            depth: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn send(&self, input: Input) -> Result<(), mpsc::error::SendError<Input>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(input).inspect_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
 pub struct IOHub {
-    hub: Arc<Mutex<PybricksHub>>,
+    hub: Arc<Mutex<Backend>>,
     io_state: Option<Arc<Mutex<IOState>>>,
-    input_queue_sender: Option<UnboundedSender<Input>>,
+    input_queue_sender: Option<InputQueueSender>,
     event_sender: broadcast::Sender<IOEvent>,
+    download_cancel: CancelToken,
+    pending_rpcs: PendingRpcs,
+    next_request_id: AtomicU32,
 }
 
 impl IOHub {
-    pub fn new() -> Self {
+    pub fn new(backend: BackendKind) -> Self {
         let (event_sender, _) = broadcast::channel(256);
         IOHub {
-            hub: Arc::new(Mutex::new(PybricksHub::new())),
+            hub: Arc::new(Mutex::new(Backend::new(backend))),
             io_state: None,
             input_queue_sender: None,
             event_sender: event_sender,
+            download_cancel: CancelToken::default(),
+            pending_rpcs: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            next_request_id: AtomicU32::new(0),
         }
     }
 
+    // can be used to cancel a download while the hub is locked by it, reset it before downloading
+    pub fn download_cancel_token(&self) -> CancelToken {
+        self.download_cancel.clone()
+    }
+
     pub async fn discover_name(&self) -> Result<String, Box<dyn Error>> {
-        let adapter = BLEAdapter::new().await?;
-        let name = adapter.discover_hub_name().await?;
+        let name = self.hub.lock().await.discover_name().await?;
         self.event_sender
             .send(IOEvent::NameDiscovered(name.clone()))?;
         Ok(name)
     }
 
+    // only while disconnected, the old backend is dropped without closing its connection
+    pub async fn set_backend(&self, backend: BackendKind) {
+        *self.hub.lock().await = Backend::new(backend);
+    }
+
     pub async fn discover(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
         let mut hub = self.hub.lock().await;
         hub.discover(name).await?;
@@ -697,10 +811,16 @@ impl IOHub {
         self.event_sender.subscribe()
     }
 
-    pub fn get_input_queue_sender(&self) -> Option<UnboundedSender<Input>> {
+    pub fn get_input_queue_sender(&self) -> Option<InputQueueSender> {
         self.input_queue_sender.clone()
     }
 
+    pub fn queue_depth(&self) -> usize {
+        self.input_queue_sender
+            .as_ref()
+            .map_or(0, |sender| sender.depth())
+    }
+
     pub async fn set_simulated_output_error(
         &self,
         error: SimulatedError,
@@ -723,16 +843,34 @@ impl IOHub {
         Ok(())
     }
 
+    // sends an IOEvent::Rssi if the adapter knows the current signal strength
+    pub async fn poll_rssi(&self) -> Result<(), Box<dyn Error>> {
+        let hub = self.hub.lock().await;
+        if let Some(rssi) = hub.rssi().await? {
+            self.event_sender.send(IOEvent::Rssi(rssi))?;
+        }
+        Ok(())
+    }
+
     pub async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
         let hub = self.hub.lock().await;
         hub.disconnect().await?;
         Ok(())
     }
 
+    pub async fn set_download_chunk_size(&self, chunk_size: Option<usize>) {
+        self.hub.lock().await.set_download_chunk_size(chunk_size);
+    }
+
+    pub async fn set_resume_downloads(&self, resume: bool) {
+        self.hub.lock().await.set_resume_downloads(resume);
+    }
+
     pub async fn download_program(&self, name: &Path) -> Result<(), Box<dyn Error>> {
         let hub = self.hub.lock().await;
         let sender = self.event_sender.clone();
-        hub.download_program(name, Some(sender)).await?;
+        hub.download_program(name, Some(sender), Some(&self.download_cancel))
+            .await?;
         Ok(())
     }
 
@@ -748,7 +886,7 @@ impl IOHub {
 
     async fn setup_io_state(
         &mut self,
-    ) -> Result<futures::lock::MutexGuard<'_, PybricksHub>, Box<dyn Error>> {
+    ) -> Result<futures::lock::MutexGuard<'_, Backend>, Box<dyn Error>> {
         let mut hub = self.hub.lock().await;
         let output_receiver = hub.subscribe_output()?;
         let (input_sender, input_receiver) = mpsc::unbounded_channel();
@@ -756,6 +894,7 @@ impl IOHub {
             hub.name().unwrap_or("Unknown".to_string()),
             input_sender,
             self.event_sender.clone(),
+            self.pending_rpcs.clone(),
         );
         self.input_queue_sender = Some(io_state.input_queue_sender.clone());
         let io_state_mutex = Arc::new(Mutex::new(io_state));
@@ -789,6 +928,8 @@ impl IOHub {
 
         self.io_state = None;
         self.input_queue_sender = None;
+        // dropping the senders fails the waiting calls
+        self.pending_rpcs.lock().unwrap().clear();
     }
 
     pub fn queue_input(&self, input: Input) -> Result<(), Box<dyn Error>> {
@@ -799,6 +940,31 @@ impl IOHub {
         Ok(())
     }
 
+    /// Calls a function of the hub program and waits for its return value.
+    /// Unlike `queue_input`, this fails if the hub doesn't answer within `timeout`.
+    pub async fn call_rpc(
+        &self,
+        funcname: &str,
+        args: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending_rpcs.lock().unwrap().insert(request_id, sender);
+        if let Err(err) = self.queue_input(Input::rpc_call(funcname, request_id, args)) {
+            self.pending_rpcs.lock().unwrap().remove(&request_id);
+            return Err(RpcError::Queue(err.to_string()));
+        }
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(RpcError::Closed),
+            Err(_) => {
+                self.pending_rpcs.lock().unwrap().remove(&request_id);
+                Err(RpcError::Timeout)
+            }
+        }
+    }
+
     pub async fn wait_for_data_event_with_id(
         &self,
         match_id: u8,
@@ -855,7 +1021,7 @@ impl IOHub {
 
     async fn forward_input_task(
         mut input_receiver: mpsc::UnboundedReceiver<Vec<u8>>,
-        task_hub: Arc<Mutex<PybricksHub>>,
+        task_hub: Arc<Mutex<Backend>>,
     ) {
         while let Some(data) = input_receiver.recv().await {
             let unlocked_hub = task_hub.lock().await;
@@ -866,6 +1032,6 @@ impl IOHub {
 
 impl Default for IOHub {
     fn default() -> Self {
-        Self::new()
+        Self::new(BackendKind::Ble)
     }
 }
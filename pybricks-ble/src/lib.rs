@@ -1,5 +1,8 @@
+pub mod hub_backend;
 pub mod io_hub;
 pub mod pybricks_hub;
+pub mod simulated_hub;
+pub mod tcp_hub;
 
 fn unpack_u32_little(data: Vec<u8>) -> u32 {
     (data[0] as u32) | ((data[1] as u32) << 8) | ((data[2] as u32) << 16) | ((data[3] as u32) << 24)
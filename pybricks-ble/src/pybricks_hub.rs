@@ -1,4 +1,15 @@
-use std::{collections::BTreeSet, error::Error, path::Path, pin::Pin, vec};
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    vec,
+};
 
 use btleplug::{
     api::{
@@ -49,10 +60,59 @@ pub struct HubStatus {
     pub running_program: u8,
 }
 
+// shared flag that lets another task abort a running download
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 pub trait DownloadProgress {
     fn from_normalized(percentage: f32) -> Self;
 }
 
+/// Bytes of a program the hub has acknowledged so far, so an interrupted download can continue.
+///
+/// This relies on the hub keeping its user RAM while disconnected. If it was switched off in
+/// between, the resumed program is incomplete, so resuming is off unless `set_resume_downloads`
+/// enables it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DownloadCheckpoint {
+    program_hash: u64,
+    program_len: usize,
+    offset: usize,
+}
+
+impl DownloadCheckpoint {
+    fn new(data: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        DownloadCheckpoint {
+            program_hash: hasher.finish(),
+            program_len: data.len(),
+            offset: 0,
+        }
+    }
+
+    // where to continue downloading data, if this checkpoint belongs to it
+    fn resume_offset(&self, data: &[u8]) -> Option<usize> {
+        let other = DownloadCheckpoint::new(data);
+        (other.program_hash == self.program_hash && other.program_len == self.program_len)
+            .then_some(self.offset)
+    }
+}
+
 #[derive(Debug)]
 enum HubEvent {
     Status(HubStatus),
@@ -229,6 +289,10 @@ pub struct PybricksHub {
     capabilities: Option<HubCapabilities>,
     output_receiver: Option<broadcast::Receiver<u8>>,
     status_receiver: Option<broadcast::Receiver<HubStatus>>,
+    // kept across reconnects, the hub object is reused
+    download_checkpoint: Mutex<Option<DownloadCheckpoint>>,
+    download_chunk_size: Option<usize>,
+    resume_downloads: bool,
 }
 
 impl std::fmt::Display for PybricksHub {
@@ -255,9 +319,22 @@ impl PybricksHub {
             capabilities: None,
             output_receiver: None,
             status_receiver: None,
+            download_checkpoint: Mutex::new(None),
+            download_chunk_size: None,
+            resume_downloads: false,
         }
     }
 
+    /// Bytes written per BLE command while downloading, capped by what the hub supports.
+    /// Smaller chunks lose less progress when the connection drops.
+    pub fn set_download_chunk_size(&mut self, chunk_size: Option<usize>) {
+        self.download_chunk_size = chunk_size;
+    }
+
+    pub fn set_resume_downloads(&mut self, resume: bool) {
+        self.resume_downloads = resume;
+    }
+
     pub fn name(&self) -> Option<String> {
         self.name.clone()
     }
@@ -314,6 +391,14 @@ impl PybricksHub {
         Ok(())
     }
 
+    pub async fn rssi(&self) -> Result<Option<i16>, Box<dyn Error>> {
+        let client = self.client.as_ref().ok_or("No client")?;
+        Ok(client
+            .properties()
+            .await?
+            .and_then(|properties| properties.rssi))
+    }
+
     pub async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
         let client = self.client.as_ref().ok_or("No client")?;
         debug!("Disconnecting from {:}", self);
@@ -348,6 +433,7 @@ impl PybricksHub {
         &self,
         path: &Path,
         progress_sender: Option<broadcast::Sender<T>>,
+        cancel: Option<&CancelToken>,
     ) -> Result<(), Box<dyn Error>> {
         info!("Downloading program to {:}", self);
 
@@ -357,19 +443,45 @@ impl PybricksHub {
             return Err("Program too large".into());
         }
 
-        self.pb_command(Command::WriteUserProgramMeta, &pack_u32(0))
-            .await?;
+        let resume_offset = self
+            .download_checkpoint
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|_| self.resume_downloads)
+            .and_then(|checkpoint| checkpoint.resume_offset(&data));
+        let mut checkpoint = DownloadCheckpoint::new(&data);
+        match resume_offset {
+            Some(offset) => {
+                info!("Resuming download to {:} at byte {}", self, offset);
+                checkpoint.offset = offset;
+            }
+            None => {
+                self.pb_command(Command::WriteUserProgramMeta, &pack_u32(0))
+                    .await?;
+            }
+        }
 
-        let payload_size = self.capabilities.as_ref().unwrap().max_write_size as usize - 5;
+        let max_payload_size = self.capabilities.as_ref().unwrap().max_write_size as usize - 5;
+        let payload_size = self
+            .download_chunk_size
+            .map_or(max_payload_size, |size| size.clamp(1, max_payload_size));
 
-        for (i, chunk) in data.chunks(payload_size).enumerate() {
-            let mut inner_data = pack_u32((i * payload_size) as u32);
-            inner_data.extend_from_slice(chunk);
+        while checkpoint.offset < data.len() {
+            // the program size is still 0, so the hub is left without a program
+            if cancel.is_some_and(|cancel| cancel.is_canceled()) {
+                info!("Download to {:} canceled", self);
+                return Err("Download canceled".into());
+            }
+            let end = (checkpoint.offset + payload_size).min(data.len());
+            let mut inner_data = pack_u32(checkpoint.offset as u32);
+            inner_data.extend_from_slice(&data[checkpoint.offset..end]);
             self.pb_command(Command::WriteUserRam, &inner_data).await?;
+            // written with response, so the hub has this chunk
+            checkpoint.offset = end;
+            *self.download_checkpoint.lock().unwrap() = Some(checkpoint.clone());
             if let Some(sender) = &progress_sender {
-                if let Err(err) = sender.send(T::from_normalized(
-                    i as f32 / ((data.len() / payload_size) as f32),
-                )) {
+                if let Err(err) = sender.send(T::from_normalized(end as f32 / data.len() as f32)) {
                     error!("Failed to send progress: {:?}", err);
                 }
             }
@@ -377,6 +489,7 @@ impl PybricksHub {
 
         self.pb_command(Command::WriteUserProgramMeta, &pack_u32(data.len() as u32))
             .await?;
+        *self.download_checkpoint.lock().unwrap() = None;
 
         info!("Downloaded program finished for {:?}", self.name);
 
@@ -432,6 +545,11 @@ async fn monitor_events(
         }
     }
     debug!("Done listening for notifications");
+    // the stream only ends when the link is gone, so let listeners know we're no longer connected
+    let _ = status_sender.send(HubStatus {
+        flags: HubStatusFlags::empty(),
+        running_program: 0,
+    });
 }
 
 #[cfg(test)]
@@ -447,6 +565,16 @@ mod test {
         assert_eq!(caps.max_program_size, 271);
     }
 
+    #[test]
+    fn test_download_checkpoint() {
+        let program = vec![1, 2, 3, 4, 5];
+        let mut checkpoint = DownloadCheckpoint::new(&program);
+        checkpoint.offset = 3;
+        assert_eq!(checkpoint.resume_offset(&program), Some(3));
+        assert_eq!(checkpoint.resume_offset(&[1, 2, 3, 4, 6]), None);
+        assert_eq!(checkpoint.resume_offset(&[1, 2, 3]), None);
+    }
+
     #[test]
     fn test_pack_unpack() {
         let n = 420;
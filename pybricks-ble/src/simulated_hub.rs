@@ -0,0 +1,318 @@
+use std::{
+    error::Error,
+    path::Path,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::broadcast;
+use tracing::{debug, info};
+
+use crate::{
+    hub_backend::HubBackend,
+    io_hub::{
+        xor_checksum, IN_ID_END, IN_ID_MSG_ACK, IN_ID_MSG_ERR, IN_ID_RPC, IN_ID_RPC_CALL,
+        IN_ID_SYS, OUT_ID_DATA, OUT_ID_END, OUT_ID_MSG_ACK, OUT_ID_MSG_ERR, OUT_ID_SYS,
+        SYS_CODE_ALIVE, SYS_CODE_READY, SYS_CODE_RPC_RESULT,
+    },
+    pybricks_hub::{CancelToken, DownloadProgress, HubStatus, HubStatusFlags},
+};
+
+/// Data id the simulated hub uses to echo back rpc inputs.
+pub const SIMULATED_ECHO_ID: u8 = 255;
+
+const ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+const DOWNLOAD_CHUNK_DELAY: Duration = Duration::from_millis(20);
+const DOWNLOAD_CHUNKS: usize = 10;
+const SIMULATED_VOLTAGE_MV: u16 = 7200;
+const SIMULATED_CURRENT_MA: u16 = 150;
+const SIMULATED_RSSI: i16 = -60;
+
+static NEXT_SIMULATED_HUB: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Default)]
+struct SimulatedState {
+    connected: bool,
+    running: bool,
+    next_output_id: u8,
+    last_input_id: Option<u8>,
+    // kept until acknowledged, so it can be resent on a NAK
+    unacknowledged_output: Option<Vec<u8>>,
+}
+
+/// An in-process hub that speaks the IO protocol of the brickrail programs without any BLE.
+pub struct SimulatedHub {
+    name: Option<String>,
+    state: Arc<Mutex<SimulatedState>>,
+    output_sender: Option<broadcast::Sender<u8>>,
+    output_receiver: Option<broadcast::Receiver<u8>>,
+    status_sender: Option<broadcast::Sender<HubStatus>>,
+    status_receiver: Option<broadcast::Receiver<HubStatus>>,
+}
+
+impl Default for SimulatedHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedHub {
+    pub fn new() -> Self {
+        SimulatedHub {
+            name: None,
+            state: Arc::new(Mutex::new(SimulatedState::default())),
+            output_sender: None,
+            output_receiver: None,
+            status_sender: None,
+            status_receiver: None,
+        }
+    }
+
+    fn send_status(&self) {
+        let state = self.state.lock().unwrap();
+        let mut flags = HubStatusFlags::empty();
+        if state.connected {
+            flags |= HubStatusFlags::BLE_HOST_CONNECTED;
+        }
+        if state.running {
+            flags |= HubStatusFlags::PROGRAM_RUNNING;
+        }
+        if let Some(sender) = &self.status_sender {
+            let _ = sender.send(HubStatus {
+                flags,
+                running_program: 0,
+            });
+        }
+    }
+
+    fn output_sender(&self) -> Result<broadcast::Sender<u8>, Box<dyn Error>> {
+        Ok(self
+            .output_sender
+            .clone()
+            .ok_or("Simulated hub not discovered")?)
+    }
+
+    fn handle_input(&self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let sender = self.output_sender()?;
+        // [len, type, payload.., (id, checksum), end]
+        if data.len() < 3 || data[0] as usize != data.len() - 2 || data[data.len() - 1] != IN_ID_END
+        {
+            debug!("Simulated hub received malformed input {:?}", data);
+            send_short(&sender, OUT_ID_MSG_ERR, 0);
+            return Ok(());
+        }
+        let body = &data[1..data.len() - 1];
+        match body[0] {
+            IN_ID_MSG_ACK => {
+                self.state.lock().unwrap().unacknowledged_output = None;
+                return Ok(());
+            }
+            IN_ID_MSG_ERR => {
+                let output = self.state.lock().unwrap().unacknowledged_output.clone();
+                if let Some(output) = output {
+                    debug!("Simulated hub resending output");
+                    send_bytes(&sender, &output);
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+        if body.len() < 3 || xor_checksum(&body[..body.len() - 1]) != body[body.len() - 1] {
+            debug!("Simulated hub received input with bad checksum {:?}", data);
+            send_short(&sender, OUT_ID_MSG_ERR, 0);
+            return Ok(());
+        }
+        let input_id = body[body.len() - 2];
+        let payload = &body[1..body.len() - 2];
+        send_short(&sender, OUT_ID_MSG_ACK, input_id);
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.last_input_id == Some(input_id) {
+                // the host missed our ack and resent
+                return Ok(());
+            }
+            state.last_input_id = Some(input_id);
+        }
+        match body[0] {
+            IN_ID_RPC => {
+                send_output(
+                    &sender,
+                    &self.state,
+                    OUT_ID_DATA,
+                    SIMULATED_ECHO_ID,
+                    payload,
+                );
+            }
+            IN_ID_RPC_CALL if payload.len() >= 6 => {
+                // skip the function hash, answer with the request id and the echoed args
+                send_output(
+                    &sender,
+                    &self.state,
+                    OUT_ID_SYS,
+                    SYS_CODE_RPC_RESULT,
+                    &payload[2..],
+                );
+            }
+            IN_ID_SYS => {
+                if payload.first() == Some(&SYS_CODE_READY) {
+                    send_output(&sender, &self.state, OUT_ID_SYS, SYS_CODE_READY, &[]);
+                }
+            }
+            // stored values and broadcasts only need the ack
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn send_bytes(sender: &broadcast::Sender<u8>, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = sender.send(*byte);
+    }
+}
+
+// acks and naks don't carry an id or checksum of their own
+fn send_short(sender: &broadcast::Sender<u8>, output_type: u8, input_id: u8) {
+    send_bytes(sender, &[2, output_type, input_id, OUT_ID_END]);
+}
+
+fn send_output(
+    sender: &broadcast::Sender<u8>,
+    state: &Mutex<SimulatedState>,
+    output_type: u8,
+    code: u8,
+    data: &[u8],
+) {
+    let mut state = state.lock().unwrap();
+    let mut buffer = vec![output_type, code];
+    buffer.extend_from_slice(data);
+    buffer.push(state.next_output_id);
+    buffer.push(xor_checksum(&buffer));
+    let mut bytes = vec![buffer.len() as u8];
+    bytes.extend(buffer);
+    bytes.push(OUT_ID_END);
+    state.next_output_id = state.next_output_id.wrapping_add(1);
+    state.unacknowledged_output = Some(bytes.clone());
+    send_bytes(sender, &bytes);
+}
+
+async fn alive_task(sender: broadcast::Sender<u8>, state: Arc<Mutex<SimulatedState>>) {
+    let mut data = SIMULATED_VOLTAGE_MV.to_be_bytes().to_vec();
+    data.extend_from_slice(&SIMULATED_CURRENT_MA.to_be_bytes());
+    loop {
+        tokio::time::sleep(ALIVE_INTERVAL).await;
+        if !state.lock().unwrap().running {
+            break;
+        }
+        send_output(&sender, &state, OUT_ID_SYS, SYS_CODE_ALIVE, &data);
+    }
+}
+
+impl HubBackend for SimulatedHub {
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    async fn discover_name(&self) -> Result<String, Box<dyn Error>> {
+        let index = NEXT_SIMULATED_HUB.fetch_add(1, Ordering::Relaxed);
+        Ok(format!("simulated{}", index))
+    }
+
+    async fn discover(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.name = Some(name.to_string());
+        let (output_sender, output_receiver) = broadcast::channel(256);
+        let (status_sender, status_receiver) = broadcast::channel(256);
+        self.output_sender = Some(output_sender);
+        self.output_receiver = Some(output_receiver);
+        self.status_sender = Some(status_sender);
+        self.status_receiver = Some(status_receiver);
+        Ok(())
+    }
+
+    fn subscribe_output(&mut self) -> Result<broadcast::Receiver<u8>, Box<dyn Error>> {
+        Ok(self
+            .output_receiver
+            .as_ref()
+            .ok_or("No output receiver")?
+            .resubscribe())
+    }
+
+    fn subscribe_status(&mut self) -> Result<broadcast::Receiver<HubStatus>, Box<dyn Error>> {
+        Ok(self
+            .status_receiver
+            .as_ref()
+            .ok_or("No status receiver")?
+            .resubscribe())
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        self.output_sender()?;
+        self.state.lock().unwrap().connected = true;
+        self.send_status();
+        info!("connected to simulated hub {:?}!", self.name);
+        Ok(())
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, Box<dyn Error>> {
+        Ok(Some(SIMULATED_RSSI))
+    }
+
+    async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
+        *self.state.lock().unwrap() = SimulatedState::default();
+        self.send_status();
+        info!("disconnected from simulated hub {:?}!", self.name);
+        Ok(())
+    }
+
+    async fn download_program<T: DownloadProgress + std::fmt::Debug + Send>(
+        &self,
+        path: &Path,
+        progress_sender: Option<broadcast::Sender<T>>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !path.exists() {
+            return Err(format!("Program {:?} not found", path).into());
+        }
+        for i in 0..DOWNLOAD_CHUNKS {
+            if cancel.is_some_and(|cancel| cancel.is_canceled()) {
+                return Err("Download canceled".into());
+            }
+            tokio::time::sleep(DOWNLOAD_CHUNK_DELAY).await;
+            if let Some(sender) = &progress_sender {
+                let _ = sender.send(T::from_normalized(i as f32 / DOWNLOAD_CHUNKS as f32));
+            }
+        }
+        Ok(())
+    }
+
+    async fn start_program(&self) -> Result<(), Box<dyn Error>> {
+        let sender = self.output_sender()?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running = true;
+            state.next_output_id = 0;
+            state.last_input_id = None;
+            state.unacknowledged_output = None;
+        }
+        self.send_status();
+        tokio::task::spawn(alive_task(sender, self.state.clone()));
+        Ok(())
+    }
+
+    async fn stop_program(&self) -> Result<(), Box<dyn Error>> {
+        self.state.lock().unwrap().running = false;
+        self.send_status();
+        Ok(())
+    }
+
+    async fn write_stdin(&self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if !self.state.lock().unwrap().running {
+            debug!("Simulated hub ignoring input without a running program");
+            return Ok(());
+        }
+        self.handle_input(data)
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    error::Error,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, tcp::OwnedWriteHalf, TcpStream},
+    sync::{broadcast, Mutex},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    hub_backend::HubBackend,
+    pybricks_hub::{CancelToken, DownloadProgress, HubStatus, HubStatusFlags},
+};
+
+/// A device reached over TCP, e.g. a microcontroller on the local network.
+///
+/// The name of the hub is its `host:port` address. The stream carries the same bytes a pybricks
+/// hub sends and receives over stdin/stdout, so the device has to speak the IO protocol of the
+/// brickrail programs itself. It runs its own firmware, so downloading is a no-op and starting or
+/// stopping only toggles whether the host treats the program as running.
+pub struct TcpHub {
+    address: Option<String>,
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+    running: Arc<AtomicBool>,
+    output_sender: Option<broadcast::Sender<u8>>,
+    output_receiver: Option<broadcast::Receiver<u8>>,
+    status_sender: Option<broadcast::Sender<HubStatus>>,
+    status_receiver: Option<broadcast::Receiver<HubStatus>>,
+}
+
+impl Default for TcpHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcpHub {
+    pub fn new() -> Self {
+        TcpHub {
+            address: None,
+            writer: Arc::new(Mutex::new(None)),
+            running: Arc::new(AtomicBool::new(false)),
+            output_sender: None,
+            output_receiver: None,
+            status_sender: None,
+            status_receiver: None,
+        }
+    }
+
+    async fn send_status(&self) {
+        let mut flags = HubStatusFlags::empty();
+        if self.writer.lock().await.is_some() {
+            flags |= HubStatusFlags::BLE_HOST_CONNECTED;
+        }
+        if self.running.load(Ordering::SeqCst) {
+            flags |= HubStatusFlags::PROGRAM_RUNNING;
+        }
+        if let Some(sender) = &self.status_sender {
+            let _ = sender.send(HubStatus {
+                flags,
+                running_program: 0,
+            });
+        }
+    }
+}
+
+async fn read_task(
+    mut reader: OwnedReadHalf,
+    output_sender: broadcast::Sender<u8>,
+    status_sender: broadcast::Sender<HubStatus>,
+    running: Arc<AtomicBool>,
+) {
+    let mut buffer = [0u8; 256];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(len) => {
+                for byte in &buffer[..len] {
+                    let _ = output_sender.send(*byte);
+                }
+            }
+            Err(err) => {
+                warn!("TCP hub read error: {:?}", err);
+                break;
+            }
+        }
+    }
+    debug!("TCP hub stream closed");
+    // same as a BLE hub dropping out
+    running.store(false, Ordering::SeqCst);
+    let _ = status_sender.send(HubStatus {
+        flags: HubStatusFlags::empty(),
+        running_program: 0,
+    });
+}
+
+impl HubBackend for TcpHub {
+    fn name(&self) -> Option<String> {
+        self.address.clone()
+    }
+
+    async fn discover_name(&self) -> Result<String, Box<dyn Error>> {
+        Err("TCP hubs can't be discovered, enter their address instead".into())
+    }
+
+    async fn discover(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.address = Some(name.to_string());
+        let (output_sender, output_receiver) = broadcast::channel(256);
+        let (status_sender, status_receiver) = broadcast::channel(256);
+        self.output_sender = Some(output_sender);
+        self.output_receiver = Some(output_receiver);
+        self.status_sender = Some(status_sender);
+        self.status_receiver = Some(status_receiver);
+        Ok(())
+    }
+
+    fn subscribe_output(&mut self) -> Result<broadcast::Receiver<u8>, Box<dyn Error>> {
+        Ok(self
+            .output_receiver
+            .as_ref()
+            .ok_or("No output receiver")?
+            .resubscribe())
+    }
+
+    fn subscribe_status(&mut self) -> Result<broadcast::Receiver<HubStatus>, Box<dyn Error>> {
+        Ok(self
+            .status_receiver
+            .as_ref()
+            .ok_or("No status receiver")?
+            .resubscribe())
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        let address = self.address.as_ref().ok_or("No address")?;
+        let output_sender = self.output_sender.clone().ok_or("TCP hub not discovered")?;
+        let status_sender = self.status_sender.clone().ok_or("TCP hub not discovered")?;
+        debug!("Connecting to {:?}", address);
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        *self.writer.lock().await = Some(writer);
+        tokio::task::spawn(read_task(
+            reader,
+            output_sender,
+            status_sender,
+            self.running.clone(),
+        ));
+        self.send_status().await;
+        info!("connected to TCP hub {:?}!", self.address);
+        Ok(())
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    async fn disconnect(&self) -> Result<(), Box<dyn Error>> {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(mut writer) = self.writer.lock().await.take() {
+            writer.shutdown().await?;
+        }
+        self.send_status().await;
+        info!("disconnected from TCP hub {:?}!", self.address);
+        Ok(())
+    }
+
+    async fn download_program<T: DownloadProgress + std::fmt::Debug + Send>(
+        &self,
+        _path: &Path,
+        progress_sender: Option<broadcast::Sender<T>>,
+        _cancel: Option<&CancelToken>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(sender) = progress_sender {
+            let _ = sender.send(T::from_normalized(1.0));
+        }
+        Ok(())
+    }
+
+    async fn start_program(&self) -> Result<(), Box<dyn Error>> {
+        if self.writer.lock().await.is_none() {
+            return Err("TCP hub not connected".into());
+        }
+        self.running.store(true, Ordering::SeqCst);
+        self.send_status().await;
+        Ok(())
+    }
+
+    async fn stop_program(&self) -> Result<(), Box<dyn Error>> {
+        self.running.store(false, Ordering::SeqCst);
+        self.send_status().await;
+        Ok(())
+    }
+
+    async fn write_stdin(&self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut writer = self.writer.lock().await;
+        writer
+            .as_mut()
+            .ok_or("TCP hub not connected")?
+            .write_all(data)
+            .await?;
+        Ok(())
+    }
+}
@@ -9,7 +9,7 @@ async fn get_and_connect_hub() -> IOHub {
     let adapter = BLEAdapter::new().await.unwrap();
     let name = adapter.discover_hub_name().await.unwrap();
     println!("Found hub with name {:?}", name);
-    let mut hub = IOHub::new();
+    let mut hub = IOHub::default();
     let mut events_receiver = hub.subscribe_events();
     hub.discover(name.as_str()).await.unwrap();
     tokio::task::spawn(async move {
@@ -0,0 +1,78 @@
+use std::{path::Path, time::Duration};
+
+use tokio::sync::broadcast;
+
+use pybricks_ble::{
+    hub_backend::BackendKind,
+    io_hub::{IOEvent, IOHub, IOMessage, Input, RpcError, SimulatedError, SysCode},
+    pybricks_hub::HubStatusFlags,
+    simulated_hub::SIMULATED_ECHO_ID,
+};
+
+async fn wait_for_sys(receiver: &mut broadcast::Receiver<IOEvent>, match_code: u8) -> Vec<u8> {
+    while let Ok(event) = receiver.recv().await {
+        if let IOEvent::Message(IOMessage::Sys { code, data }) = event {
+            if code == match_code {
+                return data;
+            }
+        }
+    }
+    panic!("Event channel closed");
+}
+
+#[test_log::test(tokio::test)]
+async fn test_simulated_hub() {
+    let mut hub = IOHub::new(BackendKind::Simulated);
+    let name = hub.discover_name().await.unwrap();
+    let mut events = hub.subscribe_events();
+    hub.connect(&name).await.unwrap();
+    match events.recv().await.unwrap() {
+        IOEvent::Status(status) => {
+            assert!(status.flags.contains(HubStatusFlags::BLE_HOST_CONNECTED));
+        }
+        event => panic!("Unexpected event {:?}", event),
+    }
+
+    hub.download_program(Path::new("Cargo.toml")).await.unwrap();
+    hub.start_program().await.unwrap();
+
+    hub.queue_input(Input::rpc("respond", &vec![29, 42]).with_error(SimulatedError::Modify(4)))
+        .unwrap();
+    assert_eq!(
+        hub.wait_for_data(SIMULATED_ECHO_ID).await.unwrap()[2..],
+        [29, 42]
+    );
+
+    hub.queue_input(Input::sys(SysCode::Ready, &[])).unwrap();
+    wait_for_sys(&mut events, 1).await;
+
+    let alive = wait_for_sys(&mut events, 2).await;
+    assert_eq!(u16::from_be_bytes([alive[0], alive[1]]), 7200);
+
+    hub.stop_program().await.unwrap();
+    hub.disconnect().await.unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn test_simulated_rpc_call() {
+    let mut hub = IOHub::new(BackendKind::Simulated);
+    let name = hub.discover_name().await.unwrap();
+    hub.connect(&name).await.unwrap();
+    hub.start_program().await.unwrap();
+
+    for args in [vec![29, 42], vec![], vec![7]] {
+        let result = hub
+            .call_rpc("respond", &args, Duration::from_secs(2))
+            .await
+            .unwrap();
+        // the simulated hub echoes the request id before the args
+        assert_eq!(result[4..], args);
+    }
+
+    hub.stop_program().await.unwrap();
+    assert!(matches!(
+        hub.call_rpc("respond", &[], Duration::from_secs(2)).await,
+        Err(RpcError::Queue(_))
+    ));
+    hub.disconnect().await.unwrap();
+}
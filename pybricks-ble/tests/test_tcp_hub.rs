@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use tokio::{io::AsyncReadExt, net::TcpListener, sync::broadcast};
+
+use pybricks_ble::{
+    hub_backend::BackendKind,
+    io_hub::{IOEvent, IOHub, Input, RpcError},
+    pybricks_hub::HubStatusFlags,
+};
+
+async fn wait_for_status(receiver: &mut broadcast::Receiver<IOEvent>) -> HubStatusFlags {
+    while let Ok(event) = receiver.recv().await {
+        if let IOEvent::Status(status) = event {
+            return status.flags;
+        }
+    }
+    panic!("Event channel closed");
+}
+
+#[test_log::test(tokio::test)]
+async fn test_tcp_hub() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    let mut hub = IOHub::new(BackendKind::Tcp);
+    assert!(hub.discover_name().await.is_err());
+    let mut events = hub.subscribe_events();
+    hub.connect(&address).await.unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert!(wait_for_status(&mut events)
+        .await
+        .contains(HubStatusFlags::BLE_HOST_CONNECTED));
+
+    hub.start_program().await.unwrap();
+    assert!(wait_for_status(&mut events)
+        .await
+        .contains(HubStatusFlags::PROGRAM_RUNNING));
+
+    hub.queue_input(Input::rpc("respond", &vec![29, 42]))
+        .unwrap();
+    let mut buffer = [0u8; 64];
+    let len = stream.read(&mut buffer).await.unwrap();
+    // [len, type, hash1, hash2, args.., id, checksum, end]
+    assert_eq!(buffer[0] as usize, len - 2);
+    assert_eq!(buffer[4..6], [29, 42]);
+
+    // nothing answers on the other end
+    assert_eq!(
+        hub.call_rpc("respond", &[1], Duration::from_millis(100))
+            .await,
+        Err(RpcError::Timeout)
+    );
+
+    // the device going away looks like a hub dropping its BLE connection
+    drop(stream);
+    assert!(wait_for_status(&mut events).await.is_empty());
+    hub.disconnect().await.unwrap();
+}
@@ -23,6 +23,7 @@ _IN_ID_SYS = const(18)  # ASCII device control 2
 _IN_ID_STORE = const(19)  # ASCII device control 3
 _IN_ID_MSG_ERR = const(21)  # ASCII nak
 _IN_ID_BROADCAST_CMD = const(22)
+_IN_ID_RPC_CALL = const(23)
 
 # _IN_IDS = [_IN_ID_START, _IN_ID_END, _IN_ID_MSG_ACK, _IN_ID_RPC, _IN_ID_SYS, _IN_ID_SIGNAL, _IN_ID_MSG_ERR]
 
@@ -35,10 +36,13 @@ _OUT_ID_SYS = const(18)  # ASCII device control 2
 _OUT_ID_MSG_ERR = const(21)  # ASCII nak
 _OUT_ID_DUMP = const(20)
 
+_STORAGE_COMM_TYPE = const(56)  # after the layout device settings
+
 _SYS_CODE_STOP = const(0)
 _SYS_CODE_READY = const(1)
 _SYS_CODE_ALIVE = const(2)
 _SYS_CODE_VERSION = const(3)
+_SYS_CODE_RPC_RESULT = const(4)
 
 VERSION = b"1.1.0"
 
@@ -145,7 +149,7 @@ class IOHub:
         self.output_retries += 1
 
     def set_ready(self):
-        kind = self.get_storage(30)
+        kind = self.get_storage(_STORAGE_COMM_TYPE)
         if kind == 0:
             self.hub.light.on(Color.GREEN)
         if kind == 1:
@@ -215,15 +219,17 @@ class IOHub:
             return
 
         if in_id == _IN_ID_RPC:
-            func_hash = bytes(msg[0:2])
-            arg_bytes = msg[2:-1]
-            func = getattr(self.device, self.device_attrs[func_hash])
-            if len(arg_bytes) > 1:
-                _result = func(arg_bytes)
-            elif len(arg_bytes) == 1:
-                _result = func(arg_bytes[0])
-            else:
-                _result = func()
+            self.call_device(bytes(msg[0:2]), msg[2:-1])
+            return
+
+        if in_id == _IN_ID_RPC_CALL:
+            request_id = bytes(msg[2:6])
+            result = self.call_device(bytes(msg[0:2]), msg[6:-1])
+            if result is None:
+                result = bytes()
+            elif isinstance(result, int):
+                result = bytes([result])
+            self.emit_sys_code(_SYS_CODE_RPC_RESULT, request_id + bytes(result))
             return
 
         if in_id == _IN_ID_STORE:
@@ -236,9 +242,13 @@ class IOHub:
         if in_id == _IN_ID_BROADCAST_CMD:
             assert self.broadcaster
             # print("broadcast cmd", list(msg))
-            device_id = (msg[0] << 8) + msg[1]
-            self.broadcast_states[device_id] = msg[2]
-            self.broadcast_ids.append(device_id)
+            # the host batches several [name_id, state_id, state] triples per input
+            for i in range(0, len(msg) - 2, 3):
+                device_id = (msg[i] << 8) + msg[i + 1]
+                self.broadcast_states[device_id] = msg[i + 2]
+                if device_id in self.broadcast_ids:
+                    self.broadcast_ids.remove(device_id)
+                self.broadcast_ids.append(device_id)
             while len(self.broadcast_ids) > 8:
                 self.broadcast_ids.pop(0)
             broadcast_data = []
@@ -252,6 +262,14 @@ class IOHub:
 
         assert False
 
+    def call_device(self, func_hash, arg_bytes):
+        func = getattr(self.device, self.device_attrs[func_hash])
+        if len(arg_bytes) > 1:
+            return func(arg_bytes)
+        elif len(arg_bytes) == 1:
+            return func(arg_bytes[0])
+        return func()
+
     def get_storage(self, address) -> int:
         data = self.hub.system.storage(address * 4, read=4)
         value = 0
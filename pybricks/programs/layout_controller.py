@@ -1,6 +1,6 @@
 from micropython import const
 
-from pybricks.pupdevices import DCMotor, Motor
+from pybricks.pupdevices import DCMotor, Light, Motor
 from pybricks.parameters import Port
 from pybricks.tools import StopWatch
 
@@ -23,9 +23,14 @@ _DATA_SWITCH_CONFIRM = const(0)
 _STORAGE_PULSE_DC = const(0)
 _STORAGE_PULSE_DURATION = const(1)
 _STORAGE_PULSE_POLARITY = const(2)
+_STORAGE_DEVICE_TYPE = const(3)
+_STORAGE_ANGLE_SERVO = const(3)
+
+_SERVO_SPEED = const(400)
 
 _DEVICE_SWITCH = const(0)
 _DEVICE_CROSSING = const(1)
+_DEVICE_SIGNAL = const(2)
 
 
 def get_device_from_command(command):
@@ -86,12 +91,16 @@ class Crossing:
 
 class Switch:
     def __init__(self, port, pulse_duration=600):
-        try:
-            self.motor = DCMotor(get_port(port))
-        except OSError:
+        self.port = port
+        self.servo = self.get_angle_val(_STORAGE_ANGLE_SERVO) == 1
+        if self.servo:
             self.motor = Motor(get_port(port))
+        else:
+            try:
+                self.motor = DCMotor(get_port(port))
+            except OSError:
+                self.motor = Motor(get_port(port))
         self.position = _SWITCH_POS_NONE
-        self.port = port
         self.pulse_duration = pulse_duration
         self.switch_stopwatch = StopWatch()
         self.switching = False
@@ -106,8 +115,20 @@ class Switch:
     def get_storage_val(self, i):
         return io_hub.get_storage(8 + self.port * 4 + i)
 
+    def get_angle_val(self, i):
+        value = io_hub.get_storage(32 + self.port * 4 + i)
+        # angles are stored as signed 32 bit values
+        if value >= 0x80000000:
+            value -= 0x100000000
+        return value
+
     def switch(self, position):
         # print("Switching to", position)
+        if self.servo:
+            self.motor.run_target(_SERVO_SPEED, self.get_angle_val(position), wait=False)
+            self.switching = True
+            self.position = position
+            return
         sdir = -1
         if position == _SWITCH_POS_RIGHT:
             sdir = 1
@@ -120,19 +141,47 @@ class Switch:
         self.position = position
 
     def update(self, delta):
-        if self.switching and self.switch_stopwatch.time() > self.get_storage_val(
-            _STORAGE_PULSE_DURATION
-        ):
+        if not self.switching:
+            return
+        if self.servo:
+            # keeps holding the angle after reaching it
+            if not self.motor.done():
+                return
+        elif self.switch_stopwatch.time() > self.get_storage_val(_STORAGE_PULSE_DURATION):
             self.motor.stop()
             self.switch_stopwatch.pause()
-            self.switching = False
-            io_hub.emit_data(bytes((_DATA_SWITCH_CONFIRM, self.port, self.position)))
+        else:
+            return
+        self.switching = False
+        io_hub.emit_data(bytes((_DATA_SWITCH_CONFIRM, self.port, self.position)))
 
     def execute(self, data):
         if data[0] == _SWITCH_COMMAND_SWITCH:
             self.switch(data[1])
 
 
+class Signal:
+    def __init__(self, port):
+        self.light = Light(get_port(port))
+        self.aspect = None
+        self.port = port
+        self.device_type = _DEVICE_SIGNAL
+
+    def get_storage_val(self, i):
+        return io_hub.get_storage(8 + self.port * 4 + i)
+
+    def set_state(self, state):
+        # storage holds the brightness for each aspect
+        self.aspect = state
+        self.light.on(self.get_storage_val(state))
+
+    def update(self, delta):
+        pass
+
+    def execute(self, data):
+        pass
+
+
 class Controller:
     def __init__(self):
         self.devices = {}
@@ -145,6 +194,8 @@ class Controller:
             return self.devices[port]
         if device_type == _DEVICE_SWITCH:
             new_device = Switch(port)
+        elif device_type == _DEVICE_SIGNAL:
+            new_device = Signal(port)
         else:
             new_device = Crossing(port)
         self.devices[port] = new_device
@@ -161,7 +212,10 @@ class Controller:
 
     def set_device_state(self, data):
         # print("set_device_state:", list(data))
-        self.ensure_device(data[0], _DEVICE_SWITCH).set_state(data[1])
+        device_type = _DEVICE_SWITCH
+        if io_hub.get_storage(8 + data[0] * 4 + _STORAGE_DEVICE_TYPE) == _DEVICE_SIGNAL:
+            device_type = _DEVICE_SIGNAL
+        self.ensure_device(data[0], device_type).set_state(data[1])
 
 
 controller = Controller()
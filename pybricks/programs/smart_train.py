@@ -1,7 +1,7 @@
 from micropython import const
 from ustruct import pack, pack_into
 
-from pybricks.pupdevices import ColorDistanceSensor, DCMotor, Motor, ColorSensor
+from pybricks.pupdevices import ColorDistanceSensor, DCMotor, Motor, ColorSensor, Light
 from pybricks.parameters import Port
 
 from io_hub_unfrozen import IOHub, VERSION
@@ -205,6 +205,29 @@ class TrainMotor:
             motor.dc(self.speed * polarity)
 
 
+class TrainLights:
+    def __init__(self, motor_ports):
+        self.lights = []
+        for i, port in enumerate(["A", "B", "C", "D", "E", "F"]):
+            if i in motor_ports:
+                continue
+            try:
+                port = getattr(Port, port)
+            except AttributeError:
+                break
+            try:
+                self.lights.append(Light(port))
+            except OSError:
+                continue
+
+    def set(self, on):
+        for light in self.lights:
+            if on:
+                light.on(100)
+            else:
+                light.off()
+
+
 class Route:
     def __init__(self):
         # print("new route")
@@ -312,6 +335,7 @@ class RouteLeg:
 class Train:
     def __init__(self):
         self.motor = TrainMotor()
+        self.lights = TrainLights(self.motor.motor_ports)
 
         try:
             self.sensor = TrainSensor(self.on_marker_passed)
@@ -385,6 +409,9 @@ class Train:
         if state & _STATE_FLAG_RUN:
             self.motor.set_target(io_hub.get_storage(2 + (state & 0x0F)))
 
+    def stop(self):
+        self.motor.set_speed(0)
+
     def new_route(self):
         self.route = Route()
 
@@ -415,6 +442,9 @@ class Train:
         if self.motor is not None:
             self.motor.update(delta)
 
+    def set_lights(self, on):
+        self.lights.set(bool(on))
+
     def set_valid_colors(self, data):
         self.sensor.valid_colors = list(data)
 
@@ -18,7 +18,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let adapter = BLEAdapter::new().await?;
     let name = adapter.discover_hub_name().await?;
     println!("Found hub with name {:?}", name);
-    let mut hub = IOHub::new();
+    let mut hub = IOHub::default();
     hub.discover(name.as_str()).await?;
     hub.connect(&name).await?;
     tokio::time::sleep(std::time::Duration::from_secs(0)).await;